@@ -0,0 +1,240 @@
+//! Anime and manga details from AniList's GraphQL API: titles, episode counts, scores and when
+//! the next episode airs. AniList needs no API key for reads.
+
+use crate::proxy::HttpProxy;
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use std::time::Duration;
+
+const API_URL: &str = "https://graphql.anilist.co";
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+/// Synopses are cut off here; they're background, not the answer.
+const MAX_DESCRIPTION_CHARS: usize = 800;
+
+const MEDIA_QUERY: &str = "
+query ($search: String, $type: MediaType) {
+  Media(search: $search, type: $type, sort: SEARCH_MATCH) {
+    siteUrl
+    title { romaji english native }
+    format
+    status
+    episodes
+    chapters
+    volumes
+    duration
+    averageScore
+    season
+    seasonYear
+    startDate { year month day }
+    endDate { year month day }
+    nextAiringEpisode { episode airingAt }
+    genres
+    studios(isMain: true) { nodes { name } }
+    description(asHtml: false)
+  }
+}";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediaType {
+    Anime,
+    Manga,
+}
+
+impl MediaType {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "anime" => Some(MediaType::Anime),
+            "manga" => Some(MediaType::Manga),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            MediaType::Anime => "ANIME",
+            MediaType::Manga => "MANGA",
+        }
+    }
+}
+
+/// Finds the anime or manga best matching `search`. `None` means AniList has nothing like it.
+pub async fn lookup(proxy: &HttpProxy, search: &str, media_type: MediaType) -> Result<Option<Value>> {
+    lookup_at(proxy, API_URL, search, media_type, Utc::now()).await
+}
+
+async fn lookup_at(
+    proxy: &HttpProxy,
+    api_url: &str,
+    search: &str,
+    media_type: MediaType,
+    now: DateTime<Utc>,
+) -> Result<Option<Value>> {
+    let search = search.trim();
+    if search.is_empty() {
+        bail!("Nothing to look up");
+    }
+    let response = proxy
+        .client()?
+        .post(api_url)
+        .json(&json!({ "query": MEDIA_QUERY, "variables": { "search": search, "type": media_type.as_str() } }))
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await
+        .context("Failed to reach AniList")?;
+    // AniList answers a search without results with a 404 and a "Not Found." error
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let json: Value = response
+        .error_for_status()
+        .context("AniList refused the request")?
+        .json()
+        .await
+        .context("AniList sent invalid JSON")?;
+    if let Some(error) = json["errors"][0]["message"].as_str() {
+        bail!("AniList answered with an error: {}", error);
+    }
+    let media = &json["data"]["Media"];
+    Ok((!media.is_null()).then(|| describe_media(media, now)))
+}
+
+/// Trims AniList's answer down to what's worth telling: nulls and empty lists go, dates are
+/// joined up, and the next episode's air time is given in UTC and as a countdown from `now`.
+fn describe_media(media: &Value, now: DateTime<Utc>) -> Value {
+    let mut described = json!({
+        "title": media["title"]["english"].as_str().or(media["title"]["romaji"].as_str()),
+        "romaji_title": media["title"]["romaji"],
+        "native_title": media["title"]["native"],
+        "url": media["siteUrl"],
+        "format": media["format"],
+        "status": media["status"],
+        "episodes": media["episodes"],
+        "episode_minutes": media["duration"],
+        "chapters": media["chapters"],
+        "volumes": media["volumes"],
+        "score": media["averageScore"].as_u64().map(|score| format!("{}/100", score)),
+        "season": media["season"].as_str().zip(media["seasonYear"].as_u64()).map(|(season, year)| format!("{} {}", season, year)),
+        "started": format_date(&media["startDate"]),
+        "ended": format_date(&media["endDate"]),
+        "genres": media["genres"],
+        "studios": media["studios"]["nodes"].as_array().map(|nodes| nodes.iter().filter_map(|n| n["name"].as_str()).collect::<Vec<_>>()),
+        "description": media["description"].as_str().map(clean_description),
+    });
+    let next = &media["nextAiringEpisode"];
+    if let Some(airing_at) = next["airingAt"].as_i64().and_then(|t| DateTime::from_timestamp(t, 0)) {
+        described["next_episode"] = json!({
+            "episode": next["episode"],
+            "airs_at": airing_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+            "airs_in": format_countdown(airing_at - now),
+        });
+    }
+    let object = described.as_object_mut().unwrap();
+    object.retain(|_, value| !(value.is_null() || value.as_array().is_some_and(Vec::is_empty)));
+    described
+}
+
+/// AniList's fuzzy dates can be missing the day or month, or be all nulls.
+fn format_date(date: &Value) -> Option<String> {
+    let year = date["year"].as_u64()?;
+    Some(match (date["month"].as_u64(), date["day"].as_u64()) {
+        (Some(month), Some(day)) => format!("{}-{:02}-{:02}", year, month, day),
+        (Some(month), None) => format!("{}-{:02}", year, month),
+        _ => year.to_string(),
+    })
+}
+
+/// "in 2d 5h", "in 40m" or "now".
+fn format_countdown(until: chrono::Duration) -> String {
+    let minutes = until.num_minutes();
+    if minutes <= 0 {
+        return "now".to_string();
+    }
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    if days > 0 {
+        format!("in {}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("in {}h {}m", hours, minutes)
+    } else {
+        format!("in {}m", minutes)
+    }
+}
+
+/// Descriptions still carry some HTML even when asked for plain text, mostly <br> and <i>.
+fn clean_description(description: &str) -> String {
+    let with_breaks = description.replace("<br>", "\n").replace("<br/>", "\n").replace("<br />", "\n");
+    let text: String = scraper::Html::parse_fragment(&with_breaks).root_element().text().collect();
+    let text = text.trim();
+    if text.chars().count() > MAX_DESCRIPTION_CHARS {
+        format!("{}…", text.chars().take(MAX_DESCRIPTION_CHARS).collect::<String>().trim_end())
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn frieren() -> Value {
+        json!({
+            "siteUrl": "https://anilist.co/anime/182255",
+            "title": {"romaji": "Sousou no Frieren 2nd Season", "english": null, "native": "葬送のフリーレン 第2期"},
+            "format": "TV",
+            "status": "RELEASING",
+            "episodes": 10,
+            "chapters": null,
+            "volumes": null,
+            "duration": 24,
+            "averageScore": 89,
+            "season": "WINTER",
+            "seasonYear": 2026,
+            "startDate": {"year": 2026, "month": 1, "day": 16},
+            "endDate": {"year": null, "month": null, "day": null},
+            "nextAiringEpisode": {"episode": 4, "airingAt": 1770966000},
+            "genres": ["Adventure", "Drama", "Fantasy"],
+            "studios": {"nodes": [{"name": "MADHOUSE"}]},
+            "description": "The second season of <i>Sousou no Frieren</i>.<br><br>\n(Source: Crunchyroll)"
+        })
+    }
+
+    #[test]
+    fn test_describe_media() {
+        let now = Utc.with_ymd_and_hms(2026, 2, 10, 12, 0, 0).unwrap();
+        let described = describe_media(&frieren(), now);
+        assert_eq!(described["title"], "Sousou no Frieren 2nd Season");
+        assert_eq!(described["score"], "89/100");
+        assert_eq!(described["season"], "WINTER 2026");
+        assert_eq!(described["started"], "2026-01-16");
+        assert_eq!(described["studios"], json!(["MADHOUSE"]));
+        assert_eq!(described["next_episode"], json!({"episode": 4, "airs_at": "2026-02-13 07:00 UTC", "airs_in": "in 2d 19h"}));
+        assert_eq!(described["description"], "The second season of Sousou no Frieren.\n\n\n(Source: Crunchyroll)");
+        // Unknown values are left out rather than sent as nulls
+        assert!(described.get("ended").is_none());
+        assert!(described.get("chapters").is_none());
+    }
+
+    #[test]
+    fn test_format_date_and_countdown() {
+        assert_eq!(format_date(&json!({"year": 2024, "month": 4, "day": null})), Some("2024-04".to_string()));
+        assert_eq!(format_date(&json!({"year": 2024, "month": null, "day": null})), Some("2024".to_string()));
+        assert_eq!(format_date(&json!({"year": null})), None);
+        assert_eq!(format_countdown(chrono::Duration::minutes(-5)), "now");
+        assert_eq!(format_countdown(chrono::Duration::minutes(45)), "in 45m");
+        assert_eq!(format_countdown(chrono::Duration::minutes(125)), "in 2h 5m");
+    }
+
+    #[tokio::test]
+    async fn test_lookup_not_found() {
+        let mut server = mockito::Server::new_async().await;
+        let _not_found = server
+            .mock("POST", "/")
+            .with_status(404)
+            .with_body(r#"{"errors": [{"message": "Not Found.", "status": 404}], "data": {"Media": null}}"#)
+            .create_async()
+            .await;
+        let found = lookup_at(&HttpProxy::default(), &server.url(), "no such show", MediaType::Anime, Utc::now()).await.unwrap();
+        assert_eq!(found, None);
+    }
+}
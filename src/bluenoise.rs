@@ -1,4 +1,9 @@
+use anyhow::{Result, bail};
+use chrono::{DateTime, Timelike, Utc};
+use chrono_tz::Tz;
 use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 // Removed unused: use rand::Rng;
 
@@ -118,6 +123,77 @@ impl BlueNoiseInterjecterInner {
     }
 }
 
+/// Interjections are this much rarer during a channel's quiet hours...
+pub const QUIET_HOURS_SCALE: f64 = 0.25;
+/// ...and this much more common during its peak hours.
+pub const PEAK_HOURS_SCALE: f64 = 2.0;
+
+/// Whole hours of the day, from `start` up to but not including `end`, like "2-8" for 2am to
+/// 8am. Ranges like "22-6" wrap past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HourRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl HourRange {
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&hour)
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+impl FromStr for HourRange {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let parsed = value.split_once('-').and_then(|(start, end)| {
+            Some(HourRange { start: start.trim().parse().ok()?, end: end.trim().parse().ok()? })
+        });
+        match parsed {
+            Some(range) if range.start < 24 && range.end <= 24 && range.start != range.end => Ok(range),
+            _ => bail!("Expected hours like 2-8 (2am to 8am) or 22-6, not \"{}\"", value),
+        }
+    }
+}
+
+impl fmt::Display for HourRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// When a channel is quiet or busy, by the clock in its time zone. Interjection chances are
+/// scaled down during quiet hours and up during peak hours; quiet hours win where both overlap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterjectionSchedule {
+    pub timezone: Tz,
+    pub quiet_hours: Option<HourRange>,
+    pub peak_hours: Option<HourRange>,
+}
+
+impl Default for InterjectionSchedule {
+    fn default() -> Self {
+        Self { timezone: Tz::UTC, quiet_hours: None, peak_hours: None }
+    }
+}
+
+impl InterjectionSchedule {
+    /// What to multiply the interjection chance by at `now`.
+    pub fn scale(&self, now: DateTime<Utc>) -> f64 {
+        let hour = now.with_timezone(&self.timezone).hour();
+        if self.quiet_hours.is_some_and(|quiet| quiet.contains(hour)) {
+            QUIET_HOURS_SCALE
+        } else if self.peak_hours.is_some_and(|peak| peak.contains(hour)) {
+            PEAK_HOURS_SCALE
+        } else {
+            1.0
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -258,4 +334,34 @@ mod tests {
         drop(inner);
     }
 
+    #[test]
+    fn test_hour_ranges() {
+        let night: HourRange = "22-6".parse().unwrap();
+        assert!(night.contains(23) && night.contains(0) && night.contains(5));
+        assert!(!night.contains(6) && !night.contains(12));
+        let morning: HourRange = " 2 - 8 ".parse().unwrap();
+        assert_eq!(morning, HourRange { start: 2, end: 8 });
+        assert_eq!(morning.to_string(), "2-8");
+        assert!(morning.contains(2) && !morning.contains(8));
+        assert!("18-24".parse::<HourRange>().unwrap().contains(23));
+        for bad in ["8", "5-5", "24-2", "2-25", "two-eight"] {
+            assert!(bad.parse::<HourRange>().is_err(), "{} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn test_schedule_scale() {
+        let schedule = InterjectionSchedule {
+            timezone: "Europe/Oslo".parse().unwrap(),
+            quiet_hours: Some("2-8".parse().unwrap()),
+            peak_hours: Some("18-23".parse().unwrap()),
+        };
+        let at = |time: &str| DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc);
+        // 03:00 and 19:00 in Oslo, in winter
+        assert_eq!(schedule.scale(at("2025-01-15T02:00:00Z")), QUIET_HOURS_SCALE);
+        assert_eq!(schedule.scale(at("2025-01-15T18:00:00Z")), PEAK_HOURS_SCALE);
+        assert_eq!(schedule.scale(at("2025-01-15T12:00:00Z")), 1.0);
+        assert_eq!(InterjectionSchedule::default().scale(at("2025-01-15T02:00:00Z")), 1.0);
+    }
+
 }
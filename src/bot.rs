@@ -1,61 +1,815 @@
 use crate::ai_handler;
 use crate::bluenoise::BlueNoiseInterjecter;
-use crate::config::{Config, RANDOM_INTERJECT_CHANCE, RANDOM_INTERJECT_CHANCE_IF_MENTIONED};
-use crate::db::{self, DbConnection};
-use anyhow::Result;
+use crate::bot_guard::{self, ExchangeCounter};
+use crate::channel_settings::{self, ChannelSettings};
+use crate::commands::{self, CommandRegistry, Dispatch, Invocation, Permission};
+use crate::config::{
+    Config, LOG_HISTORY_LINES, RANDOM_INTERJECT_CHANCE, RANDOM_INTERJECT_CHANCE_IF_MENTIONED, RESTART_REQUIRED_SETTINGS,
+    TransportKind,
+};
+use crate::ctcp::{self, Ctcp};
+use crate::db::{self, DbPool};
+use crate::dice;
+use crate::export::{self, ExportFormat};
+use crate::formatting::{self, Formatting};
+use crate::health::{self, Health};
+use crate::image_cache::{IMAGE_CACHE_SIZE, ImageCache};
+use crate::karma;
+use crate::llm::{self, LlmBackend, TokenUsage, UsageMeter};
+use crate::memory::{self, MemoryGuard};
+use crate::moderation::{self, ModerationAction, ModerationGuard};
+use crate::notify;
+use crate::outgoing::{NickBuckets, OutgoingQueue, Priority, TokenBucket};
+use crate::output_filter::OutputFilter;
+use crate::page_cache::PageCache;
+use crate::paste;
+use crate::proxy::{self, HttpProxy};
+use crate::quotes;
+use crate::relay::{self, EchoGuard};
+use crate::repetition::RecentResponses;
+use crate::response_cache::ResponseCache;
+use crate::roster::{self, Roster};
+use crate::rss;
+use crate::sanitize::{self, UNTRUSTED_CONTENT_NOTICE};
+use crate::scheduler;
+use crate::services::{LOGIN_TIMEOUT, Services, ServicesEvent};
+use crate::stats;
+use crate::summary;
+use crate::tool_log;
+use crate::tools::{ImageBudget, ToolRegistry};
+use crate::torrent_client;
+use crate::transport::{self, ChatTransport, IncomingMessage, IrcTransport, ReplyTransport};
+use crate::url_titles;
+use crate::wasm_tools;
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
 use futures::prelude::*;
 use irc::client::prelude::*;
-use lru::LruCache;
-use std::collections::{HashMap, HashSet}; // Added HashMap
-use std::num::NonZeroUsize;
-use std::sync::Arc;
+use irc::proto::{BatchSubCommand, CapSubCommand};
+use ::notify::Watcher; // The file watcher crate, not crate::notify
+use std::collections::{HashMap, HashSet, VecDeque}; // Added HashMap
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, RwLock};
 use std::time::{Duration, Instant}; // Added Instant
-use tokio::sync::Mutex;
-use tokio::time::sleep;
+use tokio::sync::{Mutex, mpsc};
+use tokio::time::{sleep, timeout};
+use tracing::Instrument;
+use unicode_segmentation::UnicodeSegmentation;
 
-// Type alias for the image cache: URL -> (MimeType, Base64Data)
-pub type ImageCache = Arc<Mutex<LruCache<String, (String, String)>>>; // Make public
-const IMAGE_CACHE_SIZE: usize = 20; // Store info for the last 20 image URLs
 const MESSAGE_BUFFER_TIMEOUT: Duration = Duration::from_millis(1500); // 1.5 seconds
 const MESSAGE_SWEEPER_INTERVAL: Duration = Duration::from_millis(500); // Check every 0.5 seconds
+const SETTINGS_RELOAD_DELAY: Duration = Duration::from_millis(500); // After a settings file changes
+const REPLY_FOLLOWUP_WINDOW: Duration = Duration::from_secs(10 * 60); // How long an answer can be followed up on
+const TYPING_INTERVAL: Duration = Duration::from_secs(3); // IRCv3 clients drop a typing notice after 6 seconds
+const INTERJECTION_CHECK_LINES: usize = 15; // Recent lines read before deciding to interject
+const CTCP_REPLY_BURST: u32 = 3; // CTCP queries one nick has answered at once
+const CTCP_REPLY_INTERVAL: Duration = Duration::from_secs(10); // And how often another after that
+const PUBLIC_COMMAND_BURST: u32 = 10; // Public commands one nick may run at once
+const PUBLIC_COMMAND_INTERVAL: Duration = Duration::from_secs(3); // And how often another after that
+const PUBLIC_REPLY_MAX_LINES: usize = 3; // Lines a public command's answer may take up
+const AI_QUEUE_IDLE_TIME: Duration = Duration::from_secs(10 * 60); // A channel's AI queue stops after this long unused
 
 // Holds message fragments while waiting for potential continuations
 struct BufferedMessage {
     message: String,
     last_arrival: Instant,
+    stamp: MessageStamp, // The first fragment's
 }
 
+/// How long after joining a channel, or asking for its history, lines stamped from before then
+/// are taken for the server replaying them, as a ZNC does with its buffer.
+const REPLAY_WINDOW: Duration = Duration::from_secs(30);
+/// The batch types a server replays history in.
+const HISTORY_BATCH_TYPES: &[&str] = &["chathistory", "draft/chathistory", "znc.in/playback"];
+
+/// When, and under what id, the server says a message was sent, from its IRCv3 server-time and
+/// msgid tags. Messages without them, and from other networks, are stamped when they arrive.
+#[derive(Debug, Clone, PartialEq)]
+struct MessageStamp {
+    time: chrono::DateTime<chrono::Utc>,
+    msgid: Option<String>,
+    /// Whether the server is replaying the message from its history rather than passing it on
+    /// as it's said; see `Replays`.
+    replayed: bool,
+    /// The services account the sender is logged in to, from the IRCv3 account tag.
+    account: Option<String>,
+}
+
+impl MessageStamp {
+    fn now() -> Self {
+        MessageStamp { time: chrono::Utc::now(), msgid: None, replayed: false, account: None }
+    }
+
+    fn of(message: &Message, replays: &Replays) -> Self {
+        let tag = |name: &str| {
+            message.tags.iter().flatten().find(|tag| tag.0 == name).and_then(|tag| tag.1.clone())
+        };
+        let time = tag("time").and_then(|time| chrono::DateTime::parse_from_rfc3339(&time).ok()).map(|time| time.to_utc());
+        let target = match &message.command {
+            Command::PRIVMSG(target, _) | Command::NOTICE(target, _) => target.as_str(),
+            _ => "",
+        };
+        let replayed = replays.is_replayed(target, tag("batch").as_deref(), time, Instant::now());
+        MessageStamp { time: time.unwrap_or_else(chrono::Utc::now), msgid: tag("msgid"), replayed, account: tag("account") }
+    }
+}
+
+/// What the server is replaying from its history rather than passing on as it's said: lines in
+/// a CHATHISTORY or ZNC playback batch, and channel lines stamped from before the bot joined (or
+/// asked for the history) that arrive just after, as a ZNC replays its buffer. Private messages
+/// only count in a batch, so a server with a wrong clock can't get an admin's command dropped.
+#[derive(Default)]
+struct Replays {
+    /// The reference tags of the history batches open.
+    batches: std::sync::Mutex<HashSet<String>>,
+    /// When each (lowercase) channel was joined or asked for its history, by the server's clock
+    /// and ours.
+    channels: std::sync::Mutex<HashMap<String, (chrono::DateTime<chrono::Utc>, Instant)>>,
+}
+
+impl Replays {
+    /// Takes note of what `message` says about replays, and stamps it. Each message goes through
+    /// here as it's read, in order, before its handling is spawned off; otherwise the lines of a
+    /// history batch could be handled before its start or after its end. A BATCH needs no more
+    /// handling, so it gives None.
+    fn read(&self, message: &Message, services: &Services) -> Option<MessageStamp> {
+        let stamp = MessageStamp::of(message, self);
+        match &message.command {
+            Command::BATCH(reference, kind, _) => {
+                self.batch(reference, kind.as_ref().map(BatchSubCommand::to_str));
+                return None;
+            }
+            Command::JOIN(channel, _, _) if message.source_nickname().is_some_and(|nick| services.is_me(nick)) => {
+                // A ZNC replays its buffer right after this; those lines are stamped from before it
+                self.expect(channel, stamp.time, Instant::now());
+            }
+            _ => {}
+        }
+        Some(stamp)
+    }
+
+    /// Follows a BATCH command: `+reference type` opens a batch, `-reference` closes it.
+    fn batch(&self, reference: &str, kind: Option<&str>) {
+        let mut batches = self.batches.lock().unwrap();
+        if let Some(reference) = reference.strip_prefix('-') {
+            batches.remove(reference);
+        } else if let Some(reference) = reference.strip_prefix('+')
+            && kind.is_some_and(|kind| HISTORY_BATCH_TYPES.iter().any(|history| history.eq_ignore_ascii_case(kind)))
+        {
+            batches.insert(reference.to_string());
+        }
+    }
+
+    /// Notes that `channel` was joined, or asked for its history, at `since` by the server's clock.
+    fn expect(&self, channel: &str, since: chrono::DateTime<chrono::Utc>, now: Instant) {
+        let mut channels = self.channels.lock().unwrap();
+        channels.retain(|_, (_, at)| now.duration_since(*at) < REPLAY_WINDOW);
+        channels.insert(channel.to_lowercase(), (since, now));
+    }
+
+    fn is_replayed(&self, target: &str, batch: Option<&str>, time: Option<chrono::DateTime<chrono::Utc>>, now: Instant) -> bool {
+        if batch.is_some_and(|batch| self.batches.lock().unwrap().contains(batch)) {
+            return true;
+        }
+        let (Some(time), true) = (time, target.starts_with('#')) else {
+            return false;
+        };
+        let channels = self.channels.lock().unwrap();
+        channels
+            .get(&target.to_lowercase())
+            .is_some_and(|(since, at)| now.duration_since(*at) < REPLAY_WINDOW && time < *since)
+    }
+}
+
+/// The IRCv3 capabilities asked for on connecting. Each is asked for on its own, since a server
+/// refuses a whole request if it lacks any of them.
+const IRC_CAPABILITIES: &[Capability] = &[
+    Capability::ServerTime,
+    Capability::EchoMessage,
+    Capability::Custom("message-tags"),
+    Capability::Custom("account-tag"),
+    Capability::Custom("batch"),
+    Capability::Custom("draft/chathistory"),
+    Capability::Custom("znc.in/playback"),
+];
+
+const USER_RATE_WINDOW: Duration = Duration::from_secs(60);
+const CHANNEL_RATE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Sliding-window limits on AI requests per user (per minute) and per channel (per hour).
+/// A limit of 0 disables that check.
+struct RateLimiter {
+    user_limit: usize,
+    channel_limit: usize,
+    user_requests: HashMap<(String, String), VecDeque<Instant>>,
+    channel_requests: HashMap<String, VecDeque<Instant>>,
+    /// Users who were already told to slow down during their current cooldown.
+    notified: HashSet<(String, String)>,
+}
+
+enum RateLimitVerdict {
+    Allowed,
+    /// Over the limit; `notify` is true the first time, so the cooldown message isn't repeated.
+    Limited { retry_after: Duration, notify: bool },
+}
+
+impl RateLimiter {
+    fn new(user_limit: usize, channel_limit: usize) -> Self {
+        Self {
+            user_limit,
+            channel_limit,
+            user_requests: HashMap::new(),
+            channel_requests: HashMap::new(),
+            notified: HashSet::new(),
+        }
+    }
+
+    fn set_limits(&mut self, user_limit: usize, channel_limit: usize) {
+        self.user_limit = user_limit;
+        self.channel_limit = channel_limit;
+    }
+
+    /// Checks (and if allowed, records) a request. Random interjections pass `nick: None`
+    /// and only count against the channel.
+    fn check(&mut self, channel: &str, nick: Option<&str>, now: Instant) -> RateLimitVerdict {
+        // Forget whoever has nothing left in the window, so the maps don't keep every nick seen
+        let in_window = |requests: &VecDeque<Instant>, window| requests.back().is_some_and(|&t| now.duration_since(t) < window);
+        self.user_requests.retain(|_, requests| in_window(requests, USER_RATE_WINDOW));
+        self.channel_requests.retain(|_, requests| in_window(requests, CHANNEL_RATE_WINDOW));
+        self.notified.retain(|key| self.user_requests.contains_key(key));
+
+        let channel_key = channel.to_lowercase();
+        let user_key = nick.map(|n| (channel_key.clone(), n.to_lowercase()));
+
+        let channel_wait = Self::wait_time(
+            self.channel_requests.entry(channel_key.clone()).or_default(),
+            self.channel_limit,
+            CHANNEL_RATE_WINDOW,
+            now,
+        );
+        let user_wait = user_key.as_ref().and_then(|key| {
+            Self::wait_time(
+                self.user_requests.entry(key.clone()).or_default(),
+                self.user_limit,
+                USER_RATE_WINDOW,
+                now,
+            )
+        });
+
+        if let Some(retry_after) = channel_wait.max(user_wait) {
+            let notify = match &user_key {
+                Some(key) => self.notified.insert(key.clone()),
+                None => false,
+            };
+            return RateLimitVerdict::Limited { retry_after, notify };
+        }
+
+        self.channel_requests.entry(channel_key).or_default().push_back(now);
+        if let Some(key) = user_key {
+            self.notified.remove(&key);
+            self.user_requests.entry(key).or_default().push_back(now);
+        }
+        RateLimitVerdict::Allowed
+    }
+
+    /// Drops requests that left the window, then returns how long until another is allowed,
+    /// or None if one is allowed now.
+    fn wait_time(requests: &mut VecDeque<Instant>, limit: usize, window: Duration, now: Instant) -> Option<Duration> {
+        while requests.front().is_some_and(|&t| now.duration_since(t) >= window) {
+            requests.pop_front();
+        }
+        if limit == 0 || requests.len() < limit {
+            return None;
+        }
+        requests.front().map(|&oldest| window - now.duration_since(oldest))
+    }
+}
+
+/// Interjecters for channels with their own chances: (channel, mention?) -> (chance, interjecter)
+type ChannelInterjecters = HashMap<(String, bool), (f64, BlueNoiseInterjecter)>;
+
 // Shared state for the bot
 #[derive(Clone)]
-pub struct BotState { // Make struct public too, as ImageCache is used in its field
-    config: Arc<Config>,
-    db_conn: DbConnection,
+pub struct BotState {
+    settings: Arc<RwLock<Arc<Settings>>>, // Replaced by !reload and the file watcher
+    db: DbPool,
     current_channels: Arc<Mutex<HashSet<String>>>, // Channels bot is currently in
-    prompt_path: Arc<std::path::PathBuf>, // Path to the prompt file
     bn_interject: BlueNoiseInterjecter,
     bn_interject_mention: BlueNoiseInterjecter,
-    image_cache: ImageCache,
+    channel_interjecters: Arc<Mutex<ChannelInterjecters>>,
+    image_cache: Arc<ImageCache>,
+    proxy: HttpProxy, // How outbound HTTP is sent, from --proxy
+    page_cache: Arc<PageCache>,
+    response_cache: Arc<ResponseCache>,
+    roster: Arc<Roster>, // Who's in the IRC channels we're in
+    health: Arc<Health>, // What /healthz reports
+    transports: Arc<RwLock<Vec<Arc<dyn ChatTransport>>>>, // The networks connected, for relaying between them
+    relay_guard: Arc<EchoGuard>, // Lines relayed lately, to notice them coming back
+    replays: Arc<Replays>, // What the IRC server is replaying from its history
+    ctcp_limiter: Arc<NickBuckets>, // How many CTCP queries each nick may have answered
+    command_limiter: Arc<NickBuckets>, // How many public commands each nick may run
+    moderation: Arc<ModerationGuard>, // Whose messages are being checked against the rules, and who was warned lately
+    memories: Arc<MemoryGuard>, // Channels whose log is being embedded into long-term memory
+    recent_responses: Arc<RecentResponses>, // What the AI said lately in each channel, so it doesn't repeat itself
+    exchanges: Arc<ExchangeCounter>, // Who the AI keeps answering, to stop endless talks with other bots
+    builtin_tools: Arc<ToolRegistry>, // Tools compiled into the bot
+    tools: Arc<Mutex<Arc<ToolRegistry>>>, // Built-in tools plus WASM plugins; replaced by !reloadtools
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    // Per-channel AI request queues, so requests in a channel are answered one at a time, in order
+    ai_queues: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<AiRequest>>>>,
+    // The last AI answer per channel and when it was sent, so follow-ups can refer back to it
+    last_replies: Arc<Mutex<HashMap<String, (Instant, ai_handler::PreviousReply)>>>,
     // Buffer for potentially fragmented messages: (Channel, Nick) -> BufferedMessage
     message_buffer: Arc<Mutex<HashMap<(String, String), BufferedMessage>>>,
+    // Given to BotBuilder in place of the configured backend and the prompt file; kept on reload
+    llm_override: Option<Arc<dyn LlmBackend>>,
+    prompt_override: Option<String>,
 }
 
 const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(5);
 const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300); // 5 minutes
 
-pub async fn run_bot(config: Config, db_conn: DbConnection) -> Result<()> {
+impl BotState {
+    async fn new(
+        config: Config,
+        db: DbPool,
+        builtin_tools: ToolRegistry,
+        proxy: HttpProxy,
+        llm_override: Option<Arc<dyn LlmBackend>>,
+        prompt_override: Option<String>,
+    ) -> Result<Self> {
+        let builtin_tools = Arc::new(builtin_tools);
+        let tools = load_tools(&builtin_tools, config.wasm_tools_dir.as_deref(), &proxy)?;
+        let prompt = load_prompt(&config, prompt_override.as_deref()).await?;
+        let image_cache = Arc::new(ImageCache::new(
+            IMAGE_CACHE_SIZE,
+            &config.image_cache_dir,
+            Duration::from_secs(config.image_cache_ttl_hours * 60 * 60),
+        ));
+        Ok(BotState {
+            builtin_tools,
+            tools: Arc::new(Mutex::new(Arc::new(tools))),
+            rate_limiter: Arc::new(Mutex::new(RateLimiter::new(
+                config.user_rate_limit,
+                config.channel_rate_limit,
+            ))),
+            settings: Arc::new(RwLock::new(Arc::new(Settings::new(config, prompt, llm_override.clone(), &proxy)?))),
+            db,
+            current_channels: Arc::new(Mutex::new(HashSet::new())),
+            bn_interject: BlueNoiseInterjecter::new(RANDOM_INTERJECT_CHANCE),
+            bn_interject_mention: BlueNoiseInterjecter::new(RANDOM_INTERJECT_CHANCE_IF_MENTIONED),
+            channel_interjecters: Arc::new(Mutex::new(HashMap::new())),
+            image_cache,
+            proxy,
+            page_cache: Arc::new(PageCache::default()),
+            response_cache: Arc::new(ResponseCache::default()),
+            roster: Arc::new(Roster::default()),
+            health: Arc::new(Health::default()),
+            transports: Arc::new(RwLock::new(Vec::new())),
+            relay_guard: Arc::new(EchoGuard::default()),
+            replays: Arc::new(Replays::default()),
+            ctcp_limiter: Arc::new(NickBuckets::new(CTCP_REPLY_BURST, CTCP_REPLY_INTERVAL)),
+            command_limiter: Arc::new(NickBuckets::new(PUBLIC_COMMAND_BURST, PUBLIC_COMMAND_INTERVAL)),
+            moderation: Arc::new(ModerationGuard::default()),
+            memories: Arc::new(MemoryGuard::default()),
+            recent_responses: Arc::new(RecentResponses::default()),
+            exchanges: Arc::new(ExchangeCounter::default()),
+            ai_queues: Arc::new(Mutex::new(HashMap::new())),
+            last_replies: Arc::new(Mutex::new(HashMap::new())),
+            message_buffer: Arc::new(Mutex::new(HashMap::new())), // Initialize buffer
+            llm_override,
+            prompt_override,
+        })
+    }
+
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    fn config(&self) -> Arc<Config> {
+        self.settings().config.clone()
+    }
+
+    fn llm(&self) -> Arc<dyn LlmBackend> {
+        self.settings().llm.clone()
+    }
+
+    /// Sends messages for the transport's channels through it from now on, in place of any
+    /// earlier connection to the same network.
+    fn connected(&self, transport: Arc<dyn ChatTransport>) {
+        let mut transports = self.transports.write().unwrap();
+        transports.retain(|known| known.name() != transport.name());
+        transports.push(transport);
+    }
+
+    /// The connected network `channel` is on.
+    fn transport_for(&self, channel: &str) -> Option<Arc<dyn ChatTransport>> {
+        self.transports.read().unwrap().iter().find(|transport| transport.serves(channel)).cloned()
+    }
+
+    /// Rolls for an interjection in a channel. Channels at the default chance share the global
+    /// interjecter, so `!interject` still works there; the others get one of their own.
+    async fn should_interject(&self, channel: &str, chance: f64, mention: bool) -> bool {
+        let (default, global) = match mention {
+            true => (RANDOM_INTERJECT_CHANCE_IF_MENTIONED, &self.bn_interject_mention),
+            false => (RANDOM_INTERJECT_CHANCE, &self.bn_interject),
+        };
+        if chance <= 0.0 {
+            return false;
+        }
+        if chance == default {
+            return global.should_interject();
+        }
+        let mut interjecters = self.channel_interjecters.lock().await;
+        let entry = interjecters
+            .entry((channel.to_lowercase(), mention))
+            .or_insert_with(|| (chance, BlueNoiseInterjecter::new(chance)));
+        if entry.0 != chance {
+            *entry = (chance, BlueNoiseInterjecter::new(chance));
+        }
+        entry.1.should_interject()
+    }
+}
+
+/// Everything derived from the config and prompt files, swapped out as a whole on reload.
+struct Settings {
+    config: Arc<Config>,
+    prompt: String,
+    output_filter: Arc<OutputFilter>,
+    llm: Arc<dyn LlmBackend>,
+}
+
+impl Settings {
+    fn new(config: Config, prompt: String, llm: Option<Arc<dyn LlmBackend>>, proxy: &HttpProxy) -> Result<Self> {
+        let llm = match llm {
+            Some(llm) => llm,
+            None => llm::backend_from_config(&config, proxy)?,
+        };
+        Ok(Settings {
+            output_filter: Arc::new(OutputFilter::from_config(&config)),
+            llm,
+            config: Arc::new(config),
+            prompt,
+        })
+    }
+}
+
+/// Re-reads the config and prompt files and swaps in the new settings, returning the names of
+/// what changed. Settings that are only read at startup keep their running values.
+async fn reload_settings(state: &BotState) -> Result<Vec<String>> {
+    let old = state.settings();
+    let mut config = old.config.reload()?;
+    let prompt = load_prompt(&config, state.prompt_override.as_deref()).await?;
+
+    let mut changes: Vec<String> = old
+        .config
+        .changed_settings(&config)
+        .into_iter()
+        .map(|name| match RESTART_REQUIRED_SETTINGS.contains(&name) {
+            true => format!("{} (needs restart)", name),
+            false => name.to_string(),
+        })
+        .collect();
+    if prompt != old.prompt {
+        changes.push("prompt".to_string());
+    }
+    config.keep_startup_settings(&old.config);
+
+    state.rate_limiter.lock().await.set_limits(config.user_rate_limit, config.channel_rate_limit);
+    *state.settings.write().unwrap() = Arc::new(Settings::new(config, prompt, state.llm_override.clone(), &state.proxy)?);
+    Ok(changes)
+}
+
+/// The system prompt: the given one, or else the prompt file's.
+async fn load_prompt(config: &Config, prompt_override: Option<&str>) -> Result<String> {
+    match prompt_override {
+        Some(prompt) => Ok(prompt.to_string()),
+        None => ai_handler::read_prompt_file(&config.prompt_path()).await,
+    }
+}
+
+/// Watches the prompt and config files, reloading the settings shortly after either is edited.
+/// The watcher stops when the returned handle is dropped.
+fn watch_settings_files(state: &BotState) -> ::notify::Result<::notify::RecommendedWatcher> {
+    let config = state.config();
+    let prompt_file = state.prompt_override.is_none().then(|| config.prompt_path());
+    let files: Vec<PathBuf> = prompt_file.into_iter().chain(config.config.clone()).collect();
+    let file_names: HashSet<OsString> = files.iter().filter_map(|f| f.file_name().map(Into::into)).collect();
+
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+    let mut watcher = ::notify::recommended_watcher(move |event: ::notify::Result<::notify::Event>| {
+        if let Ok(event) = event
+            && (event.kind.is_create() || event.kind.is_modify())
+            && event.paths.iter().any(|path| path.file_name().is_some_and(|name| file_names.contains(name)))
+        {
+            let _ = event_tx.send(());
+        }
+    })?;
+    // Editors often save by replacing the file, which a watch on the file itself would miss
+    for file in &files {
+        let dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        watcher.watch(dir, ::notify::RecursiveMode::NonRecursive)?;
+    }
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        while event_rx.recv().await.is_some() {
+            // Let the editor finish writing, and fold a burst of events into one reload
+            sleep(SETTINGS_RELOAD_DELAY).await;
+            while event_rx.try_recv().is_ok() {}
+            match reload_settings(&state).await {
+                Ok(changes) if changes.is_empty() => {}
+                Ok(changes) => tracing::info!(changed = %changes.join(", "), "Settings files changed, reloaded"),
+                Err(e) => tracing::warn!("Failed to reload settings, keeping the old ones: {:#}", e),
+            }
+        }
+    });
+    Ok(watcher)
+}
+
+/// The built-in tools plus any WASM plugins in `wasm_tools_dir`, whose HTTP goes through `proxy`.
+fn load_tools(builtin_tools: &ToolRegistry, wasm_tools_dir: Option<&std::path::Path>, proxy: &HttpProxy) -> Result<ToolRegistry> {
+    match wasm_tools_dir {
+        Some(dir) => Ok(builtin_tools.with_plugins(wasm_tools::load_tools_dir(dir, proxy)?)),
+        None => Ok(builtin_tools.clone()),
+    }
+}
+
+/// Whether a message that mentions the bot in passing is really aimed at it, by the fast model.
+async fn mention_wants_answer(state: &BotState, channel: &str, message: &str) -> Result<bool> {
+    let llm = state.llm();
+    let llm = UsageMeter::new(&*llm);
+    let mentioned = ai_handler::chatbot_mentioned(&llm, &state.config().nickname, message).await;
+    record_usage(state, channel, &llm).await;
+    mentioned
+}
+
+/// Charges the tokens `meter` counted since it was last read to `channel`, against today's
+/// budget. Failures are only logged.
+async fn record_usage(state: &BotState, channel: &str, meter: &UsageMeter<'_>) {
+    let usage = meter.take();
+    if usage == TokenUsage::default() {
+        return;
+    }
+    let (channel, day) = (channel.to_string(), chrono::Utc::now().format("%Y-%m-%d").to_string());
+    state
+        .db
+        .run(move |conn| db::record_token_usage(conn, &channel, &day, usage.prompt_tokens, usage.output_tokens))
+        .await
+        .unwrap_or_else(|e| tracing::error!("Failed to record token usage: {:?}", e));
+}
+
+/// Records how an AI request ended, for !aistats. Failures are only logged.
+async fn record_ai_outcome(state: &BotState, channel: &str, outcome: &str) {
+    let (channel, outcome) = (channel.to_string(), outcome.to_string());
+    state
+        .db
+        .run(move |conn| db::record_ai_outcome(conn, &channel, &outcome))
+        .await
+        .unwrap_or_else(|e| tracing::error!("Failed to record AI outcome: {:?}", e));
+}
+
+/// Runs every configured transport against one shared state until one of them fails.
+pub async fn run_bot(config: Config, db: DbPool) -> Result<()> {
+    BotBuilder::new(config).db(db).build().await?.run().await
+}
+
+/// Where a [`BotBuilder`] gets its database.
+enum DbSource {
+    Config,
+    Path(PathBuf),
+    Memory,
+    Pool(DbPool),
+}
+
+/// Assembles a bot from a [`Config`], with any of its parts swapped out: the LLM backend, the
+/// system prompt, the database, the built-in tools and the chat networks. Parts that aren't given
+/// come from the config, as they do for [`run_bot`]. Tests and embedders can use this to run a bot
+/// without API keys, a prompt file or an IRC server:
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use std::sync::Arc;
+/// let config = emul::Config::from_args(["emul", "--nickname", "Emul"])?;
+/// let (incoming_tx, incoming_rx) = tokio::sync::mpsc::unbounded_channel();
+/// # let my_transport: Arc<dyn emul::transport::ChatTransport> = todo!();
+/// let bot = emul::BotBuilder::new(config)
+///     .in_memory_db()
+///     .llm(Arc::new(emul::llm::EchoBackend))
+///     .prompt("You are Emul.")
+///     .transport(my_transport, incoming_rx)
+///     .build()
+///     .await?;
+/// // Messages sent on incoming_tx are now answered through my_transport
+/// bot.run().await
+/// # }
+/// ```
+pub struct BotBuilder {
+    config: Config,
+    db: DbSource,
+    llm: Option<Arc<dyn LlmBackend>>,
+    prompt: Option<String>,
+    tools: Option<ToolRegistry>,
+    transports: Vec<(Arc<dyn ChatTransport>, mpsc::UnboundedReceiver<IncomingMessage>)>,
+}
+
+impl BotBuilder {
+    pub fn new(config: Config) -> Self {
+        BotBuilder { config, db: DbSource::Config, llm: None, prompt: None, tools: None, transports: Vec::new() }
+    }
+
+    /// Uses an already opened database.
+    pub fn db(mut self, db: DbPool) -> Self {
+        self.db = DbSource::Pool(db);
+        self
+    }
+
+    /// Opens (or creates) the database at `path` instead of the configured one.
+    pub fn db_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.db = DbSource::Path(path.into());
+        self
+    }
+
+    /// Uses a fresh database that only lives in memory.
+    pub fn in_memory_db(mut self) -> Self {
+        self.db = DbSource::Memory;
+        self
+    }
+
+    /// Uses `llm` for every AI call instead of the configured backend.
+    pub fn llm(mut self, llm: Arc<dyn LlmBackend>) -> Self {
+        self.llm = Some(llm);
+        self
+    }
+
+    /// Uses `prompt` as the system prompt instead of reading the prompt file.
+    pub fn prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.prompt = Some(prompt.into());
+        self
+    }
+
+    /// Uses `tools` in place of the tools compiled into the bot. Plugins from `wasm_tools_dir`
+    /// are still added to them.
+    pub fn tools(mut self, tools: ToolRegistry) -> Self {
+        self.tools = Some(tools);
+        self
+    }
+
+    /// Adds a chat network: messages arriving on `incoming` are handled like any other, and
+    /// replies go out through `transport`. A bot given transports of its own doesn't connect
+    /// to the configured IRC or Discord.
+    pub fn transport(
+        mut self,
+        transport: Arc<dyn ChatTransport>,
+        incoming: mpsc::UnboundedReceiver<IncomingMessage>,
+    ) -> Self {
+        self.transports.push((transport, incoming));
+        self
+    }
+
+    /// Opens the database and loads the tools and prompt. Databases the builder opens itself
+    /// get the configured admin as their owner, as the binary's does.
+    pub async fn build(self) -> Result<Bot> {
+        let proxy = HttpProxy::new(self.config.proxy.as_deref())?;
+        let (db, opened) = match self.db {
+            DbSource::Pool(db) => (db, false),
+            DbSource::Config => (db::init_db(self.config.db.as_deref().context("No database configured")?)?, true),
+            DbSource::Path(path) => (db::init_db(path)?, true),
+            DbSource::Memory => (db::init_memory_db()?, true),
+        };
+        if opened {
+            let admin = self.config.admin.clone();
+            db.run(move |conn| db::add_initial_owner(conn, &admin)).await?;
+        }
+        let tools = match self.tools {
+            Some(tools) => tools,
+            None => ToolRegistry::builtin(torrent_client::client_from_config(&self.config, &proxy)?),
+        };
+        let state = BotState::new(self.config, db, tools, proxy, self.llm, self.prompt).await?;
+        Ok(Bot { state, transports: self.transports })
+    }
+}
+
+/// A bot ready to run, from [`BotBuilder::build`].
+pub struct Bot {
+    state: BotState,
+    transports: Vec<(Arc<dyn ChatTransport>, mpsc::UnboundedReceiver<IncomingMessage>)>,
+}
+
+impl Bot {
+    /// The bot's database, for looking into (or preparing) its state.
+    pub fn db(&self) -> &DbPool {
+        &self.state.db
+    }
+
+    /// Runs the bot until one of its transports (or the health check server) fails or, for
+    /// transports given to the builder, until the sender of its incoming messages is dropped.
+    pub async fn run(self) -> Result<()> {
+        let state = self.state;
+        let _watcher = watch_settings_files(&state)
+            .inspect_err(|e| tracing::warn!("Not watching the settings files for changes: {}", e))
+            .ok();
+
+        tokio::spawn(run_summaries(state.clone()));
+
+        // Transports given to the builder replace the configured ones
+        let irc_enabled = self.transports.is_empty() && state.config().uses_transport(TransportKind::Irc);
+        let mut tasks = Vec::new();
+        if !self.transports.is_empty() {
+            for (transport, incoming) in self.transports {
+                tasks.push(tokio::spawn(forward_messages(state.clone(), transport, incoming)));
+            }
+        } else {
+            if irc_enabled {
+                tasks.push(tokio::spawn(run_irc(state.clone())));
+            }
+            if state.config().uses_transport(TransportKind::Discord) {
+                tasks.push(tokio::spawn(run_discord(state.clone())));
+            }
+        }
+        if tasks.is_empty() {
+            anyhow::bail!("No transports configured");
+        }
+        if let Some(addr) = state.config().health_addr {
+            tasks.push(tokio::spawn(health::serve(addr, state.health.clone(), state.db.clone(), irc_enabled)));
+        }
+
+        let (result, _, _) = future::select_all(tasks).await;
+        result.context("Transport task panicked")?
+    }
+}
+
+async fn run_discord(state: BotState) -> Result<()> {
+    let token = state.config().discord_token.clone().context("Discord token not configured")?;
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+    let (discord, mut client) =
+        transport::connect_discord(&token, &state.config().nickname, incoming_tx).await?;
+    tokio::spawn(forward_messages(state, discord, incoming_rx));
+
+    // Serenity handles gateway reconnects itself; this only returns on fatal errors
+    client.start().await.context("Discord client stopped")
+}
+
+/// Hands each message from a transport that delivers whole messages (Discord, or one given to
+/// [`BotBuilder`]) to the pipeline, skipping the IRC fragment buffer. Returns once the
+/// transport stops sending.
+async fn forward_messages(
+    state: BotState,
+    transport: Arc<dyn ChatTransport>,
+    mut incoming: mpsc::UnboundedReceiver<IncomingMessage>,
+) -> Result<()> {
+    state.connected(transport.clone());
+    while let Some(message) = incoming.recv().await {
+        let transport = transport.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            let name = transport.name();
+            let stamp = MessageStamp::now();
+            if let Err(e) = process_complete_message(transport, state, message.channel, message.nick, message.text, stamp).await {
+                tracing::error!(transport = name, "Error processing message: {:?}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn run_irc(state: BotState) -> Result<()> {
+    let config = state.config();
+    let server = config.server.clone().context("IRC server not configured")?;
+    let socks = match (config.irc_proxy, config.proxy.as_deref()) {
+        (true, Some(url)) => Some(proxy::socks5(url)?),
+        _ => None,
+    };
+    let watchdog = (config.irc_watchdog_mins > 0).then(|| Duration::from_secs(config.irc_watchdog_mins * 60));
     let mut reconnect_delay = INITIAL_RECONNECT_DELAY;
 
     // --- Outer Reconnection Loop ---
     loop {
-        tracing::info!(server = %config.server, port = %config.port, nick = %config.nickname, "Attempting to connect to IRC...");
+        tracing::info!(server = %server, port = %config.port, nick = %config.nickname, "Attempting to connect to IRC...");
 
+        // Logging in to services is ours to do, not the irc crate's, so it works beyond NickServ
+        let services = Arc::new(Services::new(
+            config.services,
+            &config.nickname,
+            config.services_account.as_deref(),
+            config.nickserv_password.as_deref(),
+        ));
         let irc_config = irc::client::data::Config {
             nickname: Some(config.nickname.clone()),
-        nick_password: config.nickserv_password.clone(),
-        server: Some(config.server.clone()),
+        alt_nicks: services.alternative_nicks(),
+        server: Some(server.clone()),
         port: Some(config.port),
         use_tls: Some(config.use_tls),
         version: Some("EmulBotRs v0.1 - https://github.com/baughn/emulbot".to_string()), // Be polite!
+        proxy_type: socks.as_ref().map(|_| ProxyType::Socks5),
+        proxy_server: socks.as_ref().map(|socks| socks.host.clone()),
+        proxy_port: socks.as_ref().map(|socks| socks.port),
+        proxy_username: socks.as_ref().and_then(|socks| socks.username.clone()),
+        proxy_password: socks.as_ref().and_then(|socks| socks.password.clone()),
             ..irc::client::data::Config::default()
         };
 
@@ -71,6 +825,11 @@ pub async fn run_bot(config: Config, db_conn: DbConnection) -> Result<()> {
             }
         };
 
+        for capability in IRC_CAPABILITIES {
+            if let Err(e) = client.send_cap_req(std::slice::from_ref(capability)) {
+                tracing::warn!(?capability, "Failed to request IRCv3 capability: {}", e);
+            }
+        }
         if let Err(e) = client.identify() {
             tracing::error!("Failed to identify/connect to IRC server: {}", e);
             sleep(reconnect_delay).await;
@@ -80,24 +839,10 @@ pub async fn run_bot(config: Config, db_conn: DbConnection) -> Result<()> {
 
         tracing::info!("Successfully connected and identified.");
         reconnect_delay = INITIAL_RECONNECT_DELAY; // Reset delay on successful connection
+        state.current_channels.lock().await.clear(); // Reset channels on reconnect
+        state.roster.clear();
 
-        // --- State Initialization (needs config reference) ---
-        // Clone config for the state, original config is moved into state later
-        let config_clone_for_state = config.clone();
-        let state = BotState {
-            config: Arc::new(config_clone_for_state), // Use the cloned config here
-            db_conn: db_conn.clone(), // Clone the Arc<Mutex<Connection>>
-            current_channels: Arc::new(Mutex::new(HashSet::new())), // Reset channels on reconnect
-            prompt_path: Arc::new(config.prompt_path()),
-            bn_interject: BlueNoiseInterjecter::new(RANDOM_INTERJECT_CHANCE),
-            bn_interject_mention: BlueNoiseInterjecter::new(RANDOM_INTERJECT_CHANCE_IF_MENTIONED),
-            image_cache: Arc::new(Mutex::new(LruCache::new(
-                NonZeroUsize::new(IMAGE_CACHE_SIZE).unwrap(),
-            ))),
-            message_buffer: Arc::new(Mutex::new(HashMap::new())), // Initialize buffer
-        };
-
-        // --- Stream, Client Arc, and Sweeper Task ---
+        // --- Stream, Outgoing Queue, and Sweeper Task ---
         let stream_result = client.stream();
         let mut stream = match stream_result {
             Ok(s) => s,
@@ -108,24 +853,51 @@ pub async fn run_bot(config: Config, db_conn: DbConnection) -> Result<()> {
                 continue; // Retry connection
             }
         };
-        let client_arc = Arc::new(client); // Keep original client ownership here for now
-        let sender = client_arc.sender(); // Get sender for sweeper
+        let sender = client.sender();
+        let flood_limit = TokenBucket::new(
+            config.irc_burst_lines,
+            Duration::from_millis(config.irc_line_interval_ms),
+            Instant::now(),
+        );
+        let (outgoing, drainer) = OutgoingQueue::start(move |message| Ok(sender.send(message)?), flood_limit);
+        let irc_transport = Arc::new(IrcTransport::new(outgoing, &config.nickname));
+        let irc: Arc<dyn ChatTransport> = irc_transport.clone();
+        state.connected(irc.clone());
 
         // --- Start Message Buffer Sweeper Task ---
         let state_for_sweeper = state.clone();
-        tokio::spawn(async move {
-            message_buffer_sweeper(sender, state_for_sweeper).await;
-        });
+        let sweeper = tokio::spawn(message_buffer_sweeper(irc.clone(), state_for_sweeper));
+        let announcer = tokio::spawn(run_schedules(irc.clone(), state.clone()));
+        let feed_poller = tokio::spawn(run_feeds(irc, state.clone()));
+
+        state.health.set_irc_connected(true);
 
         // --- Main Event Loop ---
         loop { // Inner loop for message processing
-            match stream.next().await {
+            // A connection that died without closing never ends the stream, so the watchdog
+            // gives up on it once the server has been silent (not even a PING) for too long
+            let next = match watchdog {
+                Some(limit) => match tokio::time::timeout(limit, stream.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        tracing::warn!(silent_for = ?limit, "No traffic from the IRC server, reconnecting");
+                        break;
+                    }
+                },
+                None => stream.next().await,
+            };
+            match next {
                 Some(Ok(message)) => {
+                    state.health.server_traffic();
+                    let Some(stamp) = state.replays.read(&message, &services) else {
+                        continue;
+                    };
                 // Spawn a task to handle the message concurrently
                     let state_clone = state.clone();
-                    let client_clone = client_arc.clone(); // Clone the Arc
+                    let services = services.clone();
+                    let irc = irc_transport.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = handle_message(client_clone, state_clone, message).await {
+                        if let Err(e) = handle_message(services, irc, state_clone, message, stamp).await {
                             tracing::error!("Error handling message: {:?}", e);
                         }
                     });
@@ -142,6 +914,11 @@ pub async fn run_bot(config: Config, db_conn: DbConnection) -> Result<()> {
                 }
             }
         } // End of inner message processing loop
+        state.health.set_irc_connected(false);
+        sweeper.abort(); // The next connection starts its own sweeper, announcer, feed poller and queue
+        announcer.abort();
+        feed_poller.abort();
+        drainer.abort();
 
         // --- Reconnection Delay ---
         tracing::info!("Disconnected. Waiting {:?} before reconnecting...", reconnect_delay);
@@ -154,55 +931,158 @@ pub async fn run_bot(config: Config, db_conn: DbConnection) -> Result<()> {
     // If a condition to exit gracefully is needed, it should be added.
 }
 
-async fn handle_message(client: Arc<Client>, state: BotState, message: Message) -> Result<()> {
+/// Handles a message from the IRC server, stamped by `Replays::read` as it was read.
+async fn handle_message(
+    services: Arc<Services>,
+    irc: Arc<IrcTransport>,
+    state: BotState,
+    message: Message,
+    stamp: MessageStamp,
+) -> Result<()> {
     // Log raw messages for debugging if needed
     tracing::trace!(raw_message = ?message, "Received message");
 
     match message.command {
+        Command::Response(Response::RPL_WELCOME, ref args) => {
+            // The server tells us which nickname we got
+            if let Some(nick) = args.first() {
+                services.set_current_nick(nick);
+            }
+        }
+        Command::Response(Response::RPL_ENDOFMOTD, _) | Command::Response(Response::ERR_NOMOTD, _) => {
+            // Registered: log in to services, and join channels once they've answered
+            for command in services.on_registered() {
+                irc.queue().send(Priority::High, command)?;
+            }
+            if services.join_on_registered() {
+                if services.start_joining() {
+                    join_saved_channels(&irc, &state).await?;
+                }
+            } else {
+                tokio::spawn(async move {
+                    sleep(LOGIN_TIMEOUT).await;
+                    if services.start_joining() {
+                        tracing::warn!(timeout = ?LOGIN_TIMEOUT, "Services didn't answer, joining channels anyway");
+                        if let Err(e) = join_saved_channels(&irc, &state).await {
+                            tracing::error!("Failed to join channels: {:?}", e);
+                        }
+                    }
+                });
+            }
+        }
+        Command::CAP(_, ref subcommand, ref field, ref list) => {
+            // The capability list is the last argument, wherever the parser put it
+            let capabilities = list.as_deref().or(field.as_deref()).unwrap_or_default();
+            match subcommand {
+                CapSubCommand::ACK => {
+                    tracing::info!(%capabilities, "Server enabled IRCv3 capabilities");
+                    for capability in capabilities.split_whitespace() {
+                        irc.enable_capability(capability);
+                    }
+                }
+                CapSubCommand::NAK => tracing::debug!(%capabilities, "Server doesn't support IRCv3 capabilities"),
+                _ => {}
+            }
+        }
         Command::NOTICE(_, ref msg) => {
             let source = message.source_nickname().unwrap_or("unknown");
             tracing::info!(from = %source, %msg, "Received NOTICE");
-            // Handle NickServ notices.
-            if source == "NickServ" && (msg.contains("you are now recognized") || msg.contains("is not a registered nickname")) {
-                // *Now* we can join our channels.
-                tracing::info!("NickServ recognized us, joining channels");
-                let channels = db::get_channels(&*state.db_conn.lock().await)?;
-                for channel in channels {
-                    client.send_join(&channel)?;
+            if let Some(event) = services.parse_notice(source, msg) {
+                tracing::info!(?event, "Services answered");
+                for command in services.respond(event) {
+                    irc.queue().send(Priority::High, command)?;
+                }
+                if event == ServicesEvent::LoginFailed {
+                    tracing::error!("Services refused our password, joining channels without logging in");
+                }
+                if event.settles_login() && services.start_joining() {
+                    join_saved_channels(&irc, &state).await?;
                 }
             }
         },
         Command::NICK(ref new_nick) => {
             let old_nick = message.source_nickname().unwrap_or("");
             // If *our* nick changed (e.g., due to conflict)
-            if old_nick == client.current_nickname() {
+            if services.is_me(old_nick) {
                 tracing::info!(%old_nick, %new_nick, "My nickname changed");
-                // No need to update client state, library handles it
+                services.set_current_nick(new_nick);
+                if let Some(Prefix::Nickname(_, user, host)) = &message.prefix {
+                    irc.set_hostmask(new_nick, user, host);
+                }
+                state.roster.rename(old_nick, new_nick);
             } else {
-                // Track other users' nick changes if needed
                 tracing::debug!(%old_nick, %new_nick, "User changed nick");
+                let channels = state.roster.rename(old_nick, new_nick);
+                rename_buffered(&mut *state.message_buffer.lock().await, old_nick, new_nick);
+                log_nick_change(&state, &channels, old_nick, new_nick).await?;
             }
         }
 
         Command::JOIN(ref channel, _, _) => {
             let joined_nick = message.source_nickname().unwrap_or("");
-            if joined_nick == client.current_nickname() {
+            if services.is_me(joined_nick) {
                 tracing::info!(%channel, "Successfully joined");
+                // The server shows us our hostmask here, which decides how long our lines can be
+                if let Some(Prefix::Nickname(nick, user, host)) = &message.prefix {
+                    irc.set_hostmask(nick, user, host);
+                }
                 let mut current_chans = state.current_channels.lock().await;
                 current_chans.insert(channel.clone());
+                drop(current_chans);
+                state.roster.joined(channel);
+                request_backfill(&irc, &state, channel).await;
             } else {
                 tracing::debug!(user = %joined_nick, %channel, "User joined");
+                state.roster.join(channel, joined_nick);
             }
         }
 
         Command::PART(ref channel, _) | Command::KICK(ref channel, _, _) => {
-            let parted_nick = message.source_nickname().unwrap_or("");
-            if parted_nick == client.current_nickname() {
+            // A KICK comes from whoever did the kicking, and names who was kicked
+            let parted_nick = match message.command {
+                Command::KICK(_, ref kicked, _) => kicked.as_str(),
+                _ => message.source_nickname().unwrap_or(""),
+            };
+            if services.is_me(parted_nick) {
                 tracing::info!(%channel, "Left channel");
                 let mut current_chans = state.current_channels.lock().await;
                 current_chans.remove(channel);
+                state.roster.left(channel);
             } else {
                 tracing::debug!(user = %parted_nick, %channel, "User left");
+                state.roster.part(channel, parted_nick);
+            }
+        }
+
+        Command::QUIT(_) => {
+            if let Some(nick) = message.source_nickname() {
+                state.roster.quit(nick);
+            }
+        }
+
+        Command::Response(Response::RPL_NAMREPLY, ref args) => {
+            // [our nick, channel type, channel, names]
+            if let [.., channel, names] = args.as_slice() {
+                state.roster.add_names(channel, names);
+            }
+        }
+
+        Command::ChannelMODE(ref channel, ref modes) => {
+            for mode in modes {
+                let (given, mode, nick) = match mode {
+                    Mode::Plus(mode, Some(nick)) => (true, mode, nick),
+                    Mode::Minus(mode, Some(nick)) => (false, mode, nick),
+                    _ => continue,
+                };
+                let prefix = match mode {
+                    ChannelMode::Founder => '~',
+                    ChannelMode::Admin => '&',
+                    ChannelMode::Oper => '@',
+                    ChannelMode::Halfop => '%',
+                    ChannelMode::Voice => '+',
+                    _ => continue,
+                };
+                state.roster.set_status(channel, nick, prefix, given);
             }
         }
 
@@ -210,9 +1090,35 @@ async fn handle_message(client: Arc<Client>, state: BotState, message: Message)
             let source_nick = message.source_nickname().unwrap_or("unknown");
             tracing::debug!(from = %source_nick, %target, %msg, "PRIVMSG received");
 
-            if target == client.current_nickname() {
+            if services.is_me(source_nick) {
+                // Our own line, echoed back by the server (IRCv3 echo-message), or replayed
+                if target.starts_with('#') && stamp.replayed {
+                    log_replayed(&state, target, &state.config().nickname, msg, stamp).await?;
+                } else if target.starts_with('#') && state.relay_guard.was_relayed(target, msg, Instant::now()) {
+                    // A relayed line, which relay_message logged under its sender
+                    irc.echo_received(target);
+                } else if target.starts_with('#') {
+                    let (channel, nick, text) = (target.clone(), state.config().nickname.clone(), msg.clone());
+                    state
+                        .db
+                        .run(move |conn| db::log_message_at(conn, &channel, &nick, &text, stamp.time, stamp.msgid.as_deref()))
+                        .await?;
+                    irc.echo_received(target);
+                }
+            } else if stamp.replayed {
+                // Said while we were away: it belongs in the log, but it's too late to answer
+                if target.starts_with('#') {
+                    log_replayed(&state, target, source_nick, msg, stamp).await?;
+                }
+            } else if let Some(request) = ctcp::parse(msg) {
+                handle_ctcp(irc, state, source_nick, target, msg, request, stamp)?;
+            } else if services.is_me(target) && state.config().dm_chat && !msg.starts_with(ADMIN_COMMAND_PREFIX) {
+                // Private conversation with the AI
+                handle_direct_message(irc, &state, source_nick, stamp.account.as_deref(), msg).await?;
+            } else if services.is_me(target) {
                 // Private message or command
-                handle_admin_command(client, state, source_nick, msg).await?;
+                let account = stamp.account.as_deref();
+                handle_admin_command(irc.queue().clone(), state, &services.current_nick(), source_nick, account, msg).await?;
             } else if target.starts_with('#') {
                 // Public message in a channel
                 let channel = target;
@@ -236,6 +1142,7 @@ async fn handle_message(client: Arc<Client>, state: BotState, message: Message)
                         BufferedMessage {
                             message: msg.to_string(),
                             last_arrival: now,
+                            stamp,
                         }
                     });
                 // Drop the lock explicitly before any potential await points if needed later
@@ -257,8 +1164,140 @@ async fn handle_message(client: Arc<Client>, state: BotState, message: Message)
     Ok(())
 }
 
+/// Asks the server for what was said in `channel` since the last line logged there, so a
+/// netsplit or reconnect doesn't leave a gap in the history the AI sees.
+async fn request_backfill(irc: &IrcTransport, state: &BotState, channel: &str) {
+    let limit = state.config().irc_backfill_lines;
+    if limit == 0 {
+        return;
+    }
+    let log_channel = channel.to_string();
+    let since = match state.db.run(move |conn| db::get_last_log_time(conn, &log_channel)).await {
+        Ok(since) => since,
+        Err(e) => {
+            tracing::error!(%channel, "Failed to read the last logged time: {:?}", e);
+            return;
+        }
+    };
+    match irc.request_history(channel, since, limit) {
+        Ok(true) => {
+            tracing::info!(%channel, ?since, "Asked the server for missed history");
+            state.replays.expect(channel, chrono::Utc::now(), Instant::now());
+        }
+        Ok(false) => {}
+        Err(e) => tracing::warn!(%channel, "Failed to ask for missed history: {:?}", e),
+    }
+}
+
+/// Logs a channel line the server replayed from its history, unless it's logged already or from
+/// someone ignored.
+async fn log_replayed(state: &BotState, channel: &str, nick: &str, message: &str, stamp: MessageStamp) -> Result<()> {
+    if bot_guard::is_bot(&state.config().ignored_bots, nick) {
+        return Ok(());
+    }
+    let (channel, nick, message) = (channel.to_string(), nick.to_string(), message.to_string());
+    state
+        .db
+        .run(move |conn| {
+            if !db::is_ignored(conn, &nick)?
+                && db::log_replayed_message(conn, &channel, &nick, &message, stamp.time, stamp.msgid.as_deref())?
+            {
+                tracing::debug!(%channel, %nick, time = %stamp.time, "Logged a replayed line");
+            }
+            Ok(())
+        })
+        .await
+}
+
+/// Joins the channels saved in the database.
+async fn join_saved_channels(irc: &IrcTransport, state: &BotState) -> Result<()> {
+    let channels = state.db.run(db::get_channels).await?;
+    tracing::info!(count = channels.len(), "Joining channels");
+    for channel in channels {
+        irc.queue().send_join(&channel)?;
+    }
+    Ok(())
+}
+
+/// Answers CTCP queries and passes /me actions in channels on like ordinary messages.
+fn handle_ctcp(
+    irc: Arc<IrcTransport>,
+    state: BotState,
+    nick: &str,
+    target: &str,
+    msg: &str,
+    request: Ctcp<'_>,
+    stamp: MessageStamp,
+) -> Result<()> {
+    tracing::debug!(from = %nick, %target, ?request, "CTCP received");
+    match request {
+        Ctcp::Action(_) if target.starts_with('#') => {
+            // Actions are single lines, so they skip the fragment buffer
+            let transport: Arc<dyn ChatTransport> = irc;
+            let (channel, nick, message) = (target.to_string(), nick.to_string(), msg.to_string());
+            tokio::spawn(async move {
+                if let Err(e) = process_complete_message(transport, state, channel, nick, message, stamp).await {
+                    tracing::error!("Error processing action: {:?}", e);
+                }
+            });
+        }
+        Ctcp::Action(_) => tracing::debug!(from = %nick, "Ignoring private action"),
+        Ctcp::Other(command) => tracing::debug!(from = %nick, %command, "Ignoring unsupported CTCP request"),
+        _ if !state.ctcp_limiter.allow(nick, Instant::now()) => {
+            tracing::debug!(from = %nick, ?request, "Not answering a CTCP query, too many lately");
+        }
+        Ctcp::Version => irc.queue().send_notice(nick, ctcp::encode("VERSION", ctcp::VERSION_REPLY))?,
+        Ctcp::Ping(token) => irc.queue().send_notice(nick, ctcp::encode("PING", token))?,
+        Ctcp::Time => irc.queue().send_notice(nick, ctcp::encode("TIME", &chrono::Utc::now().to_rfc2822()))?,
+        Ctcp::ClientInfo => irc.queue().send_notice(nick, ctcp::encode("CLIENTINFO", ctcp::CLIENTINFO_REPLY))?,
+    }
+    Ok(())
+}
+
+/// Moves `old_nick`'s buffered fragments over to `new_nick`, so a message split around a nick
+/// change is still put together as one, and answered under the new nick.
+fn rename_buffered(buffer: &mut HashMap<(String, String), BufferedMessage>, old_nick: &str, new_nick: &str) {
+    let keys: Vec<(String, String)> = buffer.keys().filter(|(_, nick)| nick == old_nick).cloned().collect();
+    for key in keys {
+        let Some(mut fragments) = buffer.remove(&key) else { continue };
+        let new_key = (key.0, new_nick.to_string());
+        // Anything already said under the new nick came later
+        if let Some(later) = buffer.remove(&new_key) {
+            fragments.message.push(' ');
+            fragments.message.push_str(&later.message);
+            fragments.last_arrival = later.last_arrival;
+        }
+        buffer.insert(new_key, fragments);
+    }
+}
+
+/// Notes a nick change in the log of each channel the user is in, as "* old is now known as
+/// new", so the AI's history shows the two nicks are one person.
+async fn log_nick_change(state: &BotState, lowercase_channels: &[String], old_nick: &str, new_nick: &str) -> Result<()> {
+    let channels: Vec<String> = state
+        .current_channels
+        .lock()
+        .await
+        .iter()
+        .filter(|channel| lowercase_channels.contains(&channel.to_lowercase()))
+        .cloned()
+        .collect();
+    let nick = old_nick.to_string();
+    if channels.is_empty()
+        || bot_guard::is_bot(&state.config().ignored_bots, old_nick)
+        || state.db.run(move |conn| db::is_ignored(conn, &nick)).await?
+    {
+        return Ok(());
+    }
+    let (nick, message) = (old_nick.to_string(), ctcp::encode("ACTION", &format!("is now known as {}", new_nick)));
+    state
+        .db
+        .run(move |conn| channels.iter().try_for_each(|channel| db::log_message(conn, channel, &nick, &message)))
+        .await
+}
+
 // --- New Function: Background task to process completed messages from buffer ---
-async fn message_buffer_sweeper(sender: Sender, state: BotState) {
+async fn message_buffer_sweeper(transport: Arc<dyn ChatTransport>, state: BotState) {
     tracing::debug!("Message buffer sweeper task started.");
     loop {
         tokio::time::sleep(MESSAGE_SWEEPER_INTERVAL).await;
@@ -275,6 +1314,7 @@ async fn message_buffer_sweeper(sender: Sender, state: BotState) {
                     channel.clone(),
                     nick.clone(),
                     buffered_msg.message.clone(), // Clone message to process outside lock
+                    buffered_msg.stamp.clone(),
                 ));
                 false // Remove from buffer
             } else {
@@ -286,11 +1326,11 @@ async fn message_buffer_sweeper(sender: Sender, state: BotState) {
         drop(buffer);
 
         // Spawn processing tasks for each completed message
-        for (channel, nick, message) in messages_to_process {
-            let sender_clone = sender.clone();
+        for (channel, nick, message, stamp) in messages_to_process {
+            let transport_clone = transport.clone();
             let state_clone = state.clone();
             tokio::spawn(async move {
-                 if let Err(e) = process_complete_message(sender_clone, state_clone, channel, nick, message).await {
+                 if let Err(e) = process_complete_message(transport_clone, state_clone, channel, nick, message, stamp).await {
                      tracing::error!("Error processing completed message: {:?}", e);
                  }
             });
@@ -300,298 +1340,2665 @@ async fn message_buffer_sweeper(sender: Sender, state: BotState) {
 }
 
 
-// --- New Function: Process a fully assembled message ---
-async fn process_complete_message(
-    sender: Sender,
+/// Sends scheduled announcements when they come due. Schedules are re-read on every check, so
+/// `!schedule` changes apply right away; announcements due while disconnected are skipped.
+async fn run_schedules(transport: Arc<dyn ChatTransport>, state: BotState) {
+    let mut last_check = chrono::Local::now();
+    loop {
+        sleep(scheduler::SCHEDULE_CHECK_INTERVAL).await;
+        let now = chrono::Local::now();
+        let schedules = match state.db.run(db::get_schedules).await {
+            Ok(schedules) => schedules,
+            Err(e) => {
+                tracing::error!("Failed to load schedules: {:?}", e);
+                continue; // Keep last_check, so the next check covers this window too
+            }
+        };
+        for schedule in schedules {
+            let due = match scheduler::parse_cron(&schedule.cron) {
+                Ok(cron) => scheduler::is_due(&cron, &last_check, &now),
+                Err(e) => {
+                    tracing::warn!(id = schedule.id, "Skipping schedule: {:#}", e);
+                    false
+                }
+            };
+            if !due {
+                continue;
+            }
+            tracing::info!(id = schedule.id, channel = %schedule.channel, "Sending scheduled announcement");
+            if let Err(e) = transport.send_message(&schedule.channel, &schedule.message).await {
+                tracing::error!(id = schedule.id, "Failed to send scheduled announcement: {:?}", e);
+                continue;
+            }
+            if transport.echoes_sent_messages() {
+                continue;
+            }
+            let nickname = state.config().nickname.clone();
+            state
+                .db
+                .run(move |conn| db::log_message(conn, &schedule.channel, &nickname, &schedule.message))
+                .await
+                .unwrap_or_else(|e| tracing::error!("Failed to log scheduled announcement: {:?}", e));
+        }
+        last_check = now;
+    }
+}
+
+/// Periodically folds new conversation into the channels' rolling summaries.
+async fn run_summaries(state: BotState) {
+    loop {
+        sleep(summary::SUMMARY_INTERVAL).await;
+        if !state.config().channel_summaries {
+            continue;
+        }
+        let channels = match state.db.run(|conn| db::get_channels_needing_summary(conn, summary::SUMMARY_MIN_NEW_LINES)).await {
+            Ok(channels) => channels,
+            Err(e) => {
+                tracing::error!("Failed to find channels to summarize: {:?}", e);
+                continue;
+            }
+        };
+        for channel in channels {
+            let llm = state.llm();
+            let llm = UsageMeter::new(&*llm);
+            if let Err(e) = summary::update_summary(&llm, &state.db, &channel).await {
+                tracing::warn!(%channel, "Failed to update channel summary: {:?}", e);
+            }
+            record_usage(&state, &channel, &llm).await;
+        }
+    }
+}
+
+/// Polls the subscribed feeds and announces entries that haven't been seen before.
+async fn run_feeds(transport: Arc<dyn ChatTransport>, state: BotState) {
+    loop {
+        sleep(rss::FEED_POLL_INTERVAL).await;
+        let feeds = match state.db.run(db::get_feeds).await {
+            Ok(feeds) => feeds,
+            Err(e) => {
+                tracing::error!("Failed to load feeds: {:?}", e);
+                continue;
+            }
+        };
+        for feed in feeds {
+            if let Err(e) = poll_feed(&*transport, &state, &feed).await {
+                tracing::warn!(id = feed.id, url = %feed.url, "Failed to poll feed: {:#}", e);
+            }
+        }
+    }
+}
+
+async fn poll_feed(transport: &dyn ChatTransport, state: &BotState, feed: &db::FeedSubscription) -> Result<()> {
+    let items = rss::fetch_feed(&state.proxy, &feed.url).await?;
+    let (feed_id, entry_ids) = (feed.id, items.iter().map(|item| item.id.clone()).collect::<Vec<_>>());
+    let new_ids: HashSet<String> = state
+        .db
+        .run(move |conn| db::mark_feed_entries_seen(conn, feed_id, &entry_ids))
+        .await?
+        .into_iter()
+        .collect();
+    let mut new_items: Vec<_> = items
+        .into_iter()
+        .filter(|item| new_ids.contains(&item.id))
+        .take(rss::MAX_ANNOUNCEMENTS_PER_POLL)
+        .collect();
+    new_items.reverse(); // Oldest first
+    let settings = state.settings();
+    let llm = UsageMeter::new(&*settings.llm);
+    for item in new_items {
+        let summary = match (&item.text, feed.summarize) {
+            (Some(text), true) => ai_handler::summarize_feed_item(&llm, &item.title, text)
+                .await
+                .inspect_err(|e| tracing::warn!(id = feed.id, "Failed to summarize feed entry: {:#}", e))
+                .ok(),
+            _ => None,
+        };
+        record_usage(state, &feed.channel, &llm).await;
+        tracing::info!(id = feed.id, channel = %feed.channel, title = %item.title, "Announcing feed entry");
+        // Entries are written by whoever runs the feed, so they go through the output filter too
+        let text = settings.output_filter.apply(&item.announcement(summary.as_deref()), "");
+        transport.send_message(&feed.channel, &text).await?;
+        if !transport.echoes_sent_messages() {
+            let (log_channel, log_nick) = (feed.channel.clone(), settings.config.nickname.clone());
+            state.db.run(move |conn| db::log_message(conn, &log_channel, &log_nick, &text)).await?;
+        }
+    }
+    Ok(())
+}
+
+// --- New Function: Process a fully assembled message ---
+async fn process_complete_message(
+    transport: Arc<dyn ChatTransport>,
     state: BotState,
     channel: String,
     nick: String,
     complete_message: String,
+    stamp: MessageStamp,
 ) -> Result<()> {
     tracing::debug!(%channel, %nick, msg=%complete_message, "Processing complete message");
+    let (msgid, account) = (stamp.msgid.clone(), stamp.account.clone());
+
+    // Ignored and opted-out users are neither logged nor answered, and nor are known bots
+    let sender = nick.clone();
+    if state.db.run(move |conn| db::is_ignored(conn, &sender)).await? {
+        tracing::debug!(%channel, %nick, "Ignoring message from ignored user");
+        return Ok(());
+    }
+    if bot_guard::is_bot(&state.config().ignored_bots, &nick) {
+        tracing::debug!(%channel, %nick, "Ignoring message from a bot");
+        return Ok(());
+    }
+    state.exchanges.heard(&channel, &nick, Instant::now());
 
     // 1. Log the complete message
-    // Use a separate connection lock scope
     {
-        let conn = state.db_conn.lock().await;
-        db::log_message(&conn, &channel, &nick, &complete_message)?;
-    } // Lock released here
+        let (channel, nick, message) = (channel.clone(), nick.clone(), complete_message.clone());
+        state
+            .db
+            .run(move |conn| db::log_message_at(conn, &channel, &nick, &message, stamp.time, stamp.msgid.as_deref()))
+            .await?;
+    }
+
+    // Bridged channels get the message too
+    relay_message(&state, &channel, &nick, &complete_message).await;
+
+    // !notify: the sender's digest if they were away, then the lines saved for others it mentions
+    if !transport::is_discord_channel(&channel) {
+        deliver_notifications(&*transport, &state, &nick, account.as_deref()).await;
+        save_notifications(&state, &channel, &nick, &complete_message).await;
+    }
+
+    // Karma votes, then public commands, which are answered here and don't go to the AI
+    record_karma_votes(&state, &channel, &nick, &complete_message).await;
+    let settings_channel = channel.clone();
+    let overrides = state.db.run(move |conn| db::get_channel_settings(conn, &settings_channel)).await?;
+    let channel_settings = ChannelSettings::from_overrides(&overrides);
+
+    // Channels that asked for it have every message checked against the rules
+    if !channel_settings.moderation.is_empty() {
+        let (transport, state, channel, nick, message) =
+            (transport.clone(), state.clone(), channel.clone(), nick.clone(), complete_message.clone());
+        let channel_settings = channel_settings.clone();
+        tokio::spawn(async move {
+            if let Err(e) = moderate_message(&*transport, &state, &channel, &nick, &message, &channel_settings).await {
+                tracing::warn!(%channel, %nick, "Failed to moderate message: {:?}", e);
+            }
+        });
+    }
+
+    if handle_public_command(&transport, &state, &channel, &nick, account.as_deref(), &complete_message, &channel_settings).await? {
+        return Ok(());
+    }
+
+    // Link titles, for channels that have them turned on
+    let urls = ai_handler::extract_urls(&complete_message);
+    if !urls.is_empty() {
+        let (transport, state, channel) = (transport.clone(), state.clone(), channel.clone());
+        tokio::spawn(async move {
+            if let Err(e) = announce_url_titles(&*transport, &state, &channel, &urls).await {
+                tracing::warn!(%channel, "Failed to announce link titles: {:?}", e);
+            }
+        });
+    }
+
+    // Embed older conversation in the background once enough has accumulated; a run already
+    // underway in the channel will do
+    if state.config().memory_top_k > 0 {
+        let state = state.clone();
+        let channel = channel.clone();
+        tokio::spawn(async move {
+            let Some(_storing) = state.memories.start(&channel) else {
+                return;
+            };
+            let llm = state.llm();
+            let llm = UsageMeter::new(&*llm);
+            if let Err(e) = memory::remember_new_messages(&llm, &state.db, &channel).await {
+                tracing::warn!(%channel, "Failed to store long-term memory: {:?}", e);
+            }
+            record_usage(&state, &channel, &llm).await;
+        });
+    }
 
-    // 2. Check if AI should be triggered
-    let bot_nick_lower = state.config.nickname.to_lowercase();
+    // 2. Check if AI should be triggered, unless it's turned off here
+    if !channel_settings.ai {
+        return Ok(());
+    }
+    let bot_nick_lower = state.config().nickname.to_lowercase();
     let msg_lower = complete_message.to_lowercase();
     // Re-evaluate addressing based on the complete message
     let is_addressed = msg_lower.starts_with(&format!("{}:", bot_nick_lower))
         || msg_lower.starts_with(&format!("{},", bot_nick_lower))
         || msg_lower.split_whitespace().next() == Some(&bot_nick_lower)
         || (msg_lower.contains(format!(" {}", bot_nick_lower).as_str())
-            && (state.bn_interject_mention.should_interject()
-                || ai_handler::chatbot_mentioned(&state.config.nickname, &complete_message).await?)); // Pass complete message
+            && (state.should_interject(&channel, channel_settings.mention_chance, true).await
+                || mention_wants_answer(&state, &channel, &complete_message).await?));
+
+    // Scaled by the channel's quiet and peak hours
+    let interject_chance = channel_settings.interject_chance_at(chrono::Utc::now());
+    let should_trigger_ai = is_addressed || state.should_interject(&channel, interject_chance, false).await;
+
+    // 3. Spawn AI task if needed, within the rate limits
+    if should_trigger_ai {
+        // Another bot answering back: one echoing the last answer, or one the AI has gone back
+        // and forth with for too long
+        if state.recent_responses.quotes_last(&channel, &complete_message) {
+            tracing::info!(%channel, %nick, "Not answering a message that quotes the last answer");
+            state.exchanges.suspect(&nick, Instant::now());
+            return Ok(());
+        }
+        let config = state.config();
+        if !state.exchanges.may_answer(&channel, &nick, Instant::now(), config.max_bot_exchanges, &config.suspected_bots) {
+            tracing::info!(%channel, %nick, "Too many answers in a row to one nick, letting it drop");
+            return Ok(());
+        }
+        let limiter_nick = is_addressed.then_some(nick.as_str());
+        let verdict = state.rate_limiter.lock().await.check(&channel, limiter_nick, Instant::now());
+        if let RateLimitVerdict::Limited { retry_after, notify } = verdict {
+            tracing::info!(%channel, %nick, ?retry_after, "AI request rate limited");
+            if notify {
+                transport.send_message(&channel, &format!("{}: {}", nick, breather_message(retry_after))).await?;
+            }
+            return Ok(());
+        }
+
+        tracing::info!(%channel, %nick, addressed=%is_addressed, "Triggering AI for completed message");
+        // An answer to someone is threaded under their message, where the network shows replies
+        let transport: Arc<dyn ChatTransport> = match msgid {
+            Some(msgid) if is_addressed => Arc::new(ReplyTransport::new(transport, &channel, &msgid)),
+            _ => transport,
+        };
+        enqueue_ai_request(
+            &state,
+            AiRequest {
+                transport,
+                channel,
+                nick,
+                message: complete_message,
+                was_addressed: is_addressed,
+                direct: false,
+                account: None,
+            },
+        )
+        .await;
+    } else {
+        tracing::debug!(%channel, %nick, "No AI trigger for completed message");
+    }
+
+    Ok(())
+}
+
+
+/// Repeats a message in the channels bridged with `channel`, unless it's a relayed line coming back.
+async fn relay_message(state: &BotState, channel: &str, nick: &str, message: &str) {
+    let config = state.config();
+    let partners = relay::partners(&config.relays, channel);
+    if partners.is_empty() {
+        return;
+    }
+    let now = Instant::now();
+    if state.relay_guard.is_echo(channel, message, now) {
+        tracing::debug!(%channel, %nick, "Not relaying a relayed line that came back");
+        return;
+    }
+    let line = relay::format_line(nick, message);
+    // Only the bot's own answers are logged as the bot's; the rest as their sender's
+    let log_nick = match nick.eq_ignore_ascii_case(&config.nickname) {
+        true => config.nickname.clone(),
+        false => relay::log_nick(nick, &network_of(&config, channel)),
+    };
+    for partner in partners {
+        let Some(transport) = state.transport_for(partner) else {
+            tracing::warn!(%channel, %partner, "Not connected to the network of a relayed channel");
+            continue;
+        };
+        let parts = split_marked(transport.max_message_length(partner), &line);
+        for part in &parts {
+            state.relay_guard.relayed(partner, part, now);
+        }
+        if let Err(e) = transport.send_lines(partner, &parts, None).await {
+            tracing::warn!(%channel, %partner, "Failed to relay message: {:?}", e);
+            continue;
+        }
+        // Logged here even when the server echoes it back, as the echo would be logged as ours
+        let (log_channel, log_nick, log_text) = (partner.to_string(), log_nick.clone(), message.to_string());
+        state
+            .db
+            .run(move |conn| db::log_message(conn, &log_channel, &log_nick, &log_text))
+            .await
+            .unwrap_or_else(|e| tracing::error!("Failed to log relayed message: {:?}", e));
+    }
+}
+
+/// Sends `nick` the lines that mentioned their `!notify` keywords while they were away, if
+/// they're logged in to services as that nick; they wait for that otherwise.
+async fn deliver_notifications(transport: &dyn ChatTransport, state: &BotState, nick: &str, account: Option<&str>) {
+    if !account.is_some_and(|account| account.eq_ignore_ascii_case(nick)) {
+        return;
+    }
+    let user = nick.to_string();
+    let notifications = match state.db.run(move |conn| db::take_notifications(conn, &user)).await {
+        Ok(notifications) => notifications,
+        Err(e) => {
+            tracing::error!(%nick, "Failed to fetch notifications: {:?}", e);
+            return;
+        }
+    };
+    if notifications.is_empty() {
+        return;
+    }
+    tracing::info!(%nick, count = notifications.len(), "Sending notification digest");
+    for line in notify::digest(&notifications) {
+        if let Err(e) = transport.send_message(nick, &line).await {
+            tracing::warn!(%nick, "Failed to send notification digest: {:?}", e);
+            return;
+        }
+    }
+}
+
+/// Saves a channel message for everyone away from the channel whose `!notify` keywords it
+/// mentions: those in it, or who have spoken there, but not lately.
+async fn save_notifications(state: &BotState, channel: &str, sender: &str, message: &str) {
+    let away = chrono::Duration::minutes(state.config().notify_away_mins as i64);
+    let roster = state.roster.clone();
+    let (channel, sender, message) = (channel.to_string(), sender.to_string(), message.to_string());
+    let saved = state
+        .db
+        .run(move |conn| {
+            let mut notified: Vec<String> = Vec::new();
+            for (nick, keyword) in db::get_notify_keywords_in(conn, &message)? {
+                let done = nick.eq_ignore_ascii_case(&sender) || notified.iter().any(|other| other.eq_ignore_ascii_case(&nick));
+                if done || !notify::mentions(&message, &keyword) {
+                    continue;
+                }
+                let last_seen = db::get_last_seen(conn, &channel, &nick)?.map(|seen| seen.timestamp);
+                let member = roster.member(&channel, &nick).is_some();
+                if notify::is_away(member, last_seen, chrono::Utc::now(), away) {
+                    db::add_notification(conn, &nick, &channel, &sender, &message, notify::MAX_SAVED_LINES)?;
+                    notified.push(nick);
+                }
+            }
+            Ok(notified)
+        })
+        .await;
+    match saved {
+        Ok(notified) if !notified.is_empty() => tracing::debug!(?notified, "Saved a line for users who are away"),
+        Ok(_) => {}
+        Err(e) => tracing::error!("Failed to save notifications: {:?}", e),
+    }
+}
+
+/// Checks a message in a moderated channel against the rules. One that breaks them earns its
+/// sender a warning, and whatever else the channel asked for. Bot moderators and the channel's
+/// half-ops and up aren't checked.
+async fn moderate_message(
+    transport: &dyn ChatTransport,
+    state: &BotState,
+    channel: &str,
+    nick: &str,
+    message: &str,
+    channel_settings: &ChannelSettings,
+) -> Result<()> {
+    let channel_staff = state.roster.member(channel, nick).is_some_and(|member| member.has_status('%'));
+    if channel_staff || permission_of(state, nick).await? >= Permission::Moderator {
+        return Ok(());
+    }
+    // Someone talking fast has one message checked at a time, and the rest go by unchecked
+    let Some(_checking) = state.moderation.start(channel, nick) else {
+        tracing::debug!(%channel, %nick, "Still checking an earlier message, not checking this one");
+        return Ok(());
+    };
+    // The checks share the daily token budget with everything else
+    let today = chrono::Utc::now().format("%Y-%m-%d").to_string();
+    let budget = state.config().daily_token_budget;
+    if budget > 0 {
+        let day = today.clone();
+        if state.db.run(move |conn| db::get_tokens_used(conn, &day, None)).await? >= budget {
+            tracing::debug!(%channel, "Daily token budget exhausted, not checking messages");
+            return Ok(());
+        }
+    }
+    let llm = state.llm();
+    let llm = UsageMeter::new(&*llm);
+    let violation = ai_handler::rule_violation(&llm, channel, nick, message).await;
+    record_usage(state, channel, &llm).await;
+    let violation = violation?;
+    let Some(reason) = violation else {
+        return Ok(());
+    };
+    let since = (chrono::Utc::now() - chrono::Duration::days(moderation::WARNING_WINDOW_DAYS)).timestamp();
+    let (warned_channel, warned_nick, warned_reason) = (channel.to_string(), nick.to_string(), reason.clone());
+    let warnings = state.db.run(move |conn| db::add_warning(conn, &warned_channel, &warned_nick, &warned_reason, since)).await?;
+    tracing::info!(%channel, %nick, %reason, warnings, "Message broke the channel's rules");
+
+    let actions = &channel_settings.moderation;
+    let kick_after = actions.contains(&ModerationAction::Kick).then_some(channel_settings.kick_after);
+    // Every warning counts towards a kick, but a flood of them is told about only now and then
+    let tell = state.moderation.may_warn(channel, nick, Instant::now());
+    if tell && actions.contains(&ModerationAction::Warn) {
+        transport.send_message(channel, &moderation::warning_text(nick, &reason, warnings, kick_after)).await?;
+    }
+    if tell && actions.contains(&ModerationAction::Notify) {
+        match &channel_settings.ops_channel {
+            Some(ops_channel) => {
+                let notice = moderation::notice_text(channel, nick, &reason, warnings, message);
+                transport.send_message(ops_channel, &notice).await?;
+            }
+            None => tracing::warn!(%channel, "No ops_channel set to tell about a warning"),
+        }
+    }
+    if kick_after.is_some_and(|kick_after| warnings >= kick_after) {
+        // Going by the roster, which only IRC keeps
+        let nickname = state.config().nickname.clone();
+        match state.roster.member(channel, &nickname) {
+            Some(me) if me.has_status('%') => {
+                tracing::info!(%channel, %nick, warnings, "Kicking user for breaking the rules");
+                transport.kick(channel, nick, &format!("{} ({} warnings)", reason, warnings)).await?;
+            }
+            _ => tracing::warn!(%channel, %nick, "Not a half-op or up, so can't kick a user with too many warnings"),
+        }
+    }
+    Ok(())
+}
+
+/// Applies the "nick++" / "nick--" votes in a message. Voting for yourself doesn't count.
+async fn record_karma_votes(state: &BotState, channel: &str, voter: &str, message: &str) {
+    for (nick, delta) in karma::parse_votes(message) {
+        if nick.eq_ignore_ascii_case(voter) {
+            tracing::debug!(%channel, %voter, "Ignoring karma vote for self");
+            continue;
+        }
+        let (vote_channel, vote_nick) = (channel.to_string(), nick.clone());
+        match state.db.run(move |conn| db::add_karma(conn, &vote_channel, &vote_nick, delta)).await {
+            Ok(score) => tracing::info!(%channel, %voter, %nick, delta, score, "Karma updated"),
+            Err(e) => tracing::error!("Failed to update karma: {:?}", e),
+        }
+    }
+}
+
+/// Answers `!karma <nick>` with the nick's score, or a bare `!karma` with the channel's top scores.
+async fn answer_karma_query(transport: &dyn ChatTransport, state: &BotState, channel: &str, nick: &str) -> Result<()> {
+    let query_channel = channel.to_string();
+    let reply = match nick.split_whitespace().next().map(|nick| nick.trim_start_matches('@')) {
+        Some(nick) => {
+            let query_nick = nick.to_string();
+            let karma = state.db.run(move |conn| db::get_karma(conn, &query_channel, &query_nick)).await?;
+            format!("{} has {} karma.", nick, karma)
+        }
+        None => {
+            let top = state
+                .db
+                .run(move |conn| db::get_karma_ranking(conn, &query_channel, karma::TOP_KARMA_COUNT, true))
+                .await?;
+            if top.is_empty() {
+                "Nobody has any karma here yet! Give some with nick++.".to_string()
+            } else {
+                let scores: Vec<String> = top.iter().map(|(nick, karma)| format!("{} ({})", nick, karma)).collect();
+                format!("Top karma: {}", scores.join(", "))
+            }
+        }
+    };
+    transport.send_message(channel, &reply).await
+}
+
+/// Says the titles of the links in a message, if the channel has link titles enabled.
+async fn announce_url_titles(transport: &dyn ChatTransport, state: &BotState, channel: &str, urls: &[String]) -> Result<()> {
+    let enabled_channel = channel.to_string();
+    if !state.db.run(move |conn| db::url_titles_enabled(conn, &enabled_channel)).await? {
+        return Ok(());
+    }
+    let settings = state.settings();
+    let pages = urls.iter().filter(|url| !ai_handler::is_image_url(url)).take(url_titles::MAX_TITLES_PER_MESSAGE);
+    for url in pages {
+        let title = match url_titles::fetch_title(&state.proxy, url).await {
+            Ok(Some(title)) => title,
+            Ok(None) => continue,
+            Err(e) => {
+                tracing::debug!(%url, "No title for link: {:#}", e);
+                continue;
+            }
+        };
+        // Titles are chosen by whoever runs the site, so they go through the output filter too
+        let text = settings.output_filter.apply(&title.announcement(), "");
+        transport.send_message(channel, &text).await?;
+        if !transport.echoes_sent_messages() {
+            let (log_channel, log_nick) = (channel.to_string(), settings.config.nickname.clone());
+            state.db.run(move |conn| db::log_message(conn, &log_channel, &log_nick, &text)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Tells someone over the rate limit when to try again.
+fn breather_message(retry_after: Duration) -> String {
+    let minutes = retry_after.as_secs().div_ceil(60).max(1);
+    format!(
+        "Phew, this Emul needs a little breather! Try again in {} minute{}, okay?",
+        minutes,
+        if minutes == 1 { "" } else { "s" }
+    )
+}
+
+/// A private message that isn't a command, with `--dm-chat` on: it goes in the sender's own
+/// conversation history and is answered like a message addressing the bot in a channel. The
+/// history is kept under the sender's services `account`, since a nick is anyone's to take, so
+/// senders who aren't logged in are answered without one.
+async fn handle_direct_message(
+    transport: Arc<dyn ChatTransport>,
+    state: &BotState,
+    nick: &str,
+    account: Option<&str>,
+    message: &str,
+) -> Result<()> {
+    let sender = nick.to_string();
+    if state.db.run(move |conn| db::is_ignored(conn, &sender)).await? {
+        tracing::debug!(%nick, "Ignoring private message from ignored user");
+        return Ok(());
+    }
+    if bot_guard::is_bot(&state.config().ignored_bots, nick) {
+        tracing::debug!(%nick, "Ignoring private message from a bot");
+        return Ok(());
+    }
+    // A private conversation counts as its own channel for the rate limits
+    let verdict = state.rate_limiter.lock().await.check(nick, Some(nick), Instant::now());
+    if let RateLimitVerdict::Limited { retry_after, notify } = verdict {
+        tracing::info!(%nick, ?retry_after, "Private AI request rate limited");
+        if notify {
+            transport.send_message(nick, &breather_message(retry_after)).await?;
+        }
+        return Ok(());
+    }
+
+    if let Some(account) = account {
+        let (user, sender, text) = (account.to_string(), nick.to_string(), message.to_string());
+        state.db.run(move |conn| db::log_dm(conn, &user, &sender, &text)).await?;
+    }
+    tracing::info!(%nick, "Triggering AI for private message");
+    enqueue_ai_request(
+        state,
+        AiRequest {
+            transport,
+            channel: nick.to_string(),
+            nick: nick.to_string(),
+            message: message.to_string(),
+            was_addressed: true,
+            direct: true,
+            account: account.map(str::to_string),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+/// A triggered AI request, waiting for its turn in the channel's queue.
+struct AiRequest {
+    transport: Arc<dyn ChatTransport>,
+    channel: String,
+    nick: String,
+    message: String,
+    was_addressed: bool,
+    /// A private conversation with `nick`, whose nick is also the channel.
+    direct: bool,
+    /// The services account a private conversation's history is kept under, if they're logged in.
+    account: Option<String>,
+}
+
+/// Queues an AI request behind any others in the same channel. Each channel gets a worker task
+/// that handles its requests one by one, so every request sees the responses to earlier ones in
+/// its history instead of racing them. Workers stop once their channel has been quiet for
+/// `AI_QUEUE_IDLE_TIME`, and are started again by the next request.
+async fn enqueue_ai_request(state: &BotState, request: AiRequest) {
+    let mut queues = state.ai_queues.lock().await;
+    let queue = match queues.get(&request.channel) {
+        Some(queue) if !queue.is_closed() => queue,
+        _ => {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(run_ai_queue(state.clone(), request.channel.clone(), rx));
+            queues.entry(request.channel.clone()).insert_entry(tx).into_mut()
+        }
+    };
+    if queue.send(request).is_err() {
+        tracing::error!("AI request queue closed unexpectedly, dropping request");
+    }
+}
+
+async fn run_ai_queue(state: BotState, channel: String, mut requests: mpsc::UnboundedReceiver<AiRequest>) {
+    tracing::debug!(%channel, "Started AI request queue");
+    loop {
+        let request = match timeout(AI_QUEUE_IDLE_TIME, requests.recv()).await {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(_) => {
+                // Nothing can be queued while the queues are locked, so nothing is lost
+                let mut queues = state.ai_queues.lock().await;
+                if requests.is_empty() {
+                    queues.remove(&channel);
+                    tracing::debug!(%channel, "Stopped idle AI request queue");
+                    break;
+                }
+                continue;
+            }
+        };
+        // One span for the whole request, for tracing where its time goes
+        let span = tracing::info_span!(
+            "ai_request",
+            channel = %request.channel,
+            nick = %request.nick,
+            addressed = request.was_addressed,
+            transport = request.transport.name(),
+        );
+        let state = state.clone();
+        async move {
+            // So the history has the answer to the previous request, where the server echoes it back
+            request
+                .transport
+                .sent_messages_logged(&request.channel)
+                .instrument(tracing::info_span!("wait_for_echoes"))
+                .await;
+            handle_ai_request(
+                request.transport,
+                state,
+                request.channel,
+                request.nick,
+                request.message,
+                request.was_addressed,
+                request.direct,
+                request.account,
+            )
+            .await;
+        }
+        .instrument(span)
+        .await;
+    }
+}
+
+/// Keeps showing the bot as typing in `channel` until aborted.
+async fn keep_typing(transport: Arc<dyn ChatTransport>, channel: String) {
+    loop {
+        if let Err(e) = transport.send_typing(&channel).await {
+            tracing::debug!(%channel, "Failed to send typing notice: {:#}", e);
+        }
+        sleep(TYPING_INTERVAL).await;
+    }
+}
+
+/// The options for running the AI and its tools in `channel`.
+async fn chatbot_options(state: &BotState, config: &Config, channel: &str) -> ai_handler::ChatbotOptions {
+    let mut options = ai_handler::ChatbotOptions::from(config);
+    options.nsfw_threshold = config.nsfw_threshold_for(channel);
+    options.tools = state.tools.lock().await.clone();
+    options.db = Some(state.db.clone());
+    options.page_cache = state.page_cache.clone();
+    options.response_cache = state.response_cache.clone();
+    options.roster = Some(state.roster.clone());
+    options.proxy = state.proxy.clone();
+    options.network = network_of(config, channel);
+    options
+}
+
+/// The network a channel (or private conversation) is on, which facts about users are kept per:
+/// Discord, or the IRC server.
+fn network_of(config: &Config, channel: &str) -> String {
+    match transport::is_discord_channel(channel) {
+        true => "discord".to_string(),
+        false => irc_network(config),
+    }
+}
+
+/// The network facts about IRC users are kept per: the IRC server.
+fn irc_network(config: &Config) -> String {
+    config.server.as_deref().unwrap_or_default().to_lowercase()
+}
+
+/// Tells the admins about tool calls the AI queued for approval. Only IRC can reach them in
+/// private; requests from elsewhere wait in !pending.
+async fn notify_pending_approvals(
+    transport: &dyn ChatTransport,
+    state: &BotState,
+    channel: &str,
+    nick: &str,
+    invocations: &[ai_handler::ToolInvocation],
+) {
+    let queued: Vec<_> = invocations.iter().filter(|invocation| invocation.pending_approval.is_some()).collect();
+    if queued.is_empty() || transport.name() != "irc" {
+        return;
+    }
+    let admins: Vec<String> = match state.db.run(db::get_permission_levels).await {
+        Ok(levels) => levels
+            .into_iter()
+            .filter(|(_, level)| level.parse::<Permission>().is_ok_and(|level| level >= Permission::Admin))
+            .map(|(admin, _)| admin)
+            .collect(),
+        Err(e) => {
+            tracing::error!("Failed to fetch permission levels: {:?}", e);
+            return;
+        }
+    };
+    for invocation in queued {
+        let Some(id) = invocation.pending_approval else { continue };
+        let call = db::PendingToolCall {
+            id,
+            timestamp: chrono::Utc::now(),
+            channel: channel.to_string(),
+            nick: nick.to_string(),
+            tool: invocation.name.clone(),
+            args: invocation.args.to_string(),
+        };
+        let text = format!("Waiting for approval: {} -- !approve {} or !deny {}", tool_log::display_pending(&call), id, id);
+        for admin in &admins {
+            if let Err(e) = transport.send_message(admin, &text).await {
+                tracing::warn!(%admin, "Failed to tell an admin about a queued tool call: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Handles fetching history, calling AI, and sending response
+#[allow(clippy::too_many_arguments)]
+async fn handle_ai_request(
+    transport: Arc<dyn ChatTransport>,
+    state: BotState,
+    channel: String,
+    triggering_nick: String,
+    triggering_message: String,
+    was_addressed: bool, // Could be used to adjust AI prompt/behaviour
+    direct: bool,
+    account: Option<String>,
+) {
+    tracing::info!(%channel, nick=%triggering_nick, addressed=%was_addressed, transport = transport.name(), "Handling AI request");
+    let settings = state.settings(); // One consistent version, even if reloaded mid-request
+    let llm = UsageMeter::new(&*settings.llm); // Every call's tokens count against the budget
+
+    // Out of budget for today: stay in character instead of calling the API
+    let today = chrono::Utc::now().format("%Y-%m-%d").to_string();
+    if settings.config.daily_token_budget > 0 {
+        let day = today.clone();
+        let used = state.db.run(move |conn| db::get_tokens_used(conn, &day, None)).await.unwrap_or_else(|e| {
+            tracing::error!("Failed to read token usage: {:?}", e);
+            0
+        });
+        if used >= settings.config.daily_token_budget {
+            tracing::warn!(%channel, used, budget = settings.config.daily_token_budget, "Daily token budget exhausted, not calling AI");
+            record_ai_outcome(&state, &channel, "BUDGET").await;
+            if was_addressed {
+                let _ = transport
+                    .send_message(
+                        &channel,
+                        &format!(
+                            "{}: *yawn*... this Emul is all talked out for today. Ask again tomorrow, okay?",
+                            triggering_nick
+                        ),
+                    )
+                    .await;
+            }
+            return;
+        }
+    }
+
+    // Markdown is rendered for the network unless the channel has chosen otherwise
+    let settings_channel = channel.clone();
+    let channel_settings = state
+        .db
+        .run(move |conn| db::get_channel_settings(conn, &settings_channel))
+        .await
+        .map(|overrides| ChannelSettings::from_overrides(&overrides))
+        .unwrap_or_else(|e| {
+            tracing::error!(%channel, "Failed to read channel settings: {:?}", e);
+            ChannelSettings::default()
+        });
+    let formatting = channel_settings.formatting.unwrap_or_else(|| transport.default_formatting());
+    // Pastes would skip moderation, so moderated channels keep everything in the channel
+    let style = OutputStyle {
+        formatting,
+        paste: settings
+            .config
+            .paste_url
+            .clone()
+            .filter(|_| !settings.output_filter.needs_moderation(&channel))
+            .map(|url| (url, settings.config.paste_min_lines)),
+        proxy: state.proxy.clone(),
+    };
+
+    // 1. Fetch History
+    let (history_channel, history_account) = (channel.clone(), account.clone());
+    let (history_nick, history_message) = (triggering_nick.clone(), triggering_message.clone());
+    let history_result = state
+        .db
+        .run(move |conn| match (direct, history_account) {
+            (true, Some(account)) => db::get_dm_log(conn, &account, LOG_HISTORY_LINES),
+            // Without an account there's no telling whose history it is, so there's only this message
+            (true, None) => Ok(vec![db::LogEntry {
+                timestamp: chrono::Utc::now(),
+                channel: history_channel,
+                nick: history_nick,
+                message: history_message,
+            }]),
+            (false, _) => db::get_channel_log(conn, &history_channel),
+        })
+        .instrument(tracing::info_span!("fetch_history"))
+        .await;
+    if let Err(e) = history_result {
+        tracing::error!(%channel, "Failed to fetch channel history: {:?}", e);
+        // Maybe send an error message to the channel?
+        // let _ = client.send_privmsg(&channel, "Wawa~ I couldn't remember what we were talking about!");
+        return;
+    }
+    let history = history_result.unwrap();
 
-    let should_trigger_ai = is_addressed || state.bn_interject.should_interject();
+    // A random interjection first checks that the conversation is one worth joining
+    if !was_addressed && channel_settings.interject_check {
+        let recent = &history[history.len().saturating_sub(INTERJECTION_CHECK_LINES)..];
+        let welcome =
+            ai_handler::interjection_welcome(&llm, &settings.config.nickname, recent, &channel_settings.avoid_topics).await;
+        record_usage(&state, &channel, &llm).await;
+        match welcome {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::info!(%channel, "Interjection check found nothing to add, staying quiet");
+                return;
+            }
+            Err(e) => {
+                tracing::warn!(%channel, "Interjection check failed, staying quiet: {:#}", e);
+                return;
+            }
+        }
+    }
+
+    // 2. Call the AI Handler (your implementation)
+    let mut chatbot_options = chatbot_options(&state, &settings.config, &channel).await;
+    chatbot_options.prefetch_images = channel_settings.images.unwrap_or(settings.config.prefetch_urls);
+    chatbot_options.direct = direct;
+    // Answer in the language the bot was addressed in, or else the channel's
+    let detected_language = match was_addressed && channel_settings.detect_language {
+        true => ai_handler::detect_language(&llm, &triggering_message).await.unwrap_or_else(|e| {
+            tracing::warn!(%channel, "Language detection failed: {:#}", e);
+            None
+        }),
+        false => None,
+    };
+    record_usage(&state, &channel, &llm).await;
+    chatbot_options.reply_language = detected_language.or_else(|| channel_settings.language.clone());
+    chatbot_options.channel_language = channel_settings.language.clone();
+    chatbot_options.persona = channel_settings.persona;
+    chatbot_options.requester = triggering_nick.clone();
+    if !chatbot_options.tool_policies.is_empty() {
+        // Permissions belong to IRC nicks; a Discord user can call themselves anything
+        chatbot_options.requester_is_admin = transport.name() == "irc"
+            && permission_of(&state, &triggering_nick)
+                .await
+                .inspect_err(|e| tracing::error!(nick = %triggering_nick, "Failed to check permission level: {:?}", e))
+                .is_ok_and(|permission| permission >= Permission::Admin);
+    }
+    chatbot_options.previous_reply = state
+        .last_replies
+        .lock()
+        .await
+        .get(&channel)
+        .filter(|(sent_at, _)| sent_at.elapsed() < REPLY_FOLLOWUP_WINDOW)
+        .map(|(_, reply)| reply.clone());
+    if settings.config.channel_summaries && !direct {
+        let summary_channel = channel.clone();
+        chatbot_options.channel_summary = state
+            .db
+            .run(move |conn| db::get_channel_summary(conn, &summary_channel))
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!(%channel, "Failed to read channel summary: {:?}", e);
+                None
+            })
+            .map(|summary| summary.summary);
+    }
+    // The output filter needs the prompt to spot leaks of it
+    let system_prompt = format!("{}\n{}", settings.prompt, UNTRUSTED_CONTENT_NOTICE);
+
+    // Send sentences as they stream in, unless the whole response must be moderated first
+    let mut streamer = None;
+    if settings.config.stream_responses && !settings.output_filter.needs_moderation(&channel) {
+        let (text_tx, text_rx) = mpsc::unbounded_channel();
+        chatbot_options.text_stream = Some(text_tx);
+        streamer = Some(tokio::spawn(stream_sentences(
+            transport.clone(),
+            settings.output_filter.clone(),
+            channel.clone(),
+            system_prompt.clone(),
+            line_budget(&settings.config),
+            style.clone(),
+            text_rx,
+        )));
+    }
+
+    // Show the bot typing while it works on an answer
+    let typing = was_addressed.then(|| tokio::spawn(keep_typing(transport.clone(), channel.clone())));
+
+    // Older context that has scrolled out of the history window; private conversations have none
+    let memories = if settings.config.memory_top_k > 0 && !direct {
+        memory::recall(
+            &llm,
+            &state.db,
+            &channel,
+            &triggering_message,
+            settings.config.memory_top_k,
+            history.first().map(|entry| entry.timestamp),
+        )
+        .instrument(tracing::info_span!("recall_memories"))
+        .await
+        .unwrap_or_else(|e| {
+            tracing::warn!(%channel, "Failed to recall memories: {:?}", e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    record_usage(&state, &channel, &llm).await;
+
+    // Kept for asking again if the answer repeats an earlier one; private conversations aren't checked
+    let retry_history = (!direct).then(|| history.clone());
+    let ai_result = ai_handler::call_chatbot(
+        &llm,
+        &channel,
+        &triggering_nick,
+        &triggering_message,
+        history,
+        &memories,
+        &settings.prompt,
+        was_addressed,
+        &state.image_cache, // Pass the image cache
+        &chatbot_options,
+    )
+    .await;
+    record_usage(&state, &channel, &llm).await; // Failed calls used tokens too
+    chatbot_options.text_stream = None; // Closes the text stream so the streamer finishes
+    if let Some(typing) = typing {
+        typing.abort();
+    }
+    let streamed = match streamer {
+        Some(handle) => handle.await.unwrap_or_default(),
+        None => StreamedText::default(),
+    };
+
+    // 3. Send Response
+    match ai_result {
+        Ok(response) => {
+            state.health.ai_succeeded();
+            // Answers from the fallback model are counted apart, so !aistats shows degraded service
+            if response.used_fallback {
+                tracing::warn!(%channel, "AI request was answered by the fallback model");
+                record_ai_outcome(&state, &channel, &format!("{} (fallback)", response.finish_reason)).await;
+            } else {
+                record_ai_outcome(&state, &channel, &response.finish_reason).await;
+            }
+            tracing::info!(%channel, tokens = response.usage.total(), "AI request token usage");
+            notify_pending_approvals(&*transport, &state, &channel, &triggering_nick, &response.invoked_tools).await;
+
+            // Run the output filter before anything reaches the channel
+            let mut text_response = settings.output_filter.apply(&response.text_response, &system_prompt);
+            let mut unfiltered_response = response.text_response;
+
+            // Nearly what was said here lately: ask once more for something else, without tools so
+            // nothing they do happens twice
+            if streamed.sent_chars == 0
+                && let Some(history) = retry_history
+                && let Some(earlier) = state.recent_responses.repeated(&channel, &text_response)
+            {
+                tracing::info!(%channel, "AI response repeats an earlier one, asking for another");
+                chatbot_options.avoid_repeating = Some(earlier);
+                chatbot_options.tools = Arc::new(ToolRegistry::default());
+                let retried = ai_handler::call_chatbot(
+                    &llm,
+                    &channel,
+                    &triggering_nick,
+                    &triggering_message,
+                    history,
+                    &memories,
+                    &settings.prompt,
+                    was_addressed,
+                    &state.image_cache,
+                    &chatbot_options,
+                )
+                .await;
+                record_usage(&state, &channel, &llm).await;
+                let retried = match retried {
+                    Ok(retry) => {
+                        Some(retry.text_response).filter(|retried| {
+                            let filtered = settings.output_filter.apply(retried, &system_prompt);
+                            state.recent_responses.repeated(&channel, &filtered).is_none()
+                        })
+                    }
+                    Err(e) => {
+                        tracing::warn!(%channel, "Failed to get a different AI response: {:?}", e);
+                        None
+                    }
+                };
+                match retried {
+                    Some(retried) => {
+                        text_response = settings.output_filter.apply(&retried, &system_prompt);
+                        unfiltered_response = retried;
+                    }
+                    // Nobody asked for an interjection, so it can just as well not happen
+                    None if !was_addressed => {
+                        tracing::info!(%channel, "Not repeating an interjection");
+                        return;
+                    }
+                    None => {}
+                }
+            }
+
+            // Code goes to the paste service, and walls of text are condensed rather than
+            // flooded into the channel, with a link to the full answer if there's a paste service
+            let max_lines = line_budget(&settings.config);
+            let line_length = transport.max_message_length(&channel);
+            if streamed.sent_chars == 0 {
+                text_response = paste_long_code(text_response, &style).await;
+            }
+            let line_count = split_marked(line_length, &formatting::render(&text_response, formatting)).len();
+            if streamed.sent_chars == 0 && line_count > max_lines {
+                tracing::info!(%channel, line_count, max_lines, "AI response too long, condensing");
+                // The filtered answer was cut to the length limit; the paste gets all of it
+                let full_text = settings.output_filter.apply_with_limit(&unfiltered_response, &system_prompt, usize::MAX);
+                let full_answer = match &style.paste {
+                    Some((paste_url, _)) if max_lines > 1 => paste::upload(&style.proxy, paste_url, &full_text)
+                        .await
+                        .inspect_err(|e| tracing::warn!(%channel, "Failed to paste full AI response: {:?}", e))
+                        .ok(),
+                    _ => None,
+                };
+                let condensed_lines = max_lines - usize::from(full_answer.is_some());
+                let condensed = ai_handler::condense_response(&llm, &text_response, condensed_lines, line_length).await;
+                record_usage(&state, &channel, &llm).await;
+                match condensed {
+                    Ok(condensed) => text_response = settings.output_filter.apply(&condensed, &system_prompt),
+                    // Sending cuts the response off at the line limit instead
+                    Err(e) => tracing::warn!(%channel, "Failed to condense AI response: {:?}", e),
+                }
+                if let Some(url) = full_answer {
+                    text_response = format!("{}\nFull answer: {}", text_response.trim_end(), url);
+                }
+            }
+
+            if settings.output_filter.needs_moderation(&channel) {
+                let safe = ai_handler::response_is_safe(&llm, &text_response).await;
+                record_usage(&state, &channel, &llm).await;
+                match safe {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        tracing::warn!(%channel, response = %text_response, "AI response flagged by moderation, not sending");
+                        let _ = transport
+                            .send_message(
+                                &channel,
+                                &format!("{}: Hmm, this Emul had better not say that one...", triggering_nick),
+                            )
+                            .await;
+                        return;
+                    }
+                    Err(e) => {
+                        // Fail closed for moderated channels
+                        tracing::error!(%channel, "Moderation check failed, not sending: {:?}", e);
+                        return;
+                    }
+                }
+            }
+
+            tracing::info!(%channel, "Sending AI response");
+            // Store the AI response's text part in the database, unless the server echoes what's sent.
+            // Echoes of private messages aren't logged, so those are stored here, under the
+            // sender's account when they have one.
+            if direct {
+                if let Some(log_user) = account.clone() {
+                    let (log_nick, log_text) = (settings.config.nickname.clone(), text_response.clone());
+                    state
+                        .db
+                        .run(move |conn| db::log_dm(conn, &log_user, &log_nick, &log_text))
+                        .await
+                        .unwrap_or_else(|e| tracing::error!("Failed to log AI response: {:?}", e));
+                }
+            } else if !transport.echoes_sent_messages() {
+                let (log_channel, log_nick, log_text) = (channel.clone(), settings.config.nickname.clone(), text_response.clone());
+                state
+                    .db
+                    .run(move |conn| db::log_message(conn, &log_channel, &log_nick, &log_text))
+                    .await
+                    .unwrap_or_else(|e| tracing::error!("Failed to log AI response: {:?}", e));
+            }
+            let reply = ai_handler::PreviousReply {
+                nick: triggering_nick.clone(),
+                message: triggering_message.clone(),
+                response: text_response.clone(),
+                sent_at: chrono::Utc::now(),
+            };
+            state.last_replies.lock().await.insert(channel.clone(), (Instant::now(), reply));
+            if !direct {
+                state.exchanges.answered(&channel, &triggering_nick, Instant::now());
+                state.recent_responses.record(&channel, &text_response);
+                relay_message(&state, &channel, &settings.config.nickname, &text_response).await;
+            }
+            if streamed.sent_chars > 0 {
+                // Most of the response is already out; finish with whatever didn't end in a full sentence
+                let remaining = settings.output_filter.max_length().saturating_sub(streamed.sent_chars);
+                let remaining_lines = max_lines.saturating_sub(streamed.sent_lines);
+                if response.finish_reason != "MAX_TOKENS" && remaining > 0 && remaining_lines > 0 {
+                    let tail = settings.output_filter.apply_continued(&streamed.window, &streamed.tail, &system_prompt, remaining);
+                    let tail = paste_long_code(tail, &style).await;
+                    send_lines(&*transport, &channel, &tail, remaining_lines, &style).await;
+                }
+            } else {
+                send_lines(&*transport, &channel, &text_response, max_lines, &style).await;
+            }
+        }
+        Err(e) => {
+            if let Some(blocked) = e.downcast_ref::<ai_handler::BlockedResponse>() {
+                tracing::warn!(%channel, reason = blocked.reason(), "AI response was blocked");
+                record_ai_outcome(&state, &channel, blocked.reason()).await;
+                if streamed.sent_chars > 0 {
+                    return; // A deflection would contradict the part of the answer already out
+                }
+                // Stay in character rather than reporting an error
+                let deflection = match blocked {
+                    ai_handler::BlockedResponse::Recitation => {
+                        "Hmm, this Emul can't just recite that word for word. Ask me to put it my own way?"
+                    }
+                    _ => "Ah, um... this Emul would rather not talk about that one, you know!",
+                };
+                let _ = transport.send_message(&channel, &format!("{}: {}", triggering_nick, deflection)).await;
+                return;
+            }
+            if let Some(empty) = e.downcast_ref::<ai_handler::EmptyResponse>() {
+                tracing::warn!(%channel, finish_reason = %empty.finish_reason, "AI response was empty");
+                record_ai_outcome(&state, &channel, &empty.finish_reason).await;
+                let _ = transport
+                    .send_message(
+                        &channel,
+                        &format!(
+                            "{}: Oh! This Emul thought so hard the answer got lost. Could you ask again?",
+                            triggering_nick
+                        ),
+                    )
+                    .await;
+                return;
+            }
+
+            tracing::error!(%channel, "AI handler failed: {:?}", e);
+            record_ai_outcome(&state, &channel, "ERROR").await;
+            // Optionally send a generic error message to the channel
+            let _ = transport
+                .send_message(
+                    &channel,
+                    &format!(
+                        "{}: Eeep! I had trouble thinking about that...",
+                        triggering_nick
+                    ),
+                )
+                .await;
+        }
+    }
+}
+
+/// How response text is prepared for the channel before it's split into lines.
+#[derive(Debug, Clone)]
+struct OutputStyle {
+    formatting: Formatting,
+    /// The paste service long code blocks go to, and how many lines make one long.
+    paste: Option<(String, usize)>,
+    /// How the paste service is reached.
+    proxy: HttpProxy,
+}
+
+/// Moves a response's long code blocks to the paste service, if there is one.
+async fn paste_long_code(text: String, style: &OutputStyle) -> String {
+    match &style.paste {
+        Some((paste_url, min_lines)) => paste::paste_code_blocks(&style.proxy, paste_url, &text, *min_lines).await,
+        None => text,
+    }
+}
+
+/// Renders a response's markdown, splits it into lines that fit the transport and sends them;
+/// the transport paces them. At most `max_lines` lines are sent; returns how many were.
+async fn send_lines(
+    transport: &dyn ChatTransport,
+    channel: &str,
+    text: &str,
+    max_lines: usize,
+    style: &OutputStyle,
+) -> usize {
+    let text = formatting::render(text, style.formatting);
+    let mut lines = split_marked(transport.max_message_length(channel), &text);
+    if lines.len() > max_lines {
+        tracing::warn!(%channel, lines = lines.len(), max_lines, "Response exceeds the line limit, cutting it off");
+        lines.truncate(max_lines);
+    }
+    // All at once, so a concurrent answer's lines can't end up among these
+    match transport.send_lines(channel, &lines, None).await {
+        Ok(()) => lines.len(),
+        Err(e) => {
+            tracing::error!(%channel, "Failed to send AI response: {}", e);
+            0
+        }
+    }
+}
+
+/// Maximum number of lines in one reply.
+fn line_budget(config: &Config) -> usize {
+    match config.max_reply_lines {
+        0 => usize::MAX,
+        max_lines => max_lines,
+    }
+}
+
+/// What `stream_sentences` managed to send, and the trailing text it held back.
+#[derive(Debug, Default)]
+struct StreamedText {
+    sent_chars: usize,
+    sent_lines: usize,
+    tail: String,
+    /// The end of the text sent so far, long enough to hold any system prompt line, for the
+    /// output filter to spot leaks split between batches.
+    window: String,
+}
+
+impl StreamedText {
+    /// Adds sent text to the window, dropping what no prompt line could still reach into.
+    fn slide_window(&mut self, sent: &str, system_prompt: &str) {
+        let longest_line = system_prompt.lines().map(str::len).max().unwrap_or(0);
+        self.window.push_str(sent);
+        let mut cut = self.window.len().saturating_sub(longest_line);
+        while !self.window.is_char_boundary(cut) {
+            cut += 1;
+        }
+        self.window.drain(..cut);
+    }
+}
+
+/// Sends streamed response text to the channel one batch of complete sentences at a time.
+/// Each batch goes through the output filter along with the end of the batches before it, and
+/// the total stays within its length limit and `max_lines`. Streamed text can't be condensed
+/// afterwards, so it is cut off at the line limit.
+async fn stream_sentences(
+    transport: Arc<dyn ChatTransport>,
+    output_filter: Arc<OutputFilter>,
+    channel: String,
+    system_prompt: String,
+    max_lines: usize,
+    style: OutputStyle,
+    mut text_rx: mpsc::UnboundedReceiver<ai_handler::StreamEvent>,
+) -> StreamedText {
+    let mut streamed = StreamedText::default();
+    while let Some(event) = text_rx.recv().await {
+        let text = match event {
+            ai_handler::StreamEvent::Text(text) => text,
+            // What the model said before calling a tool isn't part of the answer
+            ai_handler::StreamEvent::Discard => {
+                streamed.tail.clear();
+                continue;
+            }
+        };
+        streamed.tail.push_str(&text);
+        // Code blocks are sent whole, so they can be pasted and rendered as one
+        let open_block = paste::unclosed_code_block(&streamed.tail).map(|start| streamed.tail.split_off(start));
+        let sentences = take_complete_sentences(&mut streamed.tail);
+        streamed.tail.push_str(&open_block.unwrap_or_default());
+        let Some(sentences) = sentences else {
+            continue;
+        };
+        let remaining = output_filter.max_length().saturating_sub(streamed.sent_chars);
+        let remaining_lines = max_lines.saturating_sub(streamed.sent_lines);
+        if remaining == 0 || remaining_lines == 0 {
+            continue; // Keep draining so the sender never blocks on us
+        }
+        let filtered = output_filter.apply_continued(&streamed.window, &sentences, &system_prompt, remaining);
+        let filtered = paste_long_code(filtered, &style).await;
+        streamed.slide_window(&sentences, &system_prompt);
+        streamed.sent_chars += filtered.chars().count();
+        tracing::debug!(%channel, chars = streamed.sent_chars, "Streaming AI response");
+        streamed.sent_lines += send_lines(&*transport, &channel, &filtered, remaining_lines, &style).await;
+    }
+    streamed
+}
+
+/// Removes everything up to the last sentence end (terminal punctuation followed by
+/// whitespace, or a newline) from `buffer` and returns it.
+fn take_complete_sentences(buffer: &mut String) -> Option<String> {
+    let mut end = None;
+    let mut prev: Option<(usize, char)> = None;
+    for (i, c) in buffer.char_indices() {
+        if c == '\n' {
+            end = Some(i + 1);
+        } else if c.is_whitespace()
+            && let Some((j, p)) = prev
+            && matches!(p, '.' | '!' | '?' | '…' | '~')
+        {
+            end = Some(j + p.len_utf8());
+        }
+        prev = Some((i, c));
+    }
+    let end = end?;
+    let sentences = buffer[..end].trim().to_string();
+    *buffer = buffer[end..].trim_start().to_string();
+    (!sentences.is_empty()).then_some(sentences)
+}
+
+/// What public command handlers work with.
+struct ChannelContext {
+    transport: Arc<dyn ChatTransport>,
+    state: BotState,
+    channel: String,
+    settings: ChannelSettings,
+    /// The account the sender is logged in to, if the network says; on Discord, their user name.
+    account: Option<String>,
+}
+
+impl ChannelContext {
+    /// Answers the user in the channel, split to fit the network's lines and cut off after a few.
+    async fn reply(&self, nick: &str, text: &str) -> Result<()> {
+        let text = format!("{}: {}", nick, text);
+        let mut lines = split_marked(self.transport.max_message_length(&self.channel), &text);
+        if lines.len() > PUBLIC_REPLY_MAX_LINES {
+            lines.truncate(PUBLIC_REPLY_MAX_LINES);
+            if let Some(last) = lines.last_mut() {
+                last.push_str(" [...]");
+            }
+        }
+        self.transport.send_lines(&self.channel, &lines, None).await
+    }
+}
+
+/// Wraps an async public command handler into the `fn` a `CommandRegistry` holds.
+macro_rules! channel_handler {
+    ($handler:ident) => {{
+        fn boxed<'a>(ctx: &'a ChannelContext, cmd: Invocation<'a>) -> BoxFuture<'a, Result<()>> {
+            Box::pin($handler(ctx, cmd))
+        }
+        boxed
+    }};
+}
+
+/// The commands anyone can use in a channel, in the order `!help` lists them. Channels can turn
+/// them off with `!set #channel commands ...`.
+static PUBLIC_COMMANDS: LazyLock<CommandRegistry<ChannelContext>> = LazyLock::new(|| {
+    use Permission::*;
+    use commands::Command; // Not IRC's
+    CommandRegistry::new(vec![
+        Command::new("help", "[<command>]", Anyone, "Lists this channel's commands, or explains one", channel_handler!(show_channel_help)),
+        Command::new("roll", "<dice>", Anyone, "Rolls dice, like 2d6+1, 4d6kh3 or d20adv", channel_handler!(roll)),
+        Command::new("seen", "<nick>", Anyone, "Says when someone last spoke here, and what they said", channel_handler!(last_seen)),
+        Command::new("karma", "[<nick>]", Anyone, "Shows someone's karma, or the channel's top scores", channel_handler!(show_karma)),
+        Command::new("quote add", "<text>", Anyone, "Saves a quote", channel_handler!(add_quote)),
+        Command::new("quote get", "<number>", Anyone, "Shows a quote", channel_handler!(get_quote)),
+        Command::new("quote search", "<text>", Anyone, "Shows the newest quotes containing the text", channel_handler!(search_quotes)),
+        Command::new("quote random", "", Anyone, "Shows a random quote", channel_handler!(random_quote)),
+        Command::new("quote del", "<number>", Moderator, "Removes a quote", channel_handler!(remove_quote)),
+        Command::new("forgetme", "", Anyone, "Deletes the facts the AI has noted about you", channel_handler!(forget_me)),
+    ])
+});
+
+/// Runs the public command in a channel message, if it is one and the channel has it enabled.
+/// Returns whether the message was a command, so it shouldn't also go to the AI.
+async fn handle_public_command(
+    transport: &Arc<dyn ChatTransport>,
+    state: &BotState,
+    channel: &str,
+    nick: &str,
+    account: Option<&str>,
+    message: &str,
+    settings: &ChannelSettings,
+) -> Result<bool> {
+    let prefix = state.config().command_prefix.clone();
+    let Some(name) = message.strip_prefix(prefix.as_str()).and_then(|rest| rest.split_whitespace().next()) else {
+        return Ok(false);
+    };
+    if !settings.command_enabled(name) {
+        return Ok(false);
+    }
+    let permission = permission_of(state, nick).await?;
+    let dispatch = PUBLIC_COMMANDS.dispatch(&prefix, message, permission);
+    if !matches!(dispatch, Dispatch::Unknown) && !state.command_limiter.allow(nick, Instant::now()) {
+        tracing::debug!(%channel, %nick, "Ignoring a public command, too many lately");
+        return Ok(true);
+    }
+    match dispatch {
+        Dispatch::Run(command, args) => {
+            tracing::info!(%channel, %nick, command = command.name, "Public command received");
+            let ctx = ChannelContext {
+                transport: transport.clone(),
+                state: state.clone(),
+                channel: channel.to_string(),
+                settings: settings.clone(),
+                // Discord names are unique accounts of their own
+                account: match transport.name() {
+                    "irc" => account.map(str::to_string),
+                    _ => Some(nick.to_string()),
+                },
+            };
+            (command.handler)(&ctx, Invocation { nick, permission, args }).await?;
+        }
+        Dispatch::Usage(usage) => transport.send_message(channel, &format!("{}: Usage: {}", nick, usage)).await?,
+        Dispatch::Denied(command) => {
+            let reply = format!("{}: Sorry, {} is only for {}s and up!", nick, command.usage(&prefix), command.permission);
+            transport.send_message(channel, &reply).await?
+        }
+        // Not ours; maybe another bot's, or just a message that starts with the prefix
+        Dispatch::Unknown => return Ok(false),
+    }
+    Ok(true)
+}
+
+// --- Public command handlers ---
+
+async fn show_channel_help(ctx: &ChannelContext, cmd: Invocation<'_>) -> Result<()> {
+    let config = ctx.state.config();
+    let prefix = &config.command_prefix;
+    let enabled = |name: &str| ctx.settings.command_enabled(name);
+    if cmd.args.get(0).is_none() {
+        let commands = PUBLIC_COMMANDS.help(prefix, cmd.permission, enabled);
+        return ctx.reply(cmd.nick, &format!("Commands: {}. Try {}help <command> for details.", commands, prefix)).await;
+    }
+    let lines = PUBLIC_COMMANDS.describe(prefix, cmd.args.rest(0), cmd.permission, enabled);
+    if lines.is_empty() {
+        return ctx.reply(cmd.nick, &format!("There's no {} command here.", cmd.args.rest(0))).await;
+    }
+    for line in lines {
+        ctx.reply(cmd.nick, &line).await?;
+    }
+    Ok(())
+}
+
+async fn roll(ctx: &ChannelContext, cmd: Invocation<'_>) -> Result<()> {
+    let reply = match dice::roll(cmd.args.rest(0)) {
+        Ok(result) => result,
+        Err(e) => format!("{:#}", e),
+    };
+    ctx.reply(cmd.nick, &reply).await
+}
+
+async fn last_seen(ctx: &ChannelContext, cmd: Invocation<'_>) -> Result<()> {
+    let wanted = cmd.args.arg(0).trim_start_matches('@');
+    if wanted.eq_ignore_ascii_case(cmd.nick) {
+        return ctx.reply(cmd.nick, "You're right here, silly!").await;
+    }
+    let (channel, query_nick) = (ctx.channel.clone(), wanted.to_string());
+    let reply = match ctx.state.db.run(move |conn| db::get_last_seen(conn, &channel, &query_nick)).await? {
+        Some(entry) => format!(
+            "Last seen {}: {}",
+            ai_handler::format_relative_time(chrono::Utc::now() - entry.timestamp),
+            ctcp::display_line(&entry.nick, &entry.message)
+        ),
+        None => format!("This Emul hasn't seen {} say anything here.", wanted),
+    };
+    ctx.reply(cmd.nick, &reply).await
+}
+
+async fn show_karma(ctx: &ChannelContext, cmd: Invocation<'_>) -> Result<()> {
+    answer_karma_query(&*ctx.transport, &ctx.state, &ctx.channel, cmd.args.rest(0)).await
+}
+
+async fn forget_me(ctx: &ChannelContext, cmd: Invocation<'_>) -> Result<()> {
+    let user = match verify_user(&cmd, ctx.account.as_deref()) {
+        VerifiedUser::User(user) => user,
+        VerifiedUser::NotAdmin => return Ok(()), // The public command takes no nick
+        VerifiedUser::NotLoggedIn => return ctx.reply(cmd.nick, NOT_LOGGED_IN_MESSAGE).await,
+    };
+    let network = network_of(&ctx.state.config(), &ctx.channel);
+    let deleted = ctx.state.db.run(move |conn| db::delete_user_facts(conn, &network, &user)).await?;
+    tracing::info!(channel = %ctx.channel, nick = %cmd.nick, deleted, "Forgot facts about user");
+    ctx.reply(cmd.nick, &forgotten_facts_message(deleted)).await
+}
+
+async fn add_quote(ctx: &ChannelContext, cmd: Invocation<'_>) -> Result<()> {
+    let Some(text) = quotes::normalize(cmd.args.rest(0)) else {
+        let reply = format!("This Emul can't keep that one! Quotes can be up to {} characters.", quotes::MAX_QUOTE_LENGTH);
+        return ctx.reply(cmd.nick, &reply).await;
+    };
+    let (channel, added_by) = (ctx.channel.clone(), cmd.nick.to_string());
+    let id = ctx.state.db.run(move |conn| db::add_quote(conn, &channel, &text, &added_by)).await?;
+    tracing::info!(channel = %ctx.channel, nick = %cmd.nick, id, "Quote added");
+    ctx.reply(cmd.nick, &format!("Saved as quote #{}!", id)).await
+}
+
+/// Parses a quote number, as shown with or without its '#'.
+fn quote_id(arg: &str) -> Option<i64> {
+    arg.trim_start_matches('#').parse().ok()
+}
+
+async fn get_quote(ctx: &ChannelContext, cmd: Invocation<'_>) -> Result<()> {
+    let Some(id) = quote_id(cmd.args.arg(0)) else {
+        return ctx.reply(cmd.nick, "Quotes go by number, like 12.").await;
+    };
+    let channel = ctx.channel.clone();
+    match ctx.state.db.run(move |conn| db::get_quote(conn, &channel, id)).await? {
+        Some(quote) => ctx.reply(cmd.nick, &quotes::display(&quote)).await,
+        None => ctx.reply(cmd.nick, &format!("There's no quote #{} here.", id)).await,
+    }
+}
+
+async fn search_quotes(ctx: &ChannelContext, cmd: Invocation<'_>) -> Result<()> {
+    let (channel, text) = (ctx.channel.clone(), cmd.args.rest(0).to_string());
+    let found = ctx
+        .state
+        .db
+        .run(move |conn| db::search_quotes(conn, &channel, &text, quotes::SEARCH_RESULT_COUNT))
+        .await?;
+    if found.is_empty() {
+        return ctx.reply(cmd.nick, "No quotes match that.").await;
+    }
+    for quote in found {
+        ctx.reply(cmd.nick, &quotes::display(&quote)).await?;
+    }
+    Ok(())
+}
+
+async fn random_quote(ctx: &ChannelContext, cmd: Invocation<'_>) -> Result<()> {
+    let channel = ctx.channel.clone();
+    match ctx.state.db.run(move |conn| db::get_random_quote(conn, &channel)).await? {
+        Some(quote) => ctx.reply(cmd.nick, &quotes::display(&quote)).await,
+        None => {
+            let reply = format!("No quotes here yet! Save one with {}quote add.", ctx.state.config().command_prefix);
+            ctx.reply(cmd.nick, &reply).await
+        }
+    }
+}
+
+async fn remove_quote(ctx: &ChannelContext, cmd: Invocation<'_>) -> Result<()> {
+    let Some(id) = quote_id(cmd.args.arg(0)) else {
+        return ctx.reply(cmd.nick, "Quotes go by number, like 12.").await;
+    };
+    let channel = ctx.channel.clone();
+    if ctx.state.db.run(move |conn| db::remove_quote(conn, &channel, id)).await? {
+        tracing::info!(channel = %ctx.channel, nick = %cmd.nick, id, "Quote removed");
+        ctx.reply(cmd.nick, &format!("Quote #{} is gone.", id)).await
+    } else {
+        ctx.reply(cmd.nick, &format!("There's no quote #{} here.", id)).await
+    }
+}
+
+/// Admin commands start with this.
+const ADMIN_COMMAND_PREFIX: &str = "!";
+
+/// What admin command handlers work with.
+struct AdminContext {
+    irc: OutgoingQueue,
+    state: BotState,
+    /// The nick the server knows us by, to look ourselves up in the roster.
+    me: String,
+    /// The services account the sender is logged in to, if the server says.
+    account: Option<String>,
+}
+
+impl AdminContext {
+    /// The nick whose own data `cmd` may touch (see [`verify_user`]). Otherwise explains why not
+    /// and returns None.
+    fn verified_user(&self, cmd: &Invocation<'_>) -> Result<Option<String>> {
+        let (irc, nick) = (&self.irc, cmd.nick);
+        match verify_user(cmd, self.account.as_deref()) {
+            VerifiedUser::User(user) => return Ok(Some(user)),
+            VerifiedUser::NotAdmin => {
+                irc.send_privmsg(nick, format!("Sorry, only admins may do that for someone else. Try {}help.", ADMIN_COMMAND_PREFIX))?
+            }
+            VerifiedUser::NotLoggedIn => irc.send_privmsg(nick, NOT_LOGGED_IN_MESSAGE)?,
+        }
+        Ok(None)
+    }
+}
+
+/// What the sender of a command that touches someone's own data may do.
+enum VerifiedUser {
+    /// Act on this nick's data.
+    User(String),
+    /// Nothing; they named someone else without being an admin.
+    NotAdmin,
+    /// Nothing; they aren't logged in to services as the nick they're using.
+    NotLoggedIn,
+}
+
+/// What tells someone why they couldn't touch their own data without logging in.
+const NOT_LOGGED_IN_MESSAGE: &str =
+    "To make sure it's really you, log in to services (NickServ) as this nick first, or ask an admin to do it for you.";
+
+/// The nick whose own data `cmd` may touch: the sender's, if they're logged in to `account`
+/// under that nick, or the nick given, for admins acting on someone's behalf. A nick alone is
+/// anyone's to take.
+fn verify_user(cmd: &Invocation<'_>, account: Option<&str>) -> VerifiedUser {
+    if let Some(user) = cmd.args.get(0) {
+        return match cmd.permission >= Permission::Admin {
+            true => VerifiedUser::User(user.to_string()),
+            false => VerifiedUser::NotAdmin,
+        };
+    }
+    match account.is_some_and(|account| account.eq_ignore_ascii_case(cmd.nick)) {
+        true => VerifiedUser::User(cmd.nick.to_string()),
+        false => VerifiedUser::NotLoggedIn,
+    }
+}
+
+/// Wraps an async admin command handler into the `fn` a `CommandRegistry` holds.
+macro_rules! admin_handler {
+    ($handler:ident) => {{
+        fn boxed<'a>(ctx: &'a AdminContext, cmd: Invocation<'a>) -> BoxFuture<'a, Result<()>> {
+            Box::pin($handler(ctx, cmd))
+        }
+        boxed
+    }};
+}
+
+/// The commands taken in private messages, in the order `!help` lists them.
+static ADMIN_COMMANDS: LazyLock<CommandRegistry<AdminContext>> = LazyLock::new(|| {
+    use Permission::*;
+    use commands::Command; // Not IRC's
+    CommandRegistry::new(vec![
+        Command::new("optout", "[<nick>]", Anyone, "Stops logging and answering you (once logged in to services), and forgets what was logged; admins may name someone else", admin_handler!(opt_out)),
+        Command::new("optin", "[<nick>]", Anyone, "Undoes !optout", admin_handler!(opt_in)),
+        Command::new("forget", "[<nick>]", Anyone, "Forgets your private conversation with the bot", admin_handler!(forget_conversation)),
+        Command::new("forgetme", "[<nick>]", Anyone, "Deletes the facts the AI has noted about you", admin_handler!(forget_user_facts)),
+        Command::new("notify add", "<keyword>", Anyone, "Saves lines mentioning a word (like your nick) while you're away, for when you're back", admin_handler!(add_notify_keyword)),
+        Command::new("notify del", "<keyword>", Anyone, "Stops saving lines mentioning a word", admin_handler!(remove_notify_keyword)),
+        Command::new("notify list", "", Anyone, "Lists the words you're notified about", admin_handler!(list_notify_keywords)),
+        Command::new("notify off", "", Anyone, "Stops all notifications and drops the saved lines", admin_handler!(clear_notify)),
+        Command::new("help", "[<command>]", Anyone, "Lists the commands you may use, or explains one", admin_handler!(show_help)),
+        Command::new("ignore", "<nick>", Moderator, "Stops logging and answering a user", admin_handler!(ignore_user)),
+        Command::new("unignore", "<nick>", Moderator, "Undoes !ignore", admin_handler!(unignore_user)),
+        Command::new("ignored", "", Moderator, "Lists ignored users, including those who opted out", admin_handler!(list_ignored)),
+        Command::new("channels", "", Moderator, "Lists the channels joined on startup", admin_handler!(list_channels)),
+        Command::new("aistats", "<#channel>", Moderator, "Shows how AI requests ended in the last 24h and today's tokens", admin_handler!(show_ai_stats)),
+        Command::new("stats", "<#channel>", Moderator, "Shows the channel's top talkers, messages per day and busiest hours", admin_handler!(show_channel_stats)),
+        Command::new("toollog", "[<#channel>|<nick>|<tool>] [<count>]", Moderator, "Lists the AI's latest tool calls, with their results", admin_handler!(show_tool_log)),
+        Command::new("warnings", "<#channel> [<nick>]", Moderator, "Lists who was warned for breaking a moderated channel's rules, or a user's warnings", admin_handler!(show_warnings)),
+        Command::new("pardon", "<#channel> <nick>", Moderator, "Clears a user's warnings in a channel", admin_handler!(pardon_user)),
+        Command::new("interject", "", Moderator, "Makes the bot interject soon", admin_handler!(force_interjection)),
+        Command::new("join", "<#channel>", Admin, "Joins a channel, and joins it on startup from now on", admin_handler!(join_channel)),
+        Command::new("part", "<#channel>", Admin, "Leaves a channel, and stops joining it on startup", admin_handler!(part_channel)),
+        Command::new("urltitles", "<#channel> on|off", Admin, "Turns link title announcements on or off", admin_handler!(set_url_titles)),
+        Command::new("set", "<#channel> [<key> <value>|default]", Admin, "Changes (or lists) a channel's settings", admin_handler!(set_channel_setting)),
+        Command::new("topic", "<#channel> <text>", Moderator, "Sets a channel's topic (the bot must be a half-op or up)", admin_handler!(set_topic)),
+        Command::new("voice", "<#channel> <nick>", Moderator, "Gives a user voice (the bot must be a half-op or up)", admin_handler!(voice_user)),
+        Command::new("devoice", "<#channel> <nick>", Moderator, "Takes a user's voice away", admin_handler!(devoice_user)),
+        Command::new("op", "<#channel> <nick>", Admin, "Makes a user a channel operator (the bot must be an op)", admin_handler!(op_user)),
+        Command::new("deop", "<#channel> <nick>", Admin, "Takes a user's operator status away", admin_handler!(deop_user)),
+        Command::new("mode", "<#channel> <modes> [<args>]", Admin, "Sets channel modes, e.g. +m or +b *!*@host (the bot must be an op)", admin_handler!(set_channel_modes)),
+        Command::new("schedule add", "\"<cron>\" <#channel> <message>", Admin, "Schedules a recurring announcement", admin_handler!(add_schedule)),
+        Command::new("schedule list", "", Admin, "Lists the scheduled announcements", admin_handler!(list_schedules)),
+        Command::new("schedule del", "<id>", Admin, "Removes a scheduled announcement", admin_handler!(remove_schedule)),
+        Command::new("feed add", "<#channel> <url> [summarize]", Admin, "Announces a feed's new entries in a channel", admin_handler!(add_feed)),
+        Command::new("feed list", "", Admin, "Lists the feed subscriptions", admin_handler!(list_feeds)),
+        Command::new("feed del", "<id>", Admin, "Removes a feed subscription", admin_handler!(remove_feed)),
+        Command::new("export", "<#channel> <from> <to> [json|text]", Admin, "Writes the channel's log between two dates to a file", admin_handler!(export_channel_log)),
+        Command::new("reload", "", Admin, "Re-reads the config and prompt files", admin_handler!(reload)),
+        Command::new("reloadtools", "", Admin, "Reloads the WASM tool plugins", admin_handler!(reload_tools)),
+        Command::new("pending", "", Admin, "Lists the AI's tool calls waiting for approval", admin_handler!(list_pending_tool_calls)),
+        Command::new("approve", "<id>", Admin, "Runs a tool call waiting for approval", admin_handler!(approve_tool_call)),
+        Command::new("deny", "<id>", Admin, "Drops a tool call waiting for approval", admin_handler!(deny_tool_call)),
+        Command::new("admins", "", Admin, "Lists who has which permission level", admin_handler!(list_permission_levels)),
+        Command::new("grant", "<nick> moderator|admin|owner", Owner, "Gives a user a permission level", admin_handler!(grant_level)),
+        Command::new("revoke", "<nick>", Owner, "Takes a user's permission level away", admin_handler!(revoke_level)),
+    ])
+});
+
+/// The nick's permission level, from the database.
+async fn permission_of(state: &BotState, nick: &str) -> Result<Permission> {
+    let user = nick.to_string();
+    let level = state.db.run(move |conn| db::get_permission_level(conn, &user)).await?;
+    Ok(match level.map(|level| level.parse::<Permission>()) {
+        Some(Ok(permission)) => permission,
+        Some(Err(e)) => {
+            tracing::warn!(%nick, "Ignoring invalid permission level: {:#}", e);
+            Permission::Anyone
+        }
+        None => Permission::Anyone,
+    })
+}
+
+/// Handle commands received via private message
+async fn handle_admin_command(
+    irc: OutgoingQueue,
+    state: BotState,
+    me: &str,
+    nick: &str,
+    account: Option<&str>,
+    msg: &str,
+) -> Result<()> {
+    tracing::info!(from = %nick, ?account, %msg, "Admin command received");
+
+    let permission = permission_of(&state, nick).await?;
+    match ADMIN_COMMANDS.dispatch(ADMIN_COMMAND_PREFIX, msg, permission) {
+        Dispatch::Run(command, args) => {
+            let ctx = AdminContext { irc: irc.clone(), state, me: me.to_string(), account: account.map(str::to_string) };
+            (command.handler)(&ctx, Invocation { nick, permission, args }).await?;
+        }
+        Dispatch::Usage(usage) => irc.send_privmsg(nick, format!("Usage: {}", usage))?,
+        Dispatch::Denied(_) | Dispatch::Unknown if permission == Permission::Anyone => {
+            tracing::warn!(%nick, "Non-admin PM command attempt");
+            irc.send_privmsg(nick, "Sorry, I only take commands from registered admins, desu~")?;
+        }
+        Dispatch::Denied(command) => {
+            tracing::warn!(%nick, %permission, command = command.name, "PM command attempt above permission level");
+            irc.send_privmsg(
+                nick,
+                format!("Sorry, {} is only for {}s and up, desu~", command.usage(ADMIN_COMMAND_PREFIX), command.permission),
+            )?;
+        }
+        Dispatch::Unknown => irc.send_privmsg(nick, "Hmm? Unknown command or format. Try !help.")?,
+    }
+    Ok(())
+}
+
+// --- Admin command handlers ---
+
+// Anyone logged in to services may opt out of (or back into) being logged and answered
+async fn opt_out(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let Some(user) = ctx.verified_user(&cmd)? else {
+        return Ok(());
+    };
+    // Admin commands only come in over IRC; Discord users clear their facts with !forgetme there
+    let network = irc_network(&ctx.state.config());
+    let target = user.clone();
+    let deleted = ctx
+        .state
+        .db
+        .run(move |conn| {
+            db::ignore_user(conn, &user, true)?;
+            db::delete_user_facts(conn, &network, &user)?;
+            db::clear_notify(conn, &user)?;
+            db::delete_user_messages(conn, &user)
+        })
+        .await?;
+    tracing::info!(%nick, user = %target, deleted, "User opted out");
+    if target.eq_ignore_ascii_case(nick) {
+        irc.send_privmsg(
+            nick,
+            "Okay! This Emul won't log or answer you anymore, and has forgotten the messages it logged from you, the memories and summaries made from them, and what it noted about you. Send !optin to undo.",
+        )?;
+    } else {
+        irc.send_privmsg(nick, format!("Okay! {} is opted out, and everything logged from them is forgotten.", target))?;
+    }
+    Ok(())
+}
+
+async fn opt_in(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let Some(user) = ctx.verified_user(&cmd)? else {
+        return Ok(());
+    };
+    let target = user.clone();
+    if ctx.state.db.run(move |conn| db::unignore_user(conn, &user, true)).await? {
+        tracing::info!(%nick, user = %target, "User opted back in");
+        if target.eq_ignore_ascii_case(nick) {
+            irc.send_privmsg(nick, "Welcome back! This Emul will talk to you again.")?;
+        } else {
+            irc.send_privmsg(nick, format!("Okay! {} is opted back in.", target))?;
+        }
+    } else if target.eq_ignore_ascii_case(nick) {
+        irc.send_privmsg(nick, "You haven't opted out, so there's nothing to undo!")?;
+    } else {
+        irc.send_privmsg(nick, format!("{} hasn't opted out, so there's nothing to undo!", target))?;
+    }
+    Ok(())
+}
+
+async fn forget_conversation(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let Some(user) = ctx.verified_user(&cmd)? else { return Ok(()) };
+    let target = user.clone();
+    let deleted = ctx.state.db.run(move |conn| db::delete_dm_log(conn, &user)).await?;
+    tracing::info!(%nick, user = %target, deleted, "Forgot private conversation");
+    if target.eq_ignore_ascii_case(nick) {
+        irc.send_privmsg(nick, "Okay! This Emul has forgotten everything we talked about in private.")?;
+    } else {
+        irc.send_privmsg(nick, format!("Okay! This Emul has forgotten its private conversation with {}.", target))?;
+    }
+    Ok(())
+}
+
+async fn forget_user_facts(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let Some(user) = ctx.verified_user(&cmd)? else {
+        return Ok(());
+    };
+    let (network, target) = (irc_network(&ctx.state.config()), user.clone());
+    let deleted = ctx.state.db.run(move |conn| db::delete_user_facts(conn, &network, &user)).await?;
+    tracing::info!(%nick, user = %target, deleted, "Forgot facts about user");
+    match target.eq_ignore_ascii_case(nick) {
+        true => irc.send_privmsg(nick, forgotten_facts_message(deleted))?,
+        false => irc.send_privmsg(nick, format!("Okay! Forgot {} facts about {}.", deleted, target))?,
+    }
+    Ok(())
+}
+
+async fn add_notify_keyword(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    // The lines go to whoever has the nick, so it has to be theirs
+    if !ctx.account.as_deref().is_some_and(|account| account.eq_ignore_ascii_case(nick)) {
+        irc.send_privmsg(nick, NOT_LOGGED_IN_MESSAGE)?;
+        return Ok(());
+    }
+    let Some(keyword) = notify::normalize_keyword(cmd.args.rest(0)) else {
+        irc.send_privmsg(
+            nick,
+            format!("Keywords must be {} to {} characters long.", notify::MIN_KEYWORD_LENGTH, notify::MAX_KEYWORD_LENGTH),
+        )?;
+        return Ok(());
+    };
+    let (user, added_keyword) = (nick.to_string(), keyword.clone());
+    let added = ctx
+        .state
+        .db
+        .run(move |conn| {
+            if db::get_notify_keywords(conn, &user)?.len() >= notify::MAX_KEYWORDS {
+                return Ok(None);
+            }
+            Ok(Some(db::add_notify_keyword(conn, &user, &added_keyword)?))
+        })
+        .await?;
+    match added {
+        None => irc.send_privmsg(nick, format!("You already have {} keywords; remove one with !notify del first.", notify::MAX_KEYWORDS))?,
+        Some(false) => irc.send_privmsg(nick, format!("You're already notified about \"{}\".", keyword))?,
+        Some(true) => {
+            tracing::info!(%nick, %keyword, "Added notify keyword");
+            let away = ctx.state.config().notify_away_mins;
+            irc.send_privmsg(
+                nick,
+                format!("Okay! Lines mentioning \"{}\" after {} minutes of quiet from you in a channel are saved until you next speak.", keyword, away),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+async fn remove_notify_keyword(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let keyword = notify::normalize_keyword(cmd.args.rest(0)).unwrap_or_default();
+    let (user, removed_keyword) = (nick.to_string(), keyword.clone());
+    match ctx.state.db.run(move |conn| db::remove_notify_keyword(conn, &user, &removed_keyword)).await? {
+        true => irc.send_privmsg(nick, format!("Okay! No more notifications about \"{}\".", keyword))?,
+        false => irc.send_privmsg(nick, format!("You weren't notified about \"{}\".", keyword))?,
+    }
+    Ok(())
+}
+
+async fn list_notify_keywords(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let user = nick.to_string();
+    let keywords = ctx.state.db.run(move |conn| db::get_notify_keywords(conn, &user)).await?;
+    match keywords.is_empty() {
+        true => irc.send_privmsg(nick, "You have no notification keywords. Add one with !notify add <keyword>.")?,
+        false => irc.send_privmsg(nick, format!("You're notified about: {}", keywords.join(", ")))?,
+    }
+    Ok(())
+}
+
+async fn clear_notify(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let user = nick.to_string();
+    let cleared = ctx.state.db.run(move |conn| db::clear_notify(conn, &user)).await?;
+    tracing::info!(%nick, cleared, "Cleared notify keywords");
+    match cleared {
+        0 => irc.send_privmsg(nick, "You had no notification keywords.")?,
+        _ => irc.send_privmsg(nick, "Okay! Notifications are off, and the saved lines are gone.")?,
+    }
+    Ok(())
+}
+
+/// What `!forgetme` says once `deleted` facts are gone.
+fn forgotten_facts_message(deleted: usize) -> String {
+    match deleted {
+        0 => "This Emul didn't know anything about you to forget!".to_string(),
+        1 => "Okay! This Emul has forgotten the one thing it knew about you.".to_string(),
+        n => format!("Okay! This Emul has forgotten the {} things it knew about you.", n),
+    }
+}
+
+async fn show_help(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    if cmd.args.get(0).is_none() {
+        let commands = ADMIN_COMMANDS.help(ADMIN_COMMAND_PREFIX, cmd.permission, |_| true);
+        irc.send_privmsg(nick, format!("Commands: {}. Try !help <command> for details.", commands))?;
+        return Ok(());
+    }
+    let lines = ADMIN_COMMANDS.describe(ADMIN_COMMAND_PREFIX, cmd.args.rest(0), cmd.permission, |_| true);
+    if lines.is_empty() {
+        irc.send_privmsg(nick, format!("There's no {} command you can use.", cmd.args.rest(0)))?;
+    }
+    for line in lines {
+        irc.send_privmsg(nick, line)?;
+    }
+    Ok(())
+}
+
+async fn ignore_user(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick, ignored) = (&ctx.irc, cmd.nick, cmd.args.arg(0));
+    let user = ignored.to_string();
+    if ctx.state.db.run(move |conn| db::ignore_user(conn, &user, false)).await? {
+        tracing::info!(admin = %nick, ignored, "Ignoring user");
+        irc.send_privmsg(nick, format!("Okay, ignoring '{}' from now on.", ignored))?;
+    } else {
+        irc.send_privmsg(nick, format!("I'm already ignoring '{}'.", ignored))?;
+    }
+    Ok(())
+}
+
+async fn unignore_user(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick, ignored) = (&ctx.irc, cmd.nick, cmd.args.arg(0));
+    let user = ignored.to_string();
+    if ctx.state.db.run(move |conn| db::unignore_user(conn, &user, false)).await? {
+        tracing::info!(admin = %nick, ignored, "No longer ignoring user");
+        irc.send_privmsg(nick, format!("Okay, '{}' is no longer ignored.", ignored))?;
+    } else {
+        irc.send_privmsg(nick, format!("I wasn't ignoring '{}'.", ignored))?;
+    }
+    Ok(())
+}
+
+async fn list_ignored(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let users = ctx.state.db.run(db::get_ignored_users).await?;
+    if users.is_empty() {
+        irc.send_privmsg(nick, "Nobody is ignored.")?;
+    } else {
+        let list: Vec<String> = users
+            .into_iter()
+            .map(|(user, opted_out)| if opted_out { format!("{} (opted out)", user) } else { user })
+            .collect();
+        irc.send_privmsg(nick, format!("Ignored: {}", list.join(", ")))?;
+    }
+    Ok(())
+}
+
+async fn list_channels(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    match ctx.state.db.run(db::get_channels).await {
+        Ok(channels) => {
+            if channels.is_empty() {
+                irc.send_privmsg(nick, "I'm not set to auto-join any channels.")?;
+            } else {
+                irc.send_privmsg(
+                    nick,
+                    format!("Auto-join channels: {}", channels.join(", ")),
+                )?;
+            }
+        }
+        Err(e) => {
+            tracing::error!("Failed to fetch channels: {:?}", e);
+            irc.send_privmsg(nick, "Oops, couldn't check the channel list right now.")?;
+        }
+    }
+    Ok(())
+}
+
+async fn show_ai_stats(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, state, nick, channel) = (&ctx.irc, &ctx.state, cmd.nick, cmd.args.arg(0));
+    let since = chrono::Utc::now().timestamp() - 24 * 60 * 60;
+    let stats_channel = channel.to_string();
+    let counts = state.db.run(move |conn| db::get_ai_outcome_counts(conn, &stats_channel, since)).await?;
+    if counts.is_empty() {
+        irc.send_privmsg(nick, format!("No AI requests in {} during the last 24h.", channel))?;
+    } else {
+        let summary = counts
+            .iter()
+            .map(|(outcome, count)| format!("{}: {}", outcome, count))
+            .collect::<Vec<_>>()
+            .join(", ");
+        irc.send_privmsg(nick, format!("AI outcomes in {} (24h): {}", channel, summary))?;
+    }
+    let today = chrono::Utc::now().format("%Y-%m-%d").to_string();
+    let stats_channel = channel.to_string();
+    let (channel_tokens, total_tokens) = state
+        .db
+        .run(move |conn| {
+            Ok((
+                db::get_tokens_used(conn, &today, Some(&stats_channel))?,
+                db::get_tokens_used(conn, &today, None)?,
+            ))
+        })
+        .await?;
+    let budget = match state.config().daily_token_budget {
+        0 => "no budget".to_string(),
+        budget => format!("budget {}", budget),
+    };
+    irc.send_privmsg(
+        nick,
+        format!("Tokens today: {} in {}, {} overall ({}).", channel_tokens, channel, total_tokens, budget),
+    )?;
+    Ok(())
+}
+
+async fn show_channel_stats(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, state, channel) = (&ctx.irc, &ctx.state, cmd.args.channel(0));
+    let stats = stats::channel_stats(&state.db, &channel, &state.config().nickname, chrono::Utc::now()).await?;
+    irc.send_privmsg(cmd.nick, stats.summary(&channel))?;
+    Ok(())
+}
+
+/// How many of a user's warnings `!warnings` lists.
+const MAX_LISTED_WARNINGS: usize = 10;
+
+async fn show_warnings(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick, channel) = (&ctx.irc, cmd.nick, cmd.args.channel(0));
+    let query_channel = channel.clone();
+    match cmd.args.get(1).map(str::to_string) {
+        Some(user) => {
+            let query_user = user.clone();
+            let warnings = ctx
+                .state
+                .db
+                .run(move |conn| db::get_warnings(conn, &query_channel, &query_user, MAX_LISTED_WARNINGS))
+                .await?;
+            if warnings.is_empty() {
+                irc.send_privmsg(nick, format!("{} has no warnings in {}.", user, channel))?;
+            }
+            // Oldest first, so the latest warning ends up at the bottom
+            for warning in warnings.iter().rev() {
+                irc.send_privmsg(nick, format!("[{}] {}: {}", warning.timestamp.format("%Y-%m-%d %H:%M UTC"), warning.nick, warning.reason))?;
+            }
+        }
+        None => {
+            let since = (chrono::Utc::now() - chrono::Duration::days(moderation::WARNING_WINDOW_DAYS)).timestamp();
+            let counts = ctx.state.db.run(move |conn| db::get_warning_counts(conn, &query_channel, since)).await?;
+            if counts.is_empty() {
+                irc.send_privmsg(nick, format!("Nobody was warned in {} in the last {} days.", channel, moderation::WARNING_WINDOW_DAYS))?;
+            } else {
+                let list: Vec<String> = counts.iter().map(|(user, count)| format!("{} ({})", user, count)).collect();
+                irc.send_privmsg(nick, format!("Warned in {} in the last {} days: {}", channel, moderation::WARNING_WINDOW_DAYS, list.join(", ")))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn pardon_user(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick, channel, user) = (&ctx.irc, cmd.nick, cmd.args.channel(0), cmd.args.arg(1).to_string());
+    let (cleared_channel, cleared_user) = (channel.clone(), user.clone());
+    let cleared = ctx.state.db.run(move |conn| db::clear_warnings(conn, &cleared_channel, &cleared_user)).await?;
+    tracing::info!(moderator = %nick, %channel, %user, cleared, "Cleared warnings");
+    match cleared {
+        0 => irc.send_privmsg(nick, format!("{} has no warnings in {}.", user, channel))?,
+        _ => irc.send_privmsg(nick, format!("Okay! Cleared {}'s warnings in {}.", user, channel))?,
+    }
+    Ok(())
+}
+
+async fn show_tool_log(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    // A lone number is a count, not a filter
+    let (filter, count) = match (cmd.args.get(0), cmd.args.get(1)) {
+        (Some(first), None) if first.parse::<usize>().is_ok() => (None, first.parse().ok()),
+        (first, second) => (first.map(str::to_string), second.and_then(|n| n.parse().ok())),
+    };
+    let count = count.unwrap_or(tool_log::DEFAULT_COUNT).clamp(1, tool_log::MAX_COUNT);
+    let query_filter = filter.clone();
+    let calls = ctx.state.db.run(move |conn| db::get_tool_calls(conn, query_filter.as_deref(), count)).await?;
+    if calls.is_empty() {
+        match filter {
+            Some(filter) => irc.send_privmsg(nick, format!("No tool calls logged for {}.", filter))?,
+            None => irc.send_privmsg(nick, "No tool calls logged.")?,
+        }
+    }
+    // Oldest first, so the latest call ends up at the bottom
+    for call in calls.iter().rev() {
+        irc.send_privmsg(nick, tool_log::display(call))?;
+    }
+    Ok(())
+}
+
+async fn list_pending_tool_calls(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let calls = ctx.state.db.run(db::get_pending_tool_calls).await?;
+    if calls.is_empty() {
+        irc.send_privmsg(nick, "Nothing is waiting for approval.")?;
+    }
+    for call in &calls {
+        irc.send_privmsg(nick, tool_log::display_pending(call))?;
+    }
+    Ok(())
+}
+
+/// Reads the id of a pending tool call, taking it off the queue. `None` once the admin has been told why not.
+async fn take_pending_tool_call(ctx: &AdminContext, cmd: &Invocation<'_>, usage: &str) -> Result<Option<db::PendingToolCall>> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let Ok(id) = cmd.args.arg(0).trim_start_matches('#').parse::<i64>() else {
+        irc.send_privmsg(nick, usage)?;
+        return Ok(None);
+    };
+    let call = ctx.state.db.run(move |conn| db::take_pending_tool_call(conn, id)).await?;
+    if call.is_none() {
+        irc.send_privmsg(nick, format!("There's no request #{} waiting.", id))?;
+    }
+    Ok(call)
+}
+
+/// How much of an approved call's result the admin is shown.
+const MAX_APPROVED_OUTCOME_CHARS: usize = 300;
+
+async fn approve_tool_call(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, state, nick) = (&ctx.irc, &ctx.state, cmd.nick);
+    let Some(call) = take_pending_tool_call(ctx, &cmd, "Usage: !approve <id>").await? else {
+        return Ok(());
+    };
+    let args = serde_json::from_str(&call.args).context("Queued tool call has invalid arguments")?;
+    let settings = state.settings();
+    let mut options = chatbot_options(state, &settings.config, &call.channel).await;
+    options.requester = call.nick.clone();
+    let llm = UsageMeter::new(&*settings.llm);
+    let (invocation, _) =
+        ai_handler::execute_tool(&llm, &call.channel, &state.image_cache, &options, ImageBudget::none(), &call.tool, args).await;
+    record_usage(state, &call.channel, &llm).await;
+    ai_handler::log_tool_call(&state.db, &call.channel, &call.nick, &invocation).await;
+    tracing::info!(admin = %nick, id = call.id, tool = %call.tool, failed = invocation.error.is_some(), "Approved tool call");
+
+    let outcome = match &invocation.error {
+        Some(error) => format!("It failed: {}", error),
+        None => invocation.result["result"].as_str().map_or_else(|| invocation.result.to_string(), str::to_string),
+    };
+    irc.send_privmsg(
+        nick,
+        format!("Ran request #{} ({} for {}). {}", call.id, call.tool, call.nick, sanitize::flatten(&outcome, MAX_APPROVED_OUTCOME_CHARS)),
+    )?;
+    // Discord channels can't be reached from here
+    if !transport::is_discord_channel(&call.channel) {
+        let result = if invocation.error.is_some() { " It didn't work, sorry!" } else { "" };
+        irc.send_privmsg(&call.channel, format!("{}: An admin approved your {} request.{}", call.nick, call.tool, result))?;
+    }
+    Ok(())
+}
+
+async fn deny_tool_call(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let Some(call) = take_pending_tool_call(ctx, &cmd, "Usage: !deny <id>").await? else {
+        return Ok(());
+    };
+    tracing::info!(admin = %nick, id = call.id, tool = %call.tool, "Denied tool call");
+    irc.send_privmsg(nick, format!("Okay! Dropped request #{}.", call.id))?;
+    if !transport::is_discord_channel(&call.channel) {
+        irc.send_privmsg(&call.channel, format!("{}: An admin turned down your {} request.", call.nick, call.tool))?;
+    }
+    Ok(())
+}
+
+async fn export_channel_log(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick, channel) = (&ctx.irc, cmd.nick, cmd.args.channel(0));
+    let export_dir = ctx.state.config().export_dir.clone();
+    let exported = async {
+        let from = export::parse_date(cmd.args.arg(1))?;
+        let to = export::parse_date(cmd.args.arg(2))?;
+        let format = cmd.args.get(3).map_or(Ok(ExportFormat::Json), str::parse)?;
+        export::export_log(&ctx.state.db, &export_dir, &channel, from, to, format).await
+    };
+    match exported.await {
+        Ok((path, count)) => {
+            tracing::info!(admin = %nick, %channel, path = %path.display(), count, "Exported channel log");
+            irc.send_privmsg(nick, format!("Exported {} messages from {} to {}.", count, channel, path.display()))?;
+        }
+        Err(e) => {
+            tracing::warn!(admin = %nick, %channel, "Failed to export channel log: {:#}", e);
+            irc.send_privmsg(nick, format!("Couldn't export that: {:#}", e))?;
+        }
+    }
+    Ok(())
+}
+
+async fn force_interjection(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    ctx.state.bn_interject.force_next_interjection();
+    ctx.irc.send_privmsg(cmd.nick, "Okay, I'll try to interject soon!")?;
+    Ok(())
+}
+
+async fn join_channel(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick, channel) = (&ctx.irc, cmd.nick, cmd.args.channel(0));
+    let added = channel.clone();
+    if ctx.state.db.run(move |conn| db::add_channel(conn, &added)).await? {
+        tracing::info!(admin = %nick, %channel, "Added channel via command. Joining.");
+        irc.send_privmsg(
+            nick,
+            format!("Okay! Added {} and joining now!", channel),
+        )?;
+        irc.send_join(&channel)?; // Attempt to join immediately
+    } else {
+        irc.send_privmsg(nick, format!("I already know about {}!", channel))?;
+    }
+    Ok(())
+}
 
-    // 3. Spawn AI task if needed
-    if should_trigger_ai {
-        tracing::info!(%channel, %nick, addressed=%is_addressed, "Triggering AI for completed message");
-        // Spawn AI task, passing the complete message
-        tokio::spawn(handle_ai_request(
-            sender, // Pass the sender clone
-            state,  // Pass the state clone
-            channel, // Pass channel ownership
-            nick,    // Pass nick ownership
-            complete_message, // Pass the complete message
-            is_addressed,
-        ));
+async fn part_channel(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, state, nick, channel) = (&ctx.irc, &ctx.state, cmd.nick, cmd.args.channel(0));
+    let removed = channel.clone();
+    if state.db.run(move |conn| db::remove_channel(conn, &removed)).await? {
+        tracing::info!(admin = %nick, %channel, "Removed channel via command. Parting.");
+        irc.send_privmsg(
+            nick,
+            format!(
+                "Got it! Leaving {} and won't rejoin automatically.",
+                channel
+            ),
+        )?;
+        irc.send_part(&channel)?; // Part immediately
     } else {
-        tracing::debug!(%channel, %nick, "No AI trigger for completed message");
+        // Still part if currently in? Let's check current_channels
+        let mut current = state.current_channels.lock().await;
+        if current.contains(&channel) {
+            irc.send_privmsg(
+                nick,
+                format!(
+                    "Okay, leaving {} for this session (wasn't set to auto-join).",
+                    channel
+                ),
+            )?;
+            irc.send_part(&channel)?;
+            current.remove(&channel); // Update runtime state
+        } else {
+            irc.send_privmsg(
+                nick,
+                format!("I wasn't set to auto-join {} anyway.", channel),
+            )?;
+        }
     }
+    Ok(())
+}
 
+async fn set_url_titles(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick, channel) = (&ctx.irc, cmd.nick, cmd.args.channel(0));
+    let enabled = match cmd.args.arg(1).to_lowercase().as_str() {
+        "on" => true,
+        "off" => false,
+        _ => {
+            irc.send_privmsg(nick, "Usage: !urltitles #channel on|off")?;
+            return Ok(());
+        }
+    };
+    let changed_channel = channel.clone();
+    let changed = ctx.state.db.run(move |conn| db::set_url_titles(conn, &changed_channel, enabled)).await?;
+    let status = if enabled { "on" } else { "off" };
+    tracing::info!(admin = %nick, %channel, enabled, "Set link title announcements");
+    irc.send_privmsg(
+        nick,
+        match changed {
+            true => format!("Okay! Link titles are now {} in {}.", status, channel),
+            false => format!("Link titles were already {} in {}.", status, channel),
+        },
+    )?;
     Ok(())
 }
 
+/// Whether we hold `prefix` or better in `channel`, going by the roster. If not, tells `nick` why.
+fn check_own_status(ctx: &AdminContext, nick: &str, channel: &str, prefix: char) -> Result<bool> {
+    match ctx.state.roster.member(channel, &ctx.me) {
+        Some(me) if me.has_status(prefix) => return Ok(true),
+        Some(_) => ctx.irc.send_privmsg(nick, format!("I need to be {} or up in {} for that, desu~", roster::status_name(prefix), channel))?,
+        None => ctx.irc.send_privmsg(nick, format!("I'm not in {}!", channel))?,
+    }
+    Ok(false)
+}
 
-/// Task to handle fetching history, calling AI, and sending response
-async fn handle_ai_request(
-    sender: irc::client::Sender,
-    state: BotState,
-    channel: String,
-    triggering_nick: String,
-    triggering_message: String,
-    was_addressed: bool, // Could be used to adjust AI prompt/behaviour
-) {
-    tracing::info!(%channel, nick=%triggering_nick, addressed=%was_addressed, "Handling AI request");
+async fn set_topic(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick, channel) = (&ctx.irc, cmd.nick, cmd.args.channel(0));
+    if !check_own_status(ctx, nick, &channel, '%')? {
+        return Ok(());
+    }
+    tracing::info!(admin = %nick, %channel, topic = %cmd.args.rest(1), "Setting topic");
+    irc.send(Priority::High, Command::TOPIC(channel.clone(), Some(cmd.args.rest(1).to_string())))?;
+    irc.send_privmsg(nick, format!("Okay! Set the topic of {}.", channel))?;
+    Ok(())
+}
 
-    // 1. Fetch History
-    let history_result = db::get_channel_log(&*state.db_conn.lock().await, &channel);
-    if let Err(e) = history_result {
-        tracing::error!(%channel, "Failed to fetch channel history: {:?}", e);
-        // Maybe send an error message to the channel?
-        // let _ = client.send_privmsg(&channel, "Wawa~ I couldn't remember what we were talking about!");
-        return;
+async fn voice_user(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    set_member_status(ctx, cmd, ChannelMode::Voice, true).await
+}
+
+async fn devoice_user(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    set_member_status(ctx, cmd, ChannelMode::Voice, false).await
+}
+
+async fn op_user(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    set_member_status(ctx, cmd, ChannelMode::Oper, true).await
+}
+
+async fn deop_user(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    set_member_status(ctx, cmd, ChannelMode::Oper, false).await
+}
+
+/// Gives (or takes away) voice or operator status. Half-ops may voice people; only ops may op them.
+async fn set_member_status(ctx: &AdminContext, cmd: Invocation<'_>, mode: ChannelMode, given: bool) -> Result<()> {
+    let (irc, nick, channel, target) = (&ctx.irc, cmd.nick, cmd.args.channel(0), cmd.args.arg(1));
+    let (status, needed) = if mode == ChannelMode::Voice { ('+', '%') } else { ('@', '@') };
+    if !check_own_status(ctx, nick, &channel, needed)? {
+        return Ok(());
     }
-    let history = history_result.unwrap();
+    let Some(member) = ctx.state.roster.member(&channel, target) else {
+        irc.send_privmsg(nick, format!("{} isn't in {}.", target, channel))?;
+        return Ok(());
+    };
+    if member.status.contains(status) == given {
+        let state = if given { "already" } else { "not" };
+        irc.send_privmsg(nick, format!("{} is {} {} in {}.", member.nick, state, roster::status_name(status), channel))?;
+        return Ok(());
+    }
+    tracing::info!(admin = %nick, %channel, user = %member.nick, ?mode, given, "Setting channel status");
+    let mode = if given { Mode::Plus(mode, Some(member.nick.clone())) } else { Mode::Minus(mode, Some(member.nick.clone())) };
+    irc.send(Priority::High, Command::ChannelMODE(channel.clone(), vec![mode]))?;
+    let now = if given { "now" } else { "no longer" };
+    irc.send_privmsg(nick, format!("Okay! {} is {} {} in {}.", member.nick, now, roster::status_name(status), channel))?;
+    Ok(())
+}
 
-    // 2. Call the AI Handler (your implementation)
-    let ai_result = ai_handler::call_chatbot(
-        &channel,
-        &triggering_nick,
-        &triggering_message,
-        history,
-        &state.prompt_path,
-        was_addressed,
-        &state.image_cache, // Pass the image cache
-    )
-    .await;
+async fn set_channel_modes(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick, channel) = (&ctx.irc, cmd.nick, cmd.args.channel(0));
+    let pieces: Vec<&str> = cmd.args.rest(1).split_whitespace().collect();
+    let modes = match Mode::as_channel_modes(&pieces) {
+        Ok(modes) if !modes.is_empty() => modes,
+        _ => {
+            irc.send_privmsg(nick, "Usage: !mode #channel <modes> [<args>], e.g. !mode #channel +b *!*@example.com")?;
+            return Ok(());
+        }
+    };
+    if !check_own_status(ctx, nick, &channel, '@')? {
+        return Ok(());
+    }
+    tracing::info!(admin = %nick, %channel, modes = %cmd.args.rest(1), "Setting channel modes");
+    irc.send(Priority::High, Command::ChannelMODE(channel.clone(), modes))?;
+    irc.send_privmsg(nick, format!("Okay! Set {} on {}.", cmd.args.rest(1), channel))?;
+    Ok(())
+}
 
-    // 3. Send Response
-    match ai_result {
-        Ok(response) => {
-            tracing::info!(%channel, "Sending AI response");
-            // Store the AI response's text part in the database
-            db::log_message(&*state.db_conn.lock().await, &channel, &state.config.nickname, &response.text_response)
-                .unwrap_or_else(|e| tracing::error!("Failed to log AI response: {:?}", e));
-            // Split the text response for sending
-            let lines = split_response(430, &response.text_response);
-            for line in lines {
-                if let Err(e) = sender.send_privmsg(&channel, line) {
-                    tracing::error!(%channel, "Failed to send AI response chunk: {}", e);
-                    // Avoid infinite loops if sending fails repeatedly
-                    break;
-                }
-                tokio::time::sleep(Duration::from_millis(600)).await; // Small delay between lines
+async fn set_channel_setting(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, state, nick, channel) = (&ctx.irc, &ctx.state, cmd.nick, cmd.args.channel(0));
+    match (cmd.args.get(1).map(|k| k.to_lowercase()), cmd.args.get(2)) {
+        (Some(key), Some(value)) if value.eq_ignore_ascii_case("default") => {
+            let (removed_channel, removed_key) = (channel.clone(), key.clone());
+            let removed =
+                state.db.run(move |conn| db::remove_channel_setting(conn, &removed_channel, &removed_key)).await?;
+            tracing::info!(admin = %nick, %channel, %key, "Reset channel setting");
+            irc.send_privmsg(
+                nick,
+                match removed {
+                    true => format!("Okay! {} is back to the default in {}.", key, channel),
+                    false => format!("{} wasn't changed in {}.", key, channel),
+                },
+            )?;
+        }
+        (Some(key), Some(value)) => match ChannelSettings::default().apply(&key, value) {
+            Ok(value) => {
+                let (set_channel, set_key, set_value) = (channel.clone(), key.clone(), value.clone());
+                state
+                    .db
+                    .run(move |conn| db::set_channel_setting(conn, &set_channel, &set_key, &set_value))
+                    .await?;
+                tracing::info!(admin = %nick, %channel, %key, %value, "Set channel setting");
+                irc.send_privmsg(nick, format!("Okay! {} is now {} in {}.", key, value, channel))?;
             }
+            Err(e) => irc.send_privmsg(nick, format!("Can't set that: {:#}", e))?,
+        },
+        (None, None) => {
+            let overrides_channel = channel.clone();
+            let overrides =
+                state.db.run(move |conn| db::get_channel_settings(conn, &overrides_channel)).await?;
+            let settings = ChannelSettings::from_overrides(&overrides);
+            let listing = channel_settings::KEYS
+                .iter()
+                .map(|key| {
+                    let value = settings.get(key).unwrap_or_default();
+                    match overrides.iter().any(|(k, _)| k == key) {
+                        true => format!("{}={}", key, value),
+                        false => format!("{}={} (default)", key, value),
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            irc.send_privmsg(nick, format!("Settings in {}: {}", channel, listing))?;
+        }
+        _ => {
+            irc.send_privmsg(nick, "Usage: !set #channel [<key> <value>|default]")?;
+        }
+    }
+    Ok(())
+}
+
+async fn add_schedule(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    match scheduler::parse_add_command(cmd.args.rest(0)) {
+        Ok(new) => {
+            let next = scheduler::parse_cron(&new.cron)
+                .ok()
+                .and_then(|cron| scheduler::next_run(&cron, &chrono::Local::now()))
+                .map(|next| next.format("%Y-%m-%d %H:%M %Z").to_string())
+                .unwrap_or_else(|| "never".to_string());
+            let (schedule, creator) = (new.clone(), nick.to_string());
+            let id = ctx
+                .state
+                .db
+                .run(move |conn| db::add_schedule(conn, &schedule.channel, &schedule.cron, &schedule.message, &creator))
+                .await?;
+            tracing::info!(admin = %nick, id, cron = %new.cron, channel = %new.channel, "Added schedule");
+            irc.send_privmsg(
+                nick,
+                format!("Okay! Schedule {} will announce in {} at \"{}\" (next: {}).", id, new.channel, new.cron, next),
+            )?;
         }
         Err(e) => {
-            tracing::error!(%channel, "AI handler failed: {:?}", e);
-            // Optionally send a generic error message to the channel
-            let _ = sender.send_privmsg(
-                &channel,
-                format!(
-                    "{}: Eeep! I had trouble thinking about that...",
-                    triggering_nick
-                ),
-            );
+            irc.send_privmsg(nick, format!("{:#}. Usage: !schedule add \"<cron>\" #channel <message>", e))?;
         }
     }
+    Ok(())
 }
 
-/// Handle commands received via private message
-async fn handle_admin_command(
-    client: Arc<Client>,
-    state: BotState,
-    nick: &str,
-    msg: &str,
-) -> Result<()> {
-    tracing::info!(from = %nick, %msg, "Admin command received");
+async fn list_schedules(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let schedules = ctx.state.db.run(db::get_schedules).await?;
+    if schedules.is_empty() {
+        irc.send_privmsg(nick, "No announcements are scheduled.")?;
+    }
+    for schedule in schedules {
+        irc.send_privmsg(
+            nick,
+            format!(
+                "{}: \"{}\" {} {} (by {})",
+                schedule.id, schedule.cron, schedule.channel, schedule.message, schedule.created_by
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+async fn remove_schedule(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let Ok(id) = cmd.args.arg(0).parse::<i64>() else {
+        irc.send_privmsg(nick, "Usage: !schedule del <id>")?;
+        return Ok(());
+    };
+    if ctx.state.db.run(move |conn| db::remove_schedule(conn, id)).await? {
+        tracing::info!(admin = %nick, id, "Removed schedule");
+        irc.send_privmsg(nick, format!("Okay! Removed schedule {}.", id))?;
+    } else {
+        irc.send_privmsg(nick, format!("There's no schedule {}.", id))?;
+    }
+    Ok(())
+}
+
+async fn add_feed(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick, channel) = (&ctx.irc, cmd.nick, cmd.args.channel(0));
+    let url = cmd.args.arg(1).to_string();
+    let summarize = cmd.args.get(2).is_some_and(|flag| flag.eq_ignore_ascii_case("summarize"));
+    // Entries already in the feed count as seen, so subscribing doesn't flood the channel
+    let items = match rss::fetch_feed(&ctx.state.proxy, &url).await {
+        Ok(items) => items,
+        Err(e) => {
+            irc.send_privmsg(nick, format!("Couldn't read that feed: {:#}", e))?;
+            return Ok(());
+        }
+    };
+    let (feed_channel, feed_url, added_by) = (channel.clone(), url.clone(), nick.to_string());
+    let entry_ids: Vec<String> = items.iter().map(|item| item.id.clone()).collect();
+    let id = ctx
+        .state
+        .db
+        .run(move |conn| {
+            let id = db::add_feed(conn, &feed_channel, &feed_url, summarize, &added_by)?;
+            if let Some(id) = id {
+                db::mark_feed_entries_seen(conn, id, &entry_ids)?;
+            }
+            Ok(id)
+        })
+        .await?;
+    match id {
+        Some(id) => {
+            tracing::info!(admin = %nick, id, %channel, %url, summarize, "Added feed");
+            irc.send_privmsg(
+                nick,
+                format!("Okay! Feed {} will announce new entries in {} ({} entries so far).", id, channel, items.len()),
+            )?;
+        }
+        None => irc.send_privmsg(nick, format!("{} already follows that feed.", channel))?,
+    }
+    Ok(())
+}
 
-    // Check if sender is admin
-    if !db::is_admin(&*state.db_conn.lock().await, nick)? {
-        tracing::warn!(%nick, "Non-admin PM command attempt");
-        client.send_privmsg(
+async fn list_feeds(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let feeds = ctx.state.db.run(db::get_feeds).await?;
+    if feeds.is_empty() {
+        irc.send_privmsg(nick, "No feeds are subscribed.")?;
+    }
+    for feed in feeds {
+        let summarize = if feed.summarize { " with summaries" } else { "" };
+        irc.send_privmsg(
             nick,
-            "Sorry, I only take commands from registered admins, desu~",
+            format!("{}: {} {}{} (by {})", feed.id, feed.channel, feed.url, summarize, feed.added_by),
         )?;
+    }
+    Ok(())
+}
+
+async fn remove_feed(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    let Ok(id) = cmd.args.arg(0).parse::<i64>() else {
+        irc.send_privmsg(nick, "Usage: !feed del <id>")?;
         return Ok(());
+    };
+    if ctx.state.db.run(move |conn| db::remove_feed(conn, id)).await? {
+        tracing::info!(admin = %nick, id, "Removed feed");
+        irc.send_privmsg(nick, format!("Okay! Removed feed {}.", id))?;
+    } else {
+        irc.send_privmsg(nick, format!("There's no feed {}.", id))?;
     }
+    Ok(())
+}
 
-    let parts: Vec<&str> = msg.split_whitespace().collect();
-    let command = parts.first().map(|s| s.to_lowercase());
+async fn reload(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    match reload_settings(&ctx.state).await {
+        Ok(changes) => {
+            tracing::info!(admin = %nick, changed = %changes.join(", "), "Reloaded settings");
+            let summary = match changes.is_empty() {
+                true => "nothing changed".to_string(),
+                false => format!("changed: {}", changes.join(", ")),
+            };
+            irc.send_privmsg(nick, format!("Reloaded config and prompt; {}.", summary))?;
+        }
+        Err(e) => {
+            tracing::error!(admin = %nick, error = %e, "Failed to reload settings");
+            irc.send_privmsg(nick, format!("Failed to reload, keeping the old settings: {:#}", e))?;
+        }
+    }
+    Ok(())
+}
 
-    match command.as_deref() {
-        Some("!join") => {
-            if let Some(channel) = parts.get(1) {
-                let channel = if !channel.starts_with('#') {
-                    format!("#{}", channel)
-                } else {
-                    channel.to_string()
-                };
-                if db::add_channel(&*state.db_conn.lock().await, &channel)? {
-                    tracing::info!(admin = %nick, %channel, "Added channel via command. Joining.");
-                    client.send_privmsg(
-                        nick,
-                        format!("Okay! Added {} and joining now!", channel),
-                    )?;
-                    client.send_join(&channel)?; // Attempt to join immediately
-                } else {
-                    client.send_privmsg(nick, format!("I already know about {}!", channel))?;
-                }
-            } else {
-                client.send_privmsg(nick, "Usage: !join #channel")?;
-            }
+async fn reload_tools(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, state, nick) = (&ctx.irc, &ctx.state, cmd.nick);
+    let (builtin_tools, proxy) = (state.builtin_tools.clone(), state.proxy.clone());
+    let dir = state.config().wasm_tools_dir.clone();
+    // Compiling plugins can take a moment, so keep it off the async workers
+    let loaded = tokio::task::spawn_blocking(move || load_tools(&builtin_tools, dir.as_deref(), &proxy))
+        .await
+        .context("Tool loading panicked")?;
+    match loaded {
+        Ok(tools) => {
+            let names = tools.names().join(", ");
+            *state.tools.lock().await = Arc::new(tools);
+            tracing::info!(admin = %nick, tools = %names, "Reloaded tools");
+            irc.send_privmsg(nick, format!("Tools reloaded: {}", names))?;
         }
-        Some("!part") => {
-            if let Some(channel) = parts.get(1) {
-                let channel = if !channel.starts_with('#') {
-                    format!("#{}", channel)
-                } else {
-                    channel.to_string()
-                };
-                if db::remove_channel(&*state.db_conn.lock().await, &channel)? {
-                    tracing::info!(admin = %nick, %channel, "Removed channel via command. Parting.");
-                    client.send_privmsg(
-                        nick,
-                        format!(
-                            "Got it! Leaving {} and won't rejoin automatically.",
-                            channel
-                        ),
-                    )?;
-                    client.send_part(&channel)?; // Part immediately
-                } else {
-                    // Still part if currently in? Let's check current_channels
-                    let mut current = state.current_channels.lock().await;
-                    if current.contains(&channel) {
-                        client.send_privmsg(
-                            nick,
-                            format!(
-                                "Okay, leaving {} for this session (wasn't set to auto-join).",
-                                channel
-                            ),
-                        )?;
-                        client.send_part(&channel)?;
-                        current.remove(&channel); // Update runtime state
-                    } else {
-                        client.send_privmsg(
-                            nick,
-                            format!("I wasn't set to auto-join {} anyway.", channel),
-                        )?;
-                    }
-                }
-            } else {
-                client.send_privmsg(nick, "Usage: !part #channel")?;
-            }
-        }
-        Some("!add_admin") => {
-            if let Some(new_admin) = parts.get(1) {
-                if db::add_admin(&*state.db_conn.lock().await, new_admin)? {
-                    tracing::info!(admin = %nick, new_admin, "Added new admin");
-                    client
-                        .send_privmsg(nick, format!("Okay, '{}' is now an admin!", new_admin))?;
-                } else {
-                    client.send_privmsg(
-                        nick,
-                        format!("Failed to add '{}' (maybe already an admin?).", new_admin),
-                    )?;
-                }
-            } else {
-                client.send_privmsg(nick, "Usage: !add_admin <nickname>")?;
-            }
+        Err(e) => {
+            tracing::error!(admin = %nick, error = %e, "Failed to reload tools");
+            irc.send_privmsg(nick, format!("Failed to reload tools: {:#}", e))?;
         }
-        Some("!del_admin") => {
-            if let Some(admin_to_remove) = parts.get(1) {
-                if admin_to_remove.eq_ignore_ascii_case(nick) {
-                    client.send_privmsg(nick, "You can't remove yourself, silly!")?;
-                    return Ok(());
-                }
-                if db::remove_admin(&*state.db_conn.lock().await, admin_to_remove)? {
-                    tracing::info!(admin = %nick, removed = admin_to_remove, "Removed admin");
-                    client.send_privmsg(
-                        nick,
-                        format!("Okay, '{}' is no longer an admin.", admin_to_remove),
-                    )?;
-                } else {
-                    client.send_privmsg(
-                        nick,
-                        format!(
-                            "Failed to remove '{}' (maybe not an admin?).",
-                            admin_to_remove
-                        ),
-                    )?;
-                }
-            } else {
-                client.send_privmsg(nick, "Usage: !del_admin <nickname>")?;
-            }
-        }
-        Some("!admins") => match db::get_admins(&*state.db_conn.lock().await) {
-            Ok(admins) => {
-                if admins.is_empty() {
-                    client.send_privmsg(nick, "There are no registered admins!")?;
-                } else {
-                    client.send_privmsg(
-                        nick,
-                        format!("Registered admins: {}", admins.join(", ")),
-                    )?;
-                }
-            }
-            Err(e) => {
-                tracing::error!("Failed to fetch admins: {:?}", e);
-                client.send_privmsg(nick, "Oops, couldn't check the admin list right now.")?;
-            }
-        },
-        Some("!channels") => match db::get_channels(&*state.db_conn.lock().await) {
-            Ok(channels) => {
-                if channels.is_empty() {
-                    client.send_privmsg(nick, "I'm not set to auto-join any channels.")?;
-                } else {
-                    client.send_privmsg(
-                        nick,
-                        format!("Auto-join channels: {}", channels.join(", ")),
-                    )?;
-                }
-            }
-            Err(e) => {
-                tracing::error!("Failed to fetch channels: {:?}", e);
-                client.send_privmsg(nick, "Oops, couldn't check the channel list right now.")?;
-            }
-        },
-        Some("!interject") => {
-            // Use the correct function name after rename
-            state.bn_interject.force_next_interjection();
-            client.send_privmsg(nick, "Okay, I'll try to interject soon!")?; // Adjusted message slightly
-        },
-        Some("!help") => {
-            client.send_privmsg(nick, "Admin commands: !join <#chan>, !part <#chan>, !add_admin <nick>, !del_admin <nick>, !admins, !channels, !help")?;
+    }
+    Ok(())
+}
+
+async fn list_permission_levels(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick) = (&ctx.irc, cmd.nick);
+    match ctx.state.db.run(db::get_permission_levels).await {
+        Ok(levels) if levels.is_empty() => irc.send_privmsg(nick, "Nobody has a permission level!")?,
+        Ok(levels) => {
+            let list: Vec<String> = levels.iter().map(|(user, level)| format!("{} ({})", user, level)).collect();
+            irc.send_privmsg(nick, format!("Permission levels: {}", list.join(", ")))?;
         }
-        _ => {
-            client.send_privmsg(nick, "Hmm? Unknown command or format. Try !help.")?;
+        Err(e) => {
+            tracing::error!("Failed to fetch permission levels: {:?}", e);
+            irc.send_privmsg(nick, "Oops, couldn't check the admin list right now.")?;
         }
     }
+    Ok(())
+}
+
+async fn grant_level(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick, user) = (&ctx.irc, cmd.nick, cmd.args.arg(0));
+    let level = match cmd.args.arg(1).parse::<Permission>() {
+        Ok(level) => level,
+        Err(e) => {
+            irc.send_privmsg(nick, format!("{:#}.", e))?;
+            return Ok(());
+        }
+    };
+    if user.eq_ignore_ascii_case(nick) {
+        irc.send_privmsg(nick, "You can't change your own level, silly!")?;
+        return Ok(());
+    }
+    let granted = user.to_string();
+    if ctx.state.db.run(move |conn| db::set_permission_level(conn, &granted, level.as_str())).await? {
+        tracing::info!(owner = %nick, user, %level, "Granted permission level");
+        irc.send_privmsg(nick, format!("Okay, '{}' is now at the {} level!", user, level))?;
+    } else {
+        irc.send_privmsg(nick, format!("'{}' is already at the {} level.", user, level))?;
+    }
+    Ok(())
+}
 
+async fn revoke_level(ctx: &AdminContext, cmd: Invocation<'_>) -> Result<()> {
+    let (irc, nick, user) = (&ctx.irc, cmd.nick, cmd.args.arg(0));
+    if user.eq_ignore_ascii_case(nick) {
+        irc.send_privmsg(nick, "You can't remove yourself, silly!")?;
+        return Ok(());
+    }
+    let revoked = user.to_string();
+    if ctx.state.db.run(move |conn| db::remove_permission_level(conn, &revoked)).await? {
+        tracing::info!(owner = %nick, user, "Revoked permission level");
+        irc.send_privmsg(nick, format!("Okay, '{}' has no special permissions anymore.", user))?;
+    } else {
+        irc.send_privmsg(nick, format!("'{}' didn't have a permission level to take away.", user))?;
+    }
     Ok(())
 }
 
 
-/// Split a long response into multiple messages.
-/// This means one message per line, but also splitting long lines.
+/// Ends a line cut short for length, where it continues on the next.
+const CONTINUATION_MARKER: &str = "…";
+
+/// `split_response`, with the parts of a line too long for one message ending in
+/// `CONTINUATION_MARKER`, so readers can tell it goes on.
+fn split_marked(limit: usize, response: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    for line in response.lines() {
+        if line.len() <= limit {
+            parts.extend(split_response(limit, line).into_iter().map(str::to_string));
+            continue;
+        }
+        let pieces = split_response(limit.saturating_sub(CONTINUATION_MARKER.len()).max(1), line);
+        let last = pieces.len() - 1;
+        parts.extend(pieces.into_iter().enumerate().map(|(i, piece)| match i == last {
+            true => piece.to_string(),
+            false => format!("{}{}", piece, CONTINUATION_MARKER),
+        }));
+    }
+    parts
+}
+
+/// Split a long response into multiple messages of at most `limit` bytes.
+/// This means one message per line, but also splitting long lines: at the last space that fits,
+/// or else at the last grapheme boundary that does, so characters (and emoji made of several
+/// of them) are never cut apart.
 fn split_response(limit: usize, response: &str) -> Vec<&str> {
     let mut parts = Vec::new();
     for line in response.lines() {
@@ -600,12 +4007,32 @@ fn split_response(limit: usize, response: &str) -> Vec<&str> {
             if remaining.len() <= limit {
                 parts.push(remaining);
                 break;
-            } else {
-                // Thius is the hard bit. Find the last space before the limit, if any; otherwise, split at the limit.
-                let split_at = remaining[..limit].rfind(' ').unwrap_or(limit);
-                parts.push(&remaining[..split_at]);
-                remaining = remaining[split_at..].trim_start();
             }
+            let mut fits = 0;
+            let mut last_space = None;
+            for (i, grapheme) in remaining.grapheme_indices(true) {
+                if i + grapheme.len() > limit {
+                    break;
+                }
+                if grapheme == " " && i > 0 {
+                    last_space = Some(i);
+                }
+                fits = i + grapheme.len();
+            }
+            if fits == 0 {
+                // A single grapheme longer than the limit (combining mark soup) is split between
+                // its characters instead; always taking at least one keeps this from looping
+                let first = remaining.chars().next().map_or(0, char::len_utf8);
+                fits = remaining
+                    .char_indices()
+                    .map(|(i, c)| i + c.len_utf8())
+                    .take_while(|&end| end <= limit)
+                    .last()
+                    .unwrap_or(first);
+            }
+            let split_at = last_space.unwrap_or(fits);
+            parts.push(&remaining[..split_at]);
+            remaining = remaining[split_at..].trim_start();
         }
     }
     parts
@@ -615,6 +4042,146 @@ fn split_response(limit: usize, response: &str) -> Vec<&str> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2, 3);
+        let start = Instant::now();
+        assert!(matches!(limiter.check("#chan", Some("alice"), start), RateLimitVerdict::Allowed));
+        assert!(matches!(limiter.check("#chan", Some("Alice"), start), RateLimitVerdict::Allowed));
+        // Third request within a minute: limited, but only notified once
+        assert!(matches!(
+            limiter.check("#chan", Some("alice"), start),
+            RateLimitVerdict::Limited { notify: true, .. }
+        ));
+        assert!(matches!(
+            limiter.check("#chan", Some("alice"), start),
+            RateLimitVerdict::Limited { notify: false, .. }
+        ));
+        // Another user still fits the channel limit, then the channel is full
+        assert!(matches!(limiter.check("#chan", Some("bob"), start), RateLimitVerdict::Allowed));
+        assert!(matches!(limiter.check("#chan", None, start), RateLimitVerdict::Limited { notify: false, .. }));
+        // Alice's window has passed, but the channel's hasn't
+        let later = start + USER_RATE_WINDOW;
+        assert!(matches!(limiter.check("#chan", Some("alice"), later), RateLimitVerdict::Limited { .. }));
+        let much_later = start + CHANNEL_RATE_WINDOW;
+        assert!(matches!(limiter.check("#chan", Some("alice"), much_later), RateLimitVerdict::Allowed));
+        // Bob's quiet, so only alice is still tracked
+        assert_eq!(limiter.user_requests.len(), 1);
+        assert_eq!(limiter.channel_requests.len(), 1);
+        assert!(limiter.notified.is_empty());
+    }
+
+    #[test]
+    fn test_message_stamp() {
+        let tagged: Message = "@time=2024-05-01T12:34:56.789Z;msgid=abc123 :alice!a@example.org PRIVMSG #rust :hi\r\n".parse().unwrap();
+        let replays = Replays::default();
+        let stamp = MessageStamp::of(&tagged, &replays);
+        assert_eq!(stamp.time.to_rfc3339(), "2024-05-01T12:34:56.789+00:00");
+        assert_eq!(stamp.msgid.as_deref(), Some("abc123"));
+        assert!(!stamp.replayed); // Long past, but nothing says it's a replay
+
+        let untagged: Message = ":alice!a@example.org PRIVMSG #rust :hi\r\n".parse().unwrap();
+        let stamp = MessageStamp::of(&untagged, &replays);
+        assert_eq!(stamp.msgid, None);
+        assert!((chrono::Utc::now() - stamp.time).num_seconds() < 5);
+        assert!(!stamp.replayed);
+
+        let logged_in: Message = "@account=Alice :alice!a@example.org PRIVMSG Emul :!optout\r\n".parse().unwrap();
+        assert_eq!(MessageStamp::of(&logged_in, &replays).account.as_deref(), Some("Alice"));
+        assert_eq!(MessageStamp::of(&untagged, &replays).account, None);
+    }
+
+    #[test]
+    fn test_replays() {
+        let replays = Replays::default();
+        let parse = |line: &str| line.parse::<Message>().unwrap();
+        let batched = parse("@batch=hist1 :alice!a@example.org PRIVMSG #rust :hi\r\n");
+        let other_batch = parse("@batch=split1 :alice!a@example.org PRIVMSG #rust :hi\r\n");
+        let batch = |line: &str| match parse(line).command {
+            Command::BATCH(reference, kind, _) => replays.batch(&reference, kind.as_ref().map(BatchSubCommand::to_str)),
+            command => panic!("not a batch: {:?}", command),
+        };
+
+        // Only lines in a history batch are replays, and only while it's open
+        assert!(!MessageStamp::of(&batched, &replays).replayed);
+        batch(":irc.example.org BATCH +hist1 chathistory #rust\r\n");
+        batch(":irc.example.org BATCH +split1 netsplit irc1.example.org irc2.example.org\r\n");
+        assert!(MessageStamp::of(&batched, &replays).replayed);
+        assert!(!MessageStamp::of(&other_batch, &replays).replayed);
+        batch(":irc.example.org BATCH -hist1\r\n");
+        assert!(!MessageStamp::of(&batched, &replays).replayed);
+
+        // Lines from before joining, just after joining, are a ZNC's buffer; never private ones
+        let now = Instant::now();
+        let joined = chrono::DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z").unwrap().to_utc();
+        replays.expect("#Rust", joined, now);
+        let at = |time: &str, target: &str| {
+            let time = chrono::DateTime::parse_from_rfc3339(time).unwrap().to_utc();
+            replays.is_replayed(target, None, Some(time), now)
+        };
+        assert!(at("2024-05-01T11:00:00Z", "#rust"));
+        assert!(!at("2024-05-01T12:00:01Z", "#rust"));
+        assert!(!at("2024-05-01T11:00:00Z", "#other"));
+        assert!(!at("2024-05-01T11:00:00Z", "Emul"));
+        let later = now + REPLAY_WINDOW;
+        let old = chrono::DateTime::parse_from_rfc3339("2024-05-01T11:00:00Z").unwrap().to_utc();
+        assert!(!replays.is_replayed("#rust", None, Some(old), later));
+    }
+
+    #[test]
+    fn test_replays_read() {
+        let replays = Replays::default();
+        let services = Services::new(crate::config::ServicesKind::Nickserv, "Emul", None, None);
+        let read = |line: &str| replays.read(&line.parse::<Message>().unwrap(), &services);
+        let line = "@batch=hist1 :alice!a@example.org PRIVMSG #rust :hi\r\n";
+
+        // Lines read between a history batch's start and end are replays, whenever they're handled
+        assert_eq!(read(":irc.example.org BATCH +hist1 chathistory #rust\r\n"), None);
+        let replayed = read(line).unwrap();
+        assert_eq!(read(":irc.example.org BATCH -hist1\r\n"), None);
+        assert!(replayed.replayed);
+        assert!(!read(line).unwrap().replayed);
+
+        // Our own join is when a ZNC's buffer starts; someone else's isn't
+        let before_join = |channel: &str| {
+            let line = format!("@time=2024-05-01T11:00:00Z :alice!a@example.org PRIVMSG {} :hi\r\n", channel);
+            read(&line).unwrap().replayed
+        };
+        read("@time=2024-05-01T12:00:00Z :Emul!e@example.org JOIN #rust\r\n").unwrap();
+        read("@time=2024-05-01T12:00:00Z :alice!a@example.org JOIN #other\r\n").unwrap();
+        assert!(before_join("#rust"));
+        assert!(!before_join("#other"));
+    }
+
+    #[test]
+    fn test_rename_buffered() {
+        let now = Instant::now();
+        let fragment =
+            |message: &str| BufferedMessage { message: message.to_string(), last_arrival: now, stamp: MessageStamp::now() };
+        let mut buffer = HashMap::new();
+        buffer.insert(("#a".to_string(), "alice".to_string()), fragment("so what I"));
+        buffer.insert(("#a".to_string(), "alicia".to_string()), fragment("meant was"));
+        buffer.insert(("#b".to_string(), "alice".to_string()), fragment("hi"));
+        buffer.insert(("#b".to_string(), "bob".to_string()), fragment("hello"));
+
+        rename_buffered(&mut buffer, "alice", "alicia");
+        let mut messages: Vec<(&str, &str, &str)> =
+            buffer.iter().map(|((channel, nick), buffered)| (channel.as_str(), nick.as_str(), buffered.message.as_str())).collect();
+        messages.sort();
+        assert_eq!(messages, [("#a", "alicia", "so what I meant was"), ("#b", "alicia", "hi"), ("#b", "bob", "hello")]);
+    }
+
+    #[test]
+    fn test_take_complete_sentences() {
+        let mut buffer = "Hello there! Version 3.5 is out. And then".to_string();
+        assert_eq!(take_complete_sentences(&mut buffer).as_deref(), Some("Hello there! Version 3.5 is out."));
+        assert_eq!(buffer, "And then");
+        assert_eq!(take_complete_sentences(&mut buffer), None);
+        buffer.push_str(" a list:\n- one");
+        assert_eq!(take_complete_sentences(&mut buffer).as_deref(), Some("And then a list:"));
+        assert_eq!(buffer, "- one");
+    }
+
     #[test]
     fn test_split_response() {
         let response = "This is a test response. It should be split into multiple\nmessages.";
@@ -624,6 +4191,48 @@ mod tests {
         assert_eq!(parts[1], "messages.");
     }
 
+    #[test]
+    fn test_split_multibyte_text() {
+        // CJK characters are three bytes each, and mustn't be cut in the middle
+        let response = "日本語のテキストです";
+        let parts = split_response(10, response);
+        assert_eq!(parts, ["日本語", "のテキ", "ストで", "す"]);
+
+        // A family emoji is one grapheme of 18 bytes, and a flag one of 8
+        let response = "hi 👨‍👩‍👧 🇯🇵🇯🇵🇯🇵";
+        let parts = split_response(20, response);
+        assert_eq!(parts, ["hi", "👨‍👩‍👧", "🇯🇵🇯🇵", "🇯🇵"]);
+        assert!(parts.iter().all(|part| part.len() <= 20));
+
+        // Graphemes too long for the limit on their own are split between their characters
+        let parts = split_response(4, "👨‍👩‍👧");
+        assert_eq!(parts.concat(), "👨‍👩‍👧");
+        assert!(parts.iter().all(|part| part.len() <= 4));
+    }
+
+    #[test]
+    fn test_verify_user() {
+        let invocation = |permission, args| Invocation { nick: "alice", permission, args: commands::Args::new(args) };
+        let verified = |cmd, account| match verify_user(&cmd, account) {
+            VerifiedUser::User(user) => Some(user),
+            _ => None,
+        };
+        assert_eq!(verified(invocation(Permission::Anyone, ""), Some("Alice")).as_deref(), Some("alice"));
+        assert_eq!(verified(invocation(Permission::Anyone, ""), Some("bob")), None);
+        assert_eq!(verified(invocation(Permission::Anyone, ""), None), None);
+        assert!(matches!(verify_user(&invocation(Permission::Moderator, "bob"), Some("alice")), VerifiedUser::NotAdmin));
+        assert_eq!(verified(invocation(Permission::Admin, "bob"), None).as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn test_split_marked() {
+        let response = "Short line.\nThis line is long enough that it has to be split in two.";
+        let parts = split_marked(40, response);
+        assert_eq!(parts, ["Short line.", "This line is long enough that it has…", "to be split in two."]);
+        assert!(parts.iter().all(|part| part.len() <= 40));
+        assert_eq!(split_marked(40, "Fits.\n\nAlso fits."), ["Fits.", "Also fits."]);
+    }
+
     #[test]
     fn test_split_long_line() {
         let response = "This is a test response. It should be split into multiple messages. This line is long enough to be split into multiple parts.";
@@ -632,4 +4241,115 @@ mod tests {
         assert_eq!(parts[1], "messages. This line is long enough to be split into");
         assert_eq!(parts[2], "multiple parts.");
     }
+
+    /// Passes everything the bot sends on to the test.
+    struct RecordingTransport(mpsc::UnboundedSender<(String, String)>);
+
+    impl ChatTransport for RecordingTransport {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        fn max_message_length(&self, _channel: &str) -> usize {
+            400
+        }
+
+        fn send_message<'a>(&'a self, channel: &'a str, text: &'a str) -> future::BoxFuture<'a, Result<()>> {
+            let _ = self.0.send((channel.to_string(), text.to_string()));
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_builder_runs_bot_on_custom_transport() {
+        let config = Config::from_args(["emul", "--nickname", "Emul", "--image-cache-ttl-hours", "0"]).unwrap();
+        let (sent_tx, mut sent_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let bot = BotBuilder::new(config)
+            .in_memory_db()
+            .llm(Arc::new(llm::EchoBackend))
+            .prompt("You are Emul, a test bot.")
+            .tools(ToolRegistry::builtin(None))
+            .transport(Arc::new(RecordingTransport(sent_tx)), incoming_rx)
+            .build()
+            .await
+            .unwrap();
+        let db = bot.db().clone();
+        let running = tokio::spawn(bot.run());
+
+        let message = IncomingMessage { channel: "#test".into(), nick: "alice".into(), text: "Emul: hello there".into() };
+        incoming_tx.send(message).unwrap();
+        let (channel, text) = tokio::time::timeout(Duration::from_secs(10), sent_rx.recv()).await.unwrap().unwrap();
+        assert_eq!(channel, "#test");
+        assert!(text.starts_with("[dry run]") && text.contains("hello there"), "unexpected reply: {}", text);
+
+        // Both sides of the exchange were logged to the in-memory database
+        let log = db.run(|conn| db::get_channel_log(conn, "#test")).await.unwrap();
+        let mut nicks: Vec<&str> = log.iter().map(|entry| entry.nick.as_str()).collect();
+        nicks.sort_unstable(); // Both land in the same second, so the log's order is arbitrary
+        assert_eq!(nicks, ["Emul", "alice"]);
+
+        // The bot stops once its only transport does
+        drop(incoming_tx);
+        tokio::time::timeout(Duration::from_secs(10), running).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_public_commands_answer_in_channel() {
+        let config = Config::from_args(["emul", "--nickname", "Emul", "--command-prefix", "@", "--image-cache-ttl-hours", "0"]).unwrap();
+        let (sent_tx, mut sent_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let bot = BotBuilder::new(config)
+            .in_memory_db()
+            .llm(Arc::new(llm::EchoBackend))
+            .prompt("You are Emul, a test bot.")
+            .tools(ToolRegistry::builtin(None))
+            .transport(Arc::new(RecordingTransport(sent_tx)), incoming_rx)
+            .build()
+            .await
+            .unwrap();
+        let running = tokio::spawn(bot.run());
+        let mut ask = async |nick: &str, text: &str| {
+            let message = IncomingMessage { channel: "#test".into(), nick: nick.into(), text: text.into() };
+            incoming_tx.send(message).unwrap();
+            tokio::time::timeout(Duration::from_secs(10), sent_rx.recv()).await.unwrap().unwrap().1
+        };
+
+        assert!(ask("alice", "@roll 1d1").await.starts_with("alice: Rolled 1d1: [1]"));
+        assert_eq!(ask("bob", "@roll").await, "bob: Usage: @roll <dice>");
+        let seen = ask("bob", "@seen Alice").await;
+        assert!(seen.starts_with("bob: Last seen just now: alice: @roll 1d1"), "unexpected reply: {}", seen);
+        let help = ask("alice", "@help").await;
+        assert!(help.contains("@seen <nick>") && help.contains("@karma [<nick>]"), "unexpected reply: {}", help);
+
+        assert_eq!(ask("alice", "@quote random").await, "alice: No quotes here yet! Save one with @quote add.");
+        assert_eq!(ask("alice", "@quote add \"<bob> it works on my machine\"").await, "alice: Saved as quote #1!");
+        assert_eq!(ask("bob", "@quote get #1").await, "bob: #1: <bob> it works on my machine");
+        assert_eq!(ask("bob", "@quote search MACHINE").await, "bob: #1: <bob> it works on my machine");
+        assert_eq!(ask("bob", "@quote del 1").await, "bob: Sorry, @quote del <number> is only for moderators and up!");
+        assert_eq!(ask("bob", "@quote").await, "bob: Usage: @quote add <text> | @quote get <number> | @quote search <text> | @quote random");
+
+        // Long answers are split to fit the network's lines
+        let say = |nick: &str, text: &str| {
+            incoming_tx.send(IncomingMessage { channel: "#test".into(), nick: nick.into(), text: text.into() }).unwrap();
+        };
+        let mut next = async || tokio::time::timeout(Duration::from_secs(10), sent_rx.recv()).await.unwrap().unwrap().1;
+        say("carol", "@roll 100d1000");
+        let (first, second) = (next().await, next().await);
+        assert!(first.starts_with("carol: Rolled") && first.len() <= 400, "unexpected reply: {}", first);
+        assert!(second.ends_with(|c: char| c.is_ascii_digit()) && !second.starts_with("carol:"), "unexpected line: {}", second);
+
+        // Nobody gets to run commands without end
+        for _ in 1..PUBLIC_COMMAND_BURST {
+            say("carol", "@roll 1d1");
+            assert!(next().await.starts_with("carol: Rolled"));
+        }
+        say("carol", "@roll 1d1");
+        say("dave", "@roll 1d1");
+        assert!(next().await.starts_with("dave: Rolled"));
+        assert!(sent_rx.try_recv().is_err());
+
+        drop(incoming_tx);
+        tokio::time::timeout(Duration::from_secs(10), running).await.unwrap().unwrap().unwrap();
+    }
 }
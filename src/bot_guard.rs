@@ -0,0 +1,174 @@
+//! Keeping the bot out of endless conversations with other bots. Bots it knows of by nick
+//! (`--ignored-bots`) are ignored outright. Nicks that may be bots, because they match
+//! `--suspected-bots` or quoted the bot's last answer back at it, are answered, but once the AI has
+//! answered one `--max-bot-exchanges` times in a row, each within a minute and with nobody else
+//! talking, it stops until someone else speaks or things calm down. People are never cut off.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Answers further apart than this don't count as one back-and-forth.
+const EXCHANGE_GAP: Duration = Duration::from_secs(60);
+/// How long a nick that quoted the bot back at it stays suspected of being a bot.
+const SUSPICION_TIME: Duration = Duration::from_secs(60 * 60);
+
+/// Whether `nick` matches one of `patterns`, ignoring case; `*` matches any run of characters,
+/// `?` any one.
+pub fn is_bot(patterns: &[String], nick: &str) -> bool {
+    let nick: Vec<char> = nick.to_lowercase().chars().collect();
+    patterns.iter().any(|pattern| {
+        let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+        wildcard_match(&pattern, &nick)
+    })
+}
+
+fn wildcard_match(pattern: &[char], text: &[char]) -> bool {
+    // Where to resume after the last `*`: the pattern just past it, and how much text it has taken
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((after_star, taken)) => {
+                    star = Some((after_star, taken + 1));
+                    p = after_star;
+                    t = taken + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The back-and-forth going on in each channel: who the AI keeps answering, how many times in a
+/// row, and when it last did.
+#[derive(Default)]
+pub struct ExchangeCounter {
+    channels: Mutex<HashMap<String, (String, u32, Instant)>>,
+    /// Nicks that gave themselves away as bots (lowercase), and when they last did.
+    suspects: Mutex<HashMap<String, Instant>>,
+}
+
+impl ExchangeCounter {
+    /// Notes a message from `nick`: anyone else speaking, or a lull, ends the back-and-forth.
+    pub fn heard(&self, channel: &str, nick: &str, now: Instant) {
+        let mut channels = self.channels.lock().unwrap();
+        let channel = channel.to_lowercase();
+        if let Some((partner, _, last)) = channels.get(&channel)
+            && (!partner.eq_ignore_ascii_case(nick) || now.duration_since(*last) > EXCHANGE_GAP)
+        {
+            channels.remove(&channel);
+        }
+    }
+
+    /// Notes that `nick` behaved like a bot, e.g. by quoting the bot's last answer back at it.
+    pub fn suspect(&self, nick: &str, now: Instant) {
+        let mut suspects = self.suspects.lock().unwrap();
+        suspects.retain(|_, since| now.duration_since(*since) < SUSPICION_TIME);
+        suspects.insert(nick.to_lowercase(), now);
+    }
+
+    /// Whether the AI may answer `nick` once more: always for people, and for nicks that may be
+    /// bots (matching `patterns` or suspected) unless it already has `max` times in a row (0 for
+    /// no limit).
+    pub fn may_answer(&self, channel: &str, nick: &str, now: Instant, max: u32, patterns: &[String]) -> bool {
+        if max == 0 || !(is_bot(patterns, nick) || self.is_suspect(nick, now)) {
+            return true;
+        }
+        let channels = self.channels.lock().unwrap();
+        match channels.get(&channel.to_lowercase()) {
+            Some((partner, count, _)) if partner.eq_ignore_ascii_case(nick) => *count < max,
+            _ => true,
+        }
+    }
+
+    /// Counts an answer the AI sent to `nick`.
+    pub fn answered(&self, channel: &str, nick: &str, now: Instant) {
+        let mut channels = self.channels.lock().unwrap();
+        let entry = channels.entry(channel.to_lowercase()).or_insert_with(|| (nick.to_string(), 0, now));
+        if !entry.0.eq_ignore_ascii_case(nick) {
+            *entry = (nick.to_string(), 0, now);
+        }
+        entry.1 += 1;
+        entry.2 = now;
+    }
+
+    fn is_suspect(&self, nick: &str, now: Instant) -> bool {
+        let suspects = self.suspects.lock().unwrap();
+        suspects.get(&nick.to_lowercase()).is_some_and(|since| now.duration_since(*since) < SUSPICION_TIME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_bot() {
+        let patterns = vec!["*bot".to_string(), "?hanServ".to_string(), "Emul2".to_string()];
+        assert!(is_bot(&patterns, "GPTBot"));
+        assert!(is_bot(&patterns, "bot"));
+        assert!(is_bot(&patterns, "ChanServ"));
+        assert!(is_bot(&patterns, "emul2"));
+        assert!(!is_bot(&patterns, "botany"));
+        assert!(!is_bot(&patterns, "hanServ"));
+        assert!(!is_bot(&patterns, "Emul"));
+        assert!(is_bot(&["a*b*c".to_string()], "aXbYbZc"));
+        assert!(!is_bot(&["a*b*c".to_string()], "aXbYbZ"));
+        assert!(!is_bot(&[], "GPTBot"));
+    }
+
+    #[test]
+    fn test_exchange_counter() {
+        let counter = ExchangeCounter::default();
+        let patterns = vec!["*bot".to_string()];
+        let now = Instant::now();
+        let answer = |channel: &str, nick: &str, now: Instant, max: u32| {
+            let allowed = counter.may_answer(channel, nick, now, max, &patterns);
+            if allowed {
+                counter.answered(channel, nick, now);
+            }
+            allowed
+        };
+        for _ in 0..3 {
+            counter.heard("#test", "otherbot", now);
+            assert!(answer("#test", "otherbot", now, 3));
+        }
+        counter.heard("#test", "OtherBot", now);
+        assert!(!answer("#test", "otherbot", now, 3));
+        assert!(answer("#elsewhere", "otherbot", now, 3));
+
+        // Someone else speaking ends it
+        counter.heard("#test", "alice", now);
+        assert!(answer("#test", "otherbot", now, 3));
+
+        // So does a lull
+        let later = now + EXCHANGE_GAP * 2;
+        for _ in 0..2 {
+            assert!(answer("#test", "otherbot", now, 3));
+        }
+        counter.heard("#test", "otherbot", later);
+        assert!(answer("#test", "otherbot", later, 3));
+
+        // People are never cut off, nor is anyone when 0 means no limit
+        for _ in 0..10 {
+            assert!(answer("#test", "alice", later, 1));
+            assert!(answer("#other", "otherbot", later, 0));
+        }
+
+        // Until they give themselves away
+        counter.suspect("Alice", later);
+        assert!(!answer("#test", "alice", later, 1));
+        assert!(answer("#test", "alice", later + SUSPICION_TIME, 1));
+    }
+}
@@ -0,0 +1,1170 @@
+//! The calculate tool's evaluator, so the AI stops doing arithmetic in its head. Numbers are
+//! exact fractions of any size, so `2^100` and `0.1 + 0.2` come out right; square roots of
+//! non-squares, trigonometry, logarithms and the like fall back to floating point and the result
+//! is marked as approximate. Quantities carry units and are converted with `to`
+//! (`5 ft 11 in to cm`, `60 mph to km/h`, `100 F to C`). Currencies work like units, with
+//! exchange rates from currency.rs.
+//!
+//! The grammar, loosest first: `a to b`, `+ -`, `* / mod`, unary signs, factors written side
+//! by side (`5 kg`, which multiply), `^`, then the postfix `%` and `!`.
+
+use crate::currency::Rates;
+use anyhow::{Context, Result, bail};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{One, Signed, ToPrimitive, Zero};
+
+const MAX_EXPRESSION_LENGTH: usize = 500;
+/// Numbers whose numerator or denominator needs more bits than this are refused, so `9^9^9`
+/// can't eat the bot's memory.
+const MAX_BITS: u64 = 100_000;
+const MAX_FACTORIAL: u64 = 5000;
+/// Significant digits shown of results that aren't exact decimals, unless asked for more.
+pub const DEFAULT_PRECISION: usize = 20;
+pub const MAX_PRECISION: usize = 1000;
+/// Floating point results have no more meaningful digits than this.
+const FLOAT_DIGITS: usize = 15;
+/// Integers and exact decimals up to this long are shown in full.
+const MAX_EXACT_DIGITS: usize = 1000;
+
+/// Powers of the base units: metre, kilogram, second, kelvin, ampere, mole, byte, and the
+/// exchange rates' base currency.
+type Dims = [i32; 8];
+
+const BASE_UNITS: [&str; 7] = ["m", "kg", "s", "K", "A", "mol", "B"];
+const MONEY: usize = 7;
+
+const fn dims(length: i32, mass: i32, time: i32, temperature: i32, current: i32) -> Dims {
+    [length, mass, time, temperature, current, 0, 0, 0]
+}
+
+const fn base(index: usize) -> Dims {
+    let mut dims = [0; 8];
+    dims[index] = 1;
+    dims
+}
+
+const NONE: Dims = [0; 8];
+const LENGTH: Dims = dims(1, 0, 0, 0, 0);
+const MASS: Dims = dims(0, 1, 0, 0, 0);
+const TIME: Dims = dims(0, 0, 1, 0, 0);
+const TEMPERATURE: Dims = dims(0, 0, 0, 1, 0);
+const CURRENT: Dims = dims(0, 0, 0, 0, 1);
+const AMOUNT: Dims = base(5);
+const INFORMATION: Dims = base(6);
+const AREA: Dims = dims(2, 0, 0, 0, 0);
+const VOLUME: Dims = dims(3, 0, 0, 0, 0);
+const SPEED: Dims = dims(1, 0, -1, 0, 0);
+const FREQUENCY: Dims = dims(0, 0, -1, 0, 0);
+const FORCE: Dims = dims(1, 1, -2, 0, 0);
+const ENERGY: Dims = dims(2, 1, -2, 0, 0);
+const POWER: Dims = dims(2, 1, -3, 0, 0);
+const PRESSURE: Dims = dims(-1, 1, -2, 0, 0);
+const VOLTAGE: Dims = dims(2, 1, -3, 0, -1);
+const RESISTANCE: Dims = dims(2, 1, -3, 0, -2);
+
+/// Names for combinations of base units, used when a result has no unit of its own.
+const DERIVED_UNITS: &[(Dims, &str)] = &[
+    (FORCE, "N"),
+    (ENERGY, "J"),
+    (POWER, "W"),
+    (PRESSURE, "Pa"),
+    (FREQUENCY, "Hz"),
+    (VOLTAGE, "V"),
+    (RESISTANCE, "Ω"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Prefixes {
+    None,
+    Si,
+    /// SI prefixes, and binary ones like Ki and Mi.
+    Binary,
+}
+
+struct UnitDef {
+    names: &'static [&'static str],
+    /// Size in base units, as a decimal or a fraction of decimals.
+    factor: &'static str,
+    dims: Dims,
+    /// Added before scaling, for temperatures: kelvin = (value + offset) * factor.
+    offset: &'static str,
+    prefixes: Prefixes,
+    /// False when the factor is a rounded irrational number, like a degree's π/180.
+    exact: bool,
+}
+
+const fn unit(names: &'static [&'static str], factor: &'static str, dims: Dims) -> UnitDef {
+    UnitDef { names, factor, dims, offset: "0", prefixes: Prefixes::None, exact: true }
+}
+
+impl UnitDef {
+    const fn si(self) -> Self {
+        UnitDef { prefixes: Prefixes::Si, ..self }
+    }
+
+    const fn binary(self) -> Self {
+        UnitDef { prefixes: Prefixes::Binary, ..self }
+    }
+
+    const fn offset(self, offset: &'static str) -> Self {
+        UnitDef { offset, ..self }
+    }
+
+    const fn approximate(self) -> Self {
+        UnitDef { exact: false, ..self }
+    }
+}
+
+/// Known units, by every name they go by. Plurals ending in `s` are found without listing them.
+const UNITS: &[UnitDef] = &[
+    // Length
+    unit(&["m", "meter", "metre"], "1", LENGTH).si(),
+    unit(&["in", "inch", "inches", "\""], "0.0254", LENGTH),
+    unit(&["ft", "foot", "feet", "'"], "0.3048", LENGTH),
+    unit(&["yd", "yard"], "0.9144", LENGTH),
+    unit(&["mi", "mile"], "1609.344", LENGTH),
+    unit(&["nmi", "nauticalmile"], "1852", LENGTH),
+    unit(&["au"], "149597870700", LENGTH),
+    unit(&["ly", "lightyear"], "9460730472580800", LENGTH),
+    unit(&["pc", "parsec"], "30856775814913673", LENGTH).si().approximate(),
+    unit(&["Å", "angstrom"], "1e-10", LENGTH),
+    // Mass
+    unit(&["g", "gram", "gramme"], "0.001", MASS).si(),
+    unit(&["t", "tonne"], "1000", MASS).si(),
+    unit(&["lb", "lbs", "pound"], "0.45359237", MASS),
+    unit(&["oz", "ounce"], "0.028349523125", MASS),
+    unit(&["st", "stone"], "6.35029318", MASS),
+    unit(&["ton"], "907.18474", MASS),
+    // Time
+    unit(&["s", "sec", "second"], "1", TIME).si(),
+    unit(&["min", "minute"], "60", TIME),
+    unit(&["h", "hr", "hour"], "3600", TIME),
+    unit(&["d", "day"], "86400", TIME),
+    unit(&["wk", "week"], "604800", TIME),
+    unit(&["fortnight"], "1209600", TIME),
+    unit(&["mo", "month"], "2629800", TIME),
+    unit(&["yr", "year"], "31557600", TIME),
+    unit(&["decade"], "315576000", TIME),
+    unit(&["century", "centuries"], "3155760000", TIME),
+    // Temperature
+    unit(&["K", "kelvin"], "1", TEMPERATURE).si(),
+    unit(&["C", "°C", "degC", "celsius"], "1", TEMPERATURE).offset("273.15"),
+    unit(&["F", "°F", "degF", "fahrenheit"], "5/9", TEMPERATURE).offset("459.67"),
+    // Area and volume
+    unit(&["ha", "hectare"], "10000", AREA),
+    unit(&["acre"], "4046.8564224", AREA),
+    unit(&["L", "l", "liter", "litre"], "0.001", VOLUME).si(),
+    unit(&["gal", "gallon"], "0.003785411784", VOLUME),
+    unit(&["qt", "quart"], "0.000946352946", VOLUME),
+    unit(&["pt", "pint"], "0.000473176473", VOLUME),
+    unit(&["cup"], "0.0002365882365", VOLUME),
+    unit(&["floz"], "0.0000295735295625", VOLUME),
+    unit(&["tbsp", "tablespoon"], "0.00001478676478125", VOLUME),
+    unit(&["tsp", "teaspoon"], "0.00000492892159375", VOLUME),
+    // Speed
+    unit(&["mph"], "0.44704", SPEED),
+    unit(&["kph"], "1000/3600", SPEED),
+    unit(&["kn", "knot"], "1852/3600", SPEED),
+    // Mechanics and electricity
+    unit(&["Hz", "hertz"], "1", FREQUENCY).si(),
+    unit(&["N", "newton"], "1", FORCE).si(),
+    unit(&["lbf"], "4.4482216152605", FORCE),
+    unit(&["J", "joule"], "1", ENERGY).si(),
+    unit(&["cal", "calorie"], "4.184", ENERGY).si(),
+    unit(&["Wh"], "3600", ENERGY).si(),
+    unit(&["eV"], "1.602176634e-19", ENERGY).si(),
+    unit(&["BTU"], "1055.05585262", ENERGY),
+    unit(&["W", "watt"], "1", POWER).si(),
+    unit(&["hp", "horsepower"], "745.69987158227022", POWER),
+    unit(&["Pa", "pascal"], "1", PRESSURE).si(),
+    unit(&["bar"], "100000", PRESSURE).si(),
+    unit(&["atm"], "101325", PRESSURE),
+    unit(&["psi"], "4.4482216152605/0.00064516", PRESSURE),
+    unit(&["mmHg"], "133.322387415", PRESSURE),
+    unit(&["A", "amp", "ampere"], "1", CURRENT).si(),
+    unit(&["V", "volt"], "1", VOLTAGE).si(),
+    unit(&["Ω", "ohm"], "1", RESISTANCE).si(),
+    unit(&["mol", "mole"], "1", AMOUNT).si(),
+    // Information
+    unit(&["B", "byte"], "1", INFORMATION).binary(),
+    unit(&["b", "bit"], "1/8", INFORMATION).binary(),
+    // Angles are plain numbers; a radian is 1
+    unit(&["rad", "radian"], "1", NONE),
+    unit(&["deg", "°", "degree"], "0.017453292519943295", NONE).approximate(),
+];
+
+const SI_PREFIXES: &[(&str, &str)] = &[
+    ("Y", "1e24"),
+    ("Z", "1e21"),
+    ("E", "1e18"),
+    ("P", "1e15"),
+    ("T", "1e12"),
+    ("G", "1e9"),
+    ("M", "1e6"),
+    ("k", "1e3"),
+    ("h", "1e2"),
+    ("da", "1e1"),
+    ("d", "1e-1"),
+    ("c", "1e-2"),
+    ("m", "1e-3"),
+    ("µ", "1e-6"),
+    ("u", "1e-6"),
+    ("n", "1e-9"),
+    ("p", "1e-12"),
+    ("f", "1e-15"),
+    ("a", "1e-18"),
+    ("kilo", "1e3"),
+    ("mega", "1e6"),
+    ("giga", "1e9"),
+    ("tera", "1e12"),
+    ("centi", "1e-2"),
+    ("milli", "1e-3"),
+    ("micro", "1e-6"),
+    ("nano", "1e-9"),
+];
+const BINARY_PREFIXES: &[(&str, &str)] = &[
+    ("Ki", "1024"),
+    ("Mi", "1048576"),
+    ("Gi", "1073741824"),
+    ("Ti", "1099511627776"),
+    ("Pi", "1125899906842624"),
+];
+const CURRENCY_SYMBOLS: &[(&str, &str)] = &[("$", "USD"), ("€", "EUR"), ("£", "GBP"), ("¥", "JPY"), ("₹", "INR")];
+const CONSTANTS: &[&str] = &["pi", "π", "e", "tau"];
+const FUNCTIONS: &[&str] = &[
+    "sqrt", "cbrt", "abs", "floor", "ceil", "round", "min", "max", "exp", "ln", "log", "log2", "log10", "sin", "cos",
+    "tan", "asin", "acos", "atan",
+];
+
+/// Evaluates `expression`, converting currencies with `rates`, and describes the result, like
+/// "5 ft to cm = 152.4 cm". Results that aren't exact decimals are shown to `precision`
+/// significant digits.
+pub fn evaluate(expression: &str, rates: Option<&Rates>, precision: usize) -> Result<String> {
+    let expression = expression.trim();
+    if expression.chars().count() > MAX_EXPRESSION_LENGTH {
+        bail!("That expression is too long; the limit is {} characters.", MAX_EXPRESSION_LENGTH);
+    }
+    let tokens = tokenize(expression)?;
+    let mut parts = tokens.split(|token| *token == Token::Convert);
+    let (source, target) = (parts.next().unwrap_or_default(), parts.next());
+    if parts.next().is_some() {
+        bail!("Only one conversion at a time, please.");
+    }
+
+    let mut evaluator = Evaluator::new(source, rates);
+    let quantity = evaluator.run()?;
+    let (value, label, exact) = match target {
+        Some(target) => {
+            let mut target_evaluator = Evaluator::new(target, rates);
+            let unit = target_evaluator.run()?;
+            if unit.dims != quantity.dims {
+                bail!("Can't convert {} to {}.", describe_dims(&quantity.dims, rates), describe_dims(&unit.dims, rates));
+            }
+            let mut value = &quantity.value / &unit.value;
+            // A lone temperature unit converts between scales, not just degree sizes
+            if let [Token::Ident(_)] = target
+                && let [only] = target_evaluator.units.as_slice()
+            {
+                value -= &only.offset;
+            }
+            (value, label(target), quantity.exact && unit.exact)
+        }
+        None => match evaluator.units.iter().find(|unit| unit.dims == quantity.dims && quantity.dims != NONE) {
+            // Shown in the first unit the expression used for this kind of quantity
+            Some(unit) => (&quantity.value / &unit.factor - &unit.offset, unit.name.clone(), quantity.exact),
+            None => (quantity.value.clone(), describe_dims(&quantity.dims, rates), quantity.exact),
+        },
+    };
+
+    let digits = if exact { precision.clamp(1, MAX_PRECISION) } else { precision.clamp(1, FLOAT_DIGITS) };
+    let (number, exact) = format_number(&value, exact, digits);
+    let unit = if label.is_empty() { String::new() } else { format!(" {}", label) };
+    Ok(format!("{} {} {}{}", expression, if exact { "=" } else { "≈" }, number, unit))
+}
+
+/// Whether `expression` mentions a currency, so exchange rates are needed to evaluate it.
+pub fn mentions_currency(expression: &str) -> bool {
+    let Ok(tokens) = tokenize(expression) else {
+        return false;
+    };
+    tokens.iter().any(|token| match token {
+        Token::Ident(name) => {
+            CURRENCY_SYMBOLS.iter().any(|(symbol, _)| symbol == name)
+                || (name.len() == 3
+                    && name.chars().all(|c| c.is_ascii_alphabetic())
+                    && lookup_unit(name).is_none()
+                    && !FUNCTIONS.contains(&name.as_str())
+                    && !CONSTANTS.contains(&name.as_str())
+                    && name != "mod")
+        }
+        _ => false,
+    })
+}
+
+/// Parses a decimal like `12`, `0.5` or `6.02e23` exactly, or a fraction of two of them.
+pub(crate) fn parse_decimal(text: &str) -> Option<BigRational> {
+    if let Some((numerator, denominator)) = text.split_once('/') {
+        let denominator = parse_decimal(denominator)?;
+        return (!denominator.is_zero()).then(|| parse_decimal(numerator).map(|n| n / denominator))?;
+    }
+    let (mantissa, exponent) = match text.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<i64>().ok()?),
+        None => (text, 0),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits = format!("{}{}", whole, fraction);
+    let digits = digits.strip_prefix('-').unwrap_or(&digits);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) || fraction.starts_with(['-', '+']) {
+        return None;
+    }
+    let exponent = exponent - fraction.len() as i64;
+    // Beyond this, 10^exponent alone is over the size limit
+    if exponent.unsigned_abs() > MAX_BITS / 3 {
+        return None;
+    }
+    let mut value = BigRational::from_integer(digits.parse::<BigInt>().ok()?) * pow10(exponent);
+    if mantissa.starts_with('-') {
+        value = -value;
+    }
+    Some(value)
+}
+
+fn pow10(exponent: i64) -> BigRational {
+    let power = BigInt::from(10).pow(exponent.unsigned_abs() as u32);
+    match exponent < 0 {
+        true => BigRational::new(BigInt::one(), power),
+        false => BigRational::from_integer(power),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(BigRational),
+    Ident(String),
+    Op(char),
+    Open,
+    Close,
+    Comma,
+    /// `to`, `as`, `in` or `->` between a quantity and the unit to show it in.
+    Convert,
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || matches!(c, '_' | '°' | 'µ' | 'Ω' | 'Å')
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let token = if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.' || chars[i] == '_') {
+                i += 1;
+            }
+            // An exponent, unless the e is Euler's number, as in 2e
+            if i < chars.len() && matches!(chars[i], 'e' | 'E') {
+                let digit_at = |j: usize| chars.get(j).is_some_and(|c| c.is_ascii_digit());
+                if digit_at(i + 1) || (matches!(chars.get(i + 1), Some('+' | '-')) && digit_at(i + 2)) {
+                    i += 2;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let number: String = chars[start..i].iter().filter(|&&c| c != '_').collect();
+            let value = parse_decimal(&number).with_context(|| format!("'{}' isn't a number I can work with", number))?;
+            tokens.push(Token::Number(value));
+            continue;
+        } else if is_ident_start(c) {
+            let start = i;
+            while i < chars.len() && (is_ident_start(chars[i]) || chars[i].is_ascii_digit()) {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+            // `in` after a value is a conversion, but after a number it's inches
+            let converts = match name.as_str() {
+                "to" | "as" => true,
+                "in" => matches!(tokens.last(), Some(Token::Ident(_) | Token::Close)) && chars[i..].iter().any(|c| !c.is_whitespace()),
+                _ => false,
+            };
+            tokens.push(if converts { Token::Convert } else { Token::Ident(name) });
+            continue;
+        } else if (c == '-' && next == Some('>')) || c == '→' {
+            if c == '-' {
+                i += 1;
+            }
+            Token::Convert
+        } else if c == '*' && next == Some('*') {
+            i += 1;
+            Token::Op('^')
+        } else if c == '²' || c == '³' {
+            tokens.push(Token::Op('^'));
+            Token::Number(BigRational::from_integer(BigInt::from(if c == '²' { 2 } else { 3 })))
+        } else {
+            match c {
+                '+' | '-' | '*' | '/' | '^' | '%' | '!' => Token::Op(c),
+                '−' => Token::Op('-'),
+                '×' | '·' => Token::Op('*'),
+                '÷' => Token::Op('/'),
+                '(' | '[' => Token::Open,
+                ')' | ']' => Token::Close,
+                ',' => Token::Comma,
+                '"' | '\'' => Token::Ident(c.to_string()),
+                _ if CURRENCY_SYMBOLS.iter().any(|(symbol, _)| symbol.starts_with(c)) => Token::Ident(c.to_string()),
+                _ => bail!("Unexpected '{}' in the expression", c),
+            }
+        };
+        tokens.push(token);
+        i += 1;
+    }
+    if tokens.is_empty() {
+        bail!("There's nothing to calculate.");
+    }
+    Ok(tokens)
+}
+
+/// The text of a unit expression, for showing a result in it.
+fn label(tokens: &[Token]) -> String {
+    let mut label = String::new();
+    let mut previous_was_word = false;
+    for token in tokens {
+        let (text, is_word) = match token {
+            Token::Number(n) => (n.to_string(), true),
+            Token::Ident(name) => (name.clone(), true),
+            Token::Op(c) => (c.to_string(), false),
+            Token::Open => ("(".to_string(), false),
+            Token::Close => (")".to_string(), false),
+            Token::Comma => (", ".to_string(), false),
+            Token::Convert => (" to ".to_string(), false),
+        };
+        if is_word && previous_was_word {
+            label.push(' ');
+        }
+        label.push_str(&text);
+        previous_was_word = is_word;
+    }
+    label
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Quantity {
+    /// The value in base units.
+    value: BigRational,
+    dims: Dims,
+    exact: bool,
+}
+
+impl Quantity {
+    fn number(value: BigRational) -> Self {
+        Quantity { value, dims: NONE, exact: true }
+    }
+
+    fn approximate(value: f64) -> Result<Self> {
+        let value = BigRational::from_float(value).context("The result isn't a real number")?;
+        Ok(Quantity { value, dims: NONE, exact: false })
+    }
+
+    fn to_f64(&self) -> f64 {
+        self.value.to_f64().unwrap_or(f64::NAN)
+    }
+}
+
+/// A unit as it was written, like `km`, and its size in base units.
+#[derive(Debug, Clone)]
+struct Unit {
+    name: String,
+    factor: BigRational,
+    dims: Dims,
+    offset: BigRational,
+    exact: bool,
+}
+
+fn lookup_unit(name: &str) -> Option<Unit> {
+    let found = |def: &UnitDef, factor: BigRational| Unit {
+        name: name.to_string(),
+        factor: factor * parse_decimal(def.factor).expect("valid unit factor"),
+        dims: def.dims,
+        offset: parse_decimal(def.offset).expect("valid unit offset"),
+        exact: def.exact,
+    };
+    let exact_match = |name: &str| UNITS.iter().find(|def| def.names.contains(&name));
+    let candidates = [Some(name), name.strip_suffix('s').filter(|stem| stem.len() > 2)];
+    for name in candidates.into_iter().flatten() {
+        if let Some(def) = exact_match(name) {
+            return Some(found(def, BigRational::one()));
+        }
+        for (prefix, factor) in SI_PREFIXES.iter().chain(BINARY_PREFIXES) {
+            let Some(def) = name.strip_prefix(prefix).and_then(exact_match) else {
+                continue;
+            };
+            let binary = BINARY_PREFIXES.iter().any(|(binary_prefix, _)| binary_prefix == prefix);
+            let allowed = match def.prefixes {
+                Prefixes::None => false,
+                Prefixes::Si => !binary,
+                Prefixes::Binary => true,
+            };
+            if allowed {
+                return Some(found(def, parse_decimal(factor).expect("valid prefix")));
+            }
+        }
+    }
+    // Long names in any case, like Celsius or Miles
+    if name.chars().count() > 3 {
+        let lowercase = name.to_lowercase();
+        if lowercase != name {
+            return lookup_unit(&lowercase);
+        }
+    }
+    None
+}
+
+struct Evaluator<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    rates: Option<&'a Rates>,
+    /// Units in the order they were used.
+    units: Vec<Unit>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(tokens: &'a [Token], rates: Option<&'a Rates>) -> Self {
+        Evaluator { tokens, pos: 0, rates, units: Vec::new() }
+    }
+
+    fn run(&mut self) -> Result<Quantity> {
+        if self.tokens.is_empty() {
+            bail!("Something is missing around the conversion.");
+        }
+        let value = self.expression()?;
+        match self.tokens.get(self.pos) {
+            None => Ok(value),
+            Some(Token::Close) => bail!("There's a ')' without a '('."),
+            Some(token) => bail!("Unexpected {} in the expression", describe(token)),
+        }
+    }
+
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn eat_op(&mut self, op: char) -> bool {
+        let found = self.peek() == Some(&Token::Op(op));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expression(&mut self) -> Result<Quantity> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat_op('+') {
+                value = add(value, self.term()?, self.rates)?;
+            } else if self.eat_op('-') {
+                value = add(value, negate(self.term()?), self.rates)?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Quantity> {
+        let mut value = self.unary()?;
+        loop {
+            value = if self.eat_op('*') {
+                multiply(value, self.unary()?)?
+            } else if self.eat_op('/') {
+                divide(value, self.unary()?)?
+            } else if matches!(self.peek(), Some(Token::Ident(name)) if name == "mod") {
+                self.pos += 1;
+                modulo(value, self.unary()?, self.rates)?
+            } else {
+                return Ok(value);
+            };
+        }
+    }
+
+    fn unary(&mut self) -> Result<Quantity> {
+        let mut negative = false;
+        loop {
+            if self.eat_op('-') {
+                negative = !negative;
+            } else if !self.eat_op('+') {
+                break;
+            }
+        }
+        self.juxtaposed(negative)
+    }
+
+    /// Factors written side by side, like `5 kg` or `3 m s^-1`, which multiply. A number after
+    /// a quantity with units starts another one that's added, as in `5 ft 3 in`, and a number
+    /// followed by a temperature unit is a temperature on that scale. `negative` is the sign in
+    /// front, which belongs to the number when it's a temperature.
+    fn juxtaposed(&mut self, mut negative: bool) -> Result<Quantity> {
+        let mut value = self.power()?;
+        loop {
+            match self.peek() {
+                Some(Token::Ident(name)) if name == "mod" => break,
+                Some(Token::Number(_)) if value.dims != NONE && matches!(self.tokens.get(self.pos + 1), Some(Token::Ident(_))) => {
+                    let rest = self.juxtaposed(false)?;
+                    value = add(value, rest, self.rates)?;
+                    break;
+                }
+                Some(Token::Ident(name)) if value.dims == NONE && self.tokens.get(self.pos + 1) != Some(&Token::Op('^')) => {
+                    if let Some(unit) = lookup_unit(name).filter(|unit| !unit.offset.is_zero()) {
+                        self.pos += 1;
+                        if negative {
+                            value = negate(value);
+                            negative = false;
+                        }
+                        value = Quantity {
+                            value: (value.value + &unit.offset) * &unit.factor,
+                            dims: unit.dims,
+                            exact: value.exact && unit.exact,
+                        };
+                        self.units.push(unit);
+                        continue;
+                    }
+                }
+                Some(Token::Number(_) | Token::Ident(_) | Token::Open) => {}
+                _ => break,
+            }
+            value = multiply(value, self.power()?)?;
+        }
+        Ok(if negative { negate(value) } else { value })
+    }
+
+    fn power(&mut self) -> Result<Quantity> {
+        let base = self.postfix()?;
+        if !self.eat_op('^') {
+            return Ok(base);
+        }
+        // Right-associative, and the exponent may have a sign
+        let mut negative = false;
+        while let Some(Token::Op(sign @ ('+' | '-'))) = self.peek() {
+            negative ^= *sign == '-';
+            self.pos += 1;
+        }
+        let exponent = self.power()?;
+        pow(base, if negative { negate(exponent) } else { exponent })
+    }
+
+    fn postfix(&mut self) -> Result<Quantity> {
+        let mut value = self.primary()?;
+        loop {
+            if self.eat_op('%') {
+                value.value /= BigRational::from_integer(BigInt::from(100));
+            } else if self.eat_op('!') {
+                value = factorial(value)?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Quantity> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Quantity::number(value.clone())),
+            Some(Token::Open) => {
+                let value = self.expression()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(value),
+                    _ => bail!("A '(' is missing its ')'."),
+                }
+            }
+            Some(Token::Ident(name)) => self.identifier(name),
+            Some(token) => bail!("Unexpected {} in the expression", describe(token)),
+            None => bail!("The expression ends where a number should be."),
+        }
+    }
+
+    fn identifier(&mut self, name: &str) -> Result<Quantity> {
+        if FUNCTIONS.contains(&name) && self.peek() == Some(&Token::Open) {
+            self.pos += 1;
+            let mut arguments = vec![self.expression()?];
+            loop {
+                match self.next() {
+                    Some(Token::Comma) => arguments.push(self.expression()?),
+                    Some(Token::Close) => break,
+                    _ => bail!("{}( is missing its ')'.", name),
+                }
+            }
+            return call(name, arguments, self.rates);
+        }
+        match name {
+            "pi" | "π" => return Quantity::approximate(std::f64::consts::PI),
+            "tau" => return Quantity::approximate(std::f64::consts::TAU),
+            "e" => return Quantity::approximate(std::f64::consts::E),
+            _ => {}
+        }
+        let unit = match lookup_unit(name) {
+            Some(unit) => unit,
+            None => self.currency(name)?,
+        };
+        let quantity = Quantity { value: unit.factor.clone(), dims: unit.dims, exact: unit.exact };
+        self.units.push(unit);
+        Ok(quantity)
+    }
+
+    fn currency(&self, name: &str) -> Result<Unit> {
+        let code = match CURRENCY_SYMBOLS.iter().find(|(symbol, _)| *symbol == name) {
+            Some((_, code)) => code.to_string(),
+            None if name.len() == 3 && name.chars().all(|c| c.is_ascii_alphabetic()) => name.to_uppercase(),
+            None => bail!("Unknown unit or function '{}'", name),
+        };
+        let rates = self.rates.with_context(|| format!("Exchange rates aren't available, so '{}' can't be used", name))?;
+        let rate = rates.get(&code).with_context(|| format!("Unknown unit or currency '{}'", name))?;
+        Ok(Unit {
+            name: code,
+            factor: rate.recip(),
+            dims: base(MONEY),
+            offset: BigRational::zero(),
+            // Exchange rates are only a snapshot
+            exact: false,
+        })
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Number(n) => format!("number {}", n),
+        Token::Ident(name) => format!("'{}'", name),
+        Token::Op(c) => format!("'{}'", c),
+        Token::Open => "'('".to_string(),
+        Token::Close => "')'".to_string(),
+        Token::Comma => "','".to_string(),
+        Token::Convert => "conversion".to_string(),
+    }
+}
+
+/// The units of a quantity, like `m/s^2` or `N`.
+fn describe_dims(dims: &Dims, rates: Option<&Rates>) -> String {
+    if let Some((_, name)) = DERIVED_UNITS.iter().find(|(derived, _)| derived == dims) {
+        return name.to_string();
+    }
+    let base_name = |index: usize| match index {
+        MONEY => rates.map_or("money", |rates| rates.base.as_str()).to_string(),
+        _ => BASE_UNITS[index].to_string(),
+    };
+    let powers = |sign: i32| -> Vec<String> {
+        (0..dims.len())
+            .filter(|&i| dims[i].signum() == sign)
+            .map(|i| match dims[i].abs() {
+                1 => base_name(i),
+                power => format!("{}^{}", base_name(i), power),
+            })
+            .collect()
+    };
+    let (above, below) = (powers(1), powers(-1));
+    let above = if above.is_empty() { "1".to_string() } else { above.join("·") };
+    match below.len() {
+        0 if above == "1" => String::new(),
+        0 => above,
+        1 => format!("{}/{}", above, below[0]),
+        _ => format!("{}/({})", above, below.join("·")),
+    }
+}
+
+fn check_size(quantity: Quantity) -> Result<Quantity> {
+    if quantity.value.numer().bits() > MAX_BITS || quantity.value.denom().bits() > MAX_BITS {
+        bail!("The numbers got too big to work with.");
+    }
+    Ok(quantity)
+}
+
+fn negate(quantity: Quantity) -> Quantity {
+    Quantity { value: -quantity.value, ..quantity }
+}
+
+fn same_dims(a: &Quantity, b: &Quantity, action: &str, rates: Option<&Rates>) -> Result<()> {
+    if a.dims != b.dims {
+        let name = |dims: &Dims| match describe_dims(dims, rates) {
+            name if name.is_empty() => "a plain number".to_string(),
+            name => name,
+        };
+        bail!("Can't {} {} and {}.", action, name(&a.dims), name(&b.dims));
+    }
+    Ok(())
+}
+
+fn add(a: Quantity, b: Quantity, rates: Option<&Rates>) -> Result<Quantity> {
+    same_dims(&a, &b, "add", rates)?;
+    check_size(Quantity { value: a.value + b.value, dims: a.dims, exact: a.exact && b.exact })
+}
+
+fn multiply(a: Quantity, b: Quantity) -> Result<Quantity> {
+    let dims = std::array::from_fn(|i| a.dims[i] + b.dims[i]);
+    check_size(Quantity { value: a.value * b.value, dims, exact: a.exact && b.exact })
+}
+
+fn divide(a: Quantity, b: Quantity) -> Result<Quantity> {
+    if b.value.is_zero() {
+        bail!("Division by zero.");
+    }
+    let dims = std::array::from_fn(|i| a.dims[i] - b.dims[i]);
+    check_size(Quantity { value: a.value / b.value, dims, exact: a.exact && b.exact })
+}
+
+fn modulo(a: Quantity, b: Quantity, rates: Option<&Rates>) -> Result<Quantity> {
+    same_dims(&a, &b, "take the remainder of", rates)?;
+    if b.value.is_zero() {
+        bail!("Division by zero.");
+    }
+    let quotient = (&a.value / &b.value).floor();
+    check_size(Quantity { value: &a.value - &b.value * quotient, dims: a.dims, exact: a.exact && b.exact })
+}
+
+/// The `n`th root of `value`, if it's exactly a fraction.
+fn exact_root(value: &BigRational, n: u32) -> Option<BigRational> {
+    if value.is_negative() && n.is_multiple_of(2) {
+        return None;
+    }
+    let root = |x: &BigInt| Some(x.nth_root(n)).filter(|root| root.pow(n) == *x);
+    Some(BigRational::new(root(value.numer())?, root(value.denom())?))
+}
+
+fn pow(base: Quantity, exponent: Quantity) -> Result<Quantity> {
+    if exponent.dims != NONE {
+        bail!("Exponents can't have units.");
+    }
+    let (numerator, denominator) = (exponent.value.numer(), exponent.value.denom());
+    // Units can only be raised to powers that leave whole powers of them
+    let scale = |dims: &Dims| -> Option<Dims> {
+        let denominator = denominator.to_i32()?;
+        let numerator = numerator.to_i32()?;
+        dims.iter().all(|d| d * numerator % denominator == 0).then(|| std::array::from_fn(|i| dims[i] * numerator / denominator))
+    };
+    let dims = if base.dims == NONE { NONE } else { scale(&base.dims).context("That power leaves fractional units.")? };
+
+    if exponent.exact && exponent.value.is_integer() {
+        if base.value.is_zero() && exponent.value.is_negative() {
+            bail!("Division by zero.");
+        }
+        let bits = base.value.numer().bits().max(base.value.denom().bits());
+        let power = numerator.to_i32().filter(|power| bits <= 1 || bits.saturating_mul(power.unsigned_abs() as u64) <= MAX_BITS);
+        let power = power.context("The numbers got too big to work with.")?;
+        return check_size(Quantity { value: base.value.pow(power), dims, exact: base.exact });
+    }
+    // Roots of exact numbers may be exact too
+    if exponent.exact
+        && let (Some(root), Some(power)) = (denominator.to_u32(), numerator.to_i32())
+        && let Some(value) = exact_root(&base.value, root)
+    {
+        let bits = value.numer().bits().max(value.denom().bits());
+        if bits.saturating_mul(power.unsigned_abs() as u64) <= MAX_BITS && !(value.is_zero() && power < 0) {
+            return check_size(Quantity { value: value.pow(power), dims, exact: base.exact });
+        }
+    }
+    let (x, y) = (base.to_f64(), exponent.to_f64());
+    let value = match x < 0.0 && denominator.to_u32().is_some_and(|root| root % 2 == 1) {
+        // Odd roots of negative numbers are real
+        true if numerator.bit(0) => -(-x).powf(y),
+        true => (-x).powf(y),
+        false => x.powf(y),
+    };
+    Ok(Quantity { dims, ..Quantity::approximate(value)? })
+}
+
+fn factorial(value: Quantity) -> Result<Quantity> {
+    let n = match value.value.is_integer() && value.dims == NONE {
+        true => value.value.to_integer().to_u64().filter(|&n| n <= MAX_FACTORIAL),
+        false => None,
+    };
+    let n = n.with_context(|| format!("Factorials are for whole numbers from 0 to {}.", MAX_FACTORIAL))?;
+    let product = (1..=n).fold(BigInt::one(), |product, i| product * i);
+    Ok(Quantity { value: BigRational::from_integer(product), dims: NONE, exact: value.exact })
+}
+
+fn call(name: &str, mut arguments: Vec<Quantity>, rates: Option<&Rates>) -> Result<Quantity> {
+    let expected = match name {
+        "min" | "max" => arguments.len().max(1),
+        "round" | "log" => arguments.len().clamp(1, 2),
+        _ => 1,
+    };
+    if arguments.len() != expected {
+        bail!("{}() takes {} argument{}.", name, expected, if expected == 1 { "" } else { "s" });
+    }
+    let x = arguments.remove(0);
+    let plain = |x: &Quantity| -> Result<f64> {
+        if x.dims != NONE {
+            bail!("{}() needs a plain number, not {}.", name, describe_dims(&x.dims, rates));
+        }
+        Ok(x.to_f64())
+    };
+
+    match name {
+        "sqrt" => pow(x, Quantity::number(BigRational::new(1.into(), 2.into()))),
+        "cbrt" => pow(x, Quantity::number(BigRational::new(1.into(), 3.into()))),
+        "abs" => Ok(Quantity { value: x.value.abs(), ..x }),
+        "floor" | "ceil" | "round" => {
+            plain(&x)?;
+            let places = match arguments.pop() {
+                Some(places) => places.value.to_integer().to_i64().filter(|places| places.abs() <= 100),
+                None => Some(0),
+            };
+            let scale = pow10(places.context("round() rounds to between -100 and 100 decimal places.")?);
+            let scaled = &x.value * &scale;
+            let rounded = match name {
+                "floor" => scaled.floor(),
+                "ceil" => scaled.ceil(),
+                _ => scaled.round(),
+            };
+            Ok(Quantity { value: rounded / scale, ..x })
+        }
+        "min" | "max" => arguments.into_iter().try_fold(x, |best, other| {
+            same_dims(&best, &other, "compare", rates)?;
+            let other_wins = if name == "min" { other.value < best.value } else { other.value > best.value };
+            Ok(if other_wins { other } else { best })
+        }),
+        "log" if !arguments.is_empty() => {
+            let base = plain(&arguments[0])?;
+            Quantity::approximate(plain(&x)?.ln() / base.ln())
+        }
+        _ => {
+            let x = plain(&x)?;
+            let result = match name {
+                "exp" => x.exp(),
+                "ln" => x.ln(),
+                "log" | "log10" => x.log10(),
+                "log2" => x.log2(),
+                "sin" => x.sin(),
+                "cos" => x.cos(),
+                "tan" => x.tan(),
+                "asin" => x.asin(),
+                "acos" => x.acos(),
+                _ => x.atan(),
+            };
+            Quantity::approximate(result)
+        }
+    }
+}
+
+/// An exact `value` written out in full if it's an integer or decimal of reasonable length,
+/// and otherwise rounded to `digits` significant digits. Also says whether it's exact.
+pub(crate) fn format_number(value: &BigRational, exact: bool, digits: usize) -> (String, bool) {
+    if exact
+        && let Some(decimal) = exact_decimal(value)
+        && decimal.trim_start_matches('-').len() <= MAX_EXACT_DIGITS
+    {
+        return (decimal, true);
+    }
+    (significant_digits(value, digits), false)
+}
+
+/// `value` as a decimal, if it has a finite one.
+fn exact_decimal(value: &BigRational) -> Option<String> {
+    let mut denominator = value.denom().clone();
+    let (two, five) = (BigInt::from(2), BigInt::from(5));
+    let (mut twos, mut fives) = (0, 0);
+    while (&denominator % &two).is_zero() {
+        denominator /= &two;
+        twos += 1;
+    }
+    while (&denominator % &five).is_zero() {
+        denominator /= &five;
+        fives += 1;
+    }
+    if !denominator.is_one() {
+        return None;
+    }
+    let places: i64 = twos.max(fives);
+    let digits = (value.abs() * pow10(places)).to_integer().to_string();
+    Some(format!("{}{}", if value.is_negative() { "-" } else { "" }, place_point(&digits, digits.len() as i64 - places)))
+}
+
+/// `digits` with a decimal point after the first `whole` of them, padded with zeros as needed,
+/// and without trailing zeros after the point.
+fn place_point(digits: &str, whole: i64) -> String {
+    let text = if whole <= 0 {
+        format!("0.{}{}", "0".repeat(whole.unsigned_abs() as usize), digits)
+    } else if whole as usize >= digits.len() {
+        return format!("{}{}", digits, "0".repeat(whole as usize - digits.len()));
+    } else {
+        format!("{}.{}", &digits[..whole as usize], &digits[whole as usize..])
+    };
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+fn significant_digits(value: &BigRational, digits: usize) -> String {
+    if value.is_zero() {
+        return "0".to_string();
+    }
+    let magnitude = value.abs();
+    // The power of ten of the first digit, estimated from the lengths and then corrected
+    let mut exponent = magnitude.numer().to_string().len() as i64 - magnitude.denom().to_string().len() as i64;
+    while magnitude >= pow10(exponent + 1) {
+        exponent += 1;
+    }
+    while magnitude < pow10(exponent) {
+        exponent -= 1;
+    }
+    let mut mantissa = (&magnitude * pow10(digits as i64 - 1 - exponent)).round().to_integer().to_string();
+    // Rounding up can carry into a new digit, as 9.99 to 10.0
+    if mantissa.len() > digits {
+        mantissa.pop();
+        exponent += 1;
+    }
+    let sign = if value.is_negative() { "-" } else { "" };
+    if (-6..digits as i64).contains(&exponent) {
+        return format!("{}{}", sign, place_point(&mantissa, exponent + 1));
+    }
+    let fraction = mantissa[1..].trim_end_matches('0');
+    let point = if fraction.is_empty() { "" } else { "." };
+    format!("{}{}{}{}e{}", sign, &mantissa[..1], point, fraction, exponent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn calc(expression: &str) -> String {
+        evaluate(expression, None, DEFAULT_PRECISION).unwrap_or_else(|e| panic!("{:?} failed: {:#}", expression, e))
+    }
+
+    fn error(expression: &str) -> String {
+        format!("{:#}", evaluate(expression, None, DEFAULT_PRECISION).expect_err(expression))
+    }
+
+    fn rates() -> Rates {
+        let rates = [("USD", "1.25"), ("GBP", "0.8"), ("JPY", "160")];
+        Rates::new("EUR", None, rates.into_iter().map(|(code, rate)| (code.to_string(), parse_decimal(rate).unwrap())).collect::<HashMap<_, _>>())
+    }
+
+    #[test]
+    fn test_exact_arithmetic() {
+        assert_eq!(calc("1 + 2 * 3"), "1 + 2 * 3 = 7");
+        assert_eq!(calc("(1 + 2) * 3"), "(1 + 2) * 3 = 9");
+        assert_eq!(calc("0.1 + 0.2"), "0.1 + 0.2 = 0.3");
+        assert_eq!(calc("2^100"), "2^100 = 1267650600228229401496703205376");
+        assert_eq!(calc("2^3^2"), "2^3^2 = 512");
+        assert_eq!(calc("-2^2"), "-2^2 = -4");
+        assert_eq!(calc("2^-2"), "2^-2 = 0.25");
+        assert_eq!(calc("7 / 8"), "7 / 8 = 0.875");
+        assert_eq!(calc("1/3"), "1/3 ≈ 0.33333333333333333333");
+        assert_eq!(calc("2/3 * 3"), "2/3 * 3 = 2");
+        assert_eq!(calc("10 mod 3"), "10 mod 3 = 1");
+        assert_eq!(calc("-7 mod 3"), "-7 mod 3 = 2");
+        assert_eq!(calc("20!"), "20! = 2432902008176640000");
+        assert_eq!(calc("15% * 80"), "15% * 80 = 12");
+        assert_eq!(calc("1e3 + 2.5E-1"), "1e3 + 2.5E-1 = 1000.25");
+        assert_eq!(calc("1_000_000 × 3 ÷ 2"), "1_000_000 × 3 ÷ 2 = 1500000");
+        assert_eq!(calc("3²"), "3² = 9");
+        assert_eq!(calc("2 ** 10"), "2 ** 10 = 1024");
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(calc("sqrt(16/9)"), "sqrt(16/9) ≈ 1.3333333333333333333");
+        assert_eq!(calc("sqrt(2.25)"), "sqrt(2.25) = 1.5");
+        assert_eq!(calc("cbrt(-27)"), "cbrt(-27) = -3");
+        assert_eq!(calc("sqrt(2)"), "sqrt(2) ≈ 1.4142135623731");
+        assert_eq!(calc("round(2.345, 2)"), "round(2.345, 2) = 2.35");
+        assert_eq!(calc("floor(-1.5) + ceil(1.2)"), "floor(-1.5) + ceil(1.2) = 0");
+        assert_eq!(calc("max(3, 9, 4) - min(2, 5)"), "max(3, 9, 4) - min(2, 5) = 7");
+        assert_eq!(calc("abs(-4.5)"), "abs(-4.5) = 4.5");
+        assert_eq!(calc("sin(30 deg)"), "sin(30 deg) ≈ 0.5");
+        assert_eq!(calc("log(1000)"), "log(1000) ≈ 3");
+        assert_eq!(calc("log(8, 2)"), "log(8, 2) ≈ 3");
+        assert_eq!(calc("2 pi"), "2 pi ≈ 6.28318530717959");
+        assert_eq!(calc("(-8)^(1/3)"), "(-8)^(1/3) = -2");
+    }
+
+    #[test]
+    fn test_units() {
+        assert_eq!(calc("5 ft to m"), "5 ft to m = 1.524 m");
+        assert_eq!(calc("5 ft 11 in to cm"), "5 ft 11 in to cm = 180.34 cm");
+        assert_eq!(calc("12 in in cm"), "12 in in cm = 30.48 cm");
+        assert_eq!(calc("60 mph to km/h"), "60 mph to km/h = 96.56064 km/h");
+        assert_eq!(calc("100 km/h to mph"), "100 km/h to mph ≈ 62.137119223733396962 mph");
+        assert_eq!(calc("1 GiB to MB"), "1 GiB to MB = 1073.741824 MB");
+        assert_eq!(calc("2 kilometers + 500 m"), "2 kilometers + 500 m = 2.5 kilometers");
+        assert_eq!(calc("3 kg * 2 m/s^2"), "3 kg * 2 m/s^2 = 6 N");
+        assert_eq!(calc("10 m / 4 s"), "10 m / 4 s = 2.5 m/s");
+        assert_eq!(calc("1 acre -> m^2"), "1 acre -> m^2 = 4046.8564224 m^2");
+        assert_eq!(calc("1 kWh to J"), "1 kWh to J = 3600000 J");
+        assert_eq!(calc("90 minutes to h"), "90 minutes to h = 1.5 h");
+        assert_eq!(calc("sqrt(9 m^2)"), "sqrt(9 m^2) = 3 m");
+    }
+
+    #[test]
+    fn test_temperatures() {
+        assert_eq!(calc("100 C to F"), "100 C to F = 212 F");
+        assert_eq!(calc("-40 °F to °C"), "-40 °F to °C = -40 °C");
+        assert_eq!(calc("0 K to celsius"), "0 K to celsius = -273.15 celsius");
+        assert_eq!(calc("20 C"), "20 C = 20 C");
+    }
+
+    #[test]
+    fn test_currencies() {
+        let rates = rates();
+        let convert = |expression| evaluate(expression, Some(&rates), DEFAULT_PRECISION).unwrap();
+        assert_eq!(convert("100 USD to EUR"), "100 USD to EUR ≈ 80 EUR");
+        assert_eq!(convert("$10 + 8 GBP"), "$10 + 8 GBP ≈ 22.5 USD");
+        assert_eq!(convert("1000 jpy in gbp"), "1000 jpy in gbp ≈ 5 gbp");
+        assert!(evaluate("5 XYZ", Some(&rates), DEFAULT_PRECISION).unwrap_err().to_string().contains("Unknown unit or currency 'XYZ'"));
+        assert!(error("5 USD").contains("Exchange rates aren't available"));
+        assert!(mentions_currency("5 usd to eur"));
+        assert!(mentions_currency("€20"));
+        assert!(!mentions_currency("5 min to s"));
+        assert!(!mentions_currency("sin(1) mod 2"));
+    }
+
+    #[test]
+    fn test_precision() {
+        assert_eq!(evaluate("1/7", None, 5).unwrap(), "1/7 ≈ 0.14286");
+        assert_eq!(evaluate("2/3", None, 50).unwrap(), format!("2/3 ≈ 0.{}7", "6".repeat(49)));
+        assert_eq!(evaluate("sqrt(2)", None, 50).unwrap(), "sqrt(2) ≈ 1.4142135623731");
+        assert_eq!(calc("10^30 / 3"), "10^30 / 3 ≈ 3.3333333333333333333e29");
+        assert_eq!(calc("1 / 3e7"), "1 / 3e7 ≈ 3.3333333333333333333e-8");
+        assert_eq!(calc("2/3 * 1e-5"), "2/3 * 1e-5 ≈ 0.0000066666666666666666667");
+        assert_eq!(calc("2/3 * 1e-6"), "2/3 * 1e-6 ≈ 6.6666666666666666667e-7");
+        assert_eq!(significant_digits(&parse_decimal("0.99999").unwrap(), 3), "1");
+    }
+
+    #[test]
+    fn test_parse_decimal() {
+        assert_eq!(parse_decimal("12.50"), Some(BigRational::new(25.into(), 2.into())));
+        assert_eq!(parse_decimal("-3e2"), Some(BigRational::from_integer((-300).into())));
+        assert_eq!(parse_decimal(".5"), Some(BigRational::new(1.into(), 2.into())));
+        assert_eq!(parse_decimal("5/9"), Some(BigRational::new(5.into(), 9.into())));
+        assert_eq!(parse_decimal("1.2.3"), None);
+        assert_eq!(parse_decimal("1e999999"), None);
+        assert_eq!(parse_decimal("1/0"), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(error("").contains("nothing to calculate"));
+        assert!(error("1 +").contains("ends where a number should be"));
+        assert!(error("(1 + 2").contains("missing its ')'"));
+        assert!(error("1 + 2)").contains("')' without a '('"));
+        assert!(error("1 / 0").contains("Division by zero"));
+        assert!(error("5 m + 2 s").contains("Can't add m and s"));
+        assert!(error("5 m + 2").contains("Can't add m and a plain number"));
+        assert!(error("5 kg to m").contains("Can't convert kg to m"));
+        assert!(error("5 wombats").contains("Unknown unit or function 'wombats'"));
+        assert!(error("9^9^9").contains("too big"));
+        assert!(error("10000!").contains("Factorials are for whole numbers"));
+        assert!(error("sqrt(2 m)").contains("fractional units"));
+        assert!(error("sin(2 m)").contains("needs a plain number"));
+        assert!(error("1 to m to ft").contains("Only one conversion"));
+        assert!(error("5 m to").contains("Something is missing"));
+        assert!(error("1 @ 2").contains("Unexpected '@'"));
+        assert!(error(&"1+".repeat(300)).contains("too long"));
+    }
+}
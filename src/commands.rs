@@ -0,0 +1,366 @@
+//! Commands typed to the bot, such as `!join #channel`.
+//!
+//! Each command is declared once in a `CommandRegistry`, with its arguments, a line of help and
+//! the permission level it needs. The registry parses messages, checks the sender's level and
+//! generates `!help`, so adding a command doesn't mean touching the dispatch code. What the
+//! handlers get to work with (`C`) is up to whoever owns the registry.
+
+use anyhow::{Result, bail};
+use futures::future::BoxFuture;
+use std::fmt;
+use std::str::FromStr;
+
+/// How far the bot trusts a user. Each level may run the commands of the levels below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    /// Anyone who messages the bot.
+    Anyone,
+    /// Keeps the channels tidy: ignores users, looks at stats.
+    Moderator,
+    /// Runs the bot: channels, settings, schedules and feeds.
+    Admin,
+    /// Decides who else is trusted.
+    Owner,
+}
+
+impl Permission {
+    /// The levels that can be granted, lowest first.
+    pub const GRANTABLE: &[Permission] = &[Permission::Moderator, Permission::Admin, Permission::Owner];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::Anyone => "anyone",
+            Permission::Moderator => "moderator",
+            Permission::Admin => "admin",
+            Permission::Owner => "owner",
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Parses a grantable level; everyone is "anyone" already.
+impl FromStr for Permission {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match Permission::GRANTABLE.iter().find(|level| level.as_str().eq_ignore_ascii_case(s)) {
+            Some(level) => Ok(*level),
+            None => bail!("Unknown level \"{}\"; try one of moderator, admin, owner", s),
+        }
+    }
+}
+
+/// The words after a command's name.
+#[derive(Debug, Clone)]
+pub struct Args<'a> {
+    text: &'a str,
+    words: Vec<&'a str>,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(text: &'a str) -> Self {
+        Args { text, words: text.split_whitespace().collect() }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&'a str> {
+        self.words.get(index).copied()
+    }
+
+    /// An argument the command declares as required, which the registry has checked is there.
+    pub fn arg(&self, index: usize) -> &'a str {
+        self.get(index).unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    /// Required argument `index` as a channel name, with the `#` added if it was left off.
+    pub fn channel(&self, index: usize) -> String {
+        match self.arg(index) {
+            channel if channel.starts_with('#') => channel.to_string(),
+            channel => format!("#{}", channel),
+        }
+    }
+
+    /// Everything from argument `index` on, as typed.
+    pub fn rest(&self, index: usize) -> &'a str {
+        skip_words(self.text, index)
+    }
+}
+
+/// `text` without its first `count` words and the whitespace around them.
+fn skip_words(text: &str, count: usize) -> &str {
+    let mut rest = text.trim_start();
+    for _ in 0..count {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest.trim_end()
+}
+
+/// One run of a command.
+pub struct Invocation<'a> {
+    /// Who sent it.
+    pub nick: &'a str,
+    /// The sender's level.
+    pub permission: Permission,
+    pub args: Args<'a>,
+}
+
+pub type Handler<C> = for<'a> fn(&'a C, Invocation<'a>) -> BoxFuture<'a, Result<()>>;
+
+pub struct Command<C> {
+    /// The command's name without the prefix, subcommand included: `join`, `schedule add`.
+    pub name: &'static str,
+    /// The arguments, for usage messages: `<#channel>`, `<#channel> [<key> <value>]`. Those
+    /// before the first optional (`[...]`) one are required.
+    pub args: &'static str,
+    pub permission: Permission,
+    /// One line for `!help <command>`.
+    pub help: &'static str,
+    pub handler: Handler<C>,
+}
+
+impl<C> Command<C> {
+    pub fn new(
+        name: &'static str,
+        args: &'static str,
+        permission: Permission,
+        help: &'static str,
+        handler: Handler<C>,
+    ) -> Self {
+        Command { name, args, permission, help, handler }
+    }
+
+    /// `!join <#channel>`, with the given prefix.
+    pub fn usage(&self, prefix: &str) -> String {
+        format!("{}{} {}", prefix, self.name, self.args).trim_end().to_string()
+    }
+
+    fn required_args(&self) -> usize {
+        self.args.split_whitespace().take_while(|arg| !arg.starts_with('[')).count()
+    }
+
+    fn word_count(&self) -> usize {
+        self.name.split_whitespace().count()
+    }
+
+    /// Whether `words` (the message, prefix stripped from the first) start with this command's name.
+    fn matches(&self, words: &[&str]) -> bool {
+        let name: Vec<&str> = self.name.split_whitespace().collect();
+        name.len() <= words.len() && name.iter().zip(words).all(|(n, w)| n.eq_ignore_ascii_case(w))
+    }
+}
+
+/// What a message asks the registry for.
+pub enum Dispatch<'a, C> {
+    /// Run the command with these arguments.
+    Run(&'a Command<C>, Args<'a>),
+    /// Too few arguments, or a missing or unknown subcommand: the usage to show.
+    Usage(String),
+    /// A command above the sender's level.
+    Denied(&'a Command<C>),
+    /// Not a command at all.
+    Unknown,
+}
+
+pub struct CommandRegistry<C> {
+    commands: Vec<Command<C>>,
+}
+
+impl<C> CommandRegistry<C> {
+    pub fn new(commands: Vec<Command<C>>) -> Self {
+        CommandRegistry { commands }
+    }
+
+    /// Works out which command `message` runs, for a sender at `permission`. Commands start with
+    /// `prefix`; the longest matching name wins, so subcommands can be declared on their own.
+    pub fn dispatch<'a>(&'a self, prefix: &str, message: &'a str, permission: Permission) -> Dispatch<'a, C> {
+        let Some(message) = message.trim_start().strip_prefix(prefix) else {
+            return Dispatch::Unknown;
+        };
+        let words: Vec<&str> = message.split_whitespace().collect();
+        let Some(&first) = words.first() else {
+            return Dispatch::Unknown;
+        };
+        let Some(command) = self.commands.iter().filter(|c| c.matches(&words)).max_by_key(|c| c.word_count()) else {
+            // A known command with a missing or unknown subcommand
+            let group: Vec<&Command<C>> = self
+                .commands
+                .iter()
+                .filter(|c| c.name.split_whitespace().next().is_some_and(|name| name.eq_ignore_ascii_case(first)))
+                .collect();
+            let usages: Vec<String> =
+                group.iter().filter(|c| c.permission <= permission).map(|c| c.usage(prefix)).collect();
+            return match group.first() {
+                None => Dispatch::Unknown,
+                Some(&command) if usages.is_empty() => Dispatch::Denied(command),
+                Some(_) => Dispatch::Usage(usages.join(" | ")),
+            };
+        };
+        if command.permission > permission {
+            return Dispatch::Denied(command);
+        }
+        let args = Args::new(skip_words(message, command.word_count()));
+        if args.len() < command.required_args() {
+            return Dispatch::Usage(command.usage(prefix));
+        }
+        Dispatch::Run(command, args)
+    }
+
+    /// The usage of every command a sender at `permission` may run, for `!help`. Commands can be
+    /// left out by name with `enabled`.
+    pub fn help(&self, prefix: &str, permission: Permission, enabled: impl Fn(&str) -> bool) -> String {
+        self.commands
+            .iter()
+            .filter(|command| command.permission <= permission && enabled(command.name))
+            .map(|command| command.usage(prefix))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Usage and help for the commands named `name` (or, for a command with subcommands, all of
+    /// them) that a sender at `permission` may run. Empty if there are none.
+    pub fn describe(
+        &self,
+        prefix: &str,
+        name: &str,
+        permission: Permission,
+        enabled: impl Fn(&str) -> bool,
+    ) -> Vec<String> {
+        let name = name.strip_prefix(prefix).unwrap_or(name);
+        let words: Vec<&str> = name.split_whitespace().collect();
+        self.commands
+            .iter()
+            .filter(|command| command.permission <= permission && enabled(command.name))
+            .filter(|command| {
+                let command_words: Vec<&str> = command.name.split_whitespace().collect();
+                !words.is_empty()
+                    && words.len() <= command_words.len()
+                    && words.iter().zip(&command_words).all(|(w, c)| w.eq_ignore_ascii_case(c))
+            })
+            .map(|command| {
+                let level = match command.permission {
+                    Permission::Anyone => String::new(),
+                    level => format!(" ({} and up)", level),
+                };
+                format!("{}: {}{}", command.usage(prefix), command.help, level)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noop<'a>(_: &'a (), _: Invocation<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn registry() -> CommandRegistry<()> {
+        CommandRegistry::new(vec![
+            Command::new("optout", "", Permission::Anyone, "Stops logging you", noop),
+            Command::new("ignore", "<nick>", Permission::Moderator, "Ignores a user", noop),
+            Command::new("set", "<#channel> [<key> <value>]", Permission::Admin, "Changes a setting", noop),
+            Command::new("feed add", "<#channel> <url> [summarize]", Permission::Admin, "Subscribes to a feed", noop),
+            Command::new("feed list", "", Permission::Admin, "Lists feeds", noop),
+            Command::new("grant", "<nick> <level>", Permission::Owner, "Sets a user's level", noop),
+        ])
+    }
+
+    fn run_name<'a>(dispatch: Dispatch<'a, ()>) -> Option<(&'static str, Vec<&'a str>)> {
+        match dispatch {
+            Dispatch::Run(command, args) => Some((command.name, (0..args.len()).filter_map(|i| args.get(i)).collect())),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_dispatch_runs_commands() {
+        let registry = registry();
+        assert_eq!(run_name(registry.dispatch("!", "!OPTOUT", Permission::Anyone)), Some(("optout", vec![])));
+        assert_eq!(
+            run_name(registry.dispatch("!", "!set #chan ai off", Permission::Owner)),
+            Some(("set", vec!["#chan", "ai", "off"]))
+        );
+        assert_eq!(
+            run_name(registry.dispatch("!", "!feed add #chan https://example.com/feed", Permission::Admin)),
+            Some(("feed add", vec!["#chan", "https://example.com/feed"]))
+        );
+        assert!(matches!(registry.dispatch("!", "hello there", Permission::Owner), Dispatch::Unknown));
+        assert!(matches!(registry.dispatch("!", "!dance", Permission::Owner), Dispatch::Unknown));
+    }
+
+    #[test]
+    fn test_dispatch_checks_permissions() {
+        let registry = registry();
+        assert!(matches!(registry.dispatch("!", "!ignore bob", Permission::Anyone), Dispatch::Denied(c) if c.name == "ignore"));
+        assert!(matches!(registry.dispatch("!", "!ignore bob", Permission::Moderator), Dispatch::Run(..)));
+        assert!(matches!(registry.dispatch("!", "!grant bob admin", Permission::Admin), Dispatch::Denied(_)));
+        // Permission comes before usage, so a missing argument doesn't reveal the command
+        assert!(matches!(registry.dispatch("!", "!feed", Permission::Moderator), Dispatch::Denied(_)));
+    }
+
+    #[test]
+    fn test_dispatch_reports_usage() {
+        let registry = registry();
+        assert!(matches!(registry.dispatch("!", "!ignore", Permission::Owner), Dispatch::Usage(u) if u == "!ignore <nick>"));
+        assert!(matches!(
+            registry.dispatch("!", "!feed del 3", Permission::Admin),
+            Dispatch::Usage(u) if u == "!feed add <#channel> <url> [summarize] | !feed list"
+        ));
+        assert!(matches!(registry.dispatch("!", "!set #chan", Permission::Admin), Dispatch::Run(..)));
+    }
+
+    #[test]
+    fn test_dispatch_with_another_prefix() {
+        let registry = registry();
+        assert!(matches!(registry.dispatch(".", ".optout", Permission::Anyone), Dispatch::Run(..)));
+        assert!(matches!(registry.dispatch(".", "!optout", Permission::Anyone), Dispatch::Unknown));
+    }
+
+    #[test]
+    fn test_help_lists_permitted_commands() {
+        let registry = registry();
+        let all = |_: &str| true;
+        assert_eq!(registry.help("!", Permission::Moderator, all), "!optout, !ignore <nick>");
+        assert_eq!(registry.help("!", Permission::Moderator, |name| name != "optout"), "!ignore <nick>");
+        assert_eq!(
+            registry.describe("!", "!feed", Permission::Owner, all),
+            vec![
+                "!feed add <#channel> <url> [summarize]: Subscribes to a feed (admin and up)",
+                "!feed list: Lists feeds (admin and up)",
+            ]
+        );
+        assert_eq!(registry.describe("!", "optout", Permission::Anyone, all), vec!["!optout: Stops logging you"]);
+        assert!(registry.describe("!", "grant", Permission::Admin, all).is_empty());
+        assert!(registry.describe("!", "feed list", Permission::Owner, |name| !name.starts_with("feed")).is_empty());
+    }
+
+    #[test]
+    fn test_args() {
+        let args = Args::new("  \"0 20 * * FRI\"  anime   Anime night!  ");
+        assert_eq!(args.len(), 8);
+        assert_eq!(args.channel(5), "#anime");
+        assert_eq!(args.channel(1), "#20");
+        assert_eq!(args.rest(0), "\"0 20 * * FRI\"  anime   Anime night!");
+        assert_eq!(args.rest(5), "anime   Anime night!");
+        assert_eq!(args.rest(8), "");
+        assert_eq!(args.get(8), None);
+        assert_eq!(args.arg(8), "");
+    }
+
+    #[test]
+    fn test_parse_permission() {
+        assert_eq!("Admin".parse::<Permission>().unwrap(), Permission::Admin);
+        assert!("anyone".parse::<Permission>().is_err());
+        assert!(Permission::Owner > Permission::Admin && Permission::Moderator > Permission::Anyone);
+    }
+}
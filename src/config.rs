@@ -1,18 +1,263 @@
-use anyhow::Result;
-use clap::Parser;
+use crate::proxy;
+use crate::transport;
+use anyhow::{Context, Result, bail};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use serde::Deserialize;
+use std::ffi::OsString;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 pub const PROMPT_FILE_PATH: &str = "vorpal_bunny_prompt.txt";
-pub const LOG_HISTORY_LINES: usize = 500;
+/// Settings read only at startup; `!reload` reports changes to them but they need a restart.
+pub const RESTART_REQUIRED_SETTINGS: &[&str] = &[
+    "config", "transports", "server", "port", "nickname", "admin", "nickserv_password", "services",
+    "services_account", "use_tls", "irc_burst_lines", "irc_line_interval_ms", "discord_token", "db", "torrent_client", "torrent_rpc_url",
+    "torrent_rpc_username", "torrent_rpc_password", "image_cache_dir", "image_cache_ttl_hours", "proxy", "irc_proxy",
+    "irc_watchdog_mins", "health_addr", "log_dir", "log_rotation", "log_max_files", "log_levels",
+    "otlp_endpoint", "networks",
+];
+/// Most history lines fetched for a prompt; the context token budget usually trims them further.
+pub const LOG_HISTORY_LINES: usize = 2000;
 pub const RANDOM_INTERJECT_CHANCE: f64 = 0.005;
 pub const RANDOM_INTERJECT_CHANCE_IF_MENTIONED: f64 = 0.2;
+/// Conversations random interjections stay out of, unless a channel says otherwise.
+pub const DEFAULT_AVOIDED_TOPICS: &[&str] = &["sensitive or personal matters", "technical support"];
+pub const DEFAULT_MAX_FUNCTION_CALL_TURNS: usize = 5;
+pub const DEFAULT_MAX_TOOL_CALLS_PER_TURN: usize = 5;
+pub const DEFAULT_MAX_IMAGES_PER_TURN: usize = 4;
+pub const DEFAULT_MAX_PAGE_BYTES: usize = 5 * 1024 * 1024;
+pub const DEFAULT_TOOL_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_CURRENCY_RATES_URL: &str = "https://api.frankfurter.dev/v1/latest";
+pub const DEFAULT_CRYPTO_PRICES_URL: &str = "https://api.coingecko.com/api/v3/simple/price";
+pub const DEFAULT_MAX_RESPONSE_LENGTH: usize = 3000;
+pub const DEFAULT_MAX_REPLY_LINES: usize = 4;
+pub const DEFAULT_PASTE_MIN_LINES: usize = 3;
+pub const DEFAULT_NSFW_THRESHOLD: f64 = 0.7;
+pub const DEFAULT_MEMORY_TOP_K: usize = 3;
+pub const DEFAULT_CONTEXT_TOKEN_BUDGET: usize = 32_000;
+pub const DEFAULT_USER_RATE_LIMIT: usize = 5;
+pub const DEFAULT_CHANNEL_RATE_LIMIT: usize = 60;
+pub const DEFAULT_MAX_BOT_EXCHANGES: u32 = 10;
+pub const DEFAULT_COMMAND_PREFIX: &str = "!";
+pub const DEFAULT_NOTIFY_AWAY_MINS: u64 = 30;
+pub const DEFAULT_EXPORT_DIR: &str = "exports";
+pub const DEFAULT_IMAGE_CACHE_DIR: &str = "image_cache";
+pub const DEFAULT_IMAGE_CACHE_TTL_HOURS: u64 = 24 * 7;
+pub const DEFAULT_IRC_BURST_LINES: u32 = 5;
+pub const DEFAULT_IRC_LINE_INTERVAL_MS: u64 = 1500;
+pub const DEFAULT_IRC_WATCHDOG_MINS: u64 = 5;
+pub const DEFAULT_IRC_BACKFILL_LINES: usize = 100;
+pub const DEFAULT_LOG_MAX_FILES: usize = 14;
+
+/// Which LLM API the bot talks to.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LlmBackendKind {
+    /// Google Gemini (needs GEMINI_API_KEY)
+    Gemini,
+    /// Any OpenAI-compatible chat completions endpoint, e.g. llama.cpp (OPENAI_API_KEY optional)
+    Openai,
+    /// Anthropic Messages API (needs ANTHROPIC_API_KEY)
+    Anthropic,
+}
+
+/// Chat networks the bot can connect to.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    /// IRC (needs --server)
+    Irc,
+    /// Discord (needs DISCORD_TOKEN)
+    Discord,
+}
+
+/// Torrent clients the download_torrent tool can hand magnet links to.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TorrentClientKind {
+    /// Transmission RPC (URL like http://host:9091/transmission/rpc)
+    Transmission,
+    /// qBittorrent Web API (URL like http://host:8080)
+    Qbittorrent,
+}
+
+/// IRC services the bot logs in to, which differ between networks.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ServicesKind {
+    /// NickServ, as on Libera.Chat, OFTC and most networks
+    Nickserv,
+    /// QuakeNet's Q
+    Q,
+    /// Undernet's X
+    X,
+    /// No services; channels are joined straight away
+    None,
+}
+
+/// How often the JSON log file is started afresh.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    /// One file that keeps growing
+    Never,
+}
+
+/// A Gemini safety threshold for one harm category, written `category=threshold`, e.g.
+/// `harassment=block_only_high`. Both halves are case-insensitive, and the `HARM_CATEGORY_`
+/// prefix is optional.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct SafetySetting {
+    /// Full Gemini name, e.g. HARM_CATEGORY_HARASSMENT.
+    pub category: String,
+    /// Gemini threshold, e.g. BLOCK_ONLY_HIGH.
+    pub threshold: String,
+}
+
+const SAFETY_CATEGORIES: &[&str] = &[
+    "HARM_CATEGORY_HARASSMENT",
+    "HARM_CATEGORY_HATE_SPEECH",
+    "HARM_CATEGORY_SEXUALLY_EXPLICIT",
+    "HARM_CATEGORY_DANGEROUS_CONTENT",
+    "HARM_CATEGORY_CIVIC_INTEGRITY",
+];
+const SAFETY_THRESHOLDS: &[&str] =
+    &["BLOCK_NONE", "BLOCK_ONLY_HIGH", "BLOCK_MEDIUM_AND_ABOVE", "BLOCK_LOW_AND_ABOVE", "OFF"];
+
+impl FromStr for SafetySetting {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let Some((category, threshold)) = text.split_once('=') else {
+            bail!("Expected category=threshold, not \"{}\"", text);
+        };
+        let category = category.trim().to_uppercase();
+        let category = match category.starts_with("HARM_CATEGORY_") {
+            true => category,
+            false => format!("HARM_CATEGORY_{}", category),
+        };
+        let threshold = threshold.trim().to_uppercase();
+        if !SAFETY_CATEGORIES.contains(&category.as_str()) {
+            bail!("Unknown harm category {}; expected one of {}", category, SAFETY_CATEGORIES.join(", "));
+        }
+        if !SAFETY_THRESHOLDS.contains(&threshold.as_str()) {
+            bail!("Unknown safety threshold {}; expected one of {}", threshold, SAFETY_THRESHOLDS.join(", "));
+        }
+        Ok(SafetySetting { category, threshold })
+    }
+}
+
+impl TryFrom<String> for SafetySetting {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> Result<Self> {
+        text.parse()
+    }
+}
+
+/// What it takes for the AI to use a tool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ToolPolicy {
+    /// Runs whenever the AI calls it.
+    #[default]
+    Auto,
+    /// Runs only when the AI is answering an admin; anyone else's calls are refused.
+    AdminOnly,
+    /// Runs when the AI is answering an admin; anyone else's calls wait for an admin to `!approve` them.
+    Confirm,
+}
+
+/// A tool's policy, written `tool=policy`, e.g. `download_torrent=confirm`. The policy is one of
+/// `auto`, `admin` and `confirm`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct ToolPolicySetting {
+    pub tool: String,
+    pub policy: ToolPolicy,
+}
+
+impl FromStr for ToolPolicySetting {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let Some((tool, policy)) = text.split_once('=') else {
+            bail!("Expected tool=policy, not \"{}\"", text);
+        };
+        let policy = match policy.trim().to_lowercase().as_str() {
+            "auto" => ToolPolicy::Auto,
+            "admin" => ToolPolicy::AdminOnly,
+            "confirm" => ToolPolicy::Confirm,
+            other => bail!("Unknown tool policy {}; expected one of auto, admin, confirm", other),
+        };
+        Ok(ToolPolicySetting { tool: tool.trim().to_string(), policy })
+    }
+}
+
+impl TryFrom<String> for ToolPolicySetting {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> Result<Self> {
+        text.parse()
+    }
+}
+
+/// Two channels bridged by the bot, written `first=second`, e.g. `#emul=discord:123456789`. Each
+/// is an IRC channel or a Discord channel.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct RelayPair {
+    pub first: String,
+    pub second: String,
+}
+
+impl FromStr for RelayPair {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let Some((first, second)) = text.split_once('=') else {
+            bail!("Expected first=second, like #emul=discord:123456789, not \"{}\"", text);
+        };
+        let (first, second) = (first.trim(), second.trim());
+        for channel in [first, second] {
+            if !channel.starts_with('#') && !transport::is_discord_channel(channel) {
+                bail!("Can't relay {}; expected an IRC channel like #emul or a Discord one like discord:123456789", channel);
+            }
+        }
+        if first.eq_ignore_ascii_case(second) {
+            bail!("Can't relay {} to itself", first);
+        }
+        Ok(RelayPair { first: first.to_string(), second: second.to_string() })
+    }
+}
+
+impl TryFrom<String> for RelayPair {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> Result<Self> {
+        text.parse()
+    }
+}
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Config {
-    /// IRC server address
+    /// TOML or YAML config file (by its extension), which may list more IRC networks; flags given
+    /// on the command line or via env vars override its values
     #[arg(long)]
-    pub server: String,
+    pub config: Option<PathBuf>,
+
+    /// Comma-separated chat networks to connect to
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "irc")]
+    pub transports: Vec<TransportKind>,
+
+    /// IRC server address (required for the IRC transport)
+    #[arg(long)]
+    pub server: Option<String>,
 
     /// IRC server port
     #[arg(long, default_value_t = 6697)] // Default to common SSL port
@@ -22,21 +267,311 @@ pub struct Config {
     #[arg(long, short, default_value = "Emul")]
     pub nickname: String,
 
-    /// Initial admin nickname (can also be set via EMUL_BOT_ADMIN env var)
+    /// Nickname made owner when the database has none (can also be set via EMUL_BOT_ADMIN env var)
     #[arg(long, env = "EMUL_BOT_ADMIN", default_value = "Baughn")]
     pub admin: String,
 
-    /// Optional NickServ password (can also be set via NICKSERV_PASSWORD env var)
+    /// Prefix of the commands anyone can use in channels, like !roll 2d6
+    #[arg(long, default_value = DEFAULT_COMMAND_PREFIX)]
+    pub command_prefix: String,
+
+    /// Minutes without a word in a channel after which a user's !notify highlights there are saved for them
+    #[arg(long, default_value_t = DEFAULT_NOTIFY_AWAY_MINS)]
+    pub notify_away_mins: u64,
+
+    /// Comma-separated channel pairs to bridge, like `#emul=discord:123456789`: what's said in
+    /// one is repeated in the other as `<nick> message`, both ways
+    #[arg(long, value_delimiter = ',')]
+    pub relays: Vec<RelayPair>,
+
+    /// Optional password for services, usually NickServ (can also be set via NICKSERV_PASSWORD env var)
     #[arg(long, env = "NICKSERV_PASSWORD")]
     pub nickserv_password: Option<String>,
 
+    /// Services the password logs in to
+    #[arg(long, value_enum, default_value_t = ServicesKind::Nickserv)]
+    pub services: ServicesKind,
+
+    /// Account to log in to services as, if it isn't the nickname (usual for Q and X)
+    #[arg(long)]
+    pub services_account: Option<String>,
+
     /// Use TLS (SSL) for the connection
     #[arg(long, default_value_t = true)]
     pub use_tls: bool,
 
-    /// Bot memory file
+    /// Connect to IRC through the --proxy, which must then be a SOCKS5 proxy
+    #[arg(long)]
+    pub irc_proxy: bool,
+
+    /// Lines that can be sent to IRC in a quick burst before the flood limit kicks in
+    #[arg(long, default_value_t = DEFAULT_IRC_BURST_LINES)]
+    pub irc_burst_lines: u32,
+
+    /// Milliseconds per IRC line once a burst is used up (0 means no flood limit)
+    #[arg(long, default_value_t = DEFAULT_IRC_LINE_INTERVAL_MS)]
+    pub irc_line_interval_ms: u64,
+
+    /// Minutes without any traffic from the IRC server before reconnecting (0 means never)
+    #[arg(long, default_value_t = DEFAULT_IRC_WATCHDOG_MINS)]
+    pub irc_watchdog_mins: u64,
+
+    /// Lines of missed history asked for on joining a channel, from servers that keep it (IRCv3
+    /// CHATHISTORY, or ZNC's playback module); 0 means none
+    #[arg(long, default_value_t = DEFAULT_IRC_BACKFILL_LINES)]
+    pub irc_backfill_lines: usize,
+
+    /// Discord bot token (can also be set via DISCORD_TOKEN env var)
+    #[arg(long, env = "DISCORD_TOKEN")]
+    pub discord_token: Option<String>,
+
+    /// Bot memory file (required, here or in the config file)
+    #[arg(long)]
+    pub db: Option<String>,
+
+    /// Directory that !export writes channel logs to
+    #[arg(long, default_value = DEFAULT_EXPORT_DIR)]
+    pub export_dir: PathBuf,
+
+    /// Directory where fetched images are cached across restarts
+    #[arg(long, default_value = DEFAULT_IMAGE_CACHE_DIR)]
+    pub image_cache_dir: PathBuf,
+
+    /// Hours a cached image is kept before it's fetched again (0 keeps images in memory only)
+    #[arg(long, default_value_t = DEFAULT_IMAGE_CACHE_TTL_HOURS)]
+    pub image_cache_ttl_hours: u64,
+
+    /// Address to answer health checks on at /healthz, like 127.0.0.1:8080 (off if unset)
+    #[arg(long)]
+    pub health_addr: Option<SocketAddr>,
+
+    /// Proxy for outbound HTTP, like socks5h://127.0.0.1:9050 for Tor or http://proxy:3128
+    /// (can also be set via EMUL_PROXY env var)
+    #[arg(long, env = "EMUL_PROXY")]
+    pub proxy: Option<String>,
+
+    /// LLM API to use
+    #[arg(long, value_enum, default_value_t = LlmBackendKind::Gemini)]
+    pub llm_backend: LlmBackendKind,
+
+    /// Answer with a built-in echo model instead of calling an LLM API, for offline development
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Override the LLM API base URL (e.g. http://localhost:8080/v1 for a local llama.cpp server)
+    #[arg(long)]
+    pub llm_base_url: Option<String>,
+
+    /// Override the main chat model name
+    #[arg(long)]
+    pub llm_model: Option<String>,
+
+    /// Override the fast model used for classification and summarization
+    #[arg(long)]
+    pub llm_fast_model: Option<String>,
+
+    /// Model to retry chat requests with when the main model keeps failing or is out of quota
+    #[arg(long)]
+    pub llm_fallback_model: Option<String>,
+
+    /// Comma-separated Gemini safety thresholds per harm category, like
+    /// `harassment=block_only_high,dangerous_content=block_none` (unset categories use Gemini's defaults)
+    #[arg(long, value_delimiter = ',')]
+    pub safety_settings: Vec<SafetySetting>,
+
+    /// Torrent client that download_torrent sends magnet links to (downloads are disabled if unset)
+    #[arg(long, value_enum)]
+    pub torrent_client: Option<TorrentClientKind>,
+
+    /// Torrent client RPC/Web API URL
+    #[arg(long)]
+    pub torrent_rpc_url: Option<String>,
+
+    /// Torrent client username (can also be set via TORRENT_RPC_USERNAME env var)
+    #[arg(long, env = "TORRENT_RPC_USERNAME")]
+    pub torrent_rpc_username: Option<String>,
+
+    /// Torrent client password (can also be set via TORRENT_RPC_PASSWORD env var)
+    #[arg(long, env = "TORRENT_RPC_PASSWORD")]
+    pub torrent_rpc_password: Option<String>,
+
+    /// Directory of WebAssembly tool plugins (*.wasm) offered to the AI alongside the built-in tools
+    #[arg(long)]
+    pub wasm_tools_dir: Option<PathBuf>,
+
+    /// Comma-separated tool policies, like `download_torrent=confirm,transcribe_audio=admin`:
+    /// `admin` tools only run for admins, `confirm` tools wait for an admin's !approve (others are `auto`)
+    #[arg(long, value_delimiter = ',')]
+    pub tool_policies: Vec<ToolPolicySetting>,
+
+    /// Maximum rounds of tool calls before the AI must answer in text
+    #[arg(long, default_value_t = DEFAULT_MAX_FUNCTION_CALL_TURNS)]
+    pub max_function_call_turns: usize,
+
+    /// Maximum number of tool calls executed from a single AI turn
+    #[arg(long, default_value_t = DEFAULT_MAX_TOOL_CALLS_PER_TURN)]
+    pub max_tool_calls_per_turn: usize,
+
+    /// Maximum number of images the AI may look at in a single function-call turn
+    #[arg(long, default_value_t = DEFAULT_MAX_IMAGES_PER_TURN)]
+    pub max_images_per_turn: usize,
+
+    /// Largest webpage or text document the AI may read, in bytes; bigger downloads are aborted
+    #[arg(long, default_value_t = DEFAULT_MAX_PAGE_BYTES)]
+    pub max_page_bytes: usize,
+
+    /// Seconds a tool call may run before it's stopped and the AI told it timed out (0 = no
+    /// limit); slow tools like transcription get longer
+    #[arg(long, default_value_t = DEFAULT_TOOL_TIMEOUT_SECS)]
+    pub tool_timeout_secs: u64,
+
+    /// Rendering service for pages that need JavaScript, called as `<url>?url=<page>` and
+    /// answering with the rendered HTML (pages are read without one if unset)
+    #[arg(long)]
+    pub render_url: Option<String>,
+
+    /// Exchange rate API for currency conversion in the calculate tool, answering with JSON
+    /// that has a `base` currency and a `rates` object (empty turns currencies off)
+    #[arg(long, default_value = DEFAULT_CURRENCY_RATES_URL)]
+    pub currency_rates_url: String,
+
+    /// Cryptocurrency price API for the get_price tool, answering like CoinGecko's
+    /// `simple/price` (empty turns coin prices off)
+    #[arg(long, default_value = DEFAULT_CRYPTO_PRICES_URL)]
+    pub crypto_prices_url: String,
+
+    /// Fetch images and pages linked in the triggering message before calling the AI
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub prefetch_urls: bool,
+
+    /// Maximum AI requests a single user can trigger per minute (0 means unlimited)
+    #[arg(long, default_value_t = DEFAULT_USER_RATE_LIMIT)]
+    pub user_rate_limit: usize,
+
+    /// Maximum AI requests per channel per hour, including random interjections (0 means unlimited)
+    #[arg(long, default_value_t = DEFAULT_CHANNEL_RATE_LIMIT)]
+    pub channel_rate_limit: usize,
+
+    /// Comma-separated nicks of other bots, which are neither logged nor answered; `*` and `?`
+    /// are wildcards, as in `*bot,*Serv`
+    #[arg(long, value_delimiter = ',')]
+    pub ignored_bots: Vec<String>,
+
+    /// Comma-separated nicks that may be bots, which the bot answers but won't go back and forth
+    /// with for long (see `--max-bot-exchanges`); `*` and `?` are wildcards
+    #[arg(long, value_delimiter = ',', default_value = "*bot")]
+    pub suspected_bots: Vec<String>,
+
+    /// Most AI answers in a row to a nick that may be a bot (one matching `--suspected-bots`, or
+    /// one that quoted the bot's last answer back at it), when nobody else speaks and each comes
+    /// within a minute, before the bot lets the conversation drop, so it can't talk with another
+    /// bot forever (0 means unlimited)
+    #[arg(long, default_value_t = DEFAULT_MAX_BOT_EXCHANGES)]
+    pub max_bot_exchanges: u32,
+
+    /// Daily token budget across all channels; once used up the bot stops calling the AI until
+    /// midnight UTC (0 means unlimited)
+    #[arg(long, default_value_t = 0)]
+    pub daily_token_budget: u64,
+
+    /// Number of long-term memories recalled into each AI prompt (0 disables long-term memory)
+    #[arg(long, default_value_t = DEFAULT_MEMORY_TOP_K)]
+    pub memory_top_k: usize,
+
+    /// Estimated tokens of system prompt, memories and chat history per AI request; the oldest
+    /// history lines are dropped to fit (0 means unlimited)
+    #[arg(long, default_value_t = DEFAULT_CONTEXT_TOKEN_BUDGET)]
+    pub context_token_budget: usize,
+
+    /// Keep a rolling summary of each channel's conversation (updated by the fast model) and
+    /// include it in AI prompts
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub channel_summaries: bool,
+
+    /// Chat with users in private messages: messages to the bot that aren't commands are answered
+    /// by the AI, with a conversation history kept for each user
+    #[arg(long, default_value_t = false, action = clap::ArgAction::Set)]
+    pub dm_chat: bool,
+
+    /// Seconds an AI answer is reused when the same nick asks the same question in the same channel,
+    /// with the same history and memories, so repeats don't each call the API (0 turns the cache off)
+    #[arg(long, default_value_t = 0)]
+    pub response_cache_secs: u64,
+
+    /// Gemini only: keep the system prompt and tools in Gemini's context cache for this many
+    /// seconds at a time, so requests don't resend them (0 turns caching off)
+    #[arg(long, default_value_t = 0)]
+    pub context_cache_secs: u64,
+
+    /// Send AI responses sentence by sentence as they are generated (never in moderated channels)
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub stream_responses: bool,
+
+    /// Comma-separated words that are masked out of AI responses
+    #[arg(long, value_delimiter = ',')]
+    pub blocked_words: Vec<String>,
+
+    /// Maximum length of an AI response in characters; longer responses are truncated
+    #[arg(long, default_value_t = DEFAULT_MAX_RESPONSE_LENGTH)]
+    pub max_response_length: usize,
+
+    /// Maximum chat lines in one AI reply; longer replies are condensed by the fast model (0 means unlimited)
+    #[arg(long, default_value_t = DEFAULT_MAX_REPLY_LINES)]
+    pub max_reply_lines: usize,
+
+    /// Paste service (like https://0x0.st) that code blocks and overlong replies are uploaded
+    /// to, so the channel gets a link instead (unset keeps everything in the channel)
+    #[arg(long)]
+    pub paste_url: Option<String>,
+
+    /// Code blocks of at least this many lines go to the paste service
+    #[arg(long, default_value_t = DEFAULT_PASTE_MIN_LINES)]
+    pub paste_min_lines: usize,
+
+    /// Comma-separated channels where AI responses are run through a moderation check before sending
+    #[arg(long, value_delimiter = ',')]
+    pub moderated_channels: Vec<String>,
+
+    /// Comma-separated channels where fetched images are screened for NSFW content before the AI sees them
+    #[arg(long, value_delimiter = ',')]
+    pub nsfw_screened_channels: Vec<String>,
+
+    /// NSFW score (0.0-1.0) above which images are withheld in screened channels
+    #[arg(long, default_value_t = DEFAULT_NSFW_THRESHOLD)]
+    pub nsfw_threshold: f64,
+
+    /// Directory to also write logs to as JSON lines, in files rotated by --log-rotation (off if unset)
     #[arg(long)]
-    pub db: String,
+    pub log_dir: Option<PathBuf>,
+
+    /// How often a new log file is started in --log-dir
+    #[arg(long, value_enum, default_value_t = LogRotation::Daily)]
+    pub log_rotation: LogRotation,
+
+    /// Rotated log files kept in --log-dir; older ones are deleted (0 keeps them all)
+    #[arg(long, default_value_t = DEFAULT_LOG_MAX_FILES)]
+    pub log_max_files: usize,
+
+    /// Comma-separated log levels for single modules, like `emul::llm=debug,serenity=warn`, on
+    /// top of the default `info` and RUST_LOG
+    #[arg(long, value_delimiter = ',')]
+    pub log_levels: Vec<String>,
+
+    /// OTLP/HTTP endpoint to export tracing spans to, like http://localhost:4318/v1/traces (off if unset)
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+
+    /// More IRC networks from the config file, each connected to by a bot of its own
+    #[arg(skip)]
+    pub networks: Vec<NetworkSection>,
+
+    // Which of the config file's networks this is the config for, if it's one of them
+    #[arg(skip)]
+    network: Option<usize>,
+
+    // The arguments the config was parsed from, so `reload` can parse them again
+    #[arg(skip)]
+    args: Vec<OsString>,
 }
 
 impl Config {
@@ -44,14 +579,666 @@ impl Config {
         // Load .env file if present
         dotenvy::dotenv().ok(); // Ignore error if .env doesn't exist
 
-        Ok(Config::parse())
+        let args: Vec<OsString> = std::env::args_os().collect();
+        let mut config = Self::from_matches(&Config::command().get_matches_from(&args))?;
+        config.args = args;
+        config.validated()
+    }
+
+    /// Parses `args` (starting with the program name) and the config file they name, instead of
+    /// the process's own command line. For embedders and tests: unlike `load`, bad arguments are
+    /// returned as an error, and settings only the standalone bot needs (`--db`, the transports'
+    /// credentials) aren't required.
+    pub fn from_args<I, T>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        let mut config = Self::from_matches(&Config::command().try_get_matches_from(&args)?)?;
+        config.args = args;
+        Ok(config)
+    }
+
+    /// Parses the arguments and config file this config came from again, for `!reload`. The
+    /// settings `validated` checks need a restart anyway, so they aren't checked again.
+    pub fn reload(&self) -> Result<Self> {
+        let config = Self::from_args(self.args.clone())?;
+        match self.network {
+            None => Ok(config),
+            Some(index) => config.network_configs().into_iter().nth(index).context("The network is gone from the config file"),
+        }
+    }
+
+    /// A config for each network in the config file's `networks`: this one, with that network's
+    /// IRC settings and database in place of its own. Those bots only connect to IRC, and the
+    /// health check is left to this one. Services credentials and channel-keyed settings are the
+    /// network's own, never the main network's: nicks and channel names mean nothing elsewhere.
+    pub fn network_configs(&self) -> Vec<Config> {
+        self.networks
+            .iter()
+            .enumerate()
+            .map(|(index, network)| {
+                let mut config = self.clone();
+                config.nickserv_password = None;
+                config.services = ServicesKind::Nickserv;
+                config.services_account = None;
+                config.relays = Vec::new();
+                config.moderated_channels = Vec::new();
+                config.nsfw_screened_channels = Vec::new();
+                macro_rules! set {
+                    ($($field:ident = $value:expr),* $(,)?) => {
+                        $(
+                            if let Some(value) = $value.clone() {
+                                config.$field = value.into();
+                            }
+                        )*
+                    };
+                }
+                set! {
+                    db = network.db,
+                    admin = network.admin,
+                    relays = network.relays,
+                    moderated_channels = network.moderated_channels,
+                    nsfw_screened_channels = network.nsfw_screened_channels,
+                    server = network.server,
+                    port = network.port,
+                    nickname = network.nickname,
+                    nickserv_password = network.nickserv_password,
+                    services = network.services,
+                    services_account = network.services_account,
+                    use_tls = network.use_tls,
+                    irc_proxy = network.proxy,
+                    irc_burst_lines = network.burst_lines,
+                    irc_line_interval_ms = network.line_interval_ms,
+                    irc_watchdog_mins = network.watchdog_mins,
+                    irc_backfill_lines = network.backfill_lines,
+                }
+                config.transports = vec![TransportKind::Irc];
+                config.health_addr = None;
+                config.networks = Vec::new();
+                config.network = Some(index);
+                config
+            })
+            .collect()
+    }
+
+    fn validated(self) -> Result<Self> {
+        let config = self;
+        if config.db.is_none() {
+            bail!("--db (or `db` in the config file) is required");
+        }
+        if config.command_prefix.trim().is_empty() {
+            bail!("--command-prefix can't be empty");
+        }
+        if config.uses_transport(TransportKind::Irc) && config.server.is_none() {
+            bail!("--server is required when the IRC transport is enabled");
+        }
+        if let Some(url) = &config.proxy {
+            proxy::parse_url(url)?;
+        }
+        if config.irc_proxy {
+            proxy::socks5(config.proxy.as_deref().context("--irc-proxy needs a --proxy to connect through")?)?;
+        }
+        if config.uses_transport(TransportKind::Discord) && config.discord_token.is_none() {
+            bail!("DISCORD_TOKEN (or --discord-token) is required when the Discord transport is enabled");
+        }
+        // Channels are only told apart by name, so each network needs its own database
+        let mut dbs = vec![config.db.clone()];
+        for network in &config.networks {
+            if network.server.is_none() {
+                bail!("Each of the config file's networks needs a server");
+            }
+            if network.admin.is_none() {
+                bail!("Each of the config file's networks needs an admin of its own");
+            }
+            if network.db.is_none() || dbs.contains(&network.db) {
+                bail!("Each of the config file's networks needs a db of its own");
+            }
+            dbs.push(network.db.clone());
+        }
+        Ok(config)
+    }
+
+    /// Builds the config from parsed arguments, filling in values from the config file if given.
+    fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let mut config = Config::from_arg_matches(matches)?;
+        if let Some(path) = config.config.clone() {
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read config file {}", path.display()))?;
+            let yaml = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml"));
+            let file: FileConfig = match yaml {
+                true => serde_yaml::from_str(&text).map_err(anyhow::Error::from),
+                false => toml::from_str(&text).map_err(anyhow::Error::from),
+            }
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+            config.apply_file(file, matches);
+        }
+        Ok(config)
+    }
+
+    /// Copies values from the config file, except for flags that were given explicitly.
+    fn apply_file(&mut self, file: FileConfig, matches: &ArgMatches) {
+        macro_rules! merge {
+            ($($field:ident = $value:expr),* $(,)?) => {
+                $(
+                    if let Some(value) = $value
+                        && !matches!(
+                            matches.value_source(stringify!($field)),
+                            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+                        )
+                    {
+                        self.$field = value.into();
+                    }
+                )*
+            };
+        }
+
+        merge! {
+            transports = file.transports,
+            db = file.db,
+            export_dir = file.export_dir,
+            image_cache_dir = file.image_cache_dir,
+            image_cache_ttl_hours = file.image_cache_ttl_hours,
+            proxy = file.proxy,
+            health_addr = file.health_addr,
+            admin = file.admin,
+            command_prefix = file.command_prefix,
+            notify_away_mins = file.notify_away_mins,
+            relays = file.relays,
+            server = file.irc.server,
+            port = file.irc.port,
+            nickname = file.irc.nickname,
+            nickserv_password = file.irc.nickserv_password,
+            services = file.irc.services,
+            services_account = file.irc.services_account,
+            use_tls = file.irc.use_tls,
+            irc_proxy = file.irc.proxy,
+            irc_burst_lines = file.irc.burst_lines,
+            irc_line_interval_ms = file.irc.line_interval_ms,
+            irc_watchdog_mins = file.irc.watchdog_mins,
+            irc_backfill_lines = file.irc.backfill_lines,
+            discord_token = file.discord.token,
+            llm_backend = file.llm.backend,
+            dry_run = file.llm.dry_run,
+            llm_base_url = file.llm.base_url,
+            llm_model = file.llm.model,
+            llm_fast_model = file.llm.fast_model,
+            llm_fallback_model = file.llm.fallback_model,
+            safety_settings = file.llm.safety_settings,
+            stream_responses = file.llm.stream_responses,
+            memory_top_k = file.llm.memory_top_k,
+            context_token_budget = file.llm.context_token_budget,
+            channel_summaries = file.llm.channel_summaries,
+            dm_chat = file.llm.dm_chat,
+            response_cache_secs = file.llm.response_cache_secs,
+            context_cache_secs = file.llm.context_cache_secs,
+            torrent_client = file.tools.torrent_client,
+            torrent_rpc_url = file.tools.torrent_rpc_url,
+            torrent_rpc_username = file.tools.torrent_rpc_username,
+            torrent_rpc_password = file.tools.torrent_rpc_password,
+            wasm_tools_dir = file.tools.wasm_tools_dir,
+            tool_policies = file.tools.tool_policies,
+            max_function_call_turns = file.tools.max_function_call_turns,
+            max_tool_calls_per_turn = file.tools.max_tool_calls_per_turn,
+            max_images_per_turn = file.tools.max_images_per_turn,
+            max_page_bytes = file.tools.max_page_bytes,
+            tool_timeout_secs = file.tools.tool_timeout_secs,
+            render_url = file.tools.render_url,
+            currency_rates_url = file.tools.currency_rates_url,
+            crypto_prices_url = file.tools.crypto_prices_url,
+            prefetch_urls = file.tools.prefetch_urls,
+            user_rate_limit = file.limits.user_rate_limit,
+            channel_rate_limit = file.limits.channel_rate_limit,
+            ignored_bots = file.limits.ignored_bots,
+            suspected_bots = file.limits.suspected_bots,
+            max_bot_exchanges = file.limits.max_bot_exchanges,
+            daily_token_budget = file.limits.daily_token_budget,
+            max_response_length = file.limits.max_response_length,
+            max_reply_lines = file.limits.max_reply_lines,
+            paste_url = file.limits.paste_url,
+            paste_min_lines = file.limits.paste_min_lines,
+            blocked_words = file.filter.blocked_words,
+            moderated_channels = file.filter.moderated_channels,
+            nsfw_screened_channels = file.filter.nsfw_screened_channels,
+            nsfw_threshold = file.filter.nsfw_threshold,
+            log_dir = file.logging.dir,
+            log_rotation = file.logging.rotation,
+            log_max_files = file.logging.max_files,
+            log_levels = file.logging.levels,
+            otlp_endpoint = file.logging.otlp_endpoint,
+        }
+        // Networks have no flags to override them
+        self.networks = file.networks;
+    }
+
+    /// Names of the settings that differ between two configs.
+    pub fn changed_settings(&self, other: &Config) -> Vec<&'static str> {
+        macro_rules! changed {
+            ($($field:ident),* $(,)?) => {
+                [$((stringify!($field), self.$field != other.$field)),*]
+                    .into_iter()
+                    .filter_map(|(name, changed)| changed.then_some(name))
+                    .collect()
+            };
+        }
+
+        changed! {
+            config, transports, server, port, nickname, admin, command_prefix, notify_away_mins, relays, nickserv_password, services,
+            services_account, use_tls, irc_proxy,
+            irc_burst_lines, irc_line_interval_ms, irc_watchdog_mins, irc_backfill_lines, discord_token, db, export_dir,
+            image_cache_dir, image_cache_ttl_hours, health_addr, proxy, llm_backend, dry_run, llm_base_url, llm_model, llm_fast_model, llm_fallback_model, safety_settings,
+            torrent_client, torrent_rpc_url, torrent_rpc_username, torrent_rpc_password,
+            wasm_tools_dir, tool_policies, max_function_call_turns, max_tool_calls_per_turn, max_images_per_turn, max_page_bytes,
+            tool_timeout_secs, render_url, currency_rates_url, crypto_prices_url,
+            prefetch_urls, user_rate_limit, channel_rate_limit, ignored_bots, suspected_bots, max_bot_exchanges, daily_token_budget, memory_top_k,
+            context_token_budget, channel_summaries, dm_chat, response_cache_secs, context_cache_secs, stream_responses, blocked_words, max_response_length, max_reply_lines,
+            paste_url, paste_min_lines,
+            moderated_channels, nsfw_screened_channels, nsfw_threshold,
+            log_dir, log_rotation, log_max_files, log_levels, otlp_endpoint, networks,
+        }
+    }
+
+    /// Copies the settings in `RESTART_REQUIRED_SETTINGS` from the running config, so a reload
+    /// doesn't pretend they changed.
+    pub fn keep_startup_settings(&mut self, running: &Config) {
+        self.config = running.config.clone();
+        self.transports = running.transports.clone();
+        self.server = running.server.clone();
+        self.port = running.port;
+        self.nickname = running.nickname.clone();
+        self.admin = running.admin.clone();
+        self.nickserv_password = running.nickserv_password.clone();
+        self.services = running.services;
+        self.services_account = running.services_account.clone();
+        self.use_tls = running.use_tls;
+        self.irc_proxy = running.irc_proxy;
+        self.irc_burst_lines = running.irc_burst_lines;
+        self.irc_line_interval_ms = running.irc_line_interval_ms;
+        self.irc_watchdog_mins = running.irc_watchdog_mins;
+        self.discord_token = running.discord_token.clone();
+        self.db = running.db.clone();
+        self.image_cache_dir = running.image_cache_dir.clone();
+        self.image_cache_ttl_hours = running.image_cache_ttl_hours;
+        self.health_addr = running.health_addr;
+        self.proxy = running.proxy.clone();
+        self.torrent_client = running.torrent_client;
+        self.torrent_rpc_url = running.torrent_rpc_url.clone();
+        self.torrent_rpc_username = running.torrent_rpc_username.clone();
+        self.torrent_rpc_password = running.torrent_rpc_password.clone();
+        self.log_dir = running.log_dir.clone();
+        self.log_rotation = running.log_rotation;
+        self.log_max_files = running.log_max_files;
+        self.log_levels = running.log_levels.clone();
+        self.otlp_endpoint = running.otlp_endpoint.clone();
+        self.networks = running.networks.clone();
+    }
+
+    pub fn uses_transport(&self, kind: TransportKind) -> bool {
+        self.transports.contains(&kind)
     }
 
     pub fn db_path(&self) -> PathBuf {
-        PathBuf::from(self.db.clone())
+        PathBuf::from(self.db.as_deref().unwrap_or_default())
     }
 
     pub fn prompt_path(&self) -> PathBuf {
         PathBuf::from(PROMPT_FILE_PATH)
     }
+
+    /// The NSFW threshold to apply to images in `channel`, or None if the channel isn't screened.
+    pub fn nsfw_threshold_for(&self, channel: &str) -> Option<f64> {
+        self.nsfw_screened_channels
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(channel))
+            .then_some(self.nsfw_threshold)
+    }
+}
+
+// --- Config File ---
+// Same settings as the flags, grouped into sections, in TOML or YAML. See emul.example.toml.
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    transports: Option<Vec<TransportKind>>,
+    db: Option<String>,
+    export_dir: Option<PathBuf>,
+    image_cache_dir: Option<PathBuf>,
+    image_cache_ttl_hours: Option<u64>,
+    health_addr: Option<SocketAddr>,
+    proxy: Option<String>,
+    admin: Option<String>,
+    command_prefix: Option<String>,
+    notify_away_mins: Option<u64>,
+    relays: Option<Vec<RelayPair>>,
+    networks: Vec<NetworkSection>,
+    irc: IrcSection,
+    discord: DiscordSection,
+    llm: LlmSection,
+    tools: ToolsSection,
+    limits: LimitsSection,
+    filter: FilterSection,
+    logging: LoggingSection,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct IrcSection {
+    server: Option<String>,
+    port: Option<u16>,
+    nickname: Option<String>,
+    nickserv_password: Option<String>,
+    services: Option<ServicesKind>,
+    services_account: Option<String>,
+    use_tls: Option<bool>,
+    proxy: Option<bool>,
+    burst_lines: Option<u32>,
+    line_interval_ms: Option<u64>,
+    watchdog_mins: Option<u64>,
+    backfill_lines: Option<usize>,
+}
+
+/// Another IRC network, with the same settings as `[irc]` and a database and admin of its own.
+/// Connection settings left out are the same as the main network's; services credentials and
+/// channel settings are unset unless given here.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkSection {
+    db: Option<String>,
+    admin: Option<String>,
+    relays: Option<Vec<RelayPair>>,
+    moderated_channels: Option<Vec<String>>,
+    nsfw_screened_channels: Option<Vec<String>>,
+    server: Option<String>,
+    port: Option<u16>,
+    nickname: Option<String>,
+    nickserv_password: Option<String>,
+    services: Option<ServicesKind>,
+    services_account: Option<String>,
+    use_tls: Option<bool>,
+    proxy: Option<bool>,
+    burst_lines: Option<u32>,
+    line_interval_ms: Option<u64>,
+    watchdog_mins: Option<u64>,
+    backfill_lines: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct DiscordSection {
+    token: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LlmSection {
+    backend: Option<LlmBackendKind>,
+    dry_run: Option<bool>,
+    base_url: Option<String>,
+    model: Option<String>,
+    fast_model: Option<String>,
+    fallback_model: Option<String>,
+    safety_settings: Option<Vec<SafetySetting>>,
+    stream_responses: Option<bool>,
+    memory_top_k: Option<usize>,
+    context_token_budget: Option<usize>,
+    channel_summaries: Option<bool>,
+    dm_chat: Option<bool>,
+    response_cache_secs: Option<u64>,
+    context_cache_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ToolsSection {
+    torrent_client: Option<TorrentClientKind>,
+    torrent_rpc_url: Option<String>,
+    torrent_rpc_username: Option<String>,
+    torrent_rpc_password: Option<String>,
+    wasm_tools_dir: Option<PathBuf>,
+    tool_policies: Option<Vec<ToolPolicySetting>>,
+    max_function_call_turns: Option<usize>,
+    max_tool_calls_per_turn: Option<usize>,
+    max_images_per_turn: Option<usize>,
+    max_page_bytes: Option<usize>,
+    tool_timeout_secs: Option<u64>,
+    render_url: Option<String>,
+    currency_rates_url: Option<String>,
+    crypto_prices_url: Option<String>,
+    prefetch_urls: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+    user_rate_limit: Option<usize>,
+    channel_rate_limit: Option<usize>,
+    ignored_bots: Option<Vec<String>>,
+    suspected_bots: Option<Vec<String>>,
+    max_bot_exchanges: Option<u32>,
+    daily_token_budget: Option<u64>,
+    max_response_length: Option<usize>,
+    max_reply_lines: Option<usize>,
+    paste_url: Option<String>,
+    paste_min_lines: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FilterSection {
+    blocked_words: Option<Vec<String>>,
+    moderated_channels: Option<Vec<String>>,
+    nsfw_screened_channels: Option<Vec<String>>,
+    nsfw_threshold: Option<f64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LoggingSection {
+    dir: Option<PathBuf>,
+    rotation: Option<LogRotation>,
+    max_files: Option<usize>,
+    levels: Option<Vec<String>>,
+    otlp_endpoint: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_config_file_with_flag_overrides() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"
+            transports = ["irc", "discord"]
+            db = "from_file.sqlite"
+
+            [irc]
+            server = "irc.example.net"
+            nickname = "FileEmul"
+            services = "q"
+
+            [llm]
+            backend = "openai"
+            model = "local-model"
+
+            [limits]
+            user_rate_limit = 2
+            "#
+        )
+        .unwrap();
+        let path = file.path().to_str().unwrap();
+
+        let matches = Config::command()
+            .try_get_matches_from(["emul", "--config", path, "--nickname", "FlagEmul"])
+            .unwrap();
+        let config = Config::from_matches(&matches).unwrap();
+        assert_eq!(config.transports, vec![TransportKind::Irc, TransportKind::Discord]);
+        assert_eq!(config.db.as_deref(), Some("from_file.sqlite"));
+        assert_eq!(config.server.as_deref(), Some("irc.example.net"));
+        assert_eq!(config.nickname, "FlagEmul"); // The flag wins
+        assert_eq!(config.services, ServicesKind::Q);
+        assert_eq!(config.llm_backend, LlmBackendKind::Openai);
+        assert_eq!(config.llm_model.as_deref(), Some("local-model"));
+        assert_eq!(config.user_rate_limit, 2);
+        assert_eq!(config.channel_rate_limit, DEFAULT_CHANNEL_RATE_LIMIT); // Untouched default
+    }
+
+    #[test]
+    fn test_yaml_config_with_networks() {
+        let mut file = tempfile::Builder::new().suffix(".yaml").tempfile().unwrap();
+        write!(
+            file,
+            r##"
+db: main.sqlite
+admin: Baughn
+relays: ["#emul=discord:123456789"]
+irc:
+  server: irc.libera.chat
+  nickname: Emul
+  nickserv_password: libera-secret
+  services: x
+  services_account: EmulAccount
+filter:
+  moderated_channels: ["#emul"]
+  nsfw_screened_channels: ["#emul"]
+llm:
+  safety_settings: ["harassment=block_only_high"]
+networks:
+  - server: irc.oftc.net
+    db: oftc.sqlite
+    admin: OftcAdmin
+  - server: irc.quakenet.org
+    db: quakenet.sqlite
+    admin: QAdmin
+    nickname: EmulQ
+    nickserv_password: q-secret
+    services: q
+    relays: ["#emul=#emul-quakenet"]
+    moderated_channels: ["#quiet"]
+"##
+        )
+        .unwrap();
+        let path = file.path().to_str().unwrap();
+        let config = Config::from_args(["emul", "--config", path, "--transports", "irc,discord"]).unwrap();
+        assert_eq!((config.server.as_deref(), config.nickname.as_str()), (Some("irc.libera.chat"), "Emul"));
+        assert_eq!(config.safety_settings[0].category, "HARM_CATEGORY_HARASSMENT");
+
+        // Each network is the main config with its own IRC settings, and only connects to IRC
+        let networks = config.network_configs();
+        assert_eq!(networks.len(), 2);
+        assert_eq!((networks[0].server.as_deref(), networks[0].db.as_deref()), (Some("irc.oftc.net"), Some("oftc.sqlite")));
+        assert_eq!(networks[0].nickname, "Emul");
+        assert_eq!((networks[1].nickname.as_str(), networks[1].services), ("EmulQ", ServicesKind::Q));
+        assert_eq!((networks[0].admin.as_str(), networks[1].admin.as_str()), ("OftcAdmin", "QAdmin"));
+        // Services credentials are never carried over from [irc] to another network
+        assert_eq!(networks[0].nickserv_password, None);
+        assert_eq!((networks[0].services, networks[0].services_account.as_deref()), (ServicesKind::Nickserv, None));
+        assert_eq!(networks[1].nickserv_password.as_deref(), Some("q-secret"));
+        assert_eq!(networks[1].services_account, None);
+        // Neither are settings keyed by channel, whose names mean other channels there
+        assert!(networks[0].relays.is_empty());
+        assert!(networks[0].moderated_channels.is_empty() && networks[0].nsfw_screened_channels.is_empty());
+        assert_eq!(networks[1].relays[0].second, "#emul-quakenet");
+        assert_eq!(networks[1].moderated_channels, vec!["#quiet"]);
+        assert_eq!(networks[1].transports, vec![TransportKind::Irc]);
+        assert!(networks[1].network_configs().is_empty());
+        // Reloading a network's config finds the same network again
+        assert_eq!(networks[1].reload().unwrap().server.as_deref(), Some("irc.quakenet.org"));
+
+        // Networks sharing a database would mix up their channels
+        let mut shared = tempfile::Builder::new().suffix(".yml").tempfile().unwrap();
+        write!(shared, "db: main.sqlite
+irc:
+  server: irc.libera.chat
+networks:
+  - server: irc.oftc.net
+    db: main.sqlite
+    admin: OftcAdmin
+").unwrap();
+        let config = Config::from_args(["emul", "--config", shared.path().to_str().unwrap()]).unwrap();
+        assert!(config.validated().is_err());
+
+        // Each network needs its own admin; the main one's nick may belong to anyone there
+        let mut ownerless = tempfile::Builder::new().suffix(".yml").tempfile().unwrap();
+        write!(ownerless, "db: main.sqlite
+irc:
+  server: irc.libera.chat
+networks:
+  - server: irc.oftc.net
+    db: oftc.sqlite
+").unwrap();
+        let config = Config::from_args(["emul", "--config", ownerless.path().to_str().unwrap()]).unwrap();
+        assert!(config.validated().is_err());
+    }
+
+    #[test]
+    fn test_parse_safety_setting() {
+        let setting: SafetySetting = "harassment=block_only_high".parse().unwrap();
+        assert_eq!(setting.category, "HARM_CATEGORY_HARASSMENT");
+        assert_eq!(setting.threshold, "BLOCK_ONLY_HIGH");
+        let setting: SafetySetting = "HARM_CATEGORY_DANGEROUS_CONTENT = BLOCK_NONE".parse().unwrap();
+        assert_eq!(setting.category, "HARM_CATEGORY_DANGEROUS_CONTENT");
+        assert_eq!(setting.threshold, "BLOCK_NONE");
+
+        assert!("harassment".parse::<SafetySetting>().is_err());
+        assert!("rudeness=block_none".parse::<SafetySetting>().is_err());
+        assert!("harassment=block_everything".parse::<SafetySetting>().is_err());
+    }
+
+    #[test]
+    fn test_safety_settings_from_file() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "[llm]\nsafety_settings = [\"hate_speech=block_low_and_above\"]\n").unwrap();
+        let path = file.path().to_str().unwrap();
+        let matches = Config::command().try_get_matches_from(["emul", "--config", path]).unwrap();
+        let config = Config::from_matches(&matches).unwrap();
+        assert_eq!(
+            config.safety_settings,
+            vec![SafetySetting {
+                category: "HARM_CATEGORY_HATE_SPEECH".to_string(),
+                threshold: "BLOCK_LOW_AND_ABOVE".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_tool_policy_setting() {
+        let setting: ToolPolicySetting = "download_torrent=confirm".parse().unwrap();
+        assert_eq!(setting, ToolPolicySetting { tool: "download_torrent".to_string(), policy: ToolPolicy::Confirm });
+        let setting: ToolPolicySetting = "transcribe_audio = Admin".parse().unwrap();
+        assert_eq!(setting.policy, ToolPolicy::AdminOnly);
+
+        assert!("download_torrent".parse::<ToolPolicySetting>().is_err());
+        assert!("download_torrent=never".parse::<ToolPolicySetting>().is_err());
+    }
+
+    #[test]
+    fn test_parse_relay_pair() {
+        let pair: RelayPair = " #emul = discord:123456789".parse().unwrap();
+        assert_eq!(pair, RelayPair { first: "#emul".to_string(), second: "discord:123456789".to_string() });
+        assert!("#emul".parse::<RelayPair>().is_err());
+        assert!("#emul=emul".parse::<RelayPair>().is_err());
+        assert!("#emul=#EMUL".parse::<RelayPair>().is_err());
+    }
+
+    #[test]
+    fn test_changed_settings() {
+        let parse = |args: &[&str]| {
+            let matches = Config::command().try_get_matches_from(args).unwrap();
+            Config::from_matches(&matches).unwrap()
+        };
+        let running = parse(&["emul", "--db", "a.sqlite", "--nickname", "Emul"]);
+        let mut reloaded = parse(&["emul", "--db", "a.sqlite", "--nickname", "Other", "--user-rate-limit", "1"]);
+        assert_eq!(running.changed_settings(&reloaded), vec!["nickname", "user_rate_limit"]);
+
+        reloaded.keep_startup_settings(&running);
+        assert_eq!(reloaded.nickname, "Emul");
+        assert_eq!(reloaded.user_rate_limit, 1);
+    }
 }
@@ -0,0 +1,212 @@
+//! Cryptocurrency prices for the get_price tool. They come from an API answering like
+//! CoinGecko's `simple/price`: `?ids=bitcoin&vs_currencies=eur` gives `{"bitcoin": {"eur": 57000.1}}`.
+//! Prices are used for a minute, and the API is asked at most once every few seconds, since
+//! free tiers only allow a handful of requests a minute. Older prices stand in while it's down
+//! or being held off.
+
+use crate::calc;
+use crate::proxy::HttpProxy;
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Utc};
+use lru::LruCache;
+use num_rational::BigRational;
+use num_traits::Signed;
+use serde_json::Value;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a price is used before asking for a new one.
+const FRESH_FOR: Duration = Duration::from_secs(60);
+/// The shortest time between two requests to the price API.
+const MIN_FETCH_INTERVAL: Duration = Duration::from_secs(5);
+/// Coin and currency pairs remembered at once.
+const CACHE_SIZE: usize = 100;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Ticker symbols of well-known coins, with their CoinGecko ids.
+const COINS: &[(&str, &str)] = &[
+    ("BTC", "bitcoin"),
+    ("ETH", "ethereum"),
+    ("USDT", "tether"),
+    ("USDC", "usd-coin"),
+    ("BNB", "binancecoin"),
+    ("SOL", "solana"),
+    ("XRP", "ripple"),
+    ("DOGE", "dogecoin"),
+    ("ADA", "cardano"),
+    ("TRX", "tron"),
+    ("TON", "the-open-network"),
+    ("AVAX", "avalanche-2"),
+    ("DOT", "polkadot"),
+    ("LINK", "chainlink"),
+    ("SHIB", "shiba-inu"),
+    ("LTC", "litecoin"),
+    ("BCH", "bitcoin-cash"),
+    ("XLM", "stellar"),
+    ("XMR", "monero"),
+    ("ETC", "ethereum-classic"),
+    ("ATOM", "cosmos"),
+    ("ZEC", "zcash"),
+];
+
+/// The ticker symbol and API id of a well-known coin, given either of them (BTC or bitcoin).
+pub fn known_coin(name: &str) -> Option<(&'static str, &'static str)> {
+    COINS
+        .iter()
+        .find(|(symbol, id)| symbol.eq_ignore_ascii_case(name) || id.eq_ignore_ascii_case(name))
+        .copied()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Price {
+    /// How much of the currency one coin costs.
+    pub value: BigRational,
+    pub fetched_at: DateTime<Utc>,
+    fetched: Instant,
+}
+
+struct CachedPrices {
+    /// By API URL, coin id and lowercase currency.
+    prices: LruCache<(String, String, String), Price>,
+    last_fetch: Option<Instant>,
+}
+
+pub struct PriceCache {
+    cached: Mutex<CachedPrices>,
+    fresh_for: Duration,
+    min_fetch_interval: Duration,
+}
+
+impl PriceCache {
+    pub fn new(fresh_for: Duration, min_fetch_interval: Duration) -> Self {
+        PriceCache {
+            cached: Mutex::new(CachedPrices { prices: LruCache::new(NonZeroUsize::new(CACHE_SIZE).unwrap()), last_fetch: None }),
+            fresh_for,
+            min_fetch_interval,
+        }
+    }
+
+    /// The price of coin `id` in `currency`, from `url`. A stale price is used if the API fails
+    /// or was asked too recently; without one, that's an error.
+    pub async fn get(&self, proxy: &HttpProxy, url: &str, id: &str, currency: &str) -> Result<Price> {
+        let key = (url.to_string(), id.to_lowercase(), currency.to_lowercase());
+        let (cached, wait) = {
+            let mut cached = self.cached.lock().unwrap();
+            let price = cached.prices.get(&key).cloned();
+            if let Some(price) = &price
+                && price.fetched.elapsed() < self.fresh_for
+            {
+                return Ok(price.clone());
+            }
+            let wait = cached
+                .last_fetch
+                .map(|last| self.min_fetch_interval.saturating_sub(last.elapsed()))
+                .filter(|wait| !wait.is_zero());
+            if wait.is_none() {
+                cached.last_fetch = Some(Instant::now());
+            }
+            (price, wait)
+        };
+        if let Some(wait) = wait {
+            return cached.ok_or_else(|| {
+                anyhow!("The price service was asked too recently; try again in {} seconds.", wait.as_secs().max(1))
+            });
+        }
+
+        match fetch(proxy, url, &key.1, &key.2).await {
+            Ok(value) => {
+                tracing::info!(%url, coin = %key.1, currency = %key.2, "Fetched a coin price");
+                let price = Price { value, fetched_at: Utc::now(), fetched: Instant::now() };
+                self.cached.lock().unwrap().prices.put(key, price.clone());
+                Ok(price)
+            }
+            Err(e) => match cached {
+                Some(price) => {
+                    tracing::warn!(%url, coin = %key.1, "Failed to refresh a coin price, using an older one: {:#}", e);
+                    Ok(price)
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+impl Default for PriceCache {
+    fn default() -> Self {
+        PriceCache::new(FRESH_FOR, MIN_FETCH_INTERVAL)
+    }
+}
+
+async fn fetch(proxy: &HttpProxy, url: &str, id: &str, currency: &str) -> Result<BigRational> {
+    let json: Value = proxy
+        .client()?
+        .get(url)
+        .query(&[("ids", id), ("vs_currencies", currency)])
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach price service {}", url))?
+        .error_for_status()
+        .context("Price service refused the request")?
+        .json()
+        .await
+        .context("Price service sent invalid JSON")?;
+    parse_price(&json, id, currency)
+}
+
+fn parse_price(json: &Value, id: &str, currency: &str) -> Result<BigRational> {
+    let Some(prices) = json[id].as_object() else {
+        bail!("The price service doesn't know a coin called '{}'.", id);
+    };
+    let price = prices
+        .get(currency)
+        .and_then(Value::as_number)
+        .and_then(|price| calc::parse_decimal(&price.to_string()))
+        .with_context(|| format!("The price service has no {} price for {}.", currency.to_uppercase(), id))?;
+    if !price.is_positive() {
+        bail!("The price service gave {} no price.", id);
+    }
+    Ok(price)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_known_coin_and_parse_price() {
+        assert_eq!(known_coin("btc"), Some(("BTC", "bitcoin")));
+        assert_eq!(known_coin("Monero"), Some(("XMR", "monero")));
+        assert_eq!(known_coin("EUR"), None);
+
+        let json = json!({"bitcoin": {"eur": 57000.5}, "dogecoin": {"eur": 1.2e-1}});
+        assert_eq!(parse_price(&json, "bitcoin", "eur").unwrap(), calc::parse_decimal("57000.5").unwrap());
+        assert_eq!(parse_price(&json, "dogecoin", "eur").unwrap(), calc::parse_decimal("0.12").unwrap());
+        assert!(parse_price(&json, "bitcoin", "xyz").is_err());
+        assert!(parse_price(&json, "nocoin", "eur").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_prices_are_cached_and_rate_limited() {
+        let mut server = mockito::Server::new_async().await;
+        let bitcoin = server
+            .mock("GET", "/price")
+            .match_query(mockito::Matcher::UrlEncoded("ids".into(), "bitcoin".into()))
+            .with_body(r#"{"bitcoin": {"usd": 65000}}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        let url = format!("{}/price", server.url());
+
+        let cache = PriceCache::new(FRESH_FOR, Duration::from_secs(60));
+        assert_eq!(cache.get(&HttpProxy::default(), &url, "bitcoin", "USD").await.unwrap().value, calc::parse_decimal("65000").unwrap());
+        // Fresh, so this one doesn't fetch
+        assert_eq!(cache.get(&HttpProxy::default(), &url, "bitcoin", "usd").await.unwrap().value, calc::parse_decimal("65000").unwrap());
+        bitcoin.assert_async().await;
+        // Another coin would need a request, and it's too soon for one
+        let error = cache.get(&HttpProxy::default(), &url, "ethereum", "usd").await.unwrap_err();
+        assert!(error.to_string().contains("too recently"), "{}", error);
+    }
+}
@@ -0,0 +1,91 @@
+//! CTCP (Client-To-Client Protocol) messages: IRC PRIVMSGs wrapped in \x01 delimiters, used for
+//! /me actions and client queries like VERSION and PING.
+//!
+//! Actions are logged in their CTCP form, so the message log keeps the distinction between
+//! "nick: waves" and "* nick waves"; `display_line` renders either for the AI.
+
+use crate::sanitize::strip_invisible;
+
+const DELIMITER: char = '\x01';
+
+/// Reply to CTCP VERSION.
+pub const VERSION_REPLY: &str = concat!("emul ", env!("CARGO_PKG_VERSION"), " (Rust IRC bot)");
+/// Reply to CTCP CLIENTINFO: the commands we understand.
+pub const CLIENTINFO_REPLY: &str = "ACTION CLIENTINFO PING TIME VERSION";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ctcp<'a> {
+    /// A /me action, with the action text.
+    Action(&'a str),
+    Version,
+    /// PING, with the token to echo back.
+    Ping(&'a str),
+    Time,
+    ClientInfo,
+    /// Anything else, with the command name.
+    Other(&'a str),
+}
+
+/// Parses a PRIVMSG body as CTCP. The closing delimiter is optional, as some clients omit it.
+pub fn parse(message: &str) -> Option<Ctcp<'_>> {
+    let body = message.strip_prefix(DELIMITER)?;
+    let body = body.strip_suffix(DELIMITER).unwrap_or(body);
+    let (command, params) = body.split_once(' ').unwrap_or((body, ""));
+    Some(match command.to_ascii_uppercase().as_str() {
+        "ACTION" => Ctcp::Action(params),
+        "VERSION" => Ctcp::Version,
+        "PING" => Ctcp::Ping(params),
+        "TIME" => Ctcp::Time,
+        "CLIENTINFO" => Ctcp::ClientInfo,
+        _ => Ctcp::Other(command),
+    })
+}
+
+/// Encodes a CTCP message (a request in a PRIVMSG, or a reply in a NOTICE).
+pub fn encode(command: &str, params: &str) -> String {
+    if params.is_empty() {
+        format!("{DELIMITER}{command}{DELIMITER}")
+    } else {
+        format!("{DELIMITER}{command} {params}{DELIMITER}")
+    }
+}
+
+/// The action text, if a logged message is a /me action.
+pub fn action_text(message: &str) -> Option<&str> {
+    match parse(message)? {
+        Ctcp::Action(text) => Some(text),
+        _ => None,
+    }
+}
+
+/// Renders a logged chat line as "nick: message", or "* nick waves" for actions,
+/// with invisible characters removed.
+pub fn display_line(nick: &str, message: &str) -> String {
+    let nick = strip_invisible(nick);
+    match action_text(message) {
+        Some(action) => format!("* {} {}", nick, strip_invisible(action)),
+        None => format!("{}: {}", nick, strip_invisible(message)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("\x01ACTION waves at Emul\x01"), Some(Ctcp::Action("waves at Emul")));
+        assert_eq!(parse("\x01ACTION shrugs"), Some(Ctcp::Action("shrugs")));
+        assert_eq!(parse("\x01VERSION\x01"), Some(Ctcp::Version));
+        assert_eq!(parse("\x01PING 1234567\x01"), Some(Ctcp::Ping("1234567")));
+        assert_eq!(parse("\x01FINGER\x01"), Some(Ctcp::Other("FINGER")));
+        assert_eq!(parse("hello \x01there\x01"), None);
+    }
+
+    #[test]
+    fn test_display_line() {
+        assert_eq!(display_line("alice", "\x01ACTION pats Emul\x01"), "* alice pats Emul");
+        assert_eq!(display_line("bob", "hi there"), "bob: hi there");
+        assert_eq!(encode("PING", "42"), "\x01PING 42\x01");
+    }
+}
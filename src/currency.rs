@@ -0,0 +1,181 @@
+//! Exchange rates for the calculate tool. They come from a rates API answering with JSON like
+//! Frankfurter's or open.er-api.com's: a base currency and a `rates` object of how much of each
+//! currency one unit of the base buys. Rates are fetched at most once an hour, and older rates
+//! stand in while the API is down.
+
+use crate::calc;
+use crate::proxy::HttpProxy;
+use anyhow::{Context, Result, bail};
+use num_rational::BigRational;
+use num_traits::{One, Signed};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long fetched rates are used before asking for new ones.
+const FRESH_FOR: Duration = Duration::from_secs(60 * 60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rates {
+    /// The currency the rates are relative to, like EUR.
+    pub base: String,
+    /// The day the rates are from, if the API said.
+    pub date: Option<String>,
+    /// Units of each currency one unit of the base buys, by currency code.
+    per_base: HashMap<String, BigRational>,
+}
+
+impl Rates {
+    pub fn new(base: &str, date: Option<String>, mut per_base: HashMap<String, BigRational>) -> Self {
+        per_base.insert(base.to_string(), BigRational::one());
+        Rates { base: base.to_string(), date, per_base }
+    }
+
+    /// Reads the API's answer. Rates that aren't positive numbers are skipped.
+    pub fn parse(json: &Value) -> Result<Self> {
+        let base = json["base"].as_str().or(json["base_code"].as_str()).context("Exchange rates have no base currency")?;
+        let rates = json["rates"].as_object().context("Exchange rates are missing")?;
+        let per_base: HashMap<String, BigRational> = rates
+            .iter()
+            .filter_map(|(code, rate)| {
+                let rate = calc::parse_decimal(&rate.as_number()?.to_string())?;
+                rate.is_positive().then(|| (code.to_uppercase(), rate))
+            })
+            .collect();
+        if per_base.is_empty() {
+            bail!("The exchange rate service sent no rates");
+        }
+        let date = json["date"].as_str().or(json["time_last_update_utc"].as_str()).map(str::to_string);
+        Ok(Rates::new(&base.to_uppercase(), date, per_base))
+    }
+
+    /// How much of `code` one unit of the base buys.
+    pub fn get(&self, code: &str) -> Option<&BigRational> {
+        self.per_base.get(code)
+    }
+}
+
+struct CachedRates {
+    url: String,
+    fetched: Instant,
+    rates: Arc<Rates>,
+}
+
+pub struct RatesCache {
+    cached: Mutex<Option<CachedRates>>,
+    fresh_for: Duration,
+}
+
+impl RatesCache {
+    pub fn new(fresh_for: Duration) -> Self {
+        RatesCache { cached: Mutex::new(None), fresh_for }
+    }
+
+    /// The rates from `url`, fetched again through `proxy` once the cached ones are stale. If
+    /// that fails, the stale ones are used.
+    pub async fn get(&self, proxy: &HttpProxy, url: &str) -> Result<Arc<Rates>> {
+        let cached = self
+            .cached
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|cached| cached.url == url)
+            .map(|cached| (cached.fetched, cached.rates.clone()));
+        if let Some((fetched, rates)) = &cached
+            && fetched.elapsed() < self.fresh_for
+        {
+            return Ok(rates.clone());
+        }
+
+        match fetch(proxy, url).await {
+            Ok(rates) => {
+                let rates = Arc::new(rates);
+                tracing::info!(%url, base = %rates.base, date = ?rates.date, "Fetched exchange rates");
+                *self.cached.lock().unwrap() = Some(CachedRates { url: url.to_string(), fetched: Instant::now(), rates: rates.clone() });
+                Ok(rates)
+            }
+            Err(e) => match cached {
+                Some((_, rates)) => {
+                    tracing::warn!(%url, "Failed to refresh exchange rates, using older ones: {:#}", e);
+                    Ok(rates)
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+impl Default for RatesCache {
+    fn default() -> Self {
+        RatesCache::new(FRESH_FOR)
+    }
+}
+
+async fn fetch(proxy: &HttpProxy, url: &str) -> Result<Rates> {
+    let json: Value = proxy
+        .client()?
+        .get(url)
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach exchange rate service {}", url))?
+        .error_for_status()
+        .context("Exchange rate service refused the request")?
+        .json()
+        .await
+        .context("Exchange rate service sent invalid JSON")?;
+    Rates::parse(&json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_rates() {
+        let frankfurter = json!({"amount": 1.0, "base": "EUR", "date": "2026-10-15", "rates": {"USD": 1.1642, "JPY": 175.2}});
+        let rates = Rates::parse(&frankfurter).unwrap();
+        assert_eq!(rates.base, "EUR");
+        assert_eq!(rates.date.as_deref(), Some("2026-10-15"));
+        assert_eq!(rates.get("USD"), calc::parse_decimal("1.1642").as_ref());
+        assert_eq!(rates.get("EUR"), Some(&BigRational::one()));
+
+        let open_er_api = json!({"result": "success", "base_code": "usd", "rates": {"USD": 1, "EUR": 0.859, "XXX": -1, "YYY": "n/a"}});
+        let rates = Rates::parse(&open_er_api).unwrap();
+        assert_eq!(rates.base, "USD");
+        assert_eq!(rates.get("EUR"), calc::parse_decimal("0.859").as_ref());
+        assert_eq!(rates.get("XXX"), None);
+        assert_eq!(rates.get("YYY"), None);
+
+        assert!(Rates::parse(&json!({"base": "EUR", "rates": {}})).is_err());
+        assert!(Rates::parse(&json!({"error": "bad key"})).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stale_rates_stand_in_for_failed_fetches() {
+        let mut server = mockito::Server::new_async().await;
+        let ok = server
+            .mock("GET", "/latest")
+            .with_body(r#"{"base": "EUR", "rates": {"USD": 1.25}}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        let url = format!("{}/latest", server.url());
+
+        let cache = RatesCache::default();
+        assert_eq!(cache.get(&HttpProxy::default(), &url).await.unwrap().base, "EUR");
+        // Fresh, so this one doesn't fetch
+        assert_eq!(cache.get(&HttpProxy::default(), &url).await.unwrap().base, "EUR");
+        ok.assert_async().await;
+
+        ok.remove_async().await;
+        let _down = server.mock("GET", "/latest").with_status(503).create_async().await;
+        let stale = RatesCache::new(Duration::ZERO);
+        assert!(stale.get(&HttpProxy::default(), &url).await.is_err());
+        *stale.cached.lock().unwrap() = cache.cached.lock().unwrap().take();
+        assert_eq!(stale.get(&HttpProxy::default(), &url).await.unwrap().get("USD"), calc::parse_decimal("1.25").as_ref());
+    }
+}
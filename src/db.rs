@@ -1,33 +1,196 @@
 use crate::config::LOG_HISTORY_LINES;
-use anyhow::Result;
-use chrono::Utc;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{Connection, OptionalExtension, params};
-use std::{path::Path, sync::Arc};
-use tokio::sync::Mutex;
+use std::path::Path;
+use std::time::Duration;
+
+const DB_POOL_SIZE: u32 = 8;
+const DB_BUSY_TIMEOUT: Duration = Duration::from_secs(5); // How long a writer waits for another's lock
 
 #[derive(Debug, Clone)]
 pub struct LogEntry {
-    //pub timestamp: DateTime<Utc>,
+    pub timestamp: DateTime<Utc>,
+    pub channel: String,
+    pub nick: String,
+    pub message: String,
+}
+
+/// A stored chunk of older conversation, with its embedding for similarity search.
+#[derive(Debug, Clone)]
+pub struct Memory {
+    /// Time of the last message in the chunk.
+    pub timestamp: DateTime<Utc>,
+    pub content: String,
+    pub embedding: Vec<f32>,
+}
+
+/// A channel's rolling summary of its older conversation.
+#[derive(Debug, Clone)]
+pub struct ChannelSummary {
+    pub summary: String,
+    /// The message_log id of the newest message folded into the summary.
+    pub last_message_id: i64,
+}
+
+/// A recurring channel announcement.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub id: i64,
+    pub channel: String,
+    /// Five-field cron pattern, in the server's local time.
+    pub cron: String,
+    pub message: String,
+    pub created_by: String,
+}
+
+/// A channel's subscription to an RSS or Atom feed.
+#[derive(Debug, Clone)]
+pub struct FeedSubscription {
+    pub id: i64,
+    pub channel: String,
+    pub url: String,
+    /// Whether new entries are announced with an AI-written summary.
+    pub summarize: bool,
+    pub added_by: String,
+}
+
+/// A memorable line, saved with `!quote add`.
+#[derive(Debug, Clone)]
+pub struct Quote {
+    pub id: i64,
     pub channel: String,
+    pub text: String,
+    pub added_by: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Something the AI noted about a user with its remember_fact tool.
+#[derive(Debug, Clone)]
+pub struct UserFact {
+    pub id: i64,
     pub nick: String,
+    pub fact: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A line that mentioned one of a user's `!notify` keywords while they were away.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub channel: String,
+    pub sender: String,
     pub message: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A warning given for a message that broke a moderated channel's rules.
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub id: i64,
+    pub nick: String,
+    pub reason: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A tool call the AI made, kept for `!toollog`.
+#[derive(Debug, Clone)]
+pub struct ToolCall {
+    pub id: i64,
+    pub timestamp: DateTime<Utc>,
+    pub channel: String,
+    /// Who the AI was answering.
+    pub nick: String,
+    pub tool: String,
+    /// The arguments, as JSON.
+    pub args: String,
+    /// What the tool returned, as JSON, if it succeeded.
+    pub result: Option<String>,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+}
+
+/// A call to a `confirm` tool, waiting for an admin to `!approve` or `!deny` it.
+#[derive(Debug, Clone)]
+pub struct PendingToolCall {
+    pub id: i64,
+    pub timestamp: DateTime<Utc>,
+    pub channel: String,
+    /// Who the AI was answering.
+    pub nick: String,
+    pub tool: String,
+    /// The arguments, as JSON.
+    pub args: String,
+}
+
+/// A pool of SQLite connections. Queries run on tokio's blocking thread pool, so they neither
+/// stall the async runtime nor wait on each other (SQLite's WAL mode lets readers run alongside
+/// the single writer).
+#[derive(Clone, Debug)]
+pub struct DbPool {
+    pool: r2d2::Pool<SqliteConnectionManager>,
 }
 
-pub type DbConnection = Arc<Mutex<rusqlite::Connection>>;
+impl DbPool {
+    /// Runs `f` with a pooled connection, off the async runtime.
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get().context("Failed to get a database connection")?;
+            f(&conn)
+        })
+        .await
+        .context("Database task panicked")?
+    }
+}
 
 // --- Initialization ---
 
-pub fn init_db(db_path: impl AsRef<Path>) -> Result<DbConnection> {
-    let conn = Connection::open(db_path)?;
+pub fn init_db(db_path: impl AsRef<Path>) -> Result<DbPool> {
+    let manager = SqliteConnectionManager::file(db_path).with_init(|conn| conn.busy_timeout(DB_BUSY_TIMEOUT));
+    let pool = r2d2::Pool::builder()
+        .max_size(DB_POOL_SIZE)
+        .build(manager)
+        .context("Failed to open database")?;
+    init_schema(pool)
+}
+
+/// A fresh database that lives only in memory, for tests and throwaway bots. Every in-memory
+/// connection is its own database, so the pool holds just one and never retires it.
+pub fn init_memory_db() -> Result<DbPool> {
+    let pool = r2d2::Pool::builder()
+        .max_size(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .build(SqliteConnectionManager::memory())
+        .context("Failed to open in-memory database")?;
+    init_schema(pool)
+}
+
+fn init_schema(pool: r2d2::Pool<SqliteConnectionManager>) -> Result<DbPool> {
+    let conn = pool.get()?;
+    let journal_mode: String = conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+    tracing::debug!(%journal_mode, "Database journal mode set");
     conn.execute_batch(
         "BEGIN;
         -- Channels to auto-join
         CREATE TABLE IF NOT EXISTS channels (
             channel_name TEXT PRIMARY KEY COLLATE NOCASE
         );
-        -- Admin users
-        CREATE TABLE IF NOT EXISTS admins (
-            nick TEXT PRIMARY KEY COLLATE NOCASE
+        -- Users trusted with commands, and how far: 'moderator', 'admin' or 'owner'
+        CREATE TABLE IF NOT EXISTS user_permissions (
+            nick TEXT PRIMARY KEY COLLATE NOCASE,
+            level TEXT NOT NULL
+        );
+        -- Users the bot neither logs nor answers: ignored by an admin, or opted out themselves
+        CREATE TABLE IF NOT EXISTS ignored_users (
+            nick TEXT PRIMARY KEY COLLATE NOCASE,
+            opted_out INTEGER NOT NULL, -- 1 if the user asked for it with !optout
+            created_at INTEGER NOT NULL -- Unix timestamp (seconds)
         );
         -- Message log per channel
         CREATE TABLE IF NOT EXISTS message_log (
@@ -35,27 +198,223 @@ pub fn init_db(db_path: impl AsRef<Path>) -> Result<DbConnection> {
             channel_name TEXT COLLATE NOCASE NOT NULL,
             timestamp INTEGER NOT NULL, -- Unix timestamp (seconds)
             nick TEXT NOT NULL,
-            message TEXT NOT NULL
+            message TEXT NOT NULL,
+            msgid TEXT -- The server's IRCv3 message id, if it sent one
         );
         -- Index for faster log retrieval
         CREATE INDEX IF NOT EXISTS idx_message_log_channel_time
         ON message_log (channel_name, timestamp DESC);
+        -- For !seen
+        CREATE INDEX IF NOT EXISTS idx_message_log_channel_nick
+        ON message_log (channel_name, nick COLLATE NOCASE);
+        -- Private conversations with the AI, one per user
+        CREATE TABLE IF NOT EXISTS dm_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user TEXT COLLATE NOCASE NOT NULL, -- Who the conversation is with
+            timestamp INTEGER NOT NULL, -- Unix timestamp (seconds)
+            nick TEXT NOT NULL, -- Who said it: the user or the bot
+            message TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_dm_log_user
+        ON dm_log (user, id);
+        -- Facts the AI noted about users, per network (an IRC server, or discord)
+        CREATE TABLE IF NOT EXISTS user_facts (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            network TEXT COLLATE NOCASE NOT NULL,
+            nick TEXT COLLATE NOCASE NOT NULL,
+            fact TEXT NOT NULL,
+            created_at INTEGER NOT NULL -- Unix timestamp (seconds)
+        );
+        CREATE INDEX IF NOT EXISTS idx_user_facts_nick
+        ON user_facts (network, nick);
+        -- Words that users want to hear about when they're away, set with !notify
+        CREATE TABLE IF NOT EXISTS notify_keywords (
+            nick TEXT COLLATE NOCASE NOT NULL,
+            keyword TEXT COLLATE NOCASE NOT NULL,
+            PRIMARY KEY (nick, keyword)
+        );
+        -- Lines that mentioned them, kept until they next speak
+        CREATE TABLE IF NOT EXISTS notifications (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            nick TEXT COLLATE NOCASE NOT NULL,
+            channel_name TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            message TEXT NOT NULL,
+            created_at INTEGER NOT NULL -- Unix timestamp (seconds)
+        );
+        CREATE INDEX IF NOT EXISTS idx_notifications_nick
+        ON notifications (nick);
+        -- Warnings for messages that broke a moderated channel's rules, cleared with !pardon
+        CREATE TABLE IF NOT EXISTS moderation_warnings (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            channel_name TEXT COLLATE NOCASE NOT NULL,
+            nick TEXT COLLATE NOCASE NOT NULL,
+            reason TEXT NOT NULL,
+            created_at INTEGER NOT NULL -- Unix timestamp (seconds)
+        );
+        CREATE INDEX IF NOT EXISTS idx_moderation_warnings_nick
+        ON moderation_warnings (channel_name, nick, created_at);
+        -- Outcome of each AI request (finish reason or error class)
+        CREATE TABLE IF NOT EXISTS ai_stats (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            channel_name TEXT COLLATE NOCASE NOT NULL,
+            timestamp INTEGER NOT NULL, -- Unix timestamp (seconds)
+            outcome TEXT NOT NULL -- e.g. STOP, MAX_TOKENS, SAFETY, ERROR
+        );
+        -- Tokens used per channel per day
+        CREATE TABLE IF NOT EXISTS token_usage (
+            channel_name TEXT COLLATE NOCASE NOT NULL,
+            day TEXT NOT NULL, -- YYYY-MM-DD (UTC)
+            prompt_tokens INTEGER NOT NULL DEFAULT 0,
+            output_tokens INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (channel_name, day)
+        );
+        -- Embedded chunks of past conversation for long-term recall
+        CREATE TABLE IF NOT EXISTS memories (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            channel_name TEXT COLLATE NOCASE NOT NULL,
+            timestamp INTEGER NOT NULL, -- Unix timestamp (seconds) of the chunk's last message
+            last_message_id INTEGER NOT NULL, -- message_log id of the chunk's last message
+            content TEXT NOT NULL,
+            embedding BLOB NOT NULL -- Little-endian f32 values
+        );
+        CREATE INDEX IF NOT EXISTS idx_memories_channel
+        ON memories (channel_name, last_message_id);
+        -- Rolling summary of each channel's conversation, updated by the fast model
+        CREATE TABLE IF NOT EXISTS channel_summaries (
+            channel_name TEXT PRIMARY KEY COLLATE NOCASE,
+            summary TEXT NOT NULL,
+            last_message_id INTEGER NOT NULL, -- message_log id of the newest summarized message
+            updated_at INTEGER NOT NULL -- Unix timestamp (seconds)
+        );
+        -- Karma scores per channel, from 'nick++' and 'nick--'
+        CREATE TABLE IF NOT EXISTS karma (
+            channel_name TEXT COLLATE NOCASE NOT NULL,
+            nick TEXT COLLATE NOCASE NOT NULL,
+            score INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (channel_name, nick)
+        );
+        -- Recurring channel announcements, managed with !schedule
+        CREATE TABLE IF NOT EXISTS schedules (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            channel_name TEXT COLLATE NOCASE NOT NULL,
+            cron TEXT NOT NULL,
+            message TEXT NOT NULL,
+            created_by TEXT NOT NULL,
+            created_at INTEGER NOT NULL -- Unix timestamp (seconds)
+        );
+        -- RSS/Atom feeds announced in channels, managed with !feed
+        CREATE TABLE IF NOT EXISTS feeds (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            channel_name TEXT COLLATE NOCASE NOT NULL,
+            url TEXT NOT NULL,
+            summarize INTEGER NOT NULL DEFAULT 0,
+            added_by TEXT NOT NULL,
+            created_at INTEGER NOT NULL, -- Unix timestamp (seconds)
+            UNIQUE (channel_name, url)
+        );
+        -- Feed entries already announced (or skipped), so each is announced once
+        CREATE TABLE IF NOT EXISTS feed_entries (
+            feed_id INTEGER NOT NULL,
+            entry_id TEXT NOT NULL,
+            seen_at INTEGER NOT NULL, -- Unix timestamp (seconds)
+            PRIMARY KEY (feed_id, entry_id)
+        );
+        -- Quotes saved per channel, managed with !quote
+        CREATE TABLE IF NOT EXISTS quotes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            channel_name TEXT COLLATE NOCASE NOT NULL,
+            quote TEXT NOT NULL,
+            added_by TEXT NOT NULL,
+            created_at INTEGER NOT NULL -- Unix timestamp (seconds)
+        );
+        CREATE INDEX IF NOT EXISTS idx_quotes_channel
+        ON quotes (channel_name);
+        -- Channels where the titles of posted links are announced
+        CREATE TABLE IF NOT EXISTS url_title_channels (
+            channel_name TEXT PRIMARY KEY COLLATE NOCASE
+        );
+        -- Per-channel overrides of AI behaviour, managed with !set
+        CREATE TABLE IF NOT EXISTS channel_settings (
+            channel_name TEXT COLLATE NOCASE NOT NULL,
+            key TEXT NOT NULL,
+            value TEXT NOT NULL,
+            PRIMARY KEY (channel_name, key)
+        );
+        -- Every tool call the AI made, for !toollog
+        CREATE TABLE IF NOT EXISTS tool_calls (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp INTEGER NOT NULL, -- Unix timestamp (seconds)
+            channel_name TEXT COLLATE NOCASE NOT NULL,
+            nick TEXT COLLATE NOCASE NOT NULL,
+            tool TEXT COLLATE NOCASE NOT NULL,
+            args TEXT NOT NULL, -- JSON
+            result TEXT, -- JSON, NULL if the call failed
+            error TEXT,
+            duration_ms INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_tool_calls_time
+        ON tool_calls (timestamp);
+        -- Tool calls waiting for an admin's !approve
+        CREATE TABLE IF NOT EXISTS pending_tool_calls (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp INTEGER NOT NULL, -- Unix timestamp (seconds)
+            channel_name TEXT NOT NULL,
+            nick TEXT NOT NULL,
+            tool TEXT NOT NULL,
+            args TEXT NOT NULL -- JSON
+        );
         COMMIT;",
     )?;
+    migrate_admins(&conn)?;
+    migrate_message_ids(&conn)?;
     tracing::info!("Database initialized successfully");
-    Ok(Arc::new(Mutex::new(conn)))
+    Ok(DbPool { pool })
 }
 
-pub fn add_initial_admin(conn: &Connection, admin_nick: &str) -> Result<()> {
-    let count: u32 = conn.query_row("SELECT COUNT(*) FROM admins", [], |row| row.get(0))?;
+/// Moves the admins from before permission levels existed into `user_permissions`. They could do
+/// everything, so they become owners.
+fn migrate_admins(conn: &Connection) -> Result<()> {
+    let has_admins_table: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'admins')",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_admins_table {
+        conn.execute_batch(
+            "BEGIN;
+            INSERT OR IGNORE INTO user_permissions (nick, level) SELECT nick, 'owner' FROM admins;
+            DROP TABLE admins;
+            COMMIT;",
+        )?;
+        tracing::info!("Moved admins to permission levels, as owners");
+    }
+    Ok(())
+}
+
+/// Adds the msgid column to message logs from before it existed.
+fn migrate_message_ids(conn: &Connection) -> Result<()> {
+    let has_msgid: bool =
+        conn.query_row("SELECT EXISTS (SELECT 1 FROM pragma_table_info('message_log') WHERE name = 'msgid')", [], |row| row.get(0))?;
+    if !has_msgid {
+        conn.execute_batch("ALTER TABLE message_log ADD COLUMN msgid TEXT;")?;
+        tracing::info!("Added message ids to the message log");
+    }
+    Ok(())
+}
+
+/// Makes `owner_nick` an owner if the database has none, so there is always someone to run the bot.
+pub fn add_initial_owner(conn: &Connection, owner_nick: &str) -> Result<()> {
+    let count: u32 = conn.query_row("SELECT COUNT(*) FROM user_permissions WHERE level = 'owner'", [], |row| row.get(0))?;
     if count == 0 {
         conn.execute(
-            "INSERT OR IGNORE INTO admins (nick) VALUES (?)",
-            params![admin_nick],
+            "INSERT INTO user_permissions (nick, level) VALUES (?1, 'owner')
+            ON CONFLICT (nick) DO UPDATE SET level = excluded.level",
+            params![owner_nick],
         )?;
-        tracing::info!(initial_admin = %admin_nick, "Initial admin added.");
+        tracing::info!(initial_owner = %owner_nick, "Initial owner added.");
     } else {
-        tracing::debug!("Admin table not empty, skipping initial admin add.");
+        tracing::debug!("Database already has an owner, skipping initial owner add.");
     }
     Ok(())
 }
@@ -88,59 +447,275 @@ pub fn remove_channel(conn: &Connection, channel: &str) -> Result<bool> {
     Ok(changes > 0)
 }
 
-// --- Admin Management ---
+/// Turns link title announcements on or off for a channel. Returns whether anything changed.
+pub fn set_url_titles(conn: &Connection, channel: &str, enabled: bool) -> Result<bool> {
+    let changes = if enabled {
+        conn.execute(
+            "INSERT OR IGNORE INTO url_title_channels (channel_name) VALUES (?)",
+            params![channel],
+        )?
+    } else {
+        conn.execute(
+            "DELETE FROM url_title_channels WHERE channel_name = ?",
+            params![channel],
+        )?
+    };
+    Ok(changes > 0)
+}
 
-pub fn is_admin(conn: &Connection, nick: &str) -> Result<bool> {
-    let is_admin = conn
+pub fn url_titles_enabled(conn: &Connection, channel: &str) -> Result<bool> {
+    let enabled = conn
         .query_row(
-            "SELECT 1 FROM admins WHERE nick = ? COLLATE NOCASE", // Ensure case-insensitive check
-            params![nick],
-            |_| Ok(true), // If row exists, return true
+            "SELECT 1 FROM url_title_channels WHERE channel_name = ?",
+            params![channel],
+            |_| Ok(true),
         )
         .optional()?
         .is_some();
-    Ok(is_admin)
+    Ok(enabled)
 }
 
-pub fn add_admin(conn: &Connection, nick: &str) -> Result<bool> {
+/// The channel's setting overrides, as (key, value) pairs.
+pub fn get_channel_settings(conn: &Connection, channel: &str) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare("SELECT key, value FROM channel_settings WHERE channel_name = ? ORDER BY key")?;
+    let rows = stmt.query_map(params![channel], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut settings = Vec::new();
+    for setting in rows {
+        settings.push(setting?);
+    }
+    Ok(settings)
+}
+
+pub fn set_channel_setting(conn: &Connection, channel: &str, key: &str, value: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO channel_settings (channel_name, key, value) VALUES (?1, ?2, ?3)
+         ON CONFLICT (channel_name, key) DO UPDATE SET value = excluded.value",
+        params![channel, key, value],
+    )?;
+    Ok(())
+}
+
+/// Removes a channel's override, returning it to the default. Returns false if there was none.
+pub fn remove_channel_setting(conn: &Connection, channel: &str, key: &str) -> Result<bool> {
     let changes = conn.execute(
-        "INSERT OR IGNORE INTO admins (nick) VALUES (?)",
-        params![nick],
+        "DELETE FROM channel_settings WHERE channel_name = ? AND key = ?",
+        params![channel, key],
     )?;
     Ok(changes > 0)
 }
 
-pub fn remove_admin(conn: &Connection, nick: &str) -> Result<bool> {
-    let changes = conn.execute("DELETE FROM admins WHERE nick = ?", params![nick])?;
+// --- Permission Levels ---
+
+/// The nick's permission level, if it has been granted one.
+pub fn get_permission_level(conn: &Connection, nick: &str) -> Result<Option<String>> {
+    let level = conn
+        .query_row("SELECT level FROM user_permissions WHERE nick = ?", params![nick], |row| row.get(0))
+        .optional()?;
+    Ok(level)
+}
+
+/// Grants the nick a permission level. Returns false if it already had that level.
+pub fn set_permission_level(conn: &Connection, nick: &str, level: &str) -> Result<bool> {
+    let changes = conn.execute(
+        "INSERT INTO user_permissions (nick, level) VALUES (?1, ?2)
+        ON CONFLICT (nick) DO UPDATE SET level = excluded.level WHERE level != excluded.level",
+        params![nick, level],
+    )?;
     Ok(changes > 0)
 }
 
-pub fn get_admins(conn: &Connection) -> Result<Vec<String>> {
-    let mut stmt = conn.prepare("SELECT nick FROM admins ORDER BY nick")?;
-    let admin_iter = stmt.query_map([], |row| row.get(0))?;
-    let mut admins = Vec::new();
-    for admin in admin_iter {
-        admins.push(admin?);
+pub fn remove_permission_level(conn: &Connection, nick: &str) -> Result<bool> {
+    let changes = conn.execute("DELETE FROM user_permissions WHERE nick = ?", params![nick])?;
+    Ok(changes > 0)
+}
+
+/// Every nick with a permission level, as (nick, level) pairs.
+pub fn get_permission_levels(conn: &Connection) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare("SELECT nick, level FROM user_permissions ORDER BY nick")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut levels = Vec::new();
+    for level in rows {
+        levels.push(level?);
     }
-    Ok(admins)
+    Ok(levels)
+}
+
+// --- Ignored Users ---
+
+/// Adds a nick to the ignore list. Returns false if it was already on it.
+pub fn ignore_user(conn: &Connection, nick: &str, opted_out: bool) -> Result<bool> {
+    let changes = conn.execute(
+        "INSERT OR IGNORE INTO ignored_users (nick, opted_out, created_at) VALUES (?1, ?2, ?3)",
+        params![nick, opted_out, Utc::now().timestamp()],
+    )?;
+    Ok(changes > 0)
+}
+
+/// Removes a nick from the ignore list. With `only_opted_out`, nicks an admin ignored stay.
+pub fn unignore_user(conn: &Connection, nick: &str, only_opted_out: bool) -> Result<bool> {
+    let changes = conn.execute(
+        "DELETE FROM ignored_users WHERE nick = ?1 AND (opted_out = 1 OR NOT ?2)",
+        params![nick, only_opted_out],
+    )?;
+    Ok(changes > 0)
+}
+
+pub fn is_ignored(conn: &Connection, nick: &str) -> Result<bool> {
+    let ignored = conn
+        .query_row("SELECT 1 FROM ignored_users WHERE nick = ?", params![nick], |_| Ok(true))
+        .optional()?
+        .is_some();
+    Ok(ignored)
+}
+
+/// All ignored nicks, paired with whether they opted out themselves.
+pub fn get_ignored_users(conn: &Connection) -> Result<Vec<(String, bool)>> {
+    let mut stmt = conn.prepare("SELECT nick, opted_out FROM ignored_users ORDER BY nick")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut users = Vec::new();
+    for user in rows {
+        users.push(user?);
+    }
+    Ok(users)
+}
+
+/// Deletes everything a nick has said from the message log, returning how many lines went.
+/// The memories and channel summaries made from their lines go too; summaries are rebuilt from
+/// what's left of the log.
+pub fn delete_user_messages(conn: &Connection, nick: &str) -> Result<usize> {
+    // A memory covers the lines after the channel's previous memory, up to its last_message_id
+    let memories = conn.execute(
+        "DELETE FROM memories WHERE EXISTS (
+            SELECT 1 FROM message_log m
+            WHERE m.nick = ?1 COLLATE NOCASE
+              AND m.channel_name = memories.channel_name
+              AND m.id <= memories.last_message_id
+              AND m.id > COALESCE((
+                  SELECT MAX(p.last_message_id) FROM memories p
+                  WHERE p.channel_name = memories.channel_name AND p.last_message_id < memories.last_message_id
+              ), 0)
+        )",
+        params![nick],
+    )?;
+    let summaries = conn.execute(
+        "DELETE FROM channel_summaries WHERE EXISTS (
+            SELECT 1 FROM message_log m
+            WHERE m.nick = ?1 COLLATE NOCASE
+              AND m.channel_name = channel_summaries.channel_name
+              AND m.id <= channel_summaries.last_message_id
+        )",
+        params![nick],
+    )?;
+    tracing::debug!(%nick, memories, summaries, "Deleted memories and summaries made from a user's lines");
+    let changes = conn.execute("DELETE FROM message_log WHERE nick = ? COLLATE NOCASE", params![nick])?;
+    // Their lines saved for others' !notify digests go too
+    let notifications = conn.execute("DELETE FROM notifications WHERE sender = ? COLLATE NOCASE", params![nick])?;
+    Ok(changes + notifications + delete_dm_log(conn, nick)?)
 }
 
 // --- Message Logging ---
 
 pub fn log_message(conn: &Connection, channel: &str, nick: &str, message: &str) -> Result<()> {
-    let channel = channel.to_string();
-    let nick = nick.to_string();
-    let message = message.to_string();
-    let timestamp = Utc::now().timestamp();
+    log_message_at(conn, channel, nick, message, Utc::now(), None)
+}
 
+/// Logs a message as sent at `timestamp`, such as the time an IRC server stamped on it, with the
+/// server's id for it if there is one.
+pub fn log_message_at(
+    conn: &Connection,
+    channel: &str,
+    nick: &str,
+    message: &str,
+    timestamp: DateTime<Utc>,
+    msgid: Option<&str>,
+) -> Result<()> {
     conn.execute(
-        "INSERT INTO message_log (channel_name, timestamp, nick, message) VALUES (?, ?, ?, ?)",
-        params![channel, timestamp, nick, message],
+        "INSERT INTO message_log (channel_name, timestamp, nick, message, msgid) VALUES (?, ?, ?, ?, ?)",
+        params![channel, timestamp.timestamp(), nick, message, msgid],
     )?;
     // Optional: Add log cleaning here (e.g., DELETE FROM message_log WHERE timestamp < ?)
     Ok(())
 }
 
+/// Logs a line the server replayed from its history, unless it's logged already: under the same
+/// msgid, or as the same words from the same nick within a minute. Returns whether it was new.
+pub fn log_replayed_message(
+    conn: &Connection,
+    channel: &str,
+    nick: &str,
+    message: &str,
+    timestamp: DateTime<Utc>,
+    msgid: Option<&str>,
+) -> Result<bool> {
+    let logged = conn
+        .query_row(
+            "SELECT 1 FROM message_log WHERE channel_name = ?1
+            AND ((?5 IS NOT NULL AND msgid = ?5) OR (nick = ?2 AND message = ?3 AND timestamp BETWEEN ?4 - 60 AND ?4 + 60))
+            LIMIT 1",
+            params![channel, nick, message, timestamp.timestamp(), msgid],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if logged {
+        return Ok(false);
+    }
+    log_message_at(conn, channel, nick, message, timestamp, msgid)?;
+    Ok(true)
+}
+
+/// When the latest line logged in the channel was said, if anything has been.
+pub fn get_last_log_time(conn: &Connection, channel: &str) -> Result<Option<DateTime<Utc>>> {
+    let timestamp: Option<i64> =
+        conn.query_row("SELECT MAX(timestamp) FROM message_log WHERE channel_name = ?", params![channel], |row| row.get(0))?;
+    Ok(timestamp.and_then(|secs| DateTime::from_timestamp(secs, 0)))
+}
+
+/// The nick's latest message in the channel, if it has said anything there.
+pub fn get_last_seen(conn: &Connection, channel: &str, nick: &str) -> Result<Option<LogEntry>> {
+    let entry = conn
+        .query_row(
+            "SELECT timestamp, nick, message FROM message_log
+            WHERE channel_name = ?1 AND nick = ?2 COLLATE NOCASE
+            ORDER BY id DESC LIMIT 1",
+            params![channel, nick],
+            |row| {
+                let timestamp_secs: i64 = row.get(0)?;
+                Ok(LogEntry {
+                    timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(Utc::now),
+                    channel: channel.to_string(),
+                    nick: row.get(1)?,
+                    message: row.get(2)?,
+                })
+            },
+        )
+        .optional()?;
+    Ok(entry)
+}
+
+/// A channel's logged messages between two Unix timestamps (from inclusive, to exclusive), oldest first.
+pub fn get_log_between(conn: &Connection, channel: &str, from: i64, to: i64) -> Result<Vec<LogEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT timestamp, nick, message FROM message_log
+            WHERE channel_name = ?1 AND timestamp >= ?2 AND timestamp < ?3
+            ORDER BY id",
+    )?;
+    let rows = stmt.query_map(params![channel, from, to], |row| {
+        let timestamp_secs: i64 = row.get(0)?;
+        Ok(LogEntry {
+            timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(Utc::now),
+            channel: channel.to_string(),
+            nick: row.get(1)?,
+            message: row.get(2)?,
+        })
+    })?;
+    let mut entries = Vec::new();
+    for entry in rows {
+        entries.push(entry?);
+    }
+    Ok(entries)
+}
+
 pub fn get_channel_log(conn: &Connection, channel: &str) -> Result<Vec<LogEntry>> {
     let channel = channel.to_string();
     let limit = LOG_HISTORY_LINES as i64;
@@ -157,10 +732,9 @@ pub fn get_channel_log(conn: &Connection, channel: &str) -> Result<Vec<LogEntry>
             ) ORDER BY timestamp ASC",
     )?;
     let entry_iter = stmt.query_map(params![channel, limit], |row| {
-        //let timestamp_secs: i64 = row.get(0)?;
+        let timestamp_secs: i64 = row.get(0)?;
         Ok(LogEntry {
-            // Use timestamp_opt for safe conversion
-            //timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(|| Utc::now()), // Fallback if invalid
+            timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(Utc::now), // Fallback if invalid
             channel: channel.clone(),
             nick: row.get(1)?,
             message: row.get(2)?,
@@ -172,3 +746,843 @@ pub fn get_channel_log(conn: &Connection, channel: &str) -> Result<Vec<LogEntry>
     }
     Ok(result)
 }
+
+// --- Private Conversations ---
+
+/// Logs a line of the private conversation with `user`, a services account, said by `nick`:
+/// the user or the bot.
+pub fn log_dm(conn: &Connection, user: &str, nick: &str, message: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO dm_log (user, timestamp, nick, message) VALUES (?, ?, ?, ?)",
+        params![user, Utc::now().timestamp(), nick, message],
+    )?;
+    Ok(())
+}
+
+/// The last `limit` lines of the private conversation with `user`, oldest first. Accounts are
+/// matched without regard to case, as services do. Their channel is the account.
+pub fn get_dm_log(conn: &Connection, user: &str, limit: usize) -> Result<Vec<LogEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT timestamp, nick, message
+            FROM (
+                SELECT id, timestamp, nick, message
+                FROM dm_log
+                WHERE user = ?1 COLLATE NOCASE
+                ORDER BY id DESC
+                LIMIT ?2
+            ) ORDER BY id ASC",
+    )?;
+    let entry_iter = stmt.query_map(params![user, limit as i64], |row| {
+        let timestamp_secs: i64 = row.get(0)?;
+        Ok(LogEntry {
+            timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(Utc::now),
+            channel: user.to_string(),
+            nick: row.get(1)?,
+            message: row.get(2)?,
+        })
+    })?;
+    let mut result = Vec::new();
+    for entry in entry_iter {
+        result.push(entry?);
+    }
+    Ok(result)
+}
+
+/// Forgets the private conversation with `user`, returning how many lines it had.
+pub fn delete_dm_log(conn: &Connection, user: &str) -> Result<usize> {
+    let changes = conn.execute("DELETE FROM dm_log WHERE user = ? COLLATE NOCASE", params![user])?;
+    Ok(changes)
+}
+
+/// Up to `limit` log entries for a channel with ids greater than `after_id`, oldest first,
+/// paired with their ids.
+pub fn get_log_after(conn: &Connection, channel: &str, after_id: i64, limit: usize) -> Result<Vec<(i64, LogEntry)>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, timestamp, nick, message
+            FROM message_log
+            WHERE channel_name = ?1 AND id > ?2
+            ORDER BY id ASC
+            LIMIT ?3",
+    )?;
+    let entry_iter = stmt.query_map(params![channel, after_id, limit as i64], |row| {
+        let timestamp_secs: i64 = row.get(1)?;
+        Ok((
+            row.get(0)?,
+            LogEntry {
+                timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(Utc::now),
+                channel: channel.to_string(),
+                nick: row.get(2)?,
+                message: row.get(3)?,
+            },
+        ))
+    })?;
+    let mut result = Vec::new();
+    for entry in entry_iter {
+        result.push(entry?);
+    }
+    Ok(result)
+}
+
+/// The newest `limit` log entries for a channel with ids greater than `after_id`, oldest first,
+/// paired with their ids.
+pub fn get_latest_log_after(conn: &Connection, channel: &str, after_id: i64, limit: usize) -> Result<Vec<(i64, LogEntry)>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, timestamp, nick, message
+            FROM (
+                SELECT id, timestamp, nick, message
+                FROM message_log
+                WHERE channel_name = ?1 AND id > ?2
+                ORDER BY id DESC
+                LIMIT ?3
+            ) ORDER BY id ASC",
+    )?;
+    let entry_iter = stmt.query_map(params![channel, after_id, limit as i64], |row| {
+        let timestamp_secs: i64 = row.get(1)?;
+        Ok((
+            row.get(0)?,
+            LogEntry {
+                timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(Utc::now),
+                channel: channel.to_string(),
+                nick: row.get(2)?,
+                message: row.get(3)?,
+            },
+        ))
+    })?;
+    let mut result = Vec::new();
+    for entry in entry_iter {
+        result.push(entry?);
+    }
+    Ok(result)
+}
+
+// --- Channel Summaries ---
+
+pub fn get_channel_summary(conn: &Connection, channel: &str) -> Result<Option<ChannelSummary>> {
+    let summary = conn
+        .query_row(
+            "SELECT summary, last_message_id FROM channel_summaries WHERE channel_name = ?",
+            params![channel],
+            |row| {
+                Ok(ChannelSummary {
+                    summary: row.get(0)?,
+                    last_message_id: row.get(1)?,
+                })
+            },
+        )
+        .optional()?;
+    Ok(summary)
+}
+
+pub fn set_channel_summary(conn: &Connection, channel: &str, summary: &str, last_message_id: i64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO channel_summaries (channel_name, summary, last_message_id, updated_at) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (channel_name) DO UPDATE SET
+                summary = excluded.summary,
+                last_message_id = excluded.last_message_id,
+                updated_at = excluded.updated_at",
+        params![channel, summary, last_message_id, Utc::now().timestamp()],
+    )?;
+    Ok(())
+}
+
+/// Channels with at least `min_new_lines` messages logged since their last summary (or ever, if
+/// they have none).
+pub fn get_channels_needing_summary(conn: &Connection, min_new_lines: usize) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT m.channel_name
+            FROM message_log m
+            LEFT JOIN channel_summaries s ON s.channel_name = m.channel_name
+            WHERE m.id > COALESCE(s.last_message_id, 0)
+            GROUP BY m.channel_name
+            HAVING COUNT(*) >= ?",
+    )?;
+    let rows = stmt.query_map(params![min_new_lines as i64], |row| row.get(0))?;
+    let mut channels = Vec::new();
+    for channel in rows {
+        channels.push(channel?);
+    }
+    Ok(channels)
+}
+
+// --- Token Usage ---
+
+pub fn record_token_usage(conn: &Connection, channel: &str, day: &str, prompt_tokens: u64, output_tokens: u64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO token_usage (channel_name, day, prompt_tokens, output_tokens) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (channel_name, day) DO UPDATE SET
+                prompt_tokens = prompt_tokens + excluded.prompt_tokens,
+                output_tokens = output_tokens + excluded.output_tokens",
+        params![channel, day, prompt_tokens as i64, output_tokens as i64],
+    )?;
+    Ok(())
+}
+
+/// Total tokens used on a day: across all channels, or just one if `channel` is given.
+pub fn get_tokens_used(conn: &Connection, day: &str, channel: Option<&str>) -> Result<u64> {
+    let total: i64 = conn.query_row(
+        "SELECT COALESCE(SUM(prompt_tokens + output_tokens), 0) FROM token_usage
+            WHERE day = ?1 AND (?2 IS NULL OR channel_name = ?2)",
+        params![day, channel],
+        |row| row.get(0),
+    )?;
+    Ok(total as u64)
+}
+
+// --- Long-Term Memory ---
+
+/// The message_log id of the newest message already covered by a memory, or 0.
+pub fn latest_memory_message_id(conn: &Connection, channel: &str) -> Result<i64> {
+    let id: Option<i64> = conn.query_row(
+        "SELECT MAX(last_message_id) FROM memories WHERE channel_name = ?",
+        params![channel],
+        |row| row.get(0),
+    )?;
+    Ok(id.unwrap_or(0))
+}
+
+/// Stores a memory unless another task already covered these messages. Returns whether it was added.
+pub fn add_memory(
+    conn: &Connection,
+    channel: &str,
+    timestamp: DateTime<Utc>,
+    last_message_id: i64,
+    content: &str,
+    embedding: &[f32],
+) -> Result<bool> {
+    let blob: Vec<u8> = embedding.iter().flat_map(|v| v.to_le_bytes()).collect();
+    let changes = conn.execute(
+        "INSERT INTO memories (channel_name, timestamp, last_message_id, content, embedding)
+            SELECT ?1, ?2, ?3, ?4, ?5
+            WHERE NOT EXISTS (
+                SELECT 1 FROM memories WHERE channel_name = ?1 AND last_message_id >= ?3
+            )",
+        params![channel, timestamp.timestamp(), last_message_id, content, blob],
+    )?;
+    Ok(changes > 0)
+}
+
+pub fn get_memories(conn: &Connection, channel: &str) -> Result<Vec<Memory>> {
+    let mut stmt = conn.prepare(
+        "SELECT timestamp, content, embedding FROM memories WHERE channel_name = ? ORDER BY timestamp ASC",
+    )?;
+    let memory_iter = stmt.query_map(params![channel], |row| {
+        let timestamp_secs: i64 = row.get(0)?;
+        let blob: Vec<u8> = row.get(2)?;
+        Ok(Memory {
+            timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(Utc::now),
+            content: row.get(1)?,
+            embedding: blob
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        })
+    })?;
+    let mut result = Vec::new();
+    for memory in memory_iter {
+        result.push(memory?);
+    }
+    Ok(result)
+}
+
+// --- AI Statistics ---
+
+pub fn record_ai_outcome(conn: &Connection, channel: &str, outcome: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO ai_stats (channel_name, timestamp, outcome) VALUES (?, ?, ?)",
+        params![channel, Utc::now().timestamp(), outcome],
+    )?;
+    Ok(())
+}
+
+/// Counts of each AI outcome for a channel since the given Unix timestamp, most common first.
+pub fn get_ai_outcome_counts(conn: &Connection, channel: &str, since: i64) -> Result<Vec<(String, u32)>> {
+    let mut stmt = conn.prepare(
+        "SELECT outcome, COUNT(*) FROM ai_stats
+            WHERE channel_name = ?1 AND timestamp >= ?2
+            GROUP BY outcome
+            ORDER BY COUNT(*) DESC",
+    )?;
+    let rows = stmt.query_map(params![channel, since], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row?);
+    }
+    Ok(result)
+}
+
+// --- Channel Statistics ---
+
+/// How many messages a channel has had since the given Unix timestamp, leaving out
+/// `exclude_nick`, and when the first of them was sent.
+pub fn get_message_count(conn: &Connection, channel: &str, since: i64, exclude_nick: &str) -> Result<(u32, Option<i64>)> {
+    let result = conn.query_row(
+        "SELECT COUNT(*), MIN(timestamp) FROM message_log
+            WHERE channel_name = ?1 AND timestamp >= ?2 AND nick <> ?3 COLLATE NOCASE",
+        params![channel, since, exclude_nick],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok(result)
+}
+
+/// The nicks with the most messages in a channel since the given Unix timestamp, leaving out
+/// `exclude_nick`, most talkative first.
+pub fn get_top_talkers(conn: &Connection, channel: &str, since: i64, exclude_nick: &str, limit: usize) -> Result<Vec<(String, u32)>> {
+    let mut stmt = conn.prepare(
+        "SELECT MIN(nick), COUNT(*) FROM message_log
+            WHERE channel_name = ?1 AND timestamp >= ?2 AND nick <> ?3 COLLATE NOCASE
+            GROUP BY nick COLLATE NOCASE
+            ORDER BY COUNT(*) DESC, 1
+            LIMIT ?4",
+    )?;
+    let rows = stmt.query_map(params![channel, since, exclude_nick, limit as i64], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row?);
+    }
+    Ok(result)
+}
+
+/// Messages per hour of the day (UTC) in a channel since the given Unix timestamp, leaving out
+/// `exclude_nick`. Hours without messages are left out.
+pub fn get_hourly_message_counts(conn: &Connection, channel: &str, since: i64, exclude_nick: &str) -> Result<Vec<(u32, u32)>> {
+    let mut stmt = conn.prepare(
+        "SELECT CAST(strftime('%H', timestamp, 'unixepoch') AS INTEGER), COUNT(*) FROM message_log
+            WHERE channel_name = ?1 AND timestamp >= ?2 AND nick <> ?3 COLLATE NOCASE
+            GROUP BY 1
+            ORDER BY 1",
+    )?;
+    let rows = stmt.query_map(params![channel, since, exclude_nick], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row?);
+    }
+    Ok(result)
+}
+
+// --- Karma ---
+
+/// Adds `delta` to a nick's karma in a channel, returning the new score.
+pub fn add_karma(conn: &Connection, channel: &str, nick: &str, delta: i64) -> Result<i64> {
+    let score = conn.query_row(
+        "INSERT INTO karma (channel_name, nick, score) VALUES (?1, ?2, ?3)
+            ON CONFLICT (channel_name, nick) DO UPDATE SET score = score + excluded.score
+            RETURNING score",
+        params![channel, nick, delta],
+        |row| row.get(0),
+    )?;
+    Ok(score)
+}
+
+pub fn get_karma(conn: &Connection, channel: &str, nick: &str) -> Result<i64> {
+    let score = conn
+        .query_row(
+            "SELECT score FROM karma WHERE channel_name = ?1 AND nick = ?2",
+            params![channel, nick],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(score.unwrap_or(0))
+}
+
+/// The nicks with the highest positive (or, with `highest: false`, lowest negative) karma.
+pub fn get_karma_ranking(conn: &Connection, channel: &str, limit: usize, highest: bool) -> Result<Vec<(String, i64)>> {
+    let sql = if highest {
+        "SELECT nick, score FROM karma WHERE channel_name = ?1 AND score > 0 ORDER BY score DESC, nick LIMIT ?2"
+    } else {
+        "SELECT nick, score FROM karma WHERE channel_name = ?1 AND score < 0 ORDER BY score ASC, nick LIMIT ?2"
+    };
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params![channel, limit as i64], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row?);
+    }
+    Ok(result)
+}
+
+// --- Scheduled Announcements ---
+
+/// Stores a new schedule, returning its id.
+pub fn add_schedule(conn: &Connection, channel: &str, cron: &str, message: &str, created_by: &str) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO schedules (channel_name, cron, message, created_by, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![channel, cron, message, created_by, Utc::now().timestamp()],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn remove_schedule(conn: &Connection, id: i64) -> Result<bool> {
+    let changes = conn.execute("DELETE FROM schedules WHERE id = ?", params![id])?;
+    Ok(changes > 0)
+}
+
+pub fn get_schedules(conn: &Connection) -> Result<Vec<Schedule>> {
+    let mut stmt = conn.prepare("SELECT id, channel_name, cron, message, created_by FROM schedules ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(Schedule {
+            id: row.get(0)?,
+            channel: row.get(1)?,
+            cron: row.get(2)?,
+            message: row.get(3)?,
+            created_by: row.get(4)?,
+        })
+    })?;
+    let mut schedules = Vec::new();
+    for schedule in rows {
+        schedules.push(schedule?);
+    }
+    Ok(schedules)
+}
+
+// --- Feeds ---
+
+/// Subscribes a channel to a feed, returning the new subscription's id, or `None` if the
+/// channel already has it.
+pub fn add_feed(conn: &Connection, channel: &str, url: &str, summarize: bool, added_by: &str) -> Result<Option<i64>> {
+    let changes = conn.execute(
+        "INSERT OR IGNORE INTO feeds (channel_name, url, summarize, added_by, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![channel, url, summarize, added_by, Utc::now().timestamp()],
+    )?;
+    Ok((changes > 0).then(|| conn.last_insert_rowid()))
+}
+
+pub fn remove_feed(conn: &Connection, id: i64) -> Result<bool> {
+    conn.execute("DELETE FROM feed_entries WHERE feed_id = ?", params![id])?;
+    let changes = conn.execute("DELETE FROM feeds WHERE id = ?", params![id])?;
+    Ok(changes > 0)
+}
+
+pub fn get_feeds(conn: &Connection) -> Result<Vec<FeedSubscription>> {
+    let mut stmt = conn.prepare("SELECT id, channel_name, url, summarize, added_by FROM feeds ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(FeedSubscription {
+            id: row.get(0)?,
+            channel: row.get(1)?,
+            url: row.get(2)?,
+            summarize: row.get(3)?,
+            added_by: row.get(4)?,
+        })
+    })?;
+    let mut feeds = Vec::new();
+    for feed in rows {
+        feeds.push(feed?);
+    }
+    Ok(feeds)
+}
+
+/// Records entries of a feed as seen, returning the ids that hadn't been seen before, in order.
+pub fn mark_feed_entries_seen(conn: &Connection, feed_id: i64, entry_ids: &[String]) -> Result<Vec<String>> {
+    let now = Utc::now().timestamp();
+    let mut stmt = conn.prepare("INSERT OR IGNORE INTO feed_entries (feed_id, entry_id, seen_at) VALUES (?1, ?2, ?3)")?;
+    let mut new_ids = Vec::new();
+    for entry_id in entry_ids {
+        if stmt.execute(params![feed_id, entry_id, now])? > 0 {
+            new_ids.push(entry_id.clone());
+        }
+    }
+    Ok(new_ids)
+}
+
+// --- Quotes ---
+
+const QUOTE_COLUMNS: &str = "id, channel_name, quote, added_by, created_at";
+
+fn quote_from_row(row: &rusqlite::Row) -> rusqlite::Result<Quote> {
+    let timestamp_secs: i64 = row.get(4)?;
+    Ok(Quote {
+        id: row.get(0)?,
+        channel: row.get(1)?,
+        text: row.get(2)?,
+        added_by: row.get(3)?,
+        timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(Utc::now),
+    })
+}
+
+/// Saves a quote, returning its id.
+pub fn add_quote(conn: &Connection, channel: &str, text: &str, added_by: &str) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO quotes (channel_name, quote, added_by, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![channel, text, added_by, Utc::now().timestamp()],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Removes one of a channel's quotes.
+pub fn remove_quote(conn: &Connection, channel: &str, id: i64) -> Result<bool> {
+    let changes = conn.execute("DELETE FROM quotes WHERE channel_name = ?1 AND id = ?2", params![channel, id])?;
+    Ok(changes > 0)
+}
+
+/// One of a channel's quotes, by id.
+pub fn get_quote(conn: &Connection, channel: &str, id: i64) -> Result<Option<Quote>> {
+    let quote = conn
+        .query_row(
+            &format!("SELECT {} FROM quotes WHERE channel_name = ?1 AND id = ?2", QUOTE_COLUMNS),
+            params![channel, id],
+            quote_from_row,
+        )
+        .optional()?;
+    Ok(quote)
+}
+
+/// A random one of a channel's quotes, if it has any.
+pub fn get_random_quote(conn: &Connection, channel: &str) -> Result<Option<Quote>> {
+    let quote = conn
+        .query_row(
+            &format!("SELECT {} FROM quotes WHERE channel_name = ?1 ORDER BY RANDOM() LIMIT 1", QUOTE_COLUMNS),
+            params![channel],
+            quote_from_row,
+        )
+        .optional()?;
+    Ok(quote)
+}
+
+/// A channel's quotes containing `text` (case-insensitively, for ASCII), newest first.
+pub fn search_quotes(conn: &Connection, channel: &str, text: &str, limit: usize) -> Result<Vec<Quote>> {
+    // Match the text literally, not as a LIKE pattern
+    let pattern = format!("%{}%", text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM quotes WHERE channel_name = ?1 AND quote LIKE ?2 ESCAPE '\\' ORDER BY id DESC LIMIT ?3",
+        QUOTE_COLUMNS
+    ))?;
+    let rows = stmt.query_map(params![channel, pattern, limit as i64], quote_from_row)?;
+    let mut quotes = Vec::new();
+    for quote in rows {
+        quotes.push(quote?);
+    }
+    Ok(quotes)
+}
+
+// --- User Facts ---
+
+/// Remembers a fact about `nick` on `network`, dropping the oldest beyond `max_facts`. Returns
+/// false if the fact was already known.
+pub fn add_user_fact(conn: &Connection, network: &str, nick: &str, fact: &str, max_facts: usize) -> Result<bool> {
+    let known: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM user_facts WHERE network = ?1 AND nick = ?2 AND fact = ?3 COLLATE NOCASE)",
+        params![network, nick, fact],
+        |row| row.get(0),
+    )?;
+    if known {
+        return Ok(false);
+    }
+    conn.execute(
+        "INSERT INTO user_facts (network, nick, fact, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![network, nick, fact, Utc::now().timestamp()],
+    )?;
+    conn.execute(
+        "DELETE FROM user_facts WHERE network = ?1 AND nick = ?2 AND id NOT IN (
+            SELECT id FROM user_facts WHERE network = ?1 AND nick = ?2 ORDER BY id DESC LIMIT ?3
+        )",
+        params![network, nick, max_facts as i64],
+    )?;
+    Ok(true)
+}
+
+/// What's known about `nick` on `network`, oldest first.
+pub fn get_user_facts(conn: &Connection, network: &str, nick: &str) -> Result<Vec<UserFact>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, nick, fact, created_at FROM user_facts WHERE network = ?1 AND nick = ?2 ORDER BY id ASC",
+    )?;
+    let rows = stmt.query_map(params![network, nick], |row| {
+        let timestamp_secs: i64 = row.get(3)?;
+        Ok(UserFact {
+            id: row.get(0)?,
+            nick: row.get(1)?,
+            fact: row.get(2)?,
+            timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(Utc::now),
+        })
+    })?;
+    let mut facts = Vec::new();
+    for fact in rows {
+        facts.push(fact?);
+    }
+    Ok(facts)
+}
+
+/// Forgets everything known about `nick` on `network`, returning how many facts there were.
+pub fn delete_user_facts(conn: &Connection, network: &str, nick: &str) -> Result<usize> {
+    let changes = conn.execute("DELETE FROM user_facts WHERE network = ?1 AND nick = ?2", params![network, nick])?;
+    Ok(changes)
+}
+
+// --- Notifications ---
+
+/// Adds a `!notify` keyword for `nick`. Returns false if they already had it.
+pub fn add_notify_keyword(conn: &Connection, nick: &str, keyword: &str) -> Result<bool> {
+    let changes = conn.execute("INSERT OR IGNORE INTO notify_keywords (nick, keyword) VALUES (?1, ?2)", params![nick, keyword])?;
+    Ok(changes > 0)
+}
+
+/// Removes one of `nick`'s `!notify` keywords. Returns false if they didn't have it.
+pub fn remove_notify_keyword(conn: &Connection, nick: &str, keyword: &str) -> Result<bool> {
+    let changes = conn.execute("DELETE FROM notify_keywords WHERE nick = ?1 AND keyword = ?2", params![nick, keyword])?;
+    Ok(changes > 0)
+}
+
+/// `nick`'s `!notify` keywords, alphabetically.
+pub fn get_notify_keywords(conn: &Connection, nick: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT keyword FROM notify_keywords WHERE nick = ?1 ORDER BY keyword")?;
+    let rows = stmt.query_map(params![nick], |row| row.get(0))?;
+    let mut keywords = Vec::new();
+    for keyword in rows {
+        keywords.push(keyword?);
+    }
+    Ok(keywords)
+}
+
+/// The `!notify` keywords `message` may mention, as (nick, keyword) pairs: those found in it
+/// anywhere, ignoring case, which callers still check for whole words. SQLite only lowercases
+/// ASCII, so keywords with other characters are always returned.
+pub fn get_notify_keywords_in(conn: &Connection, message: &str) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare(
+        "SELECT nick, keyword FROM notify_keywords
+         WHERE instr(?1, lower(keyword)) > 0 OR keyword GLOB '*[^ -~]*'
+         ORDER BY nick",
+    )?;
+    let rows = stmt.query_map(params![message.to_lowercase()], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut keywords = Vec::new();
+    for keyword in rows {
+        keywords.push(keyword?);
+    }
+    Ok(keywords)
+}
+
+/// Saves a line for `nick`'s digest, dropping the oldest beyond `max_kept`.
+pub fn add_notification(conn: &Connection, nick: &str, channel: &str, sender: &str, message: &str, max_kept: usize) -> Result<()> {
+    conn.execute(
+        "INSERT INTO notifications (nick, channel_name, sender, message, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![nick, channel, sender, message, Utc::now().timestamp()],
+    )?;
+    conn.execute(
+        "DELETE FROM notifications WHERE nick = ?1 AND id NOT IN (
+            SELECT id FROM notifications WHERE nick = ?1 ORDER BY id DESC LIMIT ?2
+        )",
+        params![nick, max_kept as i64],
+    )?;
+    Ok(())
+}
+
+/// Takes the lines saved for `nick`'s digest, oldest first, deleting them.
+pub fn take_notifications(conn: &Connection, nick: &str) -> Result<Vec<Notification>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, channel_name, sender, message, created_at FROM notifications WHERE nick = ?1 ORDER BY id ASC",
+    )?;
+    let rows = stmt.query_map(params![nick], |row| {
+        let timestamp_secs: i64 = row.get(4)?;
+        let notification = Notification {
+            channel: row.get(1)?,
+            sender: row.get(2)?,
+            message: row.get(3)?,
+            timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(Utc::now),
+        };
+        Ok((row.get::<_, i64>(0)?, notification))
+    })?;
+    let (mut notifications, mut last_id) = (Vec::new(), 0);
+    for row in rows {
+        let (id, notification) = row?;
+        last_id = id;
+        notifications.push(notification);
+    }
+    // Lines saved since the query above wait for next time
+    conn.execute("DELETE FROM notifications WHERE nick = ?1 AND id <= ?2", params![nick, last_id])?;
+    Ok(notifications)
+}
+
+/// Stops `!notify` for `nick`: their keywords and saved lines are deleted. Returns how many
+/// keywords they had.
+pub fn clear_notify(conn: &Connection, nick: &str) -> Result<usize> {
+    let changes = conn.execute("DELETE FROM notify_keywords WHERE nick = ?1", params![nick])?;
+    conn.execute("DELETE FROM notifications WHERE nick = ?1", params![nick])?;
+    Ok(changes)
+}
+
+// --- Moderation Warnings ---
+
+/// Warns `nick` in a channel, returning how many warnings they've had there since the given Unix
+/// timestamp, this one included.
+pub fn add_warning(conn: &Connection, channel: &str, nick: &str, reason: &str, since: i64) -> Result<u32> {
+    conn.execute(
+        "INSERT INTO moderation_warnings (channel_name, nick, reason, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![channel, nick, reason, Utc::now().timestamp()],
+    )?;
+    let count = conn.query_row(
+        "SELECT COUNT(*) FROM moderation_warnings WHERE channel_name = ?1 AND nick = ?2 AND created_at >= ?3",
+        params![channel, nick, since],
+        |row| row.get(0),
+    )?;
+    Ok(count)
+}
+
+/// The nicks warned in a channel since the given Unix timestamp, with how often, most warned first.
+pub fn get_warning_counts(conn: &Connection, channel: &str, since: i64) -> Result<Vec<(String, u32)>> {
+    let mut stmt = conn.prepare(
+        "SELECT MIN(nick), COUNT(*) FROM moderation_warnings
+            WHERE channel_name = ?1 AND created_at >= ?2
+            GROUP BY nick
+            ORDER BY COUNT(*) DESC, 1",
+    )?;
+    let rows = stmt.query_map(params![channel, since], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row?);
+    }
+    Ok(result)
+}
+
+/// The latest warnings given to `nick` in a channel, newest first.
+pub fn get_warnings(conn: &Connection, channel: &str, nick: &str, limit: usize) -> Result<Vec<Warning>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, nick, reason, created_at FROM moderation_warnings
+            WHERE channel_name = ?1 AND nick = ?2
+            ORDER BY id DESC LIMIT ?3",
+    )?;
+    let rows = stmt.query_map(params![channel, nick, limit as i64], |row| {
+        let timestamp_secs: i64 = row.get(3)?;
+        Ok(Warning {
+            id: row.get(0)?,
+            nick: row.get(1)?,
+            reason: row.get(2)?,
+            timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(Utc::now),
+        })
+    })?;
+    let mut warnings = Vec::new();
+    for warning in rows {
+        warnings.push(warning?);
+    }
+    Ok(warnings)
+}
+
+/// Clears `nick`'s warnings in a channel, returning how many there were.
+pub fn clear_warnings(conn: &Connection, channel: &str, nick: &str) -> Result<usize> {
+    let changes = conn.execute("DELETE FROM moderation_warnings WHERE channel_name = ?1 AND nick = ?2", params![channel, nick])?;
+    Ok(changes)
+}
+
+// --- Tool Call Log ---
+
+/// How long tool calls are kept.
+const TOOL_CALL_RETENTION_DAYS: i64 = 90;
+
+const TOOL_CALL_COLUMNS: &str = "id, timestamp, channel_name, nick, tool, args, result, error, duration_ms";
+
+fn tool_call_from_row(row: &rusqlite::Row) -> rusqlite::Result<ToolCall> {
+    let timestamp_secs: i64 = row.get(1)?;
+    Ok(ToolCall {
+        id: row.get(0)?,
+        timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(Utc::now),
+        channel: row.get(2)?,
+        nick: row.get(3)?,
+        tool: row.get(4)?,
+        args: row.get(5)?,
+        result: row.get(6)?,
+        error: row.get(7)?,
+        duration_ms: row.get(8)?,
+    })
+}
+
+/// Logs a tool call (its `id` is ignored) and forgets calls older than TOOL_CALL_RETENTION_DAYS.
+pub fn add_tool_call(conn: &Connection, call: &ToolCall) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO tool_calls (timestamp, channel_name, nick, tool, args, result, error, duration_ms)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            call.timestamp.timestamp(),
+            call.channel,
+            call.nick,
+            call.tool,
+            call.args,
+            call.result,
+            call.error,
+            call.duration_ms
+        ],
+    )?;
+    let id = conn.last_insert_rowid();
+    let cutoff = (Utc::now() - chrono::Duration::days(TOOL_CALL_RETENTION_DAYS)).timestamp();
+    conn.execute("DELETE FROM tool_calls WHERE timestamp < ?1", params![cutoff])?;
+    Ok(id)
+}
+
+/// The latest tool calls, newest first. With a `filter`, only those made in that channel, for
+/// that nick or of that tool.
+pub fn get_tool_calls(conn: &Connection, filter: Option<&str>, limit: usize) -> Result<Vec<ToolCall>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM tool_calls
+         WHERE ?1 IS NULL OR channel_name = ?1 OR nick = ?1 OR tool = ?1
+         ORDER BY id DESC LIMIT ?2",
+        TOOL_CALL_COLUMNS
+    ))?;
+    let rows = stmt.query_map(params![filter, limit as i64], tool_call_from_row)?;
+    let mut calls = Vec::new();
+    for call in rows {
+        calls.push(call?);
+    }
+    Ok(calls)
+}
+
+// --- Tool Calls Awaiting Approval ---
+
+const PENDING_TOOL_CALL_COLUMNS: &str = "id, timestamp, channel_name, nick, tool, args";
+
+fn pending_tool_call_from_row(row: &rusqlite::Row) -> rusqlite::Result<PendingToolCall> {
+    let timestamp_secs: i64 = row.get(1)?;
+    Ok(PendingToolCall {
+        id: row.get(0)?,
+        timestamp: DateTime::from_timestamp(timestamp_secs, 0).unwrap_or_else(Utc::now),
+        channel: row.get(2)?,
+        nick: row.get(3)?,
+        tool: row.get(4)?,
+        args: row.get(5)?,
+    })
+}
+
+/// Queues a tool call for approval, returning its id.
+pub fn add_pending_tool_call(conn: &Connection, channel: &str, nick: &str, tool: &str, args: &str) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO pending_tool_calls (timestamp, channel_name, nick, tool, args) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![Utc::now().timestamp(), channel, nick, tool, args],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// The id of a call `nick` already has waiting for approval with the same tool and arguments.
+pub fn find_pending_tool_call(conn: &Connection, nick: &str, tool: &str, args: &str) -> Result<Option<i64>> {
+    let id = conn
+        .query_row(
+            "SELECT id FROM pending_tool_calls WHERE nick = ?1 COLLATE NOCASE AND tool = ?2 AND args = ?3",
+            params![nick, tool, args],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(id)
+}
+
+/// How many of `nick`'s tool calls are waiting for approval.
+pub fn count_pending_tool_calls(conn: &Connection, nick: &str) -> Result<usize> {
+    let count: i64 =
+        conn.query_row("SELECT COUNT(*) FROM pending_tool_calls WHERE nick = ?1 COLLATE NOCASE", params![nick], |row| row.get(0))?;
+    Ok(count as usize)
+}
+
+/// The tool calls waiting for approval, oldest first.
+pub fn get_pending_tool_calls(conn: &Connection) -> Result<Vec<PendingToolCall>> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM pending_tool_calls ORDER BY id", PENDING_TOOL_CALL_COLUMNS))?;
+    let rows = stmt.query_map([], pending_tool_call_from_row)?;
+    let mut calls = Vec::new();
+    for call in rows {
+        calls.push(call?);
+    }
+    Ok(calls)
+}
+
+/// Removes a call from the queue and returns it, so it's approved or denied only once.
+pub fn take_pending_tool_call(conn: &Connection, id: i64) -> Result<Option<PendingToolCall>> {
+    let call = conn
+        .query_row(
+            &format!("DELETE FROM pending_tool_calls WHERE id = ?1 RETURNING {}", PENDING_TOOL_CALL_COLUMNS),
+            params![id],
+            pending_tool_call_from_row,
+        )
+        .optional()?;
+    Ok(call)
+}
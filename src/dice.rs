@@ -0,0 +1,408 @@
+//! Dice expressions for `!roll` and the roll_dice tool. An expression is a sum of dice and
+//! numbers, like `2d6+1d4+3` or `1d20-1`. Dice can keep or drop their highest or lowest
+//! (`4d6kh3`, `4d6dl1`), explode by rolling again on their highest side (`3d6!`), and `d20adv`
+//! or `d20dis` rolls with advantage or disadvantage; `adv` and `dis` on their own mean a d20.
+//! `d%` is a d100. The result lists every die, with dropped ones in parentheses and exploded
+//! ones marked with a `!`.
+
+use anyhow::{Context, Result, bail};
+use rand::Rng;
+use std::fmt;
+
+const MAX_TERMS: usize = 20;
+/// Dice one expression may roll, not counting explosions.
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+/// Extra dice one term may explode into, so a lucky streak on a d2 can't go on forever.
+const MAX_EXPLOSIONS: usize = 100;
+/// Words for advantage and disadvantage, longest first so `adv` doesn't cut `advantage` short.
+const ADVANTAGE_WORDS: &[(&str, Keep)] = &[
+    ("advantage", Keep::Highest(1)),
+    ("adv", Keep::Highest(1)),
+    ("disadvantage", Keep::Lowest(1)),
+    ("dis", Keep::Lowest(1)),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Keep {
+    Highest(u32),
+    Lowest(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Dice {
+    count: u32,
+    sides: u32,
+    explode: bool,
+    keep: Option<Keep>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Term {
+    Dice(Dice),
+    Number(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SignedTerm {
+    negative: bool,
+    term: Term,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Die {
+    value: u32,
+    /// Whether this die rolled its highest side and added another die.
+    exploded: bool,
+    kept: bool,
+}
+
+impl fmt::Display for Die {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mark = if self.exploded { "!" } else { "" };
+        match self.kept {
+            true => write!(f, "{}{}", self.value, mark),
+            false => write!(f, "({}{})", self.value, mark),
+        }
+    }
+}
+
+/// Rolls a dice expression and describes the result, like
+/// "Rolled 4d6kh3+1: [3, 6, (2), 5] + 1 = 15".
+pub fn roll(expression: &str) -> Result<String> {
+    let mut rng = rand::rng();
+    roll_with(expression, |sides| rng.random_range(1..=sides))
+}
+
+/// Rolls an expression with `roll_die`, which gets the number of sides and returns a roll.
+fn roll_with(expression: &str, mut roll_die: impl FnMut(u32) -> u32) -> Result<String> {
+    let terms = parse(expression)?;
+    let mut total: i64 = 0;
+    let mut breakdown = String::new();
+    for (i, SignedTerm { negative, term }) in terms.iter().enumerate() {
+        let (value, shown) = match term {
+            Term::Number(n) => (*n as i64, n.to_string()),
+            Term::Dice(dice) => {
+                let rolled = roll_dice(dice, &mut roll_die);
+                let value = rolled.iter().filter(|die| die.kept).map(|die| die.value as i64).sum();
+                let shown = rolled.iter().map(Die::to_string).collect::<Vec<_>>().join(", ");
+                (value, format!("[{}]", shown))
+            }
+        };
+        breakdown.push_str(match (i, *negative) {
+            (0, false) => "",
+            (0, true) => "-",
+            (_, false) => " + ",
+            (_, true) => " - ",
+        });
+        breakdown.push_str(&shown);
+        total += if *negative { -value } else { value };
+    }
+    Ok(format!("Rolled {}: {} = {}", expression.trim(), breakdown, total))
+}
+
+fn roll_dice(dice: &Dice, roll_die: &mut impl FnMut(u32) -> u32) -> Vec<Die> {
+    let mut rolled = Vec::with_capacity(dice.count as usize);
+    let mut explosions = 0;
+    for _ in 0..dice.count {
+        loop {
+            let value = roll_die(dice.sides);
+            let exploded = dice.explode && value == dice.sides && explosions < MAX_EXPLOSIONS;
+            rolled.push(Die { value, exploded, kept: true });
+            if !exploded {
+                break;
+            }
+            explosions += 1;
+        }
+    }
+
+    if let Some(keep) = dice.keep {
+        // The sort is stable, so of two equal dice the later one is dropped
+        let mut order: Vec<usize> = (0..rolled.len()).collect();
+        let kept = match keep {
+            Keep::Highest(n) => {
+                order.sort_by_key(|&i| std::cmp::Reverse(rolled[i].value));
+                n
+            }
+            Keep::Lowest(n) => {
+                order.sort_by_key(|&i| rolled[i].value);
+                n
+            }
+        };
+        for &i in &order[kept as usize..] {
+            rolled[i].kept = false;
+        }
+    }
+    rolled
+}
+
+fn parse(expression: &str) -> Result<Vec<SignedTerm>> {
+    let normalized: String = expression.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_lowercase();
+    if normalized.is_empty() {
+        bail!("No dice to roll. Try something like 2d6+1.");
+    }
+    let mut parser = Parser { rest: &normalized };
+    let mut terms = Vec::new();
+    let mut negative = parser.eat("-");
+    if !negative {
+        parser.eat("+");
+    }
+    loop {
+        if terms.len() == MAX_TERMS {
+            bail!("That's more than {} terms.", MAX_TERMS);
+        }
+        terms.push(SignedTerm { negative, term: parser.term()? });
+        if parser.rest.is_empty() {
+            break;
+        }
+        negative = match parser.rest.as_bytes()[0] {
+            b'+' => false,
+            b'-' => true,
+            _ => bail!("Unexpected '{}' in {}", parser.rest, expression.trim()),
+        };
+        parser.rest = &parser.rest[1..];
+    }
+
+    let dice: u32 = terms
+        .iter()
+        .map(|term| match &term.term {
+            Term::Dice(dice) => dice.count,
+            Term::Number(_) => 0,
+        })
+        .sum();
+    if dice > MAX_DICE {
+        bail!("That's more than {} dice.", MAX_DICE);
+    }
+    Ok(terms)
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    /// Consumes `token` if the input starts with it.
+    fn eat(&mut self, token: &str) -> bool {
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn number(&mut self) -> Result<Option<u32>> {
+        let digits = self.rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(self.rest.len());
+        if digits == 0 {
+            return Ok(None);
+        }
+        let (number, rest) = self.rest.split_at(digits);
+        self.rest = rest;
+        number.parse().map(Some).with_context(|| format!("{} is too big a number", number))
+    }
+
+    fn advantage(&mut self) -> Option<Keep> {
+        ADVANTAGE_WORDS.iter().find(|(word, _)| self.eat(word)).map(|&(_, keep)| keep)
+    }
+
+    fn term(&mut self) -> Result<Term> {
+        if let Some(keep) = self.advantage() {
+            return Ok(Term::Dice(Dice { count: 2, sides: 20, explode: false, keep: Some(keep) }));
+        }
+        let count = self.number()?;
+        if !self.eat("d") {
+            return match count {
+                Some(n) => Ok(Term::Number(n)),
+                None if self.rest.is_empty() => bail!("The expression ends where a number or dice should be."),
+                None => bail!("Expected a number or dice at '{}'", self.rest),
+            };
+        }
+
+        let count = count.unwrap_or(1);
+        if count == 0 || count > MAX_DICE {
+            bail!("Number of dice must be between 1 and {}.", MAX_DICE);
+        }
+        let sides = match self.eat("%") {
+            true => 100,
+            false => self.number()?.context("Dice need a number of sides, like d6")?,
+        };
+        if sides == 0 || sides > MAX_SIDES {
+            bail!("Number of sides must be between 1 and {}.", MAX_SIDES);
+        }
+
+        let mut dice = Dice { count, sides, explode: false, keep: None };
+        loop {
+            if self.eat("!") {
+                if sides == 1 {
+                    bail!("A d1 would explode forever.");
+                }
+                dice.explode = true;
+                continue;
+            }
+            if let Some(keep) = self.advantage() {
+                if dice.count != 1 || dice.keep.is_some() {
+                    bail!("Advantage and disadvantage are for a single die, like d20adv.");
+                }
+                dice.count = 2;
+                dice.keep = Some(keep);
+                continue;
+            }
+            // Keeping the highest, or dropping some of the dice
+            let (highest, drop) = if self.eat("kh") {
+                (true, false)
+            } else if self.eat("kl") {
+                (false, false)
+            } else if self.eat("k") {
+                (true, false)
+            } else if self.eat("dh") {
+                (false, true)
+            } else if self.eat("dl") || self.eat("d") {
+                (true, true)
+            } else {
+                break;
+            };
+            let n = self.number()?.context("Keep and drop need a number of dice, like 4d6kh3")?;
+            if dice.keep.is_some() {
+                bail!("Only one keep, drop or advantage per dice term.");
+            }
+            if n > dice.count {
+                bail!("Can't keep or drop {} of {} dice.", n, dice.count);
+            }
+            let kept = if drop { dice.count - n } else { n };
+            dice.keep = Some(if highest { Keep::Highest(kept) } else { Keep::Lowest(kept) });
+        }
+        Ok(Term::Dice(dice))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rolls `expression` with dice that come up `rolls`, in order.
+    fn roll_scripted(expression: &str, rolls: &[u32]) -> String {
+        let mut rolls = rolls.iter().copied();
+        let result = roll_with(expression, |sides| {
+            let value = rolls.next().expect("ran out of scripted rolls");
+            assert!((1..=sides).contains(&value), "d{} can't roll {}", sides, value);
+            value
+        });
+        assert_eq!(rolls.next(), None, "not every scripted roll was used");
+        result.unwrap()
+    }
+
+    fn dice(count: u32, sides: u32, explode: bool, keep: Option<Keep>) -> SignedTerm {
+        SignedTerm { negative: false, term: Term::Dice(Dice { count, sides, explode, keep }) }
+    }
+
+    #[test]
+    fn test_simple_rolls() {
+        assert_eq!(roll_scripted("1d20", &[17]), "Rolled 1d20: [17] = 17");
+        assert_eq!(roll_scripted("2d6+3", &[3, 5]), "Rolled 2d6+3: [3, 5] + 3 = 11");
+        assert_eq!(roll_scripted("d6-1", &[1]), "Rolled d6-1: [1] - 1 = 0");
+        assert_eq!(roll_scripted("d%", &[100]), "Rolled d%: [100] = 100");
+        assert_eq!(roll_scripted("7", &[]), "Rolled 7: 7 = 7");
+    }
+
+    #[test]
+    fn test_multiple_terms() {
+        assert_eq!(roll_scripted("2d6+1d4+3", &[3, 5, 2]), "Rolled 2d6+1d4+3: [3, 5] + [2] + 3 = 13");
+        assert_eq!(roll_scripted("1d20-1d4", &[4, 3]), "Rolled 1d20-1d4: [4] - [3] = 1");
+        assert_eq!(roll_scripted("-1d4+10", &[4]), "Rolled -1d4+10: -[4] + 10 = 6");
+        assert_eq!(roll_scripted(" 2D6 + 1 ", &[1, 2]), "Rolled 2D6 + 1: [1, 2] + 1 = 4");
+    }
+
+    #[test]
+    fn test_keep_and_drop() {
+        assert_eq!(roll_scripted("4d6kh3", &[2, 6, 5, 2]), "Rolled 4d6kh3: [2, 6, 5, (2)] = 13");
+        assert_eq!(roll_scripted("4d6k3", &[1, 6, 5, 2]), "Rolled 4d6k3: [(1), 6, 5, 2] = 13");
+        assert_eq!(roll_scripted("3d6kl1", &[4, 2, 6]), "Rolled 3d6kl1: [(4), 2, (6)] = 2");
+        assert_eq!(roll_scripted("4d6dl1", &[3, 6, 1, 5]), "Rolled 4d6dl1: [3, 6, (1), 5] = 14");
+        assert_eq!(roll_scripted("4d6d1", &[3, 6, 1, 5]), "Rolled 4d6d1: [3, 6, (1), 5] = 14");
+        assert_eq!(roll_scripted("3d6dh2", &[3, 6, 1]), "Rolled 3d6dh2: [(3), (6), 1] = 1");
+        assert_eq!(roll_scripted("2d6kh0+1", &[3, 4]), "Rolled 2d6kh0+1: [(3), (4)] + 1 = 1");
+    }
+
+    #[test]
+    fn test_exploding_dice() {
+        assert_eq!(roll_scripted("2d6!", &[6, 6, 2, 3]), "Rolled 2d6!: [6!, 6!, 2, 3] = 17");
+        assert_eq!(roll_scripted("d6!+1", &[4]), "Rolled d6!+1: [4] + 1 = 5");
+        // Explosions count as dice of their own for keeping and dropping
+        assert_eq!(roll_scripted("2d6!kh2", &[6, 5, 1]), "Rolled 2d6!kh2: [6!, 5, (1)] = 11");
+        assert_eq!(roll_scripted("2d6kh1!", &[6, 1, 3]), "Rolled 2d6kh1!: [6!, (1), (3)] = 6");
+    }
+
+    #[test]
+    fn test_explosions_stop() {
+        let result = roll_with("d2!", |_| 2).unwrap();
+        let (dice, total) = result.trim_start_matches("Rolled d2!: [").split_once("] = ").unwrap();
+        let dice: Vec<&str> = dice.split(", ").collect();
+        assert_eq!(dice.len(), MAX_EXPLOSIONS + 1);
+        assert_eq!(dice.last(), Some(&"2"));
+        assert_eq!(total, (2 * (MAX_EXPLOSIONS + 1)).to_string());
+    }
+
+    #[test]
+    fn test_advantage() {
+        assert_eq!(roll_scripted("adv", &[7, 15]), "Rolled adv: [(7), 15] = 15");
+        assert_eq!(roll_scripted("advantage+5", &[7, 15]), "Rolled advantage+5: [(7), 15] + 5 = 20");
+        assert_eq!(roll_scripted("dis", &[7, 15]), "Rolled dis: [7, (15)] = 7");
+        assert_eq!(roll_scripted("1d20adv", &[12, 12]), "Rolled 1d20adv: [12, (12)] = 12");
+        assert_eq!(roll_scripted("d20dis-2", &[19, 3]), "Rolled d20dis-2: [(19), 3] - 2 = 1");
+        assert_eq!(roll_scripted("d20disadvantage", &[1, 20]), "Rolled d20disadvantage: [1, (20)] = 1");
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("4d6kh3").unwrap(), vec![dice(4, 6, false, Some(Keep::Highest(3)))]);
+        assert_eq!(parse("3d10!dh1").unwrap(), vec![dice(3, 10, true, Some(Keep::Lowest(2)))]);
+        assert_eq!(parse("d20adv").unwrap(), parse("2d20kh1").unwrap());
+        assert_eq!(parse("dis").unwrap(), parse("2d20kl1").unwrap());
+        assert_eq!(
+            parse("1d8 - 2").unwrap(),
+            vec![dice(1, 8, false, None), SignedTerm { negative: true, term: Term::Number(2) }]
+        );
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        let too_many_terms = "+1".repeat(MAX_TERMS + 1);
+        for (expression, error) in [
+            ("", "No dice to roll"),
+            ("  ", "No dice to roll"),
+            ("2x6", "Unexpected 'x6'"),
+            ("1d6+", "ends where a number or dice should be"),
+            ("1d6++2", "Expected a number or dice at '+2'"),
+            ("hello", "Expected a number or dice at 'hello'"),
+            ("0d6", "Number of dice must be between 1 and 100"),
+            ("101d6", "Number of dice must be between 1 and 100"),
+            ("99999999999d6", "too big a number"),
+            ("1d0", "Number of sides must be between 1 and 1000"),
+            ("1d1001", "Number of sides must be between 1 and 1000"),
+            ("2d", "need a number of sides"),
+            ("1d1!", "explode forever"),
+            ("4d6kh5", "Can't keep or drop 5 of 4 dice"),
+            ("4d6kh", "need a number of dice"),
+            ("4d6kh3kl1", "Only one keep"),
+            ("2d20adv", "for a single die"),
+            ("d20kh1adv", "for a single die"),
+            ("60d6+50d6", "more than 100 dice"),
+            (too_many_terms.as_str(), "more than 20 terms"),
+        ] {
+            let result = roll_with(expression, |_| 1);
+            let message = format!("{:#}", result.expect_err(expression));
+            assert!(message.contains(error), "{:?} gave {:?}", expression, message);
+        }
+    }
+
+    #[test]
+    fn test_roll_stays_in_range() {
+        assert_eq!(roll("1d1").unwrap(), "Rolled 1d1: [1] = 1");
+        for _ in 0..100 {
+            let result = roll("3d6").unwrap();
+            let total: i64 = result.rsplit(" = ").next().unwrap().parse().unwrap();
+            assert!((3..=18).contains(&total), "unexpected result: {}", result);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Channel log exports: `!export` writes a channel's logged messages between two dates to a
+//! file, so they can be archived or analyzed outside SQLite.
+
+use crate::ctcp;
+use crate::db::{self, DbPool, LogEntry};
+use anyhow::{Context, Result, bail};
+use chrono::{NaiveDate, NaiveTime};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// An array of {timestamp, nick, message} objects, with actions kept in their CTCP form.
+    Json,
+    /// One "[timestamp] <nick> message" (or "* nick waves") line per message.
+    Text,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Text => "txt",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        match text.to_ascii_lowercase().as_str() {
+            "json" => Ok(ExportFormat::Json),
+            "text" | "txt" => Ok(ExportFormat::Text),
+            _ => bail!("Unknown export format {}; expected json or text", text),
+        }
+    }
+}
+
+/// Parses a UTC date like 2024-01-01.
+pub fn parse_date(text: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(text, "%Y-%m-%d").with_context(|| format!("{} isn't a date like 2024-01-01", text))
+}
+
+/// Renders log entries in the given format.
+pub fn render(entries: &[LogEntry], format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Json => {
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|entry| json!({ "timestamp": entry.timestamp.to_rfc3339(), "nick": entry.nick, "message": entry.message }))
+                .collect();
+            serde_json::to_string_pretty(&entries).expect("Log entries always serialize")
+        }
+        ExportFormat::Text => entries
+            .iter()
+            .map(|entry| {
+                let line = match ctcp::action_text(&entry.message) {
+                    Some(action) => format!("* {} {}", entry.nick, action),
+                    None => format!("<{}> {}", entry.nick, entry.message),
+                };
+                format!("[{}] {}\n", entry.timestamp.format("%Y-%m-%d %H:%M:%S"), line)
+            })
+            .collect(),
+    }
+}
+
+/// The export file's name, e.g. "libera_2024-01-01_2024-02-01.json" for #libera. Characters that
+/// don't belong in a file name become underscores.
+pub fn file_name(channel: &str, from: NaiveDate, to: NaiveDate, format: ExportFormat) -> String {
+    let channel: String = channel
+        .trim_start_matches('#')
+        .chars()
+        .map(|c| if c.is_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+        .collect();
+    format!("{}_{}_{}.{}", channel.trim_start_matches('.'), from, to, format.extension())
+}
+
+/// Writes a channel's messages from the start of `from` up to the start of `to` (UTC) into a
+/// file in `dir`, creating the directory if needed. Returns the file's path and how many
+/// messages went into it.
+pub async fn export_log(
+    db: &DbPool,
+    dir: &Path,
+    channel: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+    format: ExportFormat,
+) -> Result<(PathBuf, usize)> {
+    if to <= from {
+        bail!("The end date has to come after the start date");
+    }
+    let start = from.and_time(NaiveTime::MIN).and_utc().timestamp();
+    let end = to.and_time(NaiveTime::MIN).and_utc().timestamp();
+    let query_channel = channel.to_string();
+    let entries = db.run(move |conn| db::get_log_between(conn, &query_channel, start, end)).await?;
+    let path = dir.join(file_name(channel, from, to, format));
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create export directory {}", dir.display()))?;
+    tokio::fs::write(&path, render(&entries, format))
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok((path, entries.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn entry(nick: &str, message: &str) -> LogEntry {
+        LogEntry {
+            timestamp: Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap(),
+            channel: "#test".to_string(),
+            nick: nick.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let entries = [entry("alice", "hello"), entry("bob", "\x01ACTION waves\x01")];
+        assert_eq!(
+            render(&entries, ExportFormat::Text),
+            "[2024-01-02 03:04:05] <alice> hello\n[2024-01-02 03:04:05] * bob waves\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&render(&entries, ExportFormat::Json)).unwrap();
+        assert_eq!(json[0], json!({"timestamp": "2024-01-02T03:04:05+00:00", "nick": "alice", "message": "hello"}));
+        assert_eq!(json[1]["message"], "\x01ACTION waves\x01");
+    }
+
+    #[test]
+    fn test_file_name() {
+        let (from, to) = (parse_date("2024-01-01").unwrap(), parse_date("2024-02-01").unwrap());
+        assert_eq!(file_name("#rust", from, to, ExportFormat::Json), "rust_2024-01-01_2024-02-01.json");
+        assert_eq!(file_name("discord:123/../x", from, to, ExportFormat::Text), "discord_123_.._x_2024-01-01_2024-02-01.txt");
+        assert!(parse_date("01/02/2024").is_err());
+        assert_eq!("TXT".parse::<ExportFormat>().unwrap(), ExportFormat::Text);
+    }
+}
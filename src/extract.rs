@@ -0,0 +1,221 @@
+//! Finding the readable text of an HTML page, for `read_webpage_content`. Readability does
+//! well on articles but often returns junk for sites built in JavaScript, so it's the first of
+//! a chain: readability, then heuristics that look for the page's main content, then all of
+//! the page's text with the tags stripped. The first extractor with a useful amount of text
+//! wins. If none has one, ai_handler can ask a rendering service for the page as a browser
+//! sees it and try again.
+
+use readability::extractor;
+use scraper::{ElementRef, Html, Node, Selector};
+use std::collections::HashMap;
+use std::io::Cursor;
+use url::Url;
+
+/// Less text than this isn't taken as the page's content.
+const MIN_USEFUL_CHARS: usize = 200;
+/// Pages this short that talk about JavaScript are "please enable JavaScript" walls.
+const MAX_JS_WALL_CHARS: usize = 2000;
+const JS_WALL_PHRASES: &[&str] = &[
+    "enable javascript",
+    "javascript is disabled",
+    "javascript is required",
+    "requires javascript",
+    "turn on javascript",
+    "javascript to run this app",
+];
+/// Containers that usually hold a page's main content, best first.
+const CONTENT_SELECTORS: &[&str] = &["article", "main", "[role=main]", "#content", ".post-content", ".entry-content"];
+const BLOCK_SELECTOR: &str = "p, h1, h2, h3, h4, h5, h6, pre, blockquote";
+const SKIPPED_ELEMENTS: &[&str] = &["script", "style", "noscript", "template", "head", "svg", "nav", "footer"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extractor {
+    Readability,
+    Heuristics,
+    TagStripping,
+}
+
+impl Extractor {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Extractor::Readability => "readability",
+            Extractor::Heuristics => "heuristics",
+            Extractor::TagStripping => "tag stripping",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extracted {
+    pub text: String,
+    pub extractor: Extractor,
+    /// Whether the text looks like the page's content, rather than a scrap or a JavaScript wall.
+    pub useful: bool,
+}
+
+/// Runs the extractor chain on a page. Without a useful result, the longest of the others
+/// is returned, so short pages still give what they have.
+pub fn extract_html(html: &str, url: &Url) -> Extracted {
+    let readability = match extractor::extract(&mut Cursor::new(html), url) {
+        Ok(product) => product.text,
+        Err(e) => {
+            tracing::debug!(%url, "Readability failed: {}", e);
+            String::new()
+        }
+    };
+    let mut candidates = vec![(readability, Extractor::Readability)];
+    let document = Html::parse_document(html);
+    for extractor in [Extractor::Heuristics, Extractor::TagStripping] {
+        if is_useful(&candidates.last().expect("readability went first").0) {
+            break;
+        }
+        let text = match extractor {
+            Extractor::Heuristics => main_content_text(&document),
+            _ => stripped_text(&document),
+        };
+        candidates.push((text, extractor));
+    }
+    if let Some((text, extractor)) = candidates.iter().find(|(text, _)| is_useful(text)) {
+        return Extracted { text: text.trim().to_string(), extractor: *extractor, useful: true };
+    }
+    let (text, extractor) = candidates
+        .into_iter()
+        .filter(|(text, _)| !is_js_wall(text))
+        .max_by_key(|(text, _)| text.trim().len())
+        .unwrap_or((String::new(), Extractor::TagStripping));
+    Extracted { text: text.trim().to_string(), extractor, useful: false }
+}
+
+fn is_useful(text: &str) -> bool {
+    text.trim().chars().count() >= MIN_USEFUL_CHARS && !is_js_wall(text)
+}
+
+fn is_js_wall(text: &str) -> bool {
+    let text = text.to_lowercase();
+    text.len() < MAX_JS_WALL_CHARS && JS_WALL_PHRASES.iter().any(|phrase| text.contains(phrase))
+}
+
+/// The text of the page's main content: the biggest of the usual content containers, or
+/// failing that, the element with the most paragraph text directly inside it.
+fn main_content_text(document: &Html) -> String {
+    let blocks = Selector::parse(BLOCK_SELECTOR).expect("valid selector");
+    for selector in CONTENT_SELECTORS {
+        let selector = Selector::parse(selector).expect("valid selector");
+        let best = document
+            .select(&selector)
+            .map(|container| block_text(container.select(&blocks)))
+            .max_by_key(|text| text.len());
+        if let Some(text) = best
+            && is_useful(&text)
+        {
+            return text;
+        }
+    }
+
+    let mut by_parent: HashMap<_, Vec<ElementRef>> = HashMap::new();
+    for block in document.select(&blocks) {
+        if let Some(parent) = block.parent() {
+            by_parent.entry(parent.id()).or_default().push(block);
+        }
+    }
+    by_parent
+        .into_values()
+        .map(|blocks| block_text(blocks.into_iter()))
+        .max_by_key(|text| text.len())
+        .unwrap_or_default()
+}
+
+/// Text blocks as paragraphs, with their whitespace collapsed except in `<pre>`.
+fn block_text<'a>(blocks: impl Iterator<Item = ElementRef<'a>>) -> String {
+    blocks
+        .map(|block| match block.value().name() {
+            "pre" => block.text().collect::<String>().trim_end().to_string(),
+            _ => collapse_whitespace(&block.text().collect::<String>()),
+        })
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// All of the page's visible text, leaving out scripts, styles and navigation.
+fn stripped_text(document: &Html) -> String {
+    let mut text = String::new();
+    for node in document.root_element().descendants() {
+        let Node::Text(fragment) = node.value() else {
+            continue;
+        };
+        let skipped = node.ancestors().any(|ancestor| {
+            ancestor.value().as_element().is_some_and(|element| SKIPPED_ELEMENTS.contains(&element.name()))
+        });
+        if !skipped {
+            text.push_str(fragment);
+            text.push(' ');
+        }
+    }
+    collapse_whitespace(&text)
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url() -> Url {
+        Url::parse("https://example.org/page").unwrap()
+    }
+
+    fn paragraph(n: usize) -> String {
+        format!("<p>This is paragraph number {} of the story, which goes on for a while so there is enough to read.</p>", n)
+    }
+
+    #[test]
+    fn test_article_uses_readability() {
+        let body: String = (1..=5).map(paragraph).collect();
+        let html = format!("<html><head><title>Story</title></head><body><article>{}</article></body></html>", body);
+        let extracted = extract_html(&html, &url());
+        assert_eq!(extracted.extractor, Extractor::Readability);
+        assert!(extracted.useful);
+        assert!(extracted.text.contains("paragraph number 5"));
+    }
+
+    #[test]
+    fn test_main_content_heuristics() {
+        let body: String = (1..=5).map(paragraph).collect();
+        let html = format!(
+            "<html><body><nav><p>Home</p></nav><main><h1>Title</h1>{}<pre>let x =  1;</pre></main></body></html>",
+            body
+        );
+        let document = Html::parse_document(&html);
+        let text = main_content_text(&document);
+        assert!(text.starts_with("Title\n\nThis is paragraph number 1"));
+        assert!(text.ends_with("let x =  1;"));
+        assert!(!text.contains("Home"));
+    }
+
+    #[test]
+    fn test_stripped_text_skips_scripts() {
+        let document = Html::parse_document(
+            "<html><head><style>p { color: red }</style></head><body><div>Hello <b>there</b></div><script>var x = 1;</script></body></html>",
+        );
+        assert_eq!(stripped_text(&document), "Hello there");
+    }
+
+    #[test]
+    fn test_javascript_walls_are_not_useful() {
+        let html = "<html><body><noscript>You need to enable JavaScript to run this app.</noscript><div id=\"root\"></div></body></html>";
+        let extracted = extract_html(html, &url());
+        assert!(!extracted.useful);
+        assert_eq!(extracted.text, "");
+        assert!(is_js_wall("Please enable JavaScript to continue."));
+    }
+
+    #[test]
+    fn test_short_pages_give_what_they_have() {
+        let extracted = extract_html("<html><body><div>Just a short note.</div></body></html>", &url());
+        assert!(!extracted.useful);
+        assert_eq!(extracted.text, "Just a short note.");
+    }
+}
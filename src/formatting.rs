@@ -0,0 +1,214 @@
+//! Rendering the AI's markdown for chat networks that don't understand it. IRC channels get it
+//! either stripped to plain text or turned into mIRC formatting codes, chosen per channel with
+//! `!set #channel formatting ...`; Discord renders markdown itself, so it's sent as written.
+
+use anyhow::{Result, bail};
+use std::fmt;
+use std::str::FromStr;
+
+const IRC_BOLD: char = '\x02';
+const IRC_ITALIC: char = '\x1D';
+const IRC_STRIKETHROUGH: char = '\x1E';
+const IRC_MONOSPACE: char = '\x11';
+const BULLET: &str = "•";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Formatting {
+    /// Sent as written.
+    Markdown,
+    /// Bold, italics, strikethrough and code become mIRC formatting codes.
+    Irc,
+    /// Markup is removed, leaving the text.
+    Plain,
+}
+
+impl Formatting {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Formatting::Markdown => "markdown",
+            Formatting::Irc => "irc",
+            Formatting::Plain => "plain",
+        }
+    }
+}
+
+impl fmt::Display for Formatting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Formatting {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        match text.to_lowercase().as_str() {
+            "markdown" | "raw" => Ok(Formatting::Markdown),
+            "irc" | "mirc" => Ok(Formatting::Irc),
+            "plain" | "strip" => Ok(Formatting::Plain),
+            _ => bail!("Expected markdown, irc or plain, not \"{}\"", text),
+        }
+    }
+}
+
+/// Renders markdown text in the given formatting. Code blocks lose their fences but keep their
+/// contents as written; list bullets become "•", headings become bold (or plain) lines, and
+/// links become "text (url)".
+pub fn render(text: &str, formatting: Formatting) -> String {
+    if formatting == Formatting::Markdown {
+        return text.to_string();
+    }
+    let mut lines = Vec::new();
+    let mut in_code_block = false;
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block {
+            lines.push(line.to_string());
+            continue;
+        }
+        let indent = &line[..line.len() - trimmed.len()];
+        let rendered = if let Some(heading) = heading_text(trimmed) {
+            wrap(&render_inline(heading, formatting), IRC_BOLD, formatting)
+        } else if let Some(item) = ["- ", "* ", "+ "].iter().find_map(|bullet| trimmed.strip_prefix(bullet)) {
+            format!("{}{} {}", indent, BULLET, render_inline(item, formatting))
+        } else {
+            format!("{}{}", indent, render_inline(trimmed, formatting))
+        };
+        lines.push(rendered);
+    }
+    lines.join("\n")
+}
+
+/// The text of a "# Heading" line.
+fn heading_text(line: &str) -> Option<&str> {
+    let text = line.trim_start_matches('#');
+    let level = line.len() - text.len();
+    ((1..=6).contains(&level) && text.starts_with(' ')).then(|| text.trim())
+}
+
+fn wrap(text: &str, code: char, formatting: Formatting) -> String {
+    match formatting {
+        Formatting::Irc => format!("{code}{text}{code}"),
+        _ => text.to_string(),
+    }
+}
+
+/// Renders the inline markup in one line: emphasis, code spans and links.
+fn render_inline(text: &str, formatting: Formatting) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        if let Some(code) = rest.strip_prefix('`')
+            && let Some(end) = code.find('`')
+            && end > 0
+        {
+            out.push_str(&wrap(&code[..end], IRC_MONOSPACE, formatting));
+            i += end + 2;
+            continue;
+        }
+        if let Some((label, url, length)) = parse_link(rest) {
+            out.push_str(&format!("{} ({})", render_inline(label, formatting), url));
+            i += length;
+            continue;
+        }
+        let emphasis = [("**", IRC_BOLD), ("__", IRC_BOLD), ("~~", IRC_STRIKETHROUGH), ("*", IRC_ITALIC), ("_", IRC_ITALIC)];
+        if let Some((inner, length, code)) = emphasis
+            .iter()
+            .find_map(|&(delimiter, code)| parse_emphasis(text, i, delimiter).map(|(inner, length)| (inner, length, code)))
+        {
+            out.push_str(&wrap(&render_inline(inner, formatting), code, formatting));
+            i += length;
+            continue;
+        }
+        let c = rest.chars().next().expect("i is within the text");
+        out.push(c);
+        i += c.len_utf8();
+    }
+    out
+}
+
+/// An emphasis span starting at byte `start` of `text`, as (inner text, length with
+/// delimiters). The delimiters must hug the inner text, and single `*` and `_` must not touch
+/// letters outside them, so "2*3*4" and snake_case_names are left alone.
+fn parse_emphasis<'a>(text: &'a str, start: usize, delimiter: &str) -> Option<(&'a str, usize)> {
+    let rest = text[start..].strip_prefix(delimiter)?;
+    let single = delimiter.len() == 1;
+    let delimiter_char = delimiter.chars().next()?;
+    let before = text[..start].chars().next_back();
+    if single && (rest.starts_with(delimiter_char) || before.is_some_and(char::is_alphanumeric)) {
+        return None;
+    }
+    if rest.chars().next().is_none_or(char::is_whitespace) {
+        return None;
+    }
+    let mut search = 0;
+    while let Some(found) = rest[search..].find(delimiter) {
+        let end = search + found;
+        let after = rest[end + delimiter.len()..].chars().next();
+        let hugs = end > 0 && !rest[..end].ends_with(char::is_whitespace);
+        let doubled = single && after == Some(delimiter_char);
+        if hugs && !doubled && !(single && after.is_some_and(char::is_alphanumeric)) {
+            return Some((&rest[..end], end + 2 * delimiter.len()));
+        }
+        search = end + delimiter.len();
+    }
+    None
+}
+
+/// A markdown link at the start of `text`, as (label, url, length).
+fn parse_link(text: &str) -> Option<(&str, &str, usize)> {
+    let label_end = text.strip_prefix('[')?.find("](")? + 1;
+    let url_start = label_end + 2;
+    let url_end = url_start + text[url_start..].find(')')?;
+    let (label, url) = (&text[1..label_end], &text[url_start..url_end]);
+    (!label.is_empty() && !url.is_empty() && !url.contains(char::is_whitespace)).then_some((label, url, url_end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain() {
+        let text = "## Results\n**Bold** and *italic*, with `code` and ~~mistakes~~.\n- one\n* two\nSee [the docs](https://example.org).";
+        assert_eq!(
+            render(text, Formatting::Plain),
+            "Results\nBold and italic, with code and mistakes.\n• one\n• two\nSee the docs (https://example.org)."
+        );
+    }
+
+    #[test]
+    fn test_irc() {
+        assert_eq!(
+            render("**Bold** with _nested **strong**_ and `x*y`", Formatting::Irc),
+            "\x02Bold\x02 with \x1Dnested \x02strong\x02\x1D and \x11x*y\x11"
+        );
+        assert_eq!(render("# Title", Formatting::Irc), "\x02Title\x02");
+    }
+
+    #[test]
+    fn test_leaves_lookalikes_alone() {
+        for text in ["2*3*4 = 24", "snake_case_name", "a * b * c", "**", "5 * 3", "#hashtag", "C++ and C#"] {
+            assert_eq!(render(text, Formatting::Plain), text);
+        }
+    }
+
+    #[test]
+    fn test_code_blocks() {
+        let text = "Try this:\n```rust\nlet x = *y;\n  **z**\n```\nDone!";
+        assert_eq!(render(text, Formatting::Plain), "Try this:\nlet x = *y;\n  **z**\nDone!");
+        assert_eq!(render(text, Formatting::Markdown), text);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("IRC".parse::<Formatting>().unwrap(), Formatting::Irc);
+        assert_eq!("strip".parse::<Formatting>().unwrap(), Formatting::Plain);
+        assert!("html".parse::<Formatting>().is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! Typed models of Gemini's GenerateContent request and response.
+//!
+//! Every backend returns Gemini-shaped JSON (see llm.rs), which ai_handler parses into these
+//! types, so a malformed response fails in one place with a precise serde error instead of
+//! deep in a chain of `.get()` calls. Parts keep any fields not modelled here (thought
+//! signatures, for one) so they survive a round trip back into the conversation history.
+
+use crate::llm::TokenUsage;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// One turn of a conversation.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Content {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub parts: Vec<Part>,
+}
+
+impl Content {
+    /// A turn with the given role and parts.
+    pub fn new(role: &str, parts: Vec<Part>) -> Self {
+        Content { role: Some(role.to_string()), parts }
+    }
+
+    /// The turn's visible text: its text parts, without thoughts, joined together. None if
+    /// there are none.
+    pub fn text(&self) -> Option<String> {
+        let texts: Vec<&str> = self
+            .parts
+            .iter()
+            .filter(|part| part.thought != Some(true))
+            .filter_map(|part| part.text.as_deref())
+            .collect();
+        (!texts.is_empty()).then(|| texts.concat())
+    }
+
+    /// The function calls the turn asks for, in order.
+    pub fn function_calls(&self) -> impl Iterator<Item = &FunctionCall> {
+        self.parts.iter().filter_map(|part| part.function_call.as_ref())
+    }
+
+    /// The turn as conversation history JSON.
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).expect("Content always serializes")
+    }
+}
+
+/// A piece of a turn. Gemini sets exactly one of the data fields.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Part {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// Set on the model's thinking, which isn't part of its answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thought: Option<bool>,
+    // The history uses the snake_case spelling; Gemini answers in camelCase
+    #[serde(default, rename = "inline_data", alias = "inlineData", skip_serializing_if = "Option::is_none")]
+    pub inline_data: Option<InlineData>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<FunctionCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function_response: Option<FunctionResponse>,
+    /// Fields this model doesn't know about, passed through untouched.
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl Part {
+    pub fn text(text: impl Into<String>) -> Self {
+        Part { text: Some(text.into()), ..Default::default() }
+    }
+
+    pub fn inline_data(mime_type: impl Into<String>, data: impl Into<String>) -> Self {
+        Part {
+            inline_data: Some(InlineData { mime_type: mime_type.into(), data: data.into() }),
+            ..Default::default()
+        }
+    }
+
+    pub fn function_response(name: impl Into<String>, response: Value) -> Self {
+        Part {
+            function_response: Some(FunctionResponse { name: name.into(), response }),
+            ..Default::default()
+        }
+    }
+}
+
+/// Base64-encoded media.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InlineData {
+    #[serde(rename = "mime_type", alias = "mimeType")]
+    pub mime_type: String,
+    pub data: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    #[serde(default = "empty_object")]
+    pub args: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionResponse {
+    pub name: String,
+    pub response: Value,
+}
+
+fn empty_object() -> Value {
+    Value::Object(Map::new())
+}
+
+/// Sampling settings sent with a request.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+}
+
+/// A response from generateContent, or the merged chunks of streamGenerateContent.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateContentResponse {
+    #[serde(default)]
+    pub candidates: Vec<Candidate>,
+    pub prompt_feedback: Option<PromptFeedback>,
+    #[serde(default)]
+    pub usage_metadata: UsageMetadata,
+}
+
+impl GenerateContentResponse {
+    /// The first candidate; the bot never asks for more than one.
+    pub fn candidate(&self) -> Option<&Candidate> {
+        self.candidates.first()
+    }
+
+    /// The first candidate's visible text, if any.
+    pub fn text(&self) -> Option<String> {
+        self.candidate().and_then(|candidate| candidate.content.text())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Candidate {
+    /// Missing entirely when the response was stopped before producing anything.
+    #[serde(default)]
+    pub content: Content,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PromptFeedback {
+    pub block_reason: Option<String>,
+}
+
+/// Token counts reported with a response; missing counts are zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct UsageMetadata {
+    pub prompt_token_count: u64,
+    pub candidates_token_count: u64,
+    pub thoughts_token_count: u64,
+}
+
+impl From<UsageMetadata> for TokenUsage {
+    fn from(usage: UsageMetadata) -> Self {
+        TokenUsage {
+            prompt_tokens: usage.prompt_token_count,
+            output_tokens: usage.candidates_token_count + usage.thoughts_token_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_response() {
+        let response: GenerateContentResponse = serde_json::from_value(json!({
+            "candidates": [{
+                "content": {"role": "model", "parts": [
+                    {"text": "Let me think...", "thought": true},
+                    {"text": "Rolling "},
+                    {"text": "now!"},
+                    {"functionCall": {"name": "roll_dice", "args": {"dice_notation": "1d6"}}}
+                ]},
+                "finishReason": "STOP"
+            }],
+            "usageMetadata": {"promptTokenCount": 10}
+        }))
+        .unwrap();
+        assert_eq!(TokenUsage::from(response.usage_metadata), TokenUsage { prompt_tokens: 10, output_tokens: 0 });
+        let candidate = response.candidate().unwrap();
+        assert_eq!(candidate.finish_reason.as_deref(), Some("STOP"));
+        assert_eq!(response.text().as_deref(), Some("Rolling now!"));
+        let calls: Vec<_> = candidate.content.function_calls().collect();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "roll_dice");
+        assert_eq!(calls[0].args, json!({"dice_notation": "1d6"}));
+    }
+
+    #[test]
+    fn test_parse_blocked_prompt() {
+        let response: GenerateContentResponse =
+            serde_json::from_value(json!({"promptFeedback": {"blockReason": "SAFETY"}})).unwrap();
+        assert!(response.candidates.is_empty());
+        assert_eq!(response.prompt_feedback.unwrap().block_reason.as_deref(), Some("SAFETY"));
+    }
+
+    #[test]
+    fn test_malformed_function_call_is_an_error() {
+        let result = serde_json::from_value::<GenerateContentResponse>(json!({
+            "candidates": [{"content": {"parts": [{"functionCall": {"args": {}}}]}}]
+        }));
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("name"), "unexpected error: {}", error);
+    }
+
+    #[test]
+    fn test_parts_round_trip_in_history_format() {
+        let part: Part = serde_json::from_value(json!({
+            "inlineData": {"mimeType": "image/png", "data": "AAAA"},
+            "thoughtSignature": "abc"
+        }))
+        .unwrap();
+        let content = Content::new("model", vec![part, Part::function_response("roll_dice", json!({"result": 4}))]);
+        assert_eq!(
+            content.to_value(),
+            json!({"role": "model", "parts": [
+                {"inline_data": {"mime_type": "image/png", "data": "AAAA"}, "thoughtSignature": "abc"},
+                {"functionResponse": {"name": "roll_dice", "response": {"result": 4}}}
+            ]})
+        );
+    }
+
+    #[test]
+    fn test_missing_args_default_to_empty_object() {
+        let call: FunctionCall = serde_json::from_value(json!({"name": "list_tools"})).unwrap();
+        assert_eq!(call.args, json!({}));
+    }
+}
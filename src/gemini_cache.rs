@@ -0,0 +1,158 @@
+//! Gemini context caching. The big, unchanging system prompt (with the tool declarations) is
+//! uploaded once as a `cachedContents` resource, and requests name the cache instead of sending
+//! it all again; cached tokens are billed at a fraction of the usual price. Caches are replaced
+//! shortly before they expire. Prompts too small to cache, and caches Gemini won't create, are
+//! sent inline as usual.
+
+use crate::llm::estimate_tokens;
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Gemini won't cache fewer tokens than this (1024 for Flash models; Pro needs more, and
+/// refuses smaller caches itself).
+const MIN_CACHED_TOKENS: usize = 1024;
+/// A cache is replaced this long before it expires, so no request names an expired one.
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+enum Entry {
+    Cached { name: String, expires: Instant },
+    /// Creating the cache failed; the prompt is sent inline until `retry_at`.
+    Failed { retry_at: Instant },
+}
+
+pub struct ContextCache {
+    /// How long each cache lives on Gemini's side.
+    ttl: Duration,
+    /// By model, system prompt and tools.
+    entries: Mutex<HashMap<u64, Entry>>,
+}
+
+impl ContextCache {
+    pub fn new(ttl: Duration) -> Self {
+        ContextCache { ttl, entries: Mutex::new(HashMap::new()) }
+    }
+
+    /// The name of a cache holding `system_prompt` and `tools` for `model`, created if there's
+    /// no live one. `None` means they have to be sent inline.
+    pub async fn get(
+        &self,
+        client: &reqwest::Client,
+        base_url: &str,
+        api_key: &str,
+        model: &str,
+        system_prompt: &str,
+        tools: Option<&Value>,
+    ) -> Option<String> {
+        if estimate_tokens(system_prompt) < MIN_CACHED_TOKENS {
+            return None;
+        }
+        let key = cache_key(model, system_prompt, tools);
+        match self.entries.lock().unwrap().get(&key) {
+            Some(Entry::Cached { name, expires }) if Instant::now() + REFRESH_MARGIN < *expires => return Some(name.clone()),
+            Some(Entry::Failed { retry_at }) if Instant::now() < *retry_at => return None,
+            _ => {}
+        }
+
+        // Requests racing here may each create a cache; the spares just expire
+        let created = create(client, base_url, api_key, model, system_prompt, tools, self.ttl).await;
+        let mut entries = self.entries.lock().unwrap();
+        match created {
+            Ok(name) => {
+                tracing::info!(%model, cache = %name, ttl = ?self.ttl, "Created Gemini context cache");
+                entries.insert(key, Entry::Cached { name: name.clone(), expires: Instant::now() + self.ttl });
+                Some(name)
+            }
+            Err(e) => {
+                tracing::warn!(%model, "Failed to create Gemini context cache, sending the prompt inline: {:#}", e);
+                entries.insert(key, Entry::Failed { retry_at: Instant::now() + self.ttl });
+                None
+            }
+        }
+    }
+
+    /// Forgets the cache `name`, after Gemini refused a request naming it, so the next request
+    /// creates a new one.
+    pub fn invalidate(&self, name: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, entry| !matches!(entry, Entry::Cached { name: cached, .. } if cached == name));
+    }
+}
+
+fn cache_key(model: &str, system_prompt: &str, tools: Option<&Value>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    model.hash(&mut hasher);
+    system_prompt.hash(&mut hasher);
+    tools.map(Value::to_string).hash(&mut hasher);
+    hasher.finish()
+}
+
+async fn create(
+    client: &reqwest::Client,
+    base_url: &str,
+    api_key: &str,
+    model: &str,
+    system_prompt: &str,
+    tools: Option<&Value>,
+    ttl: Duration,
+) -> Result<String> {
+    let mut body = json!({
+        "model": format!("models/{}", model),
+        "systemInstruction": {"parts": [{"text": system_prompt}]},
+        "ttl": format!("{}s", ttl.as_secs()),
+    });
+    if let Some(tools) = tools {
+        body["tools"] = tools.clone();
+    }
+    let response: Value = client
+        .post(format!("{}/cachedContents?key={}", base_url, api_key))
+        .json(&body)
+        .send()
+        .await?
+        .error_for_status()
+        .context("Gemini refused to create the cache")?
+        .json()
+        .await
+        .context("Failed to parse Gemini cache response")?;
+    response["name"].as_str().map(str::to_string).context("Gemini's cache response has no name")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    #[tokio::test]
+    async fn test_caches_are_reused_and_invalidated() {
+        let mut server = mockito::Server::new_async().await;
+        let create_mock = server
+            .mock("POST", "/cachedContents")
+            .match_query(Matcher::UrlEncoded("key".into(), "secret".into()))
+            .match_body(Matcher::PartialJson(json!({"model": "models/test-model", "ttl": "3600s"})))
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"name": "cachedContents/abc123"}"#)
+            .expect(2)
+            .create_async()
+            .await;
+        let client = reqwest::Client::new();
+        let cache = ContextCache::new(Duration::from_secs(3600));
+        let prompt = "You are a helpful bot. ".repeat(200);
+
+        let url = server.url();
+        let get = || cache.get(&client, &url, "secret", "test-model", &prompt, None);
+        assert_eq!(get().await.as_deref(), Some("cachedContents/abc123"));
+        assert_eq!(get().await.as_deref(), Some("cachedContents/abc123"));
+        cache.invalidate("cachedContents/abc123");
+        assert_eq!(get().await.as_deref(), Some("cachedContents/abc123"));
+        create_mock.assert_async().await;
+
+        // Short prompts aren't worth caching
+        assert_eq!(cache.get(&client, &server.url(), "secret", "test-model", "Be brief.", None).await, None);
+    }
+}
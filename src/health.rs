@@ -0,0 +1,172 @@
+//! Whether the bot is working, for a monitoring system or container orchestrator to check:
+//! `GET /healthz` on `--health-addr` answers with JSON saying whether IRC is connected, when
+//! the server was last heard from, when an AI request last succeeded and whether the database
+//! answers. It's 200 while IRC (if enabled) is connected and the database works, and 503
+//! otherwise. AI failures are reported but don't count against health, since a restart won't
+//! fix an API outage.
+
+use crate::db::DbPool;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Longest request head read; health checks send a line or two.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+/// Clients that take longer to send their request are dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct Health {
+    irc_connected: AtomicBool,
+    last_server_traffic: Mutex<Option<DateTime<Utc>>>,
+    last_ai_success: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl Health {
+    pub fn set_irc_connected(&self, connected: bool) {
+        self.irc_connected.store(connected, Ordering::SeqCst);
+    }
+
+    /// The IRC server sent us something.
+    pub fn server_traffic(&self) {
+        *self.last_server_traffic.lock().unwrap() = Some(Utc::now());
+    }
+
+    /// An AI request was answered.
+    pub fn ai_succeeded(&self) {
+        *self.last_ai_success.lock().unwrap() = Some(Utc::now());
+    }
+
+    /// Whether the bot is healthy, and the report saying so.
+    pub fn report(&self, irc_enabled: bool, db_ok: bool) -> (bool, Value) {
+        let irc_connected = self.irc_connected.load(Ordering::SeqCst);
+        let healthy = db_ok && (irc_connected || !irc_enabled);
+        let timestamp = |time: &Mutex<Option<DateTime<Utc>>>| time.lock().unwrap().map(|time| time.to_rfc3339());
+        let irc = irc_enabled.then(|| {
+            json!({
+                "connected": irc_connected,
+                "last_traffic": timestamp(&self.last_server_traffic),
+            })
+        });
+        let report = json!({
+            "status": if healthy { "ok" } else { "unhealthy" },
+            "irc": irc,
+            "last_ai_success": timestamp(&self.last_ai_success),
+            "db": db_ok,
+        });
+        (healthy, report)
+    }
+}
+
+/// Answers health checks on `addr` until the listener fails.
+pub async fn serve(addr: SocketAddr, health: Arc<Health>, db: DbPool, irc_enabled: bool) -> Result<()> {
+    let listener = TcpListener::bind(addr).await.with_context(|| format!("Failed to listen for health checks on {}", addr))?;
+    tracing::info!(%addr, "Answering health checks on /healthz");
+    serve_on(listener, health, db, irc_enabled).await
+}
+
+async fn serve_on(listener: TcpListener, health: Arc<Health>, db: DbPool, irc_enabled: bool) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await.context("Failed to accept a health check connection")?;
+        let (health, db) = (health.clone(), db.clone());
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, &health, &db, irc_enabled).await {
+                tracing::debug!(%peer, "Failed to answer health check: {:#}", e);
+            }
+        });
+    }
+}
+
+async fn answer(mut stream: TcpStream, health: &Health, db: &DbPool, irc_enabled: bool) -> Result<()> {
+    let head = timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await.context("Request timed out")??;
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let (method, target) = (request_line.next().unwrap_or_default(), request_line.next().unwrap_or_default());
+    let path = target.split('?').next().unwrap_or_default();
+
+    let (status, body) = match (method, path) {
+        ("GET", "/healthz") => {
+            let db_ok = db.run(|conn| Ok(conn.query_row("SELECT 1", [], |_| Ok(()))?)).await.is_ok();
+            let (healthy, report) = health.report(irc_enabled, db_ok);
+            (if healthy { "200 OK" } else { "503 Service Unavailable" }, report)
+        }
+        (_, "/healthz") => ("405 Method Not Allowed", json!({"error": "use GET"})),
+        _ => ("404 Not Found", json!({"error": "not found"})),
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Reads the request line and headers; any body is ignored.
+async fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") && head.len() < MAX_REQUEST_BYTES {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[tokio::test]
+    async fn test_healthz() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let health = Arc::new(Health::default());
+        tokio::spawn(serve_on(listener, health.clone(), db::init_memory_db().unwrap(), true));
+        let client = reqwest::Client::new();
+        let get = |path: &str| client.get(format!("{}{}", url, path)).send();
+
+        // Not connected to IRC yet
+        let response = get("/healthz").await.unwrap();
+        assert_eq!(response.status(), 503);
+        let report: Value = response.json().await.unwrap();
+        assert_eq!(report["status"], "unhealthy");
+        assert_eq!(report["irc"]["connected"], false);
+        assert_eq!(report["db"], true);
+        assert!(report["last_ai_success"].is_null());
+
+        health.set_irc_connected(true);
+        health.server_traffic();
+        health.ai_succeeded();
+        let response = get("/healthz?verbose").await.unwrap();
+        assert_eq!(response.status(), 200);
+        let report: Value = response.json().await.unwrap();
+        assert_eq!(report["status"], "ok");
+        assert!(report["irc"]["last_traffic"].is_string() && report["last_ai_success"].is_string());
+
+        assert_eq!(get("/").await.unwrap().status(), 404);
+        assert_eq!(client.post(format!("{}/healthz", url)).send().await.unwrap().status(), 405);
+    }
+
+    #[test]
+    fn test_report_without_irc() {
+        let health = Health::default();
+        let (healthy, report) = health.report(false, true);
+        assert!(healthy);
+        assert!(report["irc"].is_null());
+        assert!(!health.report(false, false).0);
+    }
+}
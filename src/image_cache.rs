@@ -0,0 +1,330 @@
+//! Images the AI has looked at, kept so the same link isn't downloaded and resized again. The
+//! most recent ones are held in memory as raw bytes, and every image is also written to a
+//! directory so the cache survives restarts. Each file is named by a hash of its URL and holds
+//! the MIME type (with a `frames` parameter for animations) and URL on two header lines, then
+//! the image; it's used until it's older than the TTL, and expired files are removed at startup
+//! and then now and again as new images are written. Concurrent requests for an image that isn't
+//! cached share a single download.
+
+use anyhow::{Context, Result, anyhow, bail};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use lru::LruCache;
+use std::collections::HashMap;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Images held in memory at once.
+pub const IMAGE_CACHE_SIZE: usize = 20;
+
+/// How often writing an image also removes expired ones from the disk cache, at most. A TTL
+/// shorter than this is used instead.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedImage {
+    pub mime_type: String,
+    pub data: Arc<[u8]>,
+    /// For an animation, how many of its frames the still image shows.
+    pub animation_frames: Option<usize>,
+}
+
+/// A download in progress, shared by everyone waiting for it. Errors are strings because
+/// every waiter gets a copy.
+type Fetch = Shared<BoxFuture<'static, Result<CachedImage, String>>>;
+
+pub struct ImageCache {
+    memory: Mutex<LruCache<String, CachedImage>>,
+    /// The directory and TTL of the disk cache, if there is one.
+    disk: Option<(PathBuf, Duration)>,
+    /// When expired files were last removed from the disk cache.
+    pruned: Mutex<Instant>,
+    in_flight: Mutex<HashMap<String, Fetch>>,
+}
+
+impl ImageCache {
+    /// A cache that keeps nothing across restarts.
+    pub fn in_memory(capacity: usize) -> Self {
+        ImageCache {
+            memory: Mutex::new(LruCache::new(NonZeroUsize::new(capacity.max(1)).unwrap())),
+            disk: None,
+            pruned: Mutex::new(Instant::now()),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A cache backed by `dir`, which is created if needed. Expired files are removed now and
+    /// every [`PRUNE_INTERVAL`] or so while images are being cached. A zero
+    /// `ttl`, or a directory that can't be created, leaves the cache in memory only.
+    pub fn new(capacity: usize, dir: &Path, ttl: Duration) -> Self {
+        let mut cache = ImageCache::in_memory(capacity);
+        if ttl.is_zero() {
+            return cache;
+        }
+        match std::fs::create_dir_all(dir) {
+            Ok(()) => {
+                remove_expired(dir, ttl);
+                cache.disk = Some((dir.to_path_buf(), ttl));
+            }
+            Err(e) => tracing::warn!(dir = %dir.display(), "Can't create image cache directory, caching in memory only: {}", e),
+        }
+        cache
+    }
+
+    pub async fn get(&self, url: &str) -> Option<CachedImage> {
+        let remembered = self.memory.lock().unwrap().get(url).cloned();
+        if remembered.is_some() {
+            tracing::info!(%url, "Image cache hit");
+            return remembered;
+        }
+        let (dir, ttl) = self.disk.as_ref()?;
+        let path = dir.join(file_name(url));
+        let image = match read_file(&path, url, *ttl).await {
+            Ok(image) => image?,
+            Err(e) => {
+                tracing::warn!(path = %path.display(), "Ignoring unreadable cached image: {:#}", e);
+                return None;
+            }
+        };
+        self.memory.lock().unwrap().put(url.to_string(), image.clone());
+        Some(image)
+    }
+
+    pub async fn put(&self, url: &str, mime_type: &str, data: Vec<u8>) {
+        let image = CachedImage { mime_type: mime_type.to_string(), data: data.into(), animation_frames: None };
+        self.memory.lock().unwrap().put(url.to_string(), image.clone());
+        self.write_to_disk(url, &image).await;
+    }
+
+    /// The cached image for `url`, or the result of `fetch`, which is then cached. If a fetch
+    /// for the same URL is already running, this waits for that one instead.
+    pub async fn get_or_fetch<F>(&self, url: &str, fetch: F) -> Result<CachedImage>
+    where
+        F: Future<Output = Result<CachedImage>> + Send + 'static,
+    {
+        if let Some(image) = self.get(url).await {
+            return Ok(image);
+        }
+        let shared = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(url.to_string())
+            .or_insert_with(|| fetch.map(|result| result.map_err(|e| format!("{:#}", e))).boxed().shared())
+            .clone();
+        let result = shared.clone().await;
+
+        // The first waiter to finish caches the image. It goes in memory before the download
+        // is forgotten, so a new request always finds one or the other.
+        let first = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let first = in_flight.get(url).is_some_and(|fetch| fetch.ptr_eq(&shared));
+            if first {
+                in_flight.remove(url);
+                if let Ok(image) = &result {
+                    self.memory.lock().unwrap().put(url.to_string(), image.clone());
+                }
+            }
+            first
+        };
+        let image = result.map_err(|e| anyhow!(e))?;
+        if first {
+            self.write_to_disk(url, &image).await;
+        }
+        Ok(image)
+    }
+
+    async fn write_to_disk(&self, url: &str, image: &CachedImage) {
+        let Some((dir, ttl)) = &self.disk else {
+            return;
+        };
+        let path = dir.join(file_name(url));
+        let mime_type = match image.animation_frames {
+            Some(frames) => format!("{}; frames={}", image.mime_type, frames),
+            None => image.mime_type.clone(),
+        };
+        let mut contents = format!("{}\n{}\n", mime_type, url).into_bytes();
+        contents.extend_from_slice(&image.data);
+        if let Err(e) = tokio::fs::write(&path, contents).await {
+            tracing::warn!(path = %path.display(), "Failed to write image to the cache: {}", e);
+        }
+
+        let due = {
+            let mut pruned = self.pruned.lock().unwrap();
+            let due = pruned.elapsed() >= PRUNE_INTERVAL.min(*ttl);
+            if due {
+                *pruned = Instant::now();
+            }
+            due
+        };
+        if due {
+            let (dir, ttl) = (dir.clone(), *ttl);
+            if let Err(e) = tokio::task::spawn_blocking(move || remove_expired(&dir, ttl)).await {
+                tracing::warn!("Failed to remove expired images from the cache: {}", e);
+            }
+        }
+    }
+}
+
+/// A stable 64-bit FNV-1a hash of the URL; std's hashers may change between Rust releases.
+fn file_name(url: &str) -> String {
+    let hash = url.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3));
+    format!("{:016x}.img", hash)
+}
+
+/// The image cached for `url` at `path`, or None if there is none, or it has expired or
+/// belongs to a URL with the same hash.
+async fn read_file(path: &Path, url: &str, ttl: Duration) -> Result<Option<CachedImage>> {
+    let contents = match tokio::fs::read(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("Failed to read cached image"),
+    };
+    if is_expired(&tokio::fs::metadata(path).await?, ttl) {
+        return Ok(None);
+    }
+    let mut parts = contents.splitn(3, |&byte| byte == b'\n');
+    let (Some(mime_type), Some(cached_url), Some(data)) = (parts.next(), parts.next(), parts.next()) else {
+        bail!("Cached image is missing its header");
+    };
+    if cached_url != url.as_bytes() {
+        return Ok(None);
+    }
+    let mime_type = String::from_utf8(mime_type.to_vec()).context("Cached image has an invalid MIME type")?;
+    let (mime_type, animation_frames) = match mime_type.split_once("; frames=") {
+        Some((mime_type, frames)) => {
+            (mime_type.to_string(), Some(frames.parse().context("Cached image has an invalid frame count")?))
+        }
+        None => (mime_type, None),
+    };
+    Ok(Some(CachedImage { mime_type, data: data.into(), animation_frames }))
+}
+
+fn is_expired(metadata: &std::fs::Metadata, ttl: Duration) -> bool {
+    let age = metadata.modified().ok().and_then(|modified| SystemTime::now().duration_since(modified).ok());
+    age.is_some_and(|age| age > ttl)
+}
+
+fn remove_expired(dir: &Path, ttl: Duration) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let expired = path.extension().is_some_and(|extension| extension == "img")
+            && entry.metadata().is_ok_and(|metadata| is_expired(&metadata, ttl));
+        if expired && std::fs::remove_file(&path).is_ok() {
+            removed += 1;
+        }
+    }
+    if removed > 0 {
+        tracing::info!(dir = %dir.display(), removed, "Removed expired images from the cache");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_images_survive_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let ttl = Duration::from_secs(3600);
+        let url = "https://example.org/cat.png";
+        let cache = ImageCache::new(2, dir.path(), ttl);
+        assert_eq!(cache.get(url).await, None);
+        cache.put(url, "image/png", vec![0, 10, 13, 255]).await;
+
+        let restarted = ImageCache::new(2, dir.path(), ttl);
+        let image = restarted.get(url).await.unwrap();
+        assert_eq!(image.mime_type, "image/png");
+        assert_eq!(&*image.data, &[0, 10, 13, 255]);
+        assert_eq!(restarted.get("https://example.org/dog.png").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_animation_frames_survive_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let ttl = Duration::from_secs(3600);
+        let url = "https://example.org/dancing.gif";
+        let image = CachedImage { mime_type: "image/png".to_string(), data: vec![1, 2].into(), animation_frames: Some(4) };
+        let cache = ImageCache::new(2, dir.path(), ttl);
+        assert_eq!(cache.get_or_fetch(url, async move { Ok::<_, anyhow::Error>(image) }).await.unwrap().animation_frames, Some(4));
+
+        let restarted = ImageCache::new(2, dir.path(), ttl).get(url).await.unwrap();
+        assert_eq!((restarted.mime_type.as_str(), restarted.animation_frames), ("image/png", Some(4)));
+    }
+
+    #[tokio::test]
+    async fn test_expired_images_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = "https://example.org/cat.png";
+        ImageCache::new(2, dir.path(), Duration::from_secs(3600)).put(url, "image/png", vec![1, 2, 3]).await;
+        std::thread::sleep(Duration::from_millis(20));
+
+        let cache = ImageCache::new(2, dir.path(), Duration::from_millis(10));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        assert_eq!(cache.get(url).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_expired_images_are_removed_while_caching() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ImageCache::new(2, dir.path(), Duration::from_millis(10));
+        cache.put("https://example.org/cat.png", "image/png", vec![1, 2, 3]).await;
+        std::thread::sleep(Duration::from_millis(20));
+
+        cache.put("https://example.org/dog.png", "image/png", vec![4, 5, 6]).await;
+        let files: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(files, [std::ffi::OsString::from(file_name("https://example.org/dog.png"))]);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory() {
+        let cache = ImageCache::in_memory(1);
+        cache.put("a", "image/png", vec![1]).await;
+        cache.put("b", "image/png", vec![2]).await;
+        assert_eq!(cache.get("a").await, None); // Evicted
+        assert_eq!(&*cache.get("b").await.unwrap().data, &[2]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_fetches_share_one_download() {
+        let cache = ImageCache::in_memory(2);
+        let downloads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let fetch = || {
+            let downloads = downloads.clone();
+            async move {
+                downloads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok::<_, anyhow::Error>(CachedImage { mime_type: "image/png".to_string(), data: vec![7].into(), animation_frames: None })
+            }
+        };
+        let url = "https://example.org/cat.png";
+        let (a, b) = futures::join!(cache.get_or_fetch(url, fetch()), cache.get_or_fetch(url, fetch()));
+        assert_eq!(a.unwrap(), b.unwrap());
+        assert_eq!(downloads.load(std::sync::atomic::Ordering::SeqCst), 1);
+        // Cached now, so later requests don't download either
+        assert!(cache.get_or_fetch(url, fetch()).await.is_ok());
+        assert_eq!(downloads.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_fetches_are_not_cached() {
+        let cache = ImageCache::in_memory(2);
+        let error = cache.get_or_fetch("u", async { Err::<CachedImage, _>(anyhow!("404 Not Found")) }).await.unwrap_err();
+        assert_eq!(error.to_string(), "404 Not Found");
+        assert_eq!(cache.get("u").await, None);
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_file_name_is_stable() {
+        assert_eq!(file_name(""), "cbf29ce484222325.img");
+        assert_ne!(file_name("https://example.org/a.png"), file_name("https://example.org/b.png"));
+    }
+}
@@ -0,0 +1,54 @@
+//! Karma: "nick++" and "nick--" in a channel message adjust that nick's score in the channel.
+//! Scores can be queried with `!karma <nick>`, and the AI can look them up with a tool.
+
+/// Shortest nick that can receive karma; shorter targets are mostly "C++" and "i++".
+const MIN_NICK_LENGTH: usize = 2;
+const MAX_NICK_LENGTH: usize = 32;
+/// How many nicks `!karma` without an argument lists.
+pub const TOP_KARMA_COUNT: usize = 5;
+
+/// Finds the karma votes in a message, as (nick, +1 or -1) pairs. Each nick counts once per
+/// message, so "alice++ alice++" is a single vote.
+pub fn parse_votes(message: &str) -> Vec<(String, i64)> {
+    let mut votes: Vec<(String, i64)> = Vec::new();
+    for word in message.split_whitespace() {
+        let word = word.trim_end_matches([',', '.', '!', '?', ';', ':', ')']);
+        let (target, delta) = if let Some(target) = word.strip_suffix("++") {
+            (target, 1)
+        } else if let Some(target) = word.strip_suffix("--") {
+            (target, -1)
+        } else {
+            continue;
+        };
+        let target = target.trim_start_matches(['@', '(']).trim_end_matches(':');
+        if is_nick(target) && !votes.iter().any(|(nick, _)| nick.eq_ignore_ascii_case(target)) {
+            votes.push((target.to_string(), delta));
+        }
+    }
+    votes
+}
+
+/// Whether `text` looks like an IRC nick (letters, digits and `_-[]\^{}|`` ` ``).
+fn is_nick(text: &str) -> bool {
+    (MIN_NICK_LENGTH..=MAX_NICK_LENGTH).contains(&text.chars().count())
+        && text
+            .chars()
+            .all(|c| c.is_alphanumeric() || "_-[]\\^{}|`".contains(c))
+        && !text.ends_with('-') // "foo---" is someone being emphatic, not voting for "foo-"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_votes() {
+        assert_eq!(
+            parse_votes("thanks alice++, and @bob--! carol++ alice++"),
+            vec![("alice".to_string(), 1), ("bob".to_string(), -1), ("carol".to_string(), 1)]
+        );
+        assert!(parse_votes("I write C++ and i++ loops -- sometimes").is_empty());
+        assert!(parse_votes("wow--- no").is_empty());
+        assert_eq!(parse_votes("Emul: thanks!  dave_++"), vec![("dave_".to_string(), 1)]);
+    }
+}
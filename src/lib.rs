@@ -0,0 +1,65 @@
+//! Emul, an AI chatbot for IRC and Discord.
+//!
+//! The binary is a thin wrapper around this crate: load a [`Config`], open the database with
+//! [`db::init_db`], and hand both to [`run_bot`]. Embedders can do the same, assemble a bot with
+//! their own LLM backend, database, tools or chat network using [`BotBuilder`], or reach into the
+//! public modules for the pieces (the LLM backends, the tool registry, the AI handler) they need.
+
+pub mod ai_handler;
+mod anilist;
+pub mod bluenoise;
+pub mod bot;
+mod bot_guard;
+mod calc;
+mod channel_settings;
+mod commands;
+pub mod config;
+mod crypto;
+mod ctcp;
+mod currency;
+pub mod db;
+mod dice;
+mod export;
+mod extract;
+mod formatting;
+mod gemini;
+mod gemini_cache;
+mod health;
+pub mod image_cache;
+mod karma;
+pub mod llm;
+pub mod logging;
+mod memory;
+mod moderation;
+mod notify;
+pub mod nyaa_parser;
+pub mod outgoing;
+mod output_filter;
+pub mod page_cache;
+mod paste;
+pub mod proxy;
+mod public_fetch;
+mod quotes;
+mod relay;
+mod repetition;
+pub mod response_cache;
+pub mod roster;
+mod rss;
+mod sanitize;
+mod scheduler;
+mod services;
+mod stats;
+mod summary;
+mod tool_log;
+pub mod tools;
+pub mod torrent_client;
+pub mod transport;
+mod url_titles;
+mod user_facts;
+mod wasm_tools;
+mod wiki;
+mod youtube;
+
+pub use bot::{BotBuilder, run_bot};
+pub use config::Config;
+pub use db::DbPool;
@@ -0,0 +1,1245 @@
+//! Pluggable LLM backends.
+//!
+//! Conversations are kept in Gemini's `contents` format throughout the bot (roles "user"/"model",
+//! parts holding `text`, `inline_data`, `functionCall` or `functionResponse`), and tools are declared
+//! as Gemini `functionDeclarations`. Each backend translates that into its own wire format and
+//! returns a Gemini-shaped response (`candidates[0].content.parts` plus `finishReason`), so the
+//! function-calling loop in ai_handler doesn't need to know which API it is talking to.
+
+use crate::config::{Config, LlmBackendKind, SafetySetting};
+use crate::gemini::{GenerationConfig, UsageMetadata};
+use crate::gemini_cache::ContextCache;
+use crate::proxy::HttpProxy;
+use anyhow::{Context, Result, anyhow, bail};
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Instrument;
+
+pub const GEMINI_MAIN_MODEL: &str = "gemini-2.5-pro-exp-03-25";
+pub const GEMINI_FAST_MODEL: &str = "gemini-2.5-pro-exp-03-25";
+const GEMINI_EMBEDDING_MODEL: &str = "text-embedding-004";
+const GEMINI_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+const OPENAI_MAIN_MODEL: &str = "gpt-4o";
+const OPENAI_FAST_MODEL: &str = "gpt-4o-mini";
+const OPENAI_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+const ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com/v1";
+const ANTHROPIC_MAIN_MODEL: &str = "claude-3-5-sonnet-latest";
+const ANTHROPIC_FAST_MODEL: &str = "claude-3-5-haiku-latest";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const ANTHROPIC_MAX_TOKENS: u32 = 4096; // Anthropic requires an explicit output limit
+
+/// Which of the backend's configured models a request should use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelTier {
+    /// The main chat model.
+    Main,
+    /// A cheaper model for classification and summarization calls.
+    Fast,
+    /// The model chat requests are retried with when the main one fails; the main model if
+    /// none is configured.
+    Fallback,
+}
+
+/// Tokens consumed by one or more requests, as reported by the API.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    /// Generated tokens, including any thinking tokens.
+    pub output_tokens: u64,
+}
+
+impl TokenUsage {
+    /// Reads the `usageMetadata` of a Gemini-shaped response; missing counts are zero.
+    pub fn from_response(response: &Value) -> Self {
+        UsageMetadata::deserialize(&response["usageMetadata"]).unwrap_or_default().into()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.output_tokens
+    }
+}
+
+impl std::ops::AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.output_tokens += other.output_tokens;
+    }
+}
+
+/// A rough token count for `text`, about four characters per token. Good enough for budgeting
+/// without a tokenizer or a countTokens round trip per request.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// A single generation request, in Gemini's conversation format.
+pub struct LlmRequest<'a> {
+    pub system_prompt: &'a str,
+    pub contents: &'a [Value],
+    /// Gemini-style tool list (`[{"functionDeclarations": [...]}]`), if tools are enabled.
+    pub tools: Option<&'a Value>,
+    pub tier: ModelTier,
+    /// Sampling temperature; None leaves the backend's default.
+    pub temperature: Option<f64>,
+}
+
+pub trait LlmBackend: Send + Sync {
+    /// Short name for logs.
+    fn name(&self) -> &'static str;
+
+    /// Sends one request (no retries) and returns a Gemini-shaped response.
+    fn generate<'a>(&'a self, request: LlmRequest<'a>) -> BoxFuture<'a, Result<Value>>;
+
+    /// Like `generate`, but yields Gemini-shaped chunks as they arrive; combine them with
+    /// `merge_stream_chunk`. Backends without streaming support yield a single chunk.
+    fn generate_stream<'a>(
+        &'a self,
+        request: LlmRequest<'a>,
+    ) -> BoxFuture<'a, Result<BoxStream<'a, Result<Value>>>> {
+        Box::pin(async move {
+            let response = self.generate(request).await?;
+            Ok(stream::once(async move { Ok(response) }).boxed())
+        })
+    }
+
+    /// Whether requests may carry audio as `inline_data` parts.
+    fn supports_audio(&self) -> bool {
+        false
+    }
+
+    /// Computes an embedding vector for `text`, for similarity search.
+    fn embed<'a>(&'a self, _text: &'a str) -> BoxFuture<'a, Result<Vec<f32>>> {
+        Box::pin(async move { bail!("The {} backend does not support embeddings", self.name()) })
+    }
+}
+
+/// Parses a JSON array of numbers into an embedding vector.
+fn parse_embedding(values: &Value) -> Result<Vec<f32>> {
+    values
+        .as_array()
+        .ok_or_else(|| anyhow!("Embedding response missing values"))?
+        .iter()
+        .map(|v| v.as_f64().map(|f| f as f32).ok_or_else(|| anyhow!("Non-numeric embedding value")))
+        .collect()
+}
+
+/// Folds one streamed chunk into the accumulated response. Consecutive text parts are
+/// concatenated; everything else (function calls, inline data) is appended as-is.
+pub fn merge_stream_chunk(merged: &mut Value, chunk: &Value) {
+    if merged.is_null() {
+        *merged = json!({"candidates": [{"content": {"role": "model", "parts": []}}]});
+    }
+    if let Some(feedback) = chunk.get("promptFeedback") {
+        merged["promptFeedback"] = feedback.clone();
+    }
+    if let Some(error) = chunk.get("error") {
+        merged["error"] = error.clone();
+    }
+    // Each chunk carries the running totals, so the last one wins
+    if let Some(usage) = chunk.get("usageMetadata") {
+        merged["usageMetadata"] = usage.clone();
+    }
+    let candidate = &chunk["candidates"][0];
+    if let Some(finish_reason) = candidate.get("finishReason") {
+        merged["candidates"][0]["finishReason"] = finish_reason.clone();
+    }
+    let Some(new_parts) = candidate["content"]["parts"].as_array() else {
+        return;
+    };
+    let Some(parts) = merged["candidates"][0]["content"]["parts"].as_array_mut() else {
+        return;
+    };
+    for part in new_parts {
+        if let (Some(text), Some(last)) = (plain_text(part), parts.last_mut())
+            && plain_text(last).is_some()
+            && let Some(Value::String(existing)) = last.get_mut("text")
+        {
+            existing.push_str(text);
+        } else {
+            parts.push(part.clone());
+        }
+    }
+}
+
+/// The text of a part that holds nothing but text (not a thought, call, or inline data).
+fn plain_text(part: &Value) -> Option<&str> {
+    let object = part.as_object()?;
+    if object.len() != 1 {
+        return None;
+    }
+    object.get("text")?.as_str()
+}
+
+/// Removes complete server-sent events from `buffer` and returns their `data:` payloads.
+fn drain_sse_events(buffer: &mut String) -> Vec<String> {
+    let mut events = Vec::new();
+    loop {
+        let normalized = buffer.replace("\r\n", "\n");
+        let Some(end) = normalized.find("\n\n") else {
+            *buffer = normalized;
+            return events;
+        };
+        let data: Vec<&str> = normalized[..end]
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(str::trim_start)
+            .collect();
+        if !data.is_empty() {
+            events.push(data.join("\n"));
+        }
+        *buffer = normalized[end + 2..].to_string();
+    }
+}
+
+/// Builds the backend selected in the configuration, reaching its API through `proxy`.
+pub fn backend_from_config(config: &Config, proxy: &HttpProxy) -> Result<Arc<dyn LlmBackend>> {
+    if config.dry_run {
+        return Ok(Arc::new(EchoBackend));
+    }
+    let client = proxy.client().context("Failed to build the HTTP client for the AI API")?;
+    let models = |main: &str, fast: &str| ModelNames {
+        main: config.llm_model.clone().unwrap_or_else(|| main.to_string()),
+        fast: config.llm_fast_model.clone().unwrap_or_else(|| fast.to_string()),
+        fallback: config.llm_fallback_model.clone(),
+    };
+    let base_url = |default: &str| {
+        config
+            .llm_base_url
+            .clone()
+            .unwrap_or_else(|| default.to_string())
+            .trim_end_matches('/')
+            .to_string()
+    };
+    Ok(match config.llm_backend {
+        LlmBackendKind::Gemini => Arc::new(GeminiBackend {
+            client,
+            base_url: base_url(GEMINI_BASE_URL),
+            models: models(GEMINI_MAIN_MODEL, GEMINI_FAST_MODEL),
+            safety_settings: config.safety_settings.clone(),
+            api_key: None,
+            context_cache: (config.context_cache_secs > 0).then(|| ContextCache::new(Duration::from_secs(config.context_cache_secs))),
+        }),
+        LlmBackendKind::Openai => Arc::new(OpenAiBackend {
+            client,
+            base_url: base_url(OPENAI_BASE_URL),
+            models: models(OPENAI_MAIN_MODEL, OPENAI_FAST_MODEL),
+        }),
+        LlmBackendKind::Anthropic => Arc::new(AnthropicBackend {
+            client,
+            base_url: base_url(ANTHROPIC_BASE_URL),
+            models: models(ANTHROPIC_MAIN_MODEL, ANTHROPIC_FAST_MODEL),
+        }),
+    })
+}
+
+struct ModelNames {
+    main: String,
+    fast: String,
+    fallback: Option<String>,
+}
+
+impl ModelNames {
+    fn for_tier(&self, tier: ModelTier) -> &str {
+        match tier {
+            ModelTier::Main => &self.main,
+            ModelTier::Fast => &self.fast,
+            ModelTier::Fallback => self.fallback.as_deref().unwrap_or(&self.main),
+        }
+    }
+}
+
+/// Iterates over the function declarations in a Gemini-style tool list.
+fn function_declarations(tools: &Value) -> impl Iterator<Item = &Value> {
+    tools
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|tool| tool["functionDeclarations"].as_array())
+        .flatten()
+}
+
+fn content_parts(content: &Value) -> &[Value] {
+    content["parts"].as_array().map(Vec::as_slice).unwrap_or(&[])
+}
+
+/// Wraps translated parts in the Gemini response shape.
+fn gemini_shaped_response(parts: Vec<Value>, finish_reason: &str) -> Value {
+    json!({
+        "candidates": [{
+            "content": { "role": "model", "parts": parts },
+            "finishReason": finish_reason
+        }]
+    })
+}
+
+// --- Gemini ---
+
+pub struct GeminiBackend {
+    client: reqwest::Client,
+    base_url: String,
+    models: ModelNames,
+    /// Sent as `safetySettings`; categories left out use Gemini's defaults.
+    safety_settings: Vec<SafetySetting>,
+    /// Read from GEMINI_API_KEY on each request when unset.
+    api_key: Option<String>,
+    /// Keeps large system prompts in Gemini's context cache, if enabled.
+    context_cache: Option<ContextCache>,
+}
+
+impl Default for GeminiBackend {
+    fn default() -> Self {
+        Self {
+            client: reqwest::Client::new(), // Direct; backend_from_config sets up the proxy
+            base_url: GEMINI_BASE_URL.to_string(),
+            models: ModelNames {
+                main: GEMINI_MAIN_MODEL.to_string(),
+                fast: GEMINI_FAST_MODEL.to_string(),
+                fallback: None,
+            },
+            safety_settings: Vec::new(),
+            api_key: None,
+            context_cache: None,
+        }
+    }
+}
+
+impl GeminiBackend {
+    /// A backend talking to `base_url` with a fixed key and model, e.g. a mock server in tests.
+    #[cfg(test)]
+    pub fn with_endpoint(base_url: &str, api_key: &str, model: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            models: ModelNames { main: model.to_string(), fast: model.to_string(), fallback: None },
+            api_key: Some(api_key.to_string()),
+            ..Self::default()
+        }
+    }
+
+    fn api_key(&self) -> Result<String> {
+        match &self.api_key {
+            Some(key) => Ok(key.clone()),
+            None => Ok(dotenvy::var("GEMINI_API_KEY")?),
+        }
+    }
+
+    /// The context cache holding the request's system prompt and tools, if there is one.
+    async fn cached_content(&self, api_key: &str, model: &str, request: &LlmRequest<'_>) -> Option<String> {
+        let cache = self.context_cache.as_ref()?;
+        cache
+            .get(&self.client, &self.base_url, api_key, model, request.system_prompt, request.tools)
+            .instrument(tracing::info_span!("gemini_context_cache"))
+            .await
+    }
+
+    /// Posts a request body. If Gemini refuses a request naming a context cache, the cache is
+    /// forgotten, so the retry creates a new one instead of hitting the same error.
+    async fn post(&self, url: &str, body: &Value, cached_content: Option<&str>) -> Result<reqwest::Response> {
+        let response = self.client.post(url).json(body).send().await?;
+        let status = response.status();
+        if let (Some(cache), Some(name)) = (&self.context_cache, cached_content)
+            && matches!(status, reqwest::StatusCode::BAD_REQUEST | reqwest::StatusCode::FORBIDDEN | reqwest::StatusCode::NOT_FOUND)
+        {
+            tracing::warn!(cache = %name, %status, "Gemini refused a request naming a context cache, forgetting it");
+            cache.invalidate(name);
+        }
+        response.error_for_status().context("Gemini API request failed")
+    }
+}
+
+impl LlmBackend for GeminiBackend {
+    fn name(&self) -> &'static str {
+        "gemini"
+    }
+
+    fn supports_audio(&self) -> bool {
+        true
+    }
+
+    fn generate<'a>(&'a self, request: LlmRequest<'a>) -> BoxFuture<'a, Result<Value>> {
+        // Spans name the model but never the URL, which holds the API key
+        let span = tracing::info_span!("gemini_generate", model = self.models.for_tier(request.tier));
+        let generation = async move {
+            let (api_key, model) = (self.api_key()?, self.models.for_tier(request.tier));
+            let url = format!("{}/models/{}:generateContent?key={}", self.base_url, model, api_key);
+            let cached_content = self.cached_content(&api_key, model, &request).await;
+            let body = gemini_request_body(&request, &self.safety_settings, cached_content.as_deref());
+
+            tracing::trace!(request_body = %body, "Sending request to Gemini");
+            let response: Value = self
+                .post(&url, &body, cached_content.as_deref())
+                .await?
+                .json()
+                .await
+                .context("Failed to parse Gemini JSON response")?;
+            tracing::trace!(response_body = %response, "Received response from Gemini");
+            Ok(response)
+        };
+        Box::pin(generation.instrument(span))
+    }
+
+    fn generate_stream<'a>(
+        &'a self,
+        request: LlmRequest<'a>,
+    ) -> BoxFuture<'a, Result<BoxStream<'a, Result<Value>>>> {
+        // Until the response starts; the chunks then arrive within the caller's span
+        let span = tracing::info_span!("gemini_stream", model = self.models.for_tier(request.tier));
+        let generation = async move {
+            let (api_key, model) = (self.api_key()?, self.models.for_tier(request.tier));
+            let url = format!("{}/models/{}:streamGenerateContent?alt=sse&key={}", self.base_url, model, api_key);
+            let cached_content = self.cached_content(&api_key, model, &request).await;
+            let body = gemini_request_body(&request, &self.safety_settings, cached_content.as_deref());
+
+            tracing::trace!(request_body = %body, "Sending streaming request to Gemini");
+            let response = self.post(&url, &body, cached_content.as_deref()).await?;
+
+            // Each SSE event carries a partial GenerateContentResponse
+            let chunks = stream::unfold(
+                (Some(response), String::new(), VecDeque::<String>::new()),
+                |(mut response, mut buffer, mut pending)| async move {
+                    loop {
+                        if let Some(event) = pending.pop_front() {
+                            let chunk = serde_json::from_str::<Value>(&event)
+                                .context("Failed to parse Gemini stream chunk");
+                            return Some((chunk, (response, buffer, pending)));
+                        }
+                        match response.as_mut()?.chunk().await {
+                            Ok(Some(bytes)) => {
+                                buffer.push_str(&String::from_utf8_lossy(&bytes));
+                                pending.extend(drain_sse_events(&mut buffer));
+                            }
+                            Ok(None) => return None,
+                            Err(e) => {
+                                // Surface the error once, then end the stream
+                                let err = anyhow::Error::from(e).context("Gemini stream interrupted");
+                                return Some((Err(err), (None, buffer, pending)));
+                            }
+                        }
+                    }
+                },
+            );
+            Ok(chunks.boxed())
+        };
+        Box::pin(generation.instrument(span))
+    }
+
+    fn embed<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Vec<f32>>> {
+        let embedding = async move {
+            let url = format!(
+                "{}/models/{}:embedContent?key={}",
+                self.base_url,
+                GEMINI_EMBEDDING_MODEL,
+                self.api_key()?
+            );
+            let body = json!({
+                "model": format!("models/{}", GEMINI_EMBEDDING_MODEL),
+                "content": {"parts": [{"text": text}]}
+            });
+            let response: Value = self
+                .client
+                .post(&url)
+                .json(&body)
+                .send()
+                .await?
+                .error_for_status()
+                .context("Gemini embedding request failed")?
+                .json()
+                .await
+                .context("Failed to parse Gemini embedding response")?;
+            parse_embedding(&response["embedding"]["values"])
+        };
+        Box::pin(embedding.instrument(tracing::info_span!("gemini_embed", model = GEMINI_EMBEDDING_MODEL)))
+    }
+}
+
+/// The generateContent body for `request`. With `cached_content`, the system prompt and tools
+/// come from that context cache; Gemini refuses requests that send them as well.
+fn gemini_request_body(request: &LlmRequest<'_>, safety_settings: &[SafetySetting], cached_content: Option<&str>) -> Value {
+    let generation_config = GenerationConfig {
+        // Ensure response is text, even if function calling happens
+        response_mime_type: Some("text/plain".to_string()),
+        temperature: request.temperature,
+    };
+    let mut body = json!({
+        "contents": request.contents,
+        "generationConfig": generation_config
+    });
+    if let Some(name) = cached_content {
+        body["cachedContent"] = json!(name);
+    } else {
+        body["systemInstruction"] = json!({"parts": [{"text": request.system_prompt}]});
+        if let Some(tools) = request.tools {
+            body["tools"] = tools.clone();
+        }
+    }
+    if !safety_settings.is_empty() {
+        body["safetySettings"] = safety_settings
+            .iter()
+            .map(|setting| json!({"category": setting.category, "threshold": setting.threshold}))
+            .collect();
+    }
+    body
+}
+
+// --- OpenAI-compatible (OpenAI, llama.cpp, vLLM, Ollama, ...) ---
+
+pub struct OpenAiBackend {
+    client: reqwest::Client,
+    base_url: String,
+    models: ModelNames,
+}
+
+impl LlmBackend for OpenAiBackend {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn generate<'a>(&'a self, request: LlmRequest<'a>) -> BoxFuture<'a, Result<Value>> {
+        Box::pin(async move {
+            let body = openai_request_body(self.models.for_tier(request.tier), &request);
+            tracing::trace!(request_body = %body, "Sending request to OpenAI-compatible API");
+
+            let mut http_request = self
+                .client
+                .post(format!("{}/chat/completions", self.base_url))
+                .json(&body);
+            // Local servers usually don't need a key
+            if let Ok(api_key) = dotenvy::var("OPENAI_API_KEY") {
+                http_request = http_request.bearer_auth(api_key);
+            }
+            let response: Value = http_request
+                .send()
+                .await?
+                .error_for_status()
+                .context("OpenAI-compatible API request failed")?
+                .json()
+                .await
+                .context("Failed to parse OpenAI-compatible JSON response")?;
+            tracing::trace!(response_body = %response, "Received response from OpenAI-compatible API");
+            openai_response_to_gemini(&response)
+        })
+    }
+
+    fn embed<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Vec<f32>>> {
+        Box::pin(async move {
+            let mut http_request = self
+                .client
+                .post(format!("{}/embeddings", self.base_url))
+                .json(&json!({"model": OPENAI_EMBEDDING_MODEL, "input": text}));
+            if let Ok(api_key) = dotenvy::var("OPENAI_API_KEY") {
+                http_request = http_request.bearer_auth(api_key);
+            }
+            let response: Value = http_request
+                .send()
+                .await?
+                .error_for_status()
+                .context("OpenAI-compatible embedding request failed")?
+                .json()
+                .await
+                .context("Failed to parse OpenAI-compatible embedding response")?;
+            parse_embedding(&response["data"][0]["embedding"])
+        })
+    }
+}
+
+fn openai_request_body(model: &str, request: &LlmRequest<'_>) -> Value {
+    let mut messages = vec![json!({"role": "system", "content": request.system_prompt})];
+    // Gemini function calls have no IDs; responses are matched to calls by order
+    let mut pending_call_ids: VecDeque<String> = VecDeque::new();
+    let mut next_call_id = 0;
+
+    for content in request.contents {
+        let parts = content_parts(content);
+        if content["role"] == "model" {
+            let text: String = parts.iter().filter_map(|p| p["text"].as_str()).collect();
+            let tool_calls: Vec<Value> = parts
+                .iter()
+                .filter_map(|p| p.get("functionCall"))
+                .map(|call| {
+                    let id = format!("call_{}", next_call_id);
+                    next_call_id += 1;
+                    pending_call_ids.push_back(id.clone());
+                    json!({
+                        "id": id,
+                        "type": "function",
+                        "function": {
+                            "name": call["name"],
+                            "arguments": call.get("args").cloned().unwrap_or(json!({})).to_string()
+                        }
+                    })
+                })
+                .collect();
+            let mut message = json!({
+                "role": "assistant",
+                "content": if text.is_empty() { Value::Null } else { json!(text) }
+            });
+            if !tool_calls.is_empty() {
+                message["tool_calls"] = json!(tool_calls);
+            }
+            messages.push(message);
+        } else {
+            let mut user_content = Vec::new();
+            for part in parts {
+                if let Some(function_response) = part.get("functionResponse") {
+                    // Tool messages must directly follow the assistant's tool_calls message
+                    messages.push(json!({
+                        "role": "tool",
+                        "tool_call_id": pending_call_ids.pop_front().unwrap_or_default(),
+                        "content": function_response["response"].to_string()
+                    }));
+                } else if let Some(text) = part["text"].as_str() {
+                    user_content.push(json!({"type": "text", "text": text}));
+                } else if let Some(image) = part.get("inline_data") {
+                    user_content.push(json!({
+                        "type": "image_url",
+                        "image_url": {
+                            "url": format!(
+                                "data:{};base64,{}",
+                                image["mime_type"].as_str().unwrap_or_default(),
+                                image["data"].as_str().unwrap_or_default()
+                            )
+                        }
+                    }));
+                }
+            }
+            if !user_content.is_empty() {
+                messages.push(json!({"role": "user", "content": user_content}));
+            }
+        }
+    }
+
+    let mut body = json!({"model": model, "messages": messages});
+    if let Some(temperature) = request.temperature {
+        body["temperature"] = json!(temperature);
+    }
+    if let Some(tools) = request.tools {
+        let tools: Vec<Value> = function_declarations(tools)
+            .map(|declaration| json!({"type": "function", "function": declaration}))
+            .collect();
+        body["tools"] = json!(tools);
+    }
+    body
+}
+
+fn openai_response_to_gemini(response: &Value) -> Result<Value> {
+    let choice = &response["choices"][0];
+    if choice.is_null() {
+        if let Some(error_info) = response.get("error") {
+            bail!("OpenAI-compatible API error: {}", error_info);
+        }
+        bail!("Invalid response structure from OpenAI-compatible API: Missing 'choices'");
+    }
+
+    let message = &choice["message"];
+    let mut parts = Vec::new();
+    if let Some(text) = message["content"].as_str()
+        && !text.is_empty()
+    {
+        parts.push(json!({"text": text}));
+    }
+    for call in message["tool_calls"].as_array().into_iter().flatten() {
+        let arguments = call["function"]["arguments"].as_str().unwrap_or("{}");
+        let args: Value = serde_json::from_str(arguments)
+            .map_err(|e| anyhow!("Malformed tool call arguments from model: {}", e))?;
+        parts.push(json!({
+            "functionCall": { "name": call["function"]["name"], "args": args }
+        }));
+    }
+
+    let finish_reason = match choice["finish_reason"].as_str() {
+        Some("length") => "MAX_TOKENS",
+        Some("content_filter") => "SAFETY",
+        _ => "STOP",
+    };
+    let mut shaped = gemini_shaped_response(parts, finish_reason);
+    if let Some(usage) = response.get("usage") {
+        shaped["usageMetadata"] = json!({
+            "promptTokenCount": usage["prompt_tokens"],
+            "candidatesTokenCount": usage["completion_tokens"]
+        });
+    }
+    Ok(shaped)
+}
+
+// --- Anthropic ---
+
+pub struct AnthropicBackend {
+    client: reqwest::Client,
+    base_url: String,
+    models: ModelNames,
+}
+
+impl LlmBackend for AnthropicBackend {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
+
+    fn generate<'a>(&'a self, request: LlmRequest<'a>) -> BoxFuture<'a, Result<Value>> {
+        Box::pin(async move {
+            let body = anthropic_request_body(self.models.for_tier(request.tier), &request);
+            tracing::trace!(request_body = %body, "Sending request to Anthropic");
+
+            let response: Value = self
+                .client
+                .post(format!("{}/messages", self.base_url))
+                .header("x-api-key", dotenvy::var("ANTHROPIC_API_KEY")?)
+                .header("anthropic-version", ANTHROPIC_VERSION)
+                .json(&body)
+                .send()
+                .await?
+                .error_for_status()
+                .context("Anthropic API request failed")?
+                .json()
+                .await
+                .context("Failed to parse Anthropic JSON response")?;
+            tracing::trace!(response_body = %response, "Received response from Anthropic");
+            anthropic_response_to_gemini(&response)
+        })
+    }
+}
+
+fn anthropic_request_body(model: &str, request: &LlmRequest<'_>) -> Value {
+    let mut messages = Vec::new();
+    let mut pending_call_ids: VecDeque<String> = VecDeque::new();
+    let mut next_call_id = 0;
+
+    for content in request.contents {
+        let parts = content_parts(content);
+        if content["role"] == "model" {
+            let mut blocks = Vec::new();
+            for part in parts {
+                if let Some(text) = part["text"].as_str()
+                    && !text.is_empty()
+                {
+                    blocks.push(json!({"type": "text", "text": text}));
+                } else if let Some(call) = part.get("functionCall") {
+                    let id = format!("toolu_{}", next_call_id);
+                    next_call_id += 1;
+                    pending_call_ids.push_back(id.clone());
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": id,
+                        "name": call["name"],
+                        "input": call.get("args").cloned().unwrap_or(json!({}))
+                    }));
+                }
+            }
+            messages.push(json!({"role": "assistant", "content": blocks}));
+        } else {
+            // tool_result blocks have to come before any other content in the message
+            let mut tool_results = Vec::new();
+            let mut other_blocks = Vec::new();
+            for part in parts {
+                if let Some(function_response) = part.get("functionResponse") {
+                    tool_results.push(json!({
+                        "type": "tool_result",
+                        "tool_use_id": pending_call_ids.pop_front().unwrap_or_default(),
+                        "content": function_response["response"].to_string()
+                    }));
+                } else if let Some(text) = part["text"].as_str()
+                    && !text.is_empty()
+                {
+                    other_blocks.push(json!({"type": "text", "text": text}));
+                } else if let Some(image) = part.get("inline_data") {
+                    other_blocks.push(json!({
+                        "type": "image",
+                        "source": {
+                            "type": "base64",
+                            "media_type": image["mime_type"],
+                            "data": image["data"]
+                        }
+                    }));
+                }
+            }
+            tool_results.extend(other_blocks);
+            messages.push(json!({"role": "user", "content": tool_results}));
+        }
+    }
+
+    let mut body = json!({
+        "model": model,
+        "max_tokens": ANTHROPIC_MAX_TOKENS,
+        "system": request.system_prompt,
+        "messages": messages
+    });
+    if let Some(temperature) = request.temperature {
+        body["temperature"] = json!(temperature.min(1.0)); // Anthropic's range is 0-1
+    }
+    if let Some(tools) = request.tools {
+        let tools: Vec<Value> = function_declarations(tools)
+            .map(|declaration| {
+                json!({
+                    "name": declaration["name"],
+                    "description": declaration["description"],
+                    "input_schema": declaration["parameters"]
+                })
+            })
+            .collect();
+        body["tools"] = json!(tools);
+    }
+    body
+}
+
+fn anthropic_response_to_gemini(response: &Value) -> Result<Value> {
+    if response["type"] == "error" {
+        bail!("Anthropic API error: {}", response["error"]);
+    }
+    let blocks = response["content"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid response structure from Anthropic API: Missing 'content'"))?;
+
+    let parts: Vec<Value> = blocks
+        .iter()
+        .filter_map(|block| match block["type"].as_str() {
+            Some("text") => Some(json!({"text": block["text"]})),
+            Some("tool_use") => Some(json!({
+                "functionCall": { "name": block["name"], "args": block["input"] }
+            })),
+            _ => None,
+        })
+        .collect();
+
+    let finish_reason = match response["stop_reason"].as_str() {
+        Some("max_tokens") => "MAX_TOKENS",
+        Some("refusal") => "SAFETY",
+        _ => "STOP",
+    };
+    let mut shaped = gemini_shaped_response(parts, finish_reason);
+    if let Some(usage) = response.get("usage") {
+        shaped["usageMetadata"] = json!({
+            "promptTokenCount": usage["input_tokens"],
+            "candidatesTokenCount": usage["output_tokens"]
+        });
+    }
+    Ok(shaped)
+}
+
+// --- Usage metering ---
+
+/// Wraps a backend to add up the tokens used by every request made through it, so the caller
+/// can charge them to the channel they were for. Embedding APIs don't report usage, so the
+/// tokens embedded are estimated.
+pub struct UsageMeter<'a> {
+    inner: &'a dyn LlmBackend,
+    usage: std::sync::Mutex<TokenUsage>,
+}
+
+impl<'a> UsageMeter<'a> {
+    pub fn new(inner: &'a dyn LlmBackend) -> Self {
+        Self { inner, usage: std::sync::Mutex::new(TokenUsage::default()) }
+    }
+
+    /// Returns the tokens used since the last call, and starts counting afresh.
+    pub fn take(&self) -> TokenUsage {
+        std::mem::take(&mut *self.usage.lock().unwrap())
+    }
+
+    fn add(&self, usage: TokenUsage) {
+        *self.usage.lock().unwrap() += usage;
+    }
+}
+
+impl LlmBackend for UsageMeter<'_> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn generate<'a>(&'a self, request: LlmRequest<'a>) -> BoxFuture<'a, Result<Value>> {
+        Box::pin(async move {
+            let response = self.inner.generate(request).await?;
+            self.add(TokenUsage::from_response(&response));
+            Ok(response)
+        })
+    }
+
+    fn generate_stream<'a>(
+        &'a self,
+        request: LlmRequest<'a>,
+    ) -> BoxFuture<'a, Result<BoxStream<'a, Result<Value>>>> {
+        Box::pin(async move {
+            let chunks = self.inner.generate_stream(request).await?;
+            // Each chunk carries the running totals, so only what's new since the last is added
+            let mut counted = TokenUsage::default();
+            let chunks = chunks.inspect(move |chunk| {
+                if let Ok(chunk) = chunk
+                    && chunk.get("usageMetadata").is_some()
+                {
+                    let total = TokenUsage::from_response(chunk);
+                    self.add(TokenUsage {
+                        prompt_tokens: total.prompt_tokens.saturating_sub(counted.prompt_tokens),
+                        output_tokens: total.output_tokens.saturating_sub(counted.output_tokens),
+                    });
+                    counted = total;
+                }
+            });
+            Ok(chunks.boxed())
+        })
+    }
+
+    fn supports_audio(&self) -> bool {
+        self.inner.supports_audio()
+    }
+
+    fn embed<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Vec<f32>>> {
+        Box::pin(async move {
+            let embedding = self.inner.embed(text).await?;
+            self.add(TokenUsage { prompt_tokens: estimate_tokens(text) as u64, output_tokens: 0 });
+            Ok(embedding)
+        })
+    }
+}
+
+// --- Dry run ---
+
+const ECHO_EMBEDDING_DIMENSIONS: usize = 16;
+
+/// Stands in for a real model with `--dry-run`, answering deterministically without an API key
+/// or network access. The main model echoes the last chat line, or calls a tool if that line
+/// contains `/call <tool> [json args]`, and echoes tool results on the turn after. The fast
+/// model picks the first option of one-word classification prompts and echoes anything else.
+pub struct EchoBackend;
+
+impl LlmBackend for EchoBackend {
+    fn name(&self) -> &'static str {
+        "echo"
+    }
+
+    fn generate<'a>(&'a self, request: LlmRequest<'a>) -> BoxFuture<'a, Result<Value>> {
+        Box::pin(async move {
+            let response = echo_response(&request);
+            tracing::debug!(response = %response, "Dry run response");
+            Ok(response)
+        })
+    }
+
+    fn embed<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Vec<f32>>> {
+        Box::pin(async move { Ok(echo_embedding(text)) })
+    }
+}
+
+fn echo_response(request: &LlmRequest<'_>) -> Value {
+    let text_reply = |text: String| gemini_shaped_response(vec![json!({"text": text})], "STOP");
+    let parts = request.contents.last().map(content_parts).unwrap_or_default();
+    let text = parts.iter().filter_map(|part| part["text"].as_str()).collect::<Vec<_>>().join("\n");
+    if request.tier == ModelTier::Fast {
+        return text_reply(echo_fast_reply(request.system_prompt, &text));
+    }
+
+    let results: Vec<String> = parts
+        .iter()
+        .filter_map(|part| part.get("functionResponse"))
+        .map(|result| format!("{} returned {}", result["name"].as_str().unwrap_or("?"), result["response"]))
+        .collect();
+    if !results.is_empty() {
+        return text_reply(format!("[dry run] {}", results.join("; ")));
+    }
+
+    // The chat lines sit between untrusted-content markers; the last one is what to answer
+    let line = text.lines().rev().map(str::trim).find(|line| !line.is_empty() && !line.starts_with("<<<")).unwrap_or("");
+    if let Some(tools) = request.tools
+        && let Some((name, args)) = parse_echo_call(line)
+        && function_declarations(tools).any(|declaration| declaration["name"] == name)
+    {
+        return gemini_shaped_response(vec![json!({"functionCall": {"name": name, "args": args}})], "STOP");
+    }
+    text_reply(format!("[dry run] {}", line))
+}
+
+/// Finds `/call <tool> [json args]` in a line. Missing or unparseable args become `{}`.
+fn parse_echo_call(line: &str) -> Option<(&str, Value)> {
+    let (_, call) = line.split_once("/call ")?;
+    let (name, args) = call.trim().split_once(char::is_whitespace).unwrap_or((call.trim(), ""));
+    let args = serde_json::from_str(args.trim()).unwrap_or_else(|_| json!({}));
+    (!name.is_empty()).then_some((name, args))
+}
+
+fn echo_fast_reply(system_prompt: &str, text: &str) -> String {
+    if system_prompt.contains("single word")
+        && let Some(first_option) = system_prompt.split('"').nth(1)
+    {
+        return first_option.to_string();
+    }
+    if system_prompt.contains("only the number") {
+        return "0".to_string();
+    }
+    let last_line = text.lines().rev().map(str::trim).find(|line| !line.is_empty()).unwrap_or("");
+    format!("[dry run] {}", last_line)
+}
+
+/// A bag-of-words vector, so texts sharing words come out similar.
+fn echo_embedding(text: &str) -> Vec<f32> {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    let mut embedding = vec![0.0f32; ECHO_EMBEDDING_DIMENSIONS];
+    for word in text.split_whitespace() {
+        let mut hasher = DefaultHasher::new();
+        word.to_lowercase().hash(&mut hasher);
+        embedding[hasher.finish() as usize % ECHO_EMBEDDING_DIMENSIONS] += 1.0;
+    }
+    embedding
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tools() -> Value {
+        json!([{
+            "functionDeclarations": [{
+                "name": "roll_dice",
+                "description": "Rolls dice.",
+                "parameters": {"type": "object", "properties": {"dice_notation": {"type": "string"}}}
+            }]
+        }])
+    }
+
+    fn tool_conversation() -> Vec<Value> {
+        vec![
+            json!({"role": "user", "parts": [{"text": "roll 1d6"}]}),
+            json!({"role": "model", "parts": [{"functionCall": {"name": "roll_dice", "args": {"dice_notation": "1d6"}}}]}),
+            json!({"role": "user", "parts": [
+                {"functionResponse": {"name": "roll_dice", "response": {"result": "4"}}},
+                {"inline_data": {"mime_type": "image/png", "data": "AAAA"}}
+            ]}),
+        ]
+    }
+
+    #[test]
+    fn test_gemini_request_safety_settings() {
+        let contents = tool_conversation();
+        let request = LlmRequest {
+            system_prompt: "be nice",
+            contents: &contents,
+            tools: None,
+            tier: ModelTier::Main,
+            temperature: None,
+        };
+        assert!(gemini_request_body(&request, &[], None).get("safetySettings").is_none());
+
+        let settings = vec!["harassment=block_none".parse().unwrap()];
+        let body = gemini_request_body(&request, &settings, None);
+        assert_eq!(
+            body["safetySettings"],
+            json!([{"category": "HARM_CATEGORY_HARASSMENT", "threshold": "BLOCK_NONE"}])
+        );
+    }
+
+    #[test]
+    fn test_gemini_request_with_context_cache() {
+        let contents = tool_conversation();
+        let tools = tools();
+        let request = LlmRequest {
+            system_prompt: "be nice",
+            contents: &contents,
+            tools: Some(&tools),
+            tier: ModelTier::Main,
+            temperature: None,
+        };
+        let inline = gemini_request_body(&request, &[], None);
+        assert_eq!(inline["systemInstruction"], json!({"parts": [{"text": "be nice"}]}));
+        assert_eq!(inline["tools"], tools);
+        assert!(inline.get("cachedContent").is_none());
+
+        // The cache holds the system prompt and tools, so they're left out
+        let cached = gemini_request_body(&request, &[], Some("cachedContents/abc123"));
+        assert_eq!(cached["cachedContent"], "cachedContents/abc123");
+        assert!(cached.get("systemInstruction").is_none());
+        assert!(cached.get("tools").is_none());
+        assert_eq!(cached["contents"], json!(contents));
+    }
+
+    #[test]
+    fn test_openai_request_translation() {
+        let contents = tool_conversation();
+        let tools = tools();
+        let request = LlmRequest {
+            system_prompt: "be nice",
+            contents: &contents,
+            tools: Some(&tools),
+            tier: ModelTier::Main,
+            temperature: None,
+        };
+        let body = openai_request_body("local-model", &request);
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages[0], json!({"role": "system", "content": "be nice"}));
+        assert_eq!(messages[1]["content"][0]["text"], "roll 1d6");
+        assert_eq!(messages[2]["tool_calls"][0]["id"], "call_0");
+        assert_eq!(messages[2]["tool_calls"][0]["function"]["arguments"], r#"{"dice_notation":"1d6"}"#);
+        assert_eq!(messages[3], json!({"role": "tool", "tool_call_id": "call_0", "content": r#"{"result":"4"}"#}));
+        assert_eq!(messages[4]["content"][0]["image_url"]["url"], "data:image/png;base64,AAAA");
+        assert_eq!(body["tools"][0]["function"]["name"], "roll_dice");
+    }
+
+    #[test]
+    fn test_openai_response_translation() {
+        let response = json!({
+            "choices": [{
+                "message": {
+                    "content": null,
+                    "tool_calls": [{"id": "x", "type": "function", "function": {"name": "roll_dice", "arguments": "{\"dice_notation\":\"2d6\"}"}}]
+                },
+                "finish_reason": "tool_calls"
+            }]
+        });
+        let translated = openai_response_to_gemini(&response).unwrap();
+        assert_eq!(
+            translated,
+            gemini_shaped_response(
+                vec![json!({"functionCall": {"name": "roll_dice", "args": {"dice_notation": "2d6"}}})],
+                "STOP"
+            )
+        );
+    }
+
+    #[test]
+    fn test_anthropic_request_translation() {
+        let contents = tool_conversation();
+        let tools = tools();
+        let request = LlmRequest {
+            system_prompt: "be nice",
+            contents: &contents,
+            tools: Some(&tools),
+            tier: ModelTier::Fast,
+            temperature: None,
+        };
+        let body = anthropic_request_body("claude", &request);
+        assert_eq!(body["system"], "be nice");
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages[1]["content"][0]["type"], "tool_use");
+        assert_eq!(messages[2]["content"][0]["type"], "tool_result");
+        assert_eq!(messages[2]["content"][0]["tool_use_id"], messages[1]["content"][0]["id"]);
+        assert_eq!(messages[2]["content"][1]["type"], "image");
+        assert_eq!(body["tools"][0]["input_schema"]["type"], "object");
+    }
+
+    #[test]
+    fn test_anthropic_response_translation() {
+        let response = json!({
+            "type": "message",
+            "content": [{"type": "text", "text": "Hi!"}],
+            "stop_reason": "max_tokens"
+        });
+        let translated = anthropic_response_to_gemini(&response).unwrap();
+        assert_eq!(translated, gemini_shaped_response(vec![json!({"text": "Hi!"})], "MAX_TOKENS"));
+    }
+
+    #[test]
+    fn test_echo_backend() {
+        let request = |contents: &[Value], tools: Option<&Value>, tier| {
+            let request = LlmRequest { system_prompt: "be nice", contents, tools, tier, temperature: None };
+            echo_response(&request)
+        };
+        let tools = tools();
+        let chat = [json!({"role": "user", "parts": [{"text": "<<<UNTRUSTED CHAT HISTORY BEGIN>>>\n[12:00] <alice> hi\n[12:01] <bob> roll please /call roll_dice {\"dice_notation\": \"1d6\"}\n<<<UNTRUSTED CHAT HISTORY END>>>"}]})];
+        assert_eq!(
+            request(&chat, Some(&tools), ModelTier::Main)["candidates"][0]["content"]["parts"][0],
+            json!({"functionCall": {"name": "roll_dice", "args": {"dice_notation": "1d6"}}})
+        );
+        // Without tools on offer, the line is echoed instead
+        let echoed = request(&chat, None, ModelTier::Main);
+        assert!(echoed["candidates"][0]["content"]["parts"][0]["text"].as_str().unwrap().starts_with("[dry run] [12:01] <bob>"));
+
+        let results = tool_conversation();
+        assert_eq!(
+            request(&results, Some(&tools), ModelTier::Main)["candidates"][0]["content"]["parts"][0]["text"],
+            r#"[dry run] roll_dice returned {"result":"4"}"#
+        );
+    }
+
+    #[test]
+    fn test_echo_fast_reply() {
+        let prompt = r#"Check the message. Respond with a single word, "safe" or "unsafe"."#;
+        assert_eq!(echo_fast_reply(prompt, "anything"), "safe");
+        assert_eq!(echo_fast_reply("Respond with only the number.", ""), "0");
+        assert_eq!(echo_fast_reply("Summarize.", "first\nsecond\n"), "[dry run] second");
+    }
+
+    #[tokio::test]
+    async fn test_usage_meter() {
+        /// Answers with fixed token counts: 10 in and 2 out, the stream in two chunks.
+        struct Counted;
+        impl LlmBackend for Counted {
+            fn name(&self) -> &'static str {
+                "counted"
+            }
+            fn generate<'a>(&'a self, _request: LlmRequest<'a>) -> BoxFuture<'a, Result<Value>> {
+                Box::pin(async { Ok(json!({"usageMetadata": {"promptTokenCount": 10, "candidatesTokenCount": 2}})) })
+            }
+            fn generate_stream<'a>(&'a self, _request: LlmRequest<'a>) -> BoxFuture<'a, Result<BoxStream<'a, Result<Value>>>> {
+                let chunks = [1, 2].map(|output| Ok(json!({"usageMetadata": {"promptTokenCount": 10, "candidatesTokenCount": output}})));
+                Box::pin(async move { Ok(stream::iter(chunks).boxed()) })
+            }
+            fn embed<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Vec<f32>>> {
+                Box::pin(async move { Ok(echo_embedding(text)) })
+            }
+        }
+
+        let meter = UsageMeter::new(&Counted);
+        let request = || LlmRequest { system_prompt: "be nice", contents: &[], tools: None, tier: ModelTier::Fast, temperature: None };
+        meter.generate(request()).await.unwrap();
+        assert_eq!(meter.take(), TokenUsage { prompt_tokens: 10, output_tokens: 2 });
+        let chunks: Vec<_> = meter.generate_stream(request()).await.unwrap().collect().await;
+        assert_eq!(chunks.len(), 2);
+        meter.embed("four words right here").await.unwrap();
+        let embedded = estimate_tokens("four words right here") as u64;
+        assert_eq!(meter.take(), TokenUsage { prompt_tokens: 10 + embedded, output_tokens: 2 });
+        assert_eq!(meter.take(), TokenUsage::default());
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcde"), 2);
+        assert_eq!(estimate_tokens("ぴょんぴょん"), 2); // Characters, not bytes
+    }
+
+    #[test]
+    fn test_drain_sse_events() {
+        let mut buffer = "data: {\"a\":1}\r\n\r\ndata: {\"b\":".to_string();
+        assert_eq!(drain_sse_events(&mut buffer), vec!["{\"a\":1}"]);
+        buffer.push_str("2}\n\n");
+        assert_eq!(drain_sse_events(&mut buffer), vec!["{\"b\":2}"]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_merge_stream_chunks() {
+        let chunk = |parts: Value| json!({"candidates": [{"content": {"role": "model", "parts": parts}}]});
+        let mut merged = Value::Null;
+        merge_stream_chunk(&mut merged, &chunk(json!([{"text": "Hello "}])));
+        merge_stream_chunk(&mut merged, &chunk(json!([{"text": "world."}])));
+        merge_stream_chunk(&mut merged, &chunk(json!([{"functionCall": {"name": "roll_dice", "args": {}}}])));
+        let mut last = chunk(json!([{"text": "Done"}]));
+        last["candidates"][0]["finishReason"] = json!("STOP");
+        merge_stream_chunk(&mut merged, &last);
+        assert_eq!(
+            merged["candidates"][0]["content"]["parts"],
+            json!([
+                {"text": "Hello world."},
+                {"functionCall": {"name": "roll_dice", "args": {}}},
+                {"text": "Done"}
+            ])
+        );
+        assert_eq!(merged["candidates"][0]["finishReason"], "STOP");
+    }
+
+    #[test]
+    fn test_token_usage_from_responses() {
+        let gemini = json!({"usageMetadata": {"promptTokenCount": 100, "candidatesTokenCount": 20, "thoughtsTokenCount": 5}});
+        let mut usage = TokenUsage::from_response(&gemini);
+        assert_eq!(usage, TokenUsage { prompt_tokens: 100, output_tokens: 25 });
+
+        let anthropic = json!({
+            "content": [{"type": "text", "text": "Hi"}],
+            "stop_reason": "end_turn",
+            "usage": {"input_tokens": 10, "output_tokens": 3}
+        });
+        usage += TokenUsage::from_response(&anthropic_response_to_gemini(&anthropic).unwrap());
+        assert_eq!(usage.total(), 138);
+        assert_eq!(TokenUsage::from_response(&json!({})).total(), 0);
+    }
+}
@@ -0,0 +1,119 @@
+//! Where the bot's tracing output goes: readable lines on stdout, as always, and with
+//! `--log-dir`, JSON lines in files rotated hourly or daily, for shipping to Loki, ELK and the
+//! like. `--log-levels` raises or lowers single modules' levels in both. With
+//! `--otlp-endpoint`, spans (an AI request, the model calls and tool calls within it) are also
+//! exported over OTLP, to Jaeger, Tempo or any OpenTelemetry collector.
+
+use crate::config::{Config, LogRotation};
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{EnvFilter, Layer, fmt, prelude::*};
+
+/// Log files are named like emul.2025-01-31.log.
+const LOG_FILE_PREFIX: &str = "emul";
+const LOG_FILE_SUFFIX: &str = "log";
+/// What traces are filed under.
+const SERVICE_NAME: &str = "emul";
+
+/// Keeps the log file and trace exporter going; dropping it flushes what's buffered.
+pub struct LogGuard {
+    _file: Option<WorkerGuard>,
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.tracer_provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("Failed to flush traces: {}", e);
+        }
+    }
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+/// Sets up logging as configured. Log lines and spans bound for the log file and the trace
+/// collector are sent on background threads, so keep the returned guard until exit.
+pub fn init(config: &Config) -> Result<LogGuard> {
+    let stdout = fmt::layer().with_filter(level_filter(&config.log_levels)?);
+    let (file, guard) = match &config.log_dir {
+        Some(dir) => {
+            // The appender would create it too, but complains while looking for old files first
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create log directory {}", dir.display()))?;
+            let mut appender = RollingFileAppender::builder()
+                .rotation(config.log_rotation.into())
+                .filename_prefix(LOG_FILE_PREFIX)
+                .filename_suffix(LOG_FILE_SUFFIX);
+            if config.log_max_files > 0 {
+                appender = appender.max_log_files(config.log_max_files);
+            }
+            let appender = appender
+                .build(dir)
+                .with_context(|| format!("Failed to open a log file in {}", dir.display()))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            let layer = fmt::layer().json().with_writer(writer).with_filter(level_filter(&config.log_levels)?);
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+    let (traces, tracer_provider) = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()
+                .context("Failed to set up the OTLP trace exporter")?;
+            let provider = SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+                .build();
+            let layer = tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer(SERVICE_NAME))
+                .with_filter(level_filter(&config.log_levels)?);
+            (Some(layer), Some(provider))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(stdout)
+        .with(file)
+        .with(traces)
+        .try_init()
+        .context("Failed to set up logging")?;
+    Ok(LogGuard { _file: guard, tracer_provider })
+}
+
+/// Everything at info and up, adjusted by RUST_LOG and then by `overrides`, like `emul::llm=debug`.
+fn level_filter(overrides: &[String]) -> Result<EnvFilter> {
+    let mut filter = EnvFilter::from_default_env().add_directive("info".parse()?);
+    for directive in overrides {
+        let parsed = directive.parse().with_context(|| format!("Invalid log level {}; expected e.g. emul::llm=debug", directive))?;
+        filter = filter.add_directive(parsed);
+    }
+    Ok(filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_filter() {
+        assert!(level_filter(&["emul::llm=debug".to_string(), "serenity=warn".to_string()]).is_ok());
+        assert!(level_filter(&["emul=loud".to_string()]).is_err());
+    }
+}
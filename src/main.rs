@@ -1,37 +1,22 @@
 use anyhow::{Context, Result};
-use tracing_subscriber::{EnvFilter, fmt, prelude::*};
-
-mod ai_handler;
-mod bluenoise;
-mod bot;
-mod config;
-mod db;
-mod nyaa_parser;
+use emul::{bot, config, db, logging};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Setup Logging
-    tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(EnvFilter::from_default_env().add_directive("info".parse()?)) // Default to info for our crate
-        .init();
-
-    // Setup rustls
-    rustls::crypto::ring::default_provider().install_default().expect("Failed to install rustls crypto provider");
-
-    // Load Configuration
+    // Load Configuration, which says where logs go
     let config = config::Config::load().context("Failed to load configuration")?;
-    tracing::debug!(?config, "Configuration loaded");
 
-    // Initialize Database
-    let db_conn = db::init_db(config.db_path()).context("Failed to initialize database")?;
+    // Setup Logging; the guard flushes the log file on exit
+    let _log_guard = logging::init(&config)?;
+    tracing::debug!(?config, "Configuration loaded");
 
-    // Add initial admin if needed
-    db::add_initial_admin(&*db_conn.lock().await, &config.admin)
-        .context("Failed to add initial admin")?;
+    // Setup rustls
+    rustls::crypto::ring::default_provider().install_default().expect("Failed to install rustls crypto provider");
 
-    // Run the bot's main loop
-    if let Err(e) = bot::run_bot(config, db_conn).await {
+    // Each further network in the config file gets a bot of its own, run alongside
+    let networks = config.network_configs();
+    let bots = std::iter::once(config).chain(networks).map(run);
+    if let Err(e) = futures::future::try_join_all(bots).await {
         tracing::error!("Bot exited with error: {:?}", e);
         // Depending on the error, you might want different exit codes
         return Err(e);
@@ -40,3 +25,18 @@ async fn main() -> Result<()> {
     tracing::info!("Bot shutting down gracefully.");
     Ok(())
 }
+
+/// Opens a bot's database and runs it.
+async fn run(config: config::Config) -> Result<()> {
+    // Initialize Database
+    let db = db::init_db(config.db_path()).context("Failed to initialize database")?;
+
+    // Make the configured admin the owner if there is none
+    let admin = config.admin.clone();
+    db.run(move |conn| db::add_initial_owner(conn, &admin))
+        .await
+        .context("Failed to add initial owner")?;
+
+    // Run the bot's main loop
+    bot::run_bot(config, db).await
+}
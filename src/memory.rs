@@ -0,0 +1,187 @@
+//! Long-term memory: older conversation is embedded in chunks and stored in the database,
+//! so relevant context from weeks ago can be recalled once it has scrolled out of the
+//! rolling history window.
+
+use crate::ctcp;
+use crate::db::{self, DbPool, LogEntry, Memory};
+use crate::llm::LlmBackend;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Number of log lines embedded together as one memory.
+pub const MEMORY_CHUNK_LINES: usize = 30;
+/// Memories less similar than this to the query are never recalled.
+const MIN_MEMORY_SIMILARITY: f32 = 0.55;
+const MEMORY_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M UTC";
+
+/// The channels whose log is being embedded, so each is embedded by one run at a time instead
+/// of every new message racing to embed the same chunk. Keyed by lowercase channel.
+#[derive(Default)]
+pub struct MemoryGuard {
+    storing: Mutex<HashSet<String>>,
+}
+
+impl MemoryGuard {
+    /// Starts storing memories for `channel`, or None if that's underway already. It ends when
+    /// the returned guard is dropped.
+    pub fn start(&self, channel: &str) -> Option<Storing<'_>> {
+        let key = channel.to_lowercase();
+        let started = self.storing.lock().unwrap().insert(key.clone());
+        started.then(|| Storing { guard: self, key })
+    }
+}
+
+/// Memories being stored for a channel; see [`MemoryGuard::start`].
+pub struct Storing<'a> {
+    guard: &'a MemoryGuard,
+    key: String,
+}
+
+impl Drop for Storing<'_> {
+    fn drop(&mut self) {
+        self.guard.storing.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Embeds and stores the next chunk of the channel's log, if a full chunk has accumulated
+/// since the last memory. Returns whether a memory was stored.
+pub async fn remember_new_messages(llm: &dyn LlmBackend, db: &DbPool, channel: &str) -> Result<bool> {
+    let log_channel = channel.to_string();
+    let entries = db
+        .run(move |conn| {
+            let after_id = db::latest_memory_message_id(conn, &log_channel)?;
+            db::get_log_after(conn, &log_channel, after_id, MEMORY_CHUNK_LINES)
+        })
+        .await?;
+    if entries.len() < MEMORY_CHUNK_LINES {
+        return Ok(false);
+    }
+    let Some((last_id, last_entry)) = entries.last() else {
+        return Ok(false);
+    };
+
+    let content = format_chunk(entries.iter().map(|(_, entry)| entry));
+    let embedding = llm.embed(&content).await?;
+    let (memory_channel, timestamp, last_id) = (channel.to_string(), last_entry.timestamp, *last_id);
+    let added = db
+        .run(move |conn| db::add_memory(conn, &memory_channel, timestamp, last_id, &content, &embedding))
+        .await?;
+    if added {
+        tracing::debug!(%channel, last_id, "Stored new long-term memory");
+    }
+    Ok(added)
+}
+
+/// Returns up to `top_k` stored memories most relevant to `query`, oldest first.
+/// Memories from `before` onwards are skipped, since the rolling history already covers them.
+pub async fn recall(
+    llm: &dyn LlmBackend,
+    db: &DbPool,
+    channel: &str,
+    query: &str,
+    top_k: usize,
+    before: Option<DateTime<Utc>>,
+) -> Result<Vec<Memory>> {
+    let memory_channel = channel.to_string();
+    let memories: Vec<Memory> = db
+        .run(move |conn| db::get_memories(conn, &memory_channel))
+        .await?
+        .into_iter()
+        .filter(|m| before.is_none_or(|before| m.timestamp < before))
+        .collect();
+    if memories.is_empty() || top_k == 0 {
+        return Ok(Vec::new());
+    }
+    let query_embedding = llm.embed(query).await?;
+    Ok(rank_memories(memories, &query_embedding, top_k))
+}
+
+/// Formats recalled memories for inclusion in the prompt.
+pub fn format_memories(memories: &[Memory]) -> String {
+    memories
+        .iter()
+        .map(|m| format!("From around {}:\n{}", m.timestamp.format(MEMORY_TIMESTAMP_FORMAT), m.content))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn format_chunk<'a>(entries: impl Iterator<Item = &'a LogEntry>) -> String {
+    entries
+        .map(|e| format!("[{}] {}", e.timestamp.format(MEMORY_TIMESTAMP_FORMAT), ctcp::display_line(&e.nick, &e.message)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Picks the `top_k` memories most similar to the query, returned in chronological order.
+fn rank_memories(memories: Vec<Memory>, query_embedding: &[f32], top_k: usize) -> Vec<Memory> {
+    let mut scored: Vec<(f32, Memory)> = memories
+        .into_iter()
+        .map(|m| (cosine_similarity(&m.embedding, query_embedding), m))
+        .filter(|(score, _)| *score >= MIN_MEMORY_SIMILARITY)
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.truncate(top_k);
+    let mut top: Vec<Memory> = scored.into_iter().map(|(_, m)| m).collect();
+    top.sort_by_key(|m| m.timestamp);
+    top
+}
+
+/// Cosine similarity of two vectors; 0.0 if they differ in length (e.g. after a model change).
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_guard() {
+        let guard = MemoryGuard::default();
+        let storing = guard.start("#test");
+        assert!(storing.is_some());
+        assert!(guard.start("#TEST").is_none());
+        assert!(guard.start("#other").is_some());
+        drop(storing);
+        assert!(guard.start("#test").is_some());
+    }
+
+    fn memory(secs: i64, embedding: Vec<f32>) -> Memory {
+        Memory {
+            timestamp: DateTime::from_timestamp(secs, 0).unwrap(),
+            content: format!("memory at {}", secs),
+            embedding,
+        }
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[1.0, 0.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn test_rank_memories() {
+        let memories = vec![
+            memory(100, vec![1.0, 0.0]),  // Identical direction
+            memory(200, vec![0.0, 1.0]),  // Unrelated, below threshold
+            memory(300, vec![0.8, 0.6]),  // Close
+            memory(400, vec![0.6, 0.8]),  // Less close
+        ];
+        let top = rank_memories(memories, &[1.0, 0.0], 2);
+        let times: Vec<i64> = top.iter().map(|m| m.timestamp.timestamp()).collect();
+        assert_eq!(times, vec![100, 300]); // Best two, in chronological order
+    }
+}
@@ -0,0 +1,176 @@
+//! Helping to moderate channels that ask for it with `!set #channel moderation ...`. Each message
+//! there is checked by the fast model, and one that breaks the rules earns its sender a warning,
+//! kept in the database. What happens then is up to the channel: the bot can warn the sender, tell
+//! an ops channel, and kick them once they've had `kick_after` warnings in 30 days, if it's a
+//! channel operator. Admins look warnings up with `!warnings` and clear them with `!pardon`.
+//!
+//! This is separate from `--moderated-channels`, which screens the bot's own answers.
+
+use anyhow::{Result, bail};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Warnings older than this don't count towards a kick.
+pub const WARNING_WINDOW_DAYS: i64 = 30;
+/// Warnings it takes to be kicked, for channels that haven't chosen.
+pub const DEFAULT_KICK_AFTER: u32 = 3;
+/// Longest reason kept for a warning, in characters.
+pub const MAX_REASON_LENGTH: usize = 80;
+/// Someone breaking the rules over and over is told about it (and reported) at most this often.
+const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What the bot does about a message that breaks the rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModerationAction {
+    /// Tells the sender in the channel.
+    Warn,
+    /// Tells the channel's ops channel.
+    Notify,
+    /// Kicks the sender once they have enough warnings.
+    Kick,
+}
+
+impl ModerationAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ModerationAction::Warn => "warn",
+            ModerationAction::Notify => "notify",
+            ModerationAction::Kick => "kick",
+        }
+    }
+}
+
+impl fmt::Display for ModerationAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ModerationAction {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "warn" => Ok(ModerationAction::Warn),
+            "notify" => Ok(ModerationAction::Notify),
+            "kick" => Ok(ModerationAction::Kick),
+            _ => bail!("Expected warn, notify or kick, not \"{}\"", value.trim()),
+        }
+    }
+}
+
+/// "off", or a comma-separated list of actions, like "warn,notify".
+pub fn parse_actions(value: &str) -> Result<Vec<ModerationAction>> {
+    if value.eq_ignore_ascii_case("off") {
+        return Ok(Vec::new());
+    }
+    let mut actions = Vec::new();
+    for action in value.split(',') {
+        let action = action.parse()?;
+        if !actions.contains(&action) {
+            actions.push(action);
+        }
+    }
+    Ok(actions)
+}
+
+/// Keeps the checks from costing more than they're worth: each nick has one message checked at a
+/// time, in each channel, and is told about breaking the rules at most once a minute. Keyed by
+/// lowercase (channel, nick).
+#[derive(Default)]
+pub struct ModerationGuard {
+    checking: Mutex<HashSet<(String, String)>>,
+    warned: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl ModerationGuard {
+    /// Starts checking a message from `nick`, or None if one of theirs is being checked already.
+    /// The check ends when the returned guard is dropped.
+    pub fn start(&self, channel: &str, nick: &str) -> Option<Checking<'_>> {
+        let key = (channel.to_lowercase(), nick.to_lowercase());
+        let started = self.checking.lock().unwrap().insert(key.clone());
+        started.then(|| Checking { guard: self, key })
+    }
+
+    /// Whether `nick` may be told about breaking the rules now, noting it if so.
+    pub fn may_warn(&self, channel: &str, nick: &str, now: Instant) -> bool {
+        let mut warned = self.warned.lock().unwrap();
+        warned.retain(|_, last| now.duration_since(*last) < WARNING_INTERVAL);
+        let key = (channel.to_lowercase(), nick.to_lowercase());
+        if warned.contains_key(&key) {
+            return false;
+        }
+        warned.insert(key, now);
+        true
+    }
+}
+
+/// A message being checked; see [`ModerationGuard::start`].
+pub struct Checking<'a> {
+    guard: &'a ModerationGuard,
+    key: (String, String),
+}
+
+impl Drop for Checking<'_> {
+    fn drop(&mut self) {
+        self.guard.checking.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// What the bot tells a user whose message broke the rules. `kick_after` is set where they'll be
+/// kicked after that many warnings.
+pub fn warning_text(nick: &str, reason: &str, warnings: u32, kick_after: Option<u32>) -> String {
+    match kick_after {
+        Some(kick_after) => format!("{}: please keep it friendly ({}). Warning {} of {}.", nick, reason, warnings, kick_after),
+        None => format!("{}: please keep it friendly ({}).", nick, reason),
+    }
+}
+
+/// What the ops channel is told about a message that broke the rules.
+pub fn notice_text(channel: &str, nick: &str, reason: &str, warnings: u32, message: &str) -> String {
+    format!("[{}] {} was warned for {} ({} in {} days): {}", channel, nick, reason, warnings, WARNING_WINDOW_DAYS, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_actions() {
+        assert_eq!(parse_actions("OFF").unwrap(), []);
+        assert_eq!(
+            parse_actions("warn, Notify,warn").unwrap(),
+            [ModerationAction::Warn, ModerationAction::Notify]
+        );
+        assert_eq!(parse_actions("kick").unwrap(), [ModerationAction::Kick]);
+        assert!(parse_actions("warn,ban").is_err());
+        assert!(parse_actions("warn,,kick").is_err());
+    }
+
+    #[test]
+    fn test_moderation_guard() {
+        let guard = ModerationGuard::default();
+        let checking = guard.start("#test", "bob");
+        assert!(checking.is_some());
+        assert!(guard.start("#TEST", "Bob").is_none());
+        assert!(guard.start("#other", "bob").is_some());
+        drop(checking);
+        assert!(guard.start("#test", "bob").is_some());
+
+        let now = Instant::now();
+        assert!(guard.may_warn("#test", "bob", now));
+        assert!(!guard.may_warn("#test", "BOB", now + Duration::from_secs(1)));
+        assert!(guard.may_warn("#test", "alice", now));
+        assert!(guard.may_warn("#test", "bob", now + WARNING_INTERVAL));
+    }
+
+    #[test]
+    fn test_texts() {
+        assert_eq!(warning_text("bob", "insults", 2, Some(3)), "bob: please keep it friendly (insults). Warning 2 of 3.");
+        assert_eq!(warning_text("bob", "spam", 1, None), "bob: please keep it friendly (spam).");
+        assert_eq!(notice_text("#test", "bob", "spam", 1, "buy now"), "[#test] bob was warned for spam (1 in 30 days): buy now");
+    }
+}
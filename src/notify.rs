@@ -0,0 +1,138 @@
+//! `!notify`: users register keywords (their own nick, a project's name...) in a private message,
+//! and lines in a channel that mention one while they're away from it are saved. Away means
+//! they haven't said anything there for `--notify-away-mins`; lines are only saved for people in
+//! the channel or who have spoken there, so nobody hears from channels they were never in. When
+//! they next speak, the bot sends them the saved lines in a private message. A nick is anyone's
+//! to take, so both adding keywords and getting the lines need a login to services as the nick.
+//! IRC only, as Discord has notifications of its own.
+
+use crate::ctcp;
+use crate::db::Notification;
+use chrono::{DateTime, Duration, Utc};
+
+/// Keywords one user may have.
+pub const MAX_KEYWORDS: usize = 10;
+/// Shortest keyword, in characters; shorter ones match all kinds of lines.
+pub const MIN_KEYWORD_LENGTH: usize = 3;
+/// Longest keyword, in characters.
+pub const MAX_KEYWORD_LENGTH: usize = 50;
+/// Lines saved for one user; more drop the oldest.
+pub const MAX_SAVED_LINES: usize = 20;
+/// Saved lines are cut to this many characters in the digest, to fit in one IRC message.
+const MAX_DIGEST_LINE_CHARS: usize = 300;
+
+/// Tidies a keyword for saving: runs of whitespace become single spaces. None if it's too short
+/// or too long.
+pub fn normalize_keyword(keyword: &str) -> Option<String> {
+    let keyword = keyword.split_whitespace().collect::<Vec<_>>().join(" ");
+    (MIN_KEYWORD_LENGTH..=MAX_KEYWORD_LENGTH).contains(&keyword.chars().count()).then_some(keyword)
+}
+
+/// Whether someone is away from a channel, so lines there mentioning them are saved: they last
+/// spoke there at least `away` ago, or never have but are `member`s of it.
+pub fn is_away(member: bool, last_seen: Option<DateTime<Utc>>, now: DateTime<Utc>, away: Duration) -> bool {
+    match last_seen {
+        Some(seen) => now - seen >= away,
+        None => member,
+    }
+}
+
+/// Whether `message` mentions `keyword` as a whole word, ignoring case.
+pub fn mentions(message: &str, keyword: &str) -> bool {
+    let (message, keyword) = (message.to_lowercase(), keyword.to_lowercase());
+    if keyword.is_empty() {
+        return false;
+    }
+    message.match_indices(&keyword).any(|(start, _)| {
+        let before = message[..start].chars().next_back();
+        let after = message[start + keyword.len()..].chars().next();
+        !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+    })
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// What a user who's back is sent: how often they were mentioned, then the lines.
+pub fn digest(notifications: &[Notification]) -> Vec<String> {
+    let heading = match notifications.len() {
+        1 => "While you were away, you were mentioned once:".to_string(),
+        count => format!("While you were away, you were mentioned {} times:", count),
+    };
+    let lines = notifications.iter().map(|notification| {
+        let line: String = ctcp::display_line(&notification.sender, &notification.message).chars().take(MAX_DIGEST_LINE_CHARS).collect();
+        format!("[{} {}] {}", notification.channel, notification.timestamp.format("%Y-%m-%d %H:%M UTC"), line)
+    });
+    std::iter::once(heading).chain(lines).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_normalize_keyword() {
+        assert_eq!(normalize_keyword("  release   notes ").as_deref(), Some("release notes"));
+        assert_eq!(normalize_keyword(" "), None);
+        assert_eq!(normalize_keyword(" ab "), None);
+        assert_eq!(normalize_keyword(&"x".repeat(MAX_KEYWORD_LENGTH + 1)), None);
+    }
+
+    #[test]
+    fn test_is_away() {
+        let now = Utc.with_ymd_and_hms(2025, 1, 31, 12, 30, 0).unwrap();
+        let away = Duration::minutes(30);
+        assert!(is_away(false, Some(now - Duration::hours(1)), now, away));
+        assert!(!is_away(true, Some(now - Duration::minutes(5)), now, away));
+        // Never spoke there: only members are told what they missed
+        assert!(is_away(true, None, now, away));
+        assert!(!is_away(false, None, now, away));
+    }
+
+    #[tokio::test]
+    async fn test_keywords_are_looked_up_by_message() {
+        let db = crate::db::init_memory_db().unwrap();
+        let found = db
+            .run(|conn| {
+                crate::db::add_notify_keyword(conn, "alice", "Alice")?;
+                crate::db::add_notify_keyword(conn, "bob", "Ärger")?;
+                crate::db::add_notify_keyword(conn, "carol", "release notes")?;
+                crate::db::get_notify_keywords_in(conn, "ALICE: any news?")
+            })
+            .await
+            .unwrap();
+        // Keywords SQLite can't lowercase are left for `mentions` to check
+        let nicks: Vec<&str> = found.iter().map(|(nick, _)| nick.as_str()).collect();
+        assert_eq!(nicks, ["alice", "bob"]);
+    }
+
+    #[test]
+    fn test_mentions() {
+        assert!(mentions("hey Alice, you there?", "alice"));
+        assert!(mentions("ping [bob]", "[bob]"));
+        assert!(mentions("who knows about release notes?", "Release Notes"));
+        assert!(!mentions("malice aforethought", "alice"));
+        assert!(!mentions("alice_away is gone", "alice"));
+        assert!(!mentions("anything", ""));
+    }
+
+    #[test]
+    fn test_digest() {
+        let timestamp = Utc.with_ymd_and_hms(2025, 1, 31, 12, 30, 0).unwrap();
+        let notification = |message: &str| Notification {
+            channel: "#test".to_string(),
+            sender: "bob".to_string(),
+            message: message.to_string(),
+            timestamp,
+        };
+        assert_eq!(
+            digest(&[notification("alice: lunch?")]),
+            ["While you were away, you were mentioned once:", "[#test 2025-01-31 12:30 UTC] bob: alice: lunch?"]
+        );
+        let lines = digest(&[notification("alice?"), notification(&"a".repeat(1000))]);
+        assert_eq!(lines[0], "While you were away, you were mentioned 2 times:");
+        assert!(lines[2].chars().count() < MAX_DIGEST_LINE_CHARS + 40);
+    }
+}
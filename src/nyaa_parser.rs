@@ -1,6 +1,9 @@
 use reqwest::StatusCode;
-use scraper::{Html, Selector};
+use scraper::{ElementRef, Html, Selector};
 use thiserror::Error;
+use url::Url;
+
+pub const NYAA_BASE_URL: &str = "https://nyaa.si";
 
 #[derive(Error, Debug)]
 pub enum NyaaParserError {
@@ -14,6 +17,35 @@ pub enum NyaaParserError {
     SelectorParseError(String),
     #[error("Found magnet link tag, but it is missing the 'href' attribute")]
     HrefAttributeMissing,
+    #[error("Could not find the results table in the search page")]
+    ResultsTableNotFound,
+    #[error("Invalid URL: {0}")]
+    UrlError(#[from] url::ParseError),
+    #[error("Not a Nyaa.si page: {0}")]
+    NotNyaaUrl(String),
+}
+
+/// How to order search results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchOrder {
+    /// Newest uploads first (Nyaa's default).
+    Newest,
+    /// Most seeded first.
+    Seeders,
+}
+
+/// One torrent from a Nyaa.si search results page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchResult {
+    pub title: String,
+    /// The torrent's view page, which `fetch_and_extract_magnet_url` accepts.
+    pub url: String,
+    /// As displayed, e.g. "1.4 GiB".
+    pub size: String,
+    /// Upload time as displayed, e.g. "2025-04-11 17:00" (UTC).
+    pub date: String,
+    pub seeders: u32,
+    pub leechers: u32,
 }
 
 /// Extracts the primary magnet link from the HTML content of a Nyaa.si view page.
@@ -56,16 +88,26 @@ pub fn extract_magnet_url(html_content: &str) -> Result<String, NyaaParserError>
 
 /// Fetches the HTML content from a Nyaa.si view page URL and extracts the primary magnet link.
 ///
+/// The URL usually comes from chat, so anything not on Nyaa.si is refused rather than fetched;
+/// otherwise anyone could have the bot request addresses on its own machine or network.
+///
 /// # Arguments
 ///
+/// * `client` - The HTTP client to fetch with, from the bot's `HttpProxy`.
 /// * `url` - The URL of the Nyaa.si torrent page.
 ///
 /// # Returns
 ///
 /// A `Result` containing the magnet URL as a `String` if successful, or a `NyaaParserError` otherwise.
-pub async fn fetch_and_extract_magnet_url(url: &str) -> Result<String, NyaaParserError> {
+pub async fn fetch_and_extract_magnet_url(client: &reqwest::Client, url: &str) -> Result<String, NyaaParserError> {
+    let url = Url::parse(url)?;
+    let nyaa = Url::parse(NYAA_BASE_URL)?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str() != nyaa.host_str() {
+        return Err(NyaaParserError::NotNyaaUrl(url.to_string()));
+    }
+
     // Perform the HTTP GET request
-    let response = reqwest::get(url).await?;
+    let response = client.get(url).send().await?;
 
     // Check if the request was successful
     if !response.status().is_success() {
@@ -80,6 +122,81 @@ pub async fn fetch_and_extract_magnet_url(url: &str) -> Result<String, NyaaParse
 }
 
 
+/// The Nyaa.si search URL for `query`, across all categories.
+pub fn search_url(base_url: &str, query: &str, order: SearchOrder) -> Result<Url, url::ParseError> {
+    let sort = match order {
+        SearchOrder::Newest => "id",
+        SearchOrder::Seeders => "seeders",
+    };
+    Url::parse_with_params(
+        &format!("{}/", base_url.trim_end_matches('/')),
+        &[("f", "0"), ("c", "0_0"), ("q", query), ("s", sort), ("o", "desc")],
+    )
+}
+
+/// Extracts the torrents from the HTML of a Nyaa.si search results page, in page order.
+/// View URLs are made absolute against `base_url`.
+///
+/// A search without matches gives a page without the results table, so that isn't an error;
+/// a page that has neither the table nor Nyaa's "No results found" message is.
+pub fn parse_search_results(html_content: &str, base_url: &str) -> Result<Vec<SearchResult>, NyaaParserError> {
+    let document = Html::parse_document(html_content);
+    let selector = |s: &str| Selector::parse(s).map_err(|e| NyaaParserError::SelectorParseError(e.to_string()));
+    let table_selector = selector("table.torrent-list")?;
+    let row_selector = selector("tbody tr")?;
+    let cell_selector = selector("td")?;
+    // The name cell also holds a link to the comments, which has the "comments" class
+    let title_selector = selector(r#"a[href^="/view/"]:not(.comments)"#)?;
+
+    let Some(table) = document.select(&table_selector).next() else {
+        let no_results = document.root_element().text().any(|text| text.contains("No results found"));
+        return if no_results { Ok(Vec::new()) } else { Err(NyaaParserError::ResultsTableNotFound) };
+    };
+
+    let base = Url::parse(base_url)?;
+    let cell_text = |cell: Option<&ElementRef>| {
+        cell.map(|c| c.text().collect::<String>().trim().to_string()).unwrap_or_default()
+    };
+    let mut results = Vec::new();
+    for row in table.select(&row_selector) {
+        // Columns: category, name, links, size, date, seeders, leechers, completed
+        let cells: Vec<ElementRef> = row.select(&cell_selector).collect();
+        let Some(link) = cells.get(1).and_then(|cell| cell.select(&title_selector).next()) else {
+            continue;
+        };
+        let Some(url) = link.value().attr("href").and_then(|href| base.join(href).ok()) else {
+            continue;
+        };
+        let title = link
+            .value()
+            .attr("title")
+            .map(str::to_string)
+            .unwrap_or_else(|| link.text().collect::<String>().trim().to_string());
+        results.push(SearchResult {
+            title,
+            url: url.to_string(),
+            size: cell_text(cells.get(3)),
+            date: cell_text(cells.get(4)),
+            seeders: cell_text(cells.get(5)).parse().unwrap_or(0),
+            leechers: cell_text(cells.get(6)).parse().unwrap_or(0),
+        });
+    }
+    Ok(results)
+}
+
+/// Searches Nyaa.si with `client`, returning up to `limit` results.
+pub async fn search(client: &reqwest::Client, query: &str, order: SearchOrder, limit: usize) -> Result<Vec<SearchResult>, NyaaParserError> {
+    let response = client.get(search_url(NYAA_BASE_URL, query, order)?).send().await?;
+    if !response.status().is_success() {
+        return Err(NyaaParserError::HttpStatusError(response.status()));
+    }
+    let html_content = response.text().await?;
+    let mut results = parse_search_results(&html_content, NYAA_BASE_URL)?;
+    results.truncate(limit);
+    Ok(results)
+}
+
+
 // --- Unit Tests ---
 #[cfg(test)]
 mod tests {
@@ -103,6 +220,68 @@ mod tests {
         }
     }
 
+    const SEARCH_PAGE: &str = r#"
+        <table class="table table-bordered table-hover table-striped torrent-list">
+        <thead><tr><th>Category</th><th colspan="2">Name</th></tr></thead>
+        <tbody>
+        <tr class="success">
+            <td><a href="/?c=1_2" title="Anime - English-translated"><img src="/static/img/icons/nyaa/1_2.png"></a></td>
+            <td colspan="2">
+                <a href="/view/1958832#comments" class="comments" title="3 comments"><i class="fa fa-comments-o"></i>3</a>
+                <a href="/view/1958832" title="[SubsPlease] Example Show - 02 (1080p) [ABCD1234].mkv">[SubsPlease] Example Show - 02 (1080p) [ABCD1234].mkv</a>
+            </td>
+            <td class="text-center">
+                <a href="/download/1958832.torrent"><i class="fa fa-fw fa-download"></i></a>
+                <a href="magnet:?xt=urn:btih:0000"><i class="fa fa-fw fa-magnet"></i></a>
+            </td>
+            <td class="text-center">1.4 GiB</td>
+            <td class="text-center" data-timestamp="1744390802">2025-04-11 17:00</td>
+            <td class="text-center">1234</td>
+            <td class="text-center">56</td>
+            <td class="text-center">7890</td>
+        </tr>
+        <tr class="default">
+            <td><a href="/?c=1_2"></a></td>
+            <td colspan="2"><a href="/view/1955613" title="[SubsPlease] Example Show - 01 (1080p) [5678EFAB].mkv">[SubsPlease] Example Show - 01 (1080p)</a></td>
+            <td class="text-center"></td>
+            <td class="text-center">1.3 GiB</td>
+            <td class="text-center">2025-04-04 17:00</td>
+            <td class="text-center">321</td>
+            <td class="text-center">4</td>
+            <td class="text-center">9999</td>
+        </tr>
+        </tbody>
+        </table>"#;
+
+    #[test]
+    fn test_parse_search_results() {
+        let results = parse_search_results(SEARCH_PAGE, NYAA_BASE_URL).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results[0],
+            SearchResult {
+                title: "[SubsPlease] Example Show - 02 (1080p) [ABCD1234].mkv".to_string(),
+                url: "https://nyaa.si/view/1958832".to_string(),
+                size: "1.4 GiB".to_string(),
+                date: "2025-04-11 17:00".to_string(),
+                seeders: 1234,
+                leechers: 56,
+            }
+        );
+        assert_eq!(results[1].url, "https://nyaa.si/view/1955613");
+
+        let empty = "<html><body><h3>No results found</h3></body></html>";
+        assert!(parse_search_results(empty, NYAA_BASE_URL).unwrap().is_empty());
+        assert!(matches!(
+            parse_search_results("<html>Down for maintenance</html>", NYAA_BASE_URL),
+            Err(NyaaParserError::ResultsTableNotFound)
+        ));
+        assert_eq!(
+            search_url(NYAA_BASE_URL, "example show 1080p", SearchOrder::Seeders).unwrap().as_str(),
+            "https://nyaa.si/?f=0&c=0_0&q=example+show+1080p&s=seeders&o=desc"
+        );
+    }
+
     #[test]
     fn test_no_magnet_link() {
         let html_content = r#"
@@ -187,7 +366,7 @@ mod tests {
         let url = "https://nyaa.si/view/1955613";
         let expected_magnet = "magnet:?xt=urn:btih:1695d42fae2d7655e544fa3a92f5d90fa0719106&dn=%5BSubsPlease%5D%20Danjo%20no%20Yuujou%20wa%20Seiritsu%20suru%20-%2001%20%281080p%29%20%5B605B7639%5D.mkv&tr=http%3A%2F%2Fnyaa.tracker.wf%3A7777%2Fannounce&tr=udp%3A%2F%2Fopen.stealth.si%3A80%2Fannounce&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=udp%3A%2F%2Fexodus.desync.com%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.torrent.eu.org%3A451%2Fannounce";
 
-        match fetch_and_extract_magnet_url(url).await {
+        match fetch_and_extract_magnet_url(&reqwest::Client::new(), url).await {
             Ok(magnet_url) => assert_eq!(magnet_url, expected_magnet),
             Err(e) => panic!("fetch_and_extract_live failed: {}", e),
         }
@@ -196,10 +375,21 @@ mod tests {
     #[tokio::test]
     async fn test_fetch_invalid_url() {
         let url = "invalid-url"; // Definitely not a valid URL
-        match fetch_and_extract_magnet_url(url).await {
-            Err(NyaaParserError::RequestError(_)) => (), // Expected error
+        match fetch_and_extract_magnet_url(&reqwest::Client::new(), url).await {
+            Err(NyaaParserError::UrlError(_)) => (), // Expected error
             Ok(url) => panic!("Expected error, but got URL: {}", url),
-            Err(e) => panic!("Expected RequestError, but got different error: {}", e),
+            Err(e) => panic!("Expected UrlError, but got different error: {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_refuses_other_hosts() {
+        // Never requested, so nothing needs to listen there
+        for url in ["http://127.0.0.1:8080/view/1955613", "http://169.254.169.254/latest/meta-data/", "ftp://nyaa.si/view/1", "https://nyaa.si.example.org/view/1"] {
+            match fetch_and_extract_magnet_url(&reqwest::Client::new(), url).await {
+                Err(NyaaParserError::NotNyaaUrl(_)) => (),
+                other => panic!("Expected NotNyaaUrl for {}, but got {:?}", url, other),
+            }
         }
     }
 
@@ -213,7 +403,7 @@ mod tests {
     //         .create_async().await;
     //
     //     let url = &format!("{}/notfound", server.url());
-    //     match fetch_and_extract_magnet_url(url).await {
+    //     match fetch_and_extract_magnet_url(&reqwest::Client::new(), url).await {
     //         Err(NyaaParserError::HttpStatusError(status)) => assert_eq!(status, StatusCode::NOT_FOUND),
     //         Ok(url) => panic!("Expected error, but got URL: {}", url),
     //         Err(e) => panic!("Expected HttpStatusError, but got different error: {}", e),
@@ -0,0 +1,485 @@
+//! The outgoing queue of an IRC connection. Everything the bot sends goes through it and is
+//! paced by a token bucket, so long answers and busy channels can't get the bot kicked for
+//! excess flood. Replies to people (private messages, CTCP, JOIN/PART) jump ahead of channel
+//! messages, so an admin isn't kept waiting behind a long AI answer. The lines of one answer are
+//! queued together, so no other channel message goes out in between. The irc crate answers
+//! server PINGs itself, outside the queue, so those are never held up either.
+//!
+//! The queue is bounded, so someone spamming the bot with CTCP requests or commands can't have
+//! it pile up replies without end: each nick gets only so many replies in a burst, only a few
+//! may wait for any one target, and beyond that they're dropped.
+
+use anyhow::{Result, anyhow, bail};
+use irc::proto::{Command, Message};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+/// Batches of channel messages that may wait at once; more are refused.
+const MAX_QUEUED_BATCHES: usize = 100;
+/// Replies that may wait at once, and for any one target; more are dropped.
+const MAX_QUEUED_REPLIES: usize = 100;
+const MAX_QUEUED_REPLIES_PER_TARGET: usize = 20;
+/// Replies a nick can get in a burst, and how often they get another after that.
+const REPLY_BURST: u32 = 30;
+const REPLY_INTERVAL: Duration = Duration::from_secs(2);
+/// Nicks whose reply allowance is remembered before those back at a full burst are forgotten.
+const MAX_TRACKED_NICKS: usize = 1000;
+
+/// Holds up to `capacity` tokens, and gains one every `interval`; sending a line takes one.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    interval: Duration,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket. A zero `interval` means no limit.
+    pub fn new(capacity: u32, interval: Duration, now: Instant) -> Self {
+        let capacity = capacity.max(1) as f64;
+        TokenBucket { capacity, interval, tokens: capacity, updated: now }
+    }
+
+    /// Takes a token if there is one; otherwise says how long until there will be.
+    pub fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        if self.interval.is_zero() {
+            return Ok(());
+        }
+        let refilled = now.saturating_duration_since(self.updated).as_secs_f64() / self.interval.as_secs_f64();
+        self.tokens = (self.tokens + refilled).min(self.capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(self.interval.mul_f64(1.0 - self.tokens))
+        }
+    }
+
+    /// Whether the bucket would be full at `now`, as if untouched all along.
+    pub fn is_full(&self, now: Instant) -> bool {
+        self.tokens_at(now) >= self.capacity
+    }
+
+    /// The tokens the bucket would hold at `now`, as if untouched all along.
+    fn tokens_at(&self, now: Instant) -> f64 {
+        if self.interval.is_zero() {
+            return f64::INFINITY;
+        }
+        self.tokens + now.saturating_duration_since(self.updated).as_secs_f64() / self.interval.as_secs_f64()
+    }
+}
+
+/// A token bucket for each nick, for limiting how often each one is answered. Nicks whose bucket
+/// has filled up again are forgotten, so the map only holds those answered lately.
+#[derive(Debug)]
+pub struct NickBuckets {
+    capacity: u32,
+    interval: Duration,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl NickBuckets {
+    pub fn new(capacity: u32, interval: Duration) -> Self {
+        NickBuckets { capacity, interval, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Whether `nick` may be answered now, taking a token if so.
+    pub fn allow(&self, nick: &str, now: Instant) -> bool {
+        self.allow_all([nick], now)
+    }
+
+    /// Whether all of `nicks` may be answered now, a token each (so two for a nick listed twice).
+    /// The tokens are only taken if every nick has enough.
+    pub fn allow_all<'a>(&self, nicks: impl IntoIterator<Item = &'a str>, now: Instant) -> bool {
+        let mut needed: HashMap<String, u32> = HashMap::new();
+        for nick in nicks {
+            *needed.entry(nick.to_lowercase()).or_default() += 1;
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_NICKS {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+        let (capacity, interval) = (self.capacity, self.interval);
+        for nick in needed.keys() {
+            buckets.entry(nick.clone()).or_insert_with(|| TokenBucket::new(capacity, interval, now));
+        }
+        if !needed.iter().all(|(nick, count)| buckets[nick].tokens_at(now) >= *count as f64) {
+            return false;
+        }
+        for (nick, count) in needed {
+            let bucket = buckets.get_mut(&nick).expect("every nick got a bucket above");
+            for _ in 0..count {
+                let _ = bucket.try_take(now);
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Replies to a person, and channel joins and parts.
+    High,
+    /// Channel messages: AI answers, announcements.
+    Normal,
+}
+
+/// A handle for queueing lines on one connection. Cloning it is cheap; the lines go out in
+/// order of priority, then of queueing.
+#[derive(Debug, Clone)]
+pub struct OutgoingQueue {
+    high: UnboundedSender<Vec<Message>>,
+    normal: UnboundedSender<Vec<Message>>,
+    backlog: Arc<Mutex<Backlog>>,
+}
+
+/// What's waiting in the queue, to keep it bounded.
+#[derive(Debug)]
+struct Backlog {
+    batches: usize,
+    replies: usize,
+    /// Replies waiting for each (lowercase) target.
+    replies_to: HashMap<String, usize>,
+    /// How many more replies each nick may get.
+    allowances: NickBuckets,
+}
+
+impl Default for Backlog {
+    fn default() -> Self {
+        Backlog {
+            batches: 0,
+            replies: 0,
+            replies_to: HashMap::new(),
+            allowances: NickBuckets::new(REPLY_BURST, REPLY_INTERVAL),
+        }
+    }
+}
+
+impl Backlog {
+    /// Makes room for a batch, returning false if there's none. Replies to a nick also take from
+    /// its allowance.
+    fn admit(&mut self, priority: Priority, messages: &[Message], now: Instant) -> bool {
+        if priority == Priority::Normal {
+            if self.batches >= MAX_QUEUED_BATCHES {
+                return false;
+            }
+            self.batches += 1;
+            return true;
+        }
+        let targets: Vec<String> = messages.iter().filter_map(reply_target).collect();
+        for target in &targets {
+            let waiting = self.replies_to.get(target).copied().unwrap_or(0) + targets.iter().filter(|t| *t == target).count();
+            if waiting > MAX_QUEUED_REPLIES_PER_TARGET {
+                return false;
+            }
+        }
+        if self.replies + targets.len() > MAX_QUEUED_REPLIES {
+            return false;
+        }
+        // Checked last, so a refused batch doesn't use up anyone's allowance
+        let nicks = targets.iter().filter(|target| !is_channel(target)).map(String::as_str);
+        if !self.allowances.allow_all(nicks, now) {
+            return false;
+        }
+        for target in targets {
+            *self.replies_to.entry(target).or_default() += 1;
+            self.replies += 1;
+        }
+        true
+    }
+
+    /// Notes that a batch left the queue.
+    fn taken(&mut self, priority: Priority, messages: &[Message]) {
+        if priority == Priority::Normal {
+            self.batches = self.batches.saturating_sub(1);
+            return;
+        }
+        for target in messages.iter().filter_map(reply_target) {
+            self.replies = self.replies.saturating_sub(1);
+            if let Some(waiting) = self.replies_to.get_mut(&target) {
+                *waiting -= 1;
+                if *waiting == 0 {
+                    self.replies_to.remove(&target);
+                }
+            }
+        }
+    }
+}
+
+/// Who a message is said to, for counting replies.
+fn reply_target(message: &Message) -> Option<String> {
+    match &message.command {
+        Command::PRIVMSG(target, _) | Command::NOTICE(target, _) => Some(target.to_lowercase()),
+        _ => None,
+    }
+}
+
+fn is_channel(target: &str) -> bool {
+    target.starts_with(['#', '&'])
+}
+
+impl OutgoingQueue {
+    /// Starts the task that hands queued lines to `send` as `bucket` allows. It stops when every
+    /// handle is dropped, or when `send` fails.
+    pub fn start<F>(send: F, bucket: TokenBucket) -> (Self, JoinHandle<()>)
+    where
+        F: FnMut(Message) -> Result<()> + Send + 'static,
+    {
+        let (high, high_rx) = mpsc::unbounded_channel();
+        let (normal, normal_rx) = mpsc::unbounded_channel();
+        let backlog = Arc::new(Mutex::new(Backlog::default()));
+        let task = tokio::spawn(drain(send, bucket, high_rx, normal_rx, backlog.clone()));
+        (OutgoingQueue { high, normal, backlog }, task)
+    }
+
+    /// Queues a command, or a whole message when it carries IRCv3 tags.
+    pub fn send(&self, priority: Priority, message: impl Into<Message>) -> Result<()> {
+        self.send_all(priority, vec![message.into()])
+    }
+
+    /// Queues messages that go out back to back: only replies, which jump the queue anyway, can
+    /// come between them. Replies there's no room for are dropped; channel messages are refused.
+    pub fn send_all(&self, priority: Priority, messages: Vec<Message>) -> Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        if !self.backlog.lock().unwrap().admit(priority, &messages, Instant::now()) {
+            if priority == Priority::Normal {
+                bail!("The outgoing queue is full");
+            }
+            tracing::warn!(target = ?messages.first().and_then(reply_target), "Too many replies waiting, dropping one");
+            return Ok(());
+        }
+        let queue = match priority {
+            Priority::High => &self.high,
+            Priority::Normal => &self.normal,
+        };
+        queue.send(messages).map_err(|_| anyhow!("The IRC connection is closed"))
+    }
+
+    /// Sends a private message to a user, ahead of channel messages.
+    pub fn send_privmsg(&self, target: &str, text: impl Into<String>) -> Result<()> {
+        self.send(Priority::High, Command::PRIVMSG(target.to_string(), text.into()))
+    }
+
+    pub fn send_notice(&self, target: &str, text: impl Into<String>) -> Result<()> {
+        self.send(Priority::High, Command::NOTICE(target.to_string(), text.into()))
+    }
+
+    pub fn send_join(&self, channel: &str) -> Result<()> {
+        self.send(Priority::High, Command::JOIN(channel.to_string(), None, None))
+    }
+
+    pub fn send_part(&self, channel: &str) -> Result<()> {
+        self.send(Priority::High, Command::PART(channel.to_string(), None))
+    }
+}
+
+async fn drain<F>(
+    mut send: F,
+    mut bucket: TokenBucket,
+    mut high: UnboundedReceiver<Vec<Message>>,
+    mut normal: UnboundedReceiver<Vec<Message>>,
+    backlog: Arc<Mutex<Backlog>>,
+) where
+    F: FnMut(Message) -> Result<()>,
+{
+    // The rest of the batch being sent, which goes before anything else queued
+    let mut next: VecDeque<(Priority, Message)> = VecDeque::new();
+    loop {
+        let (priority, message) = match next.pop_front() {
+            Some(queued) => queued,
+            None => {
+                let (priority, batch) = tokio::select! {
+                    biased;
+                    Some(batch) = high.recv() => (Priority::High, batch),
+                    Some(batch) = normal.recv() => (Priority::Normal, batch),
+                    else => return,
+                };
+                backlog.lock().unwrap().taken(priority, &batch);
+                next.extend(batch.into_iter().map(|message| (priority, message)));
+                continue;
+            }
+        };
+        while let Err(wait) = bucket.try_take(Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+        // Replies queued while this channel message waited for its turn go first
+        let message = if priority == Priority::Normal
+            && let Ok(mut replies) = high.try_recv()
+            && !replies.is_empty()
+        {
+            backlog.lock().unwrap().taken(Priority::High, &replies);
+            next.push_front((priority, message));
+            let reply = replies.remove(0);
+            for later in replies.into_iter().rev() {
+                next.push_front((Priority::High, later));
+            }
+            reply
+        } else {
+            message
+        };
+        if let Err(e) = send(message) {
+            tracing::error!("Failed to send IRC line, stopping the outgoing queue: {:#}", e);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, Duration::from_secs(2), start);
+        assert_eq!(bucket.try_take(start), Ok(()));
+        assert_eq!(bucket.try_take(start), Ok(()));
+        assert_eq!(bucket.try_take(start), Err(Duration::from_secs(2)));
+        assert_eq!(bucket.try_take(start + Duration::from_secs(1)), Err(Duration::from_secs(1)));
+        assert_eq!(bucket.try_take(start + Duration::from_secs(2)), Ok(()));
+        // Never holds more than its capacity
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.try_take(later).is_ok() && bucket.try_take(later).is_ok());
+        assert!(bucket.try_take(later).is_err());
+
+        let mut unlimited = TokenBucket::new(1, Duration::ZERO, start);
+        assert!((0..100).all(|_| unlimited.try_take(start).is_ok()));
+        assert!(unlimited.is_full(start));
+
+        assert!(!bucket.is_full(later));
+        assert!(bucket.is_full(later + Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn test_nick_buckets() {
+        let now = Instant::now();
+        let buckets = NickBuckets::new(2, Duration::from_secs(10));
+        assert!(buckets.allow("alice", now) && buckets.allow("Alice", now));
+        assert!(!buckets.allow("alice", now));
+        assert!(buckets.allow("bob", now));
+        assert!(buckets.allow("alice", now + Duration::from_secs(10)));
+
+        // Either every nick gets its tokens or none does
+        assert!(!buckets.allow_all(["bob", "alice"], now + Duration::from_secs(10)));
+        assert!(!buckets.allow_all(["bob", "Bob"], now));
+        assert!(buckets.allow("bob", now));
+    }
+
+    #[test]
+    fn test_backlog_is_bounded() {
+        let now = Instant::now();
+        let mut backlog = Backlog::default();
+        let reply = |target: &str| vec![Message::from(Command::NOTICE(target.to_string(), "pong".to_string()))];
+
+        // Only a few replies wait for one target, the rest are dropped
+        for _ in 0..MAX_QUEUED_REPLIES_PER_TARGET {
+            assert!(backlog.admit(Priority::High, &reply("spammer"), now));
+        }
+        assert!(!backlog.admit(Priority::High, &reply("Spammer"), now));
+        assert!(backlog.admit(Priority::High, &reply("alice"), now));
+        // Until they're sent, though even then a nick only gets so many
+        for _ in 0..MAX_QUEUED_REPLIES_PER_TARGET {
+            backlog.taken(Priority::High, &reply("spammer"));
+        }
+        assert_eq!(backlog.replies_to.get("spammer"), None);
+        for _ in MAX_QUEUED_REPLIES_PER_TARGET..REPLY_BURST as usize {
+            assert!(backlog.admit(Priority::High, &reply("spammer"), now));
+            backlog.taken(Priority::High, &reply("spammer"));
+        }
+        assert!(!backlog.admit(Priority::High, &reply("spammer"), now));
+        assert!(backlog.admit(Priority::High, &reply("spammer"), now + REPLY_INTERVAL));
+        // Channels and commands without a target have no allowance
+        backlog.taken(Priority::High, &reply("spammer"));
+        for _ in 0..REPLY_BURST + 1 {
+            assert!(backlog.admit(Priority::High, &reply("#ops"), now));
+            backlog.taken(Priority::High, &reply("#ops"));
+        }
+        assert!(backlog.admit(Priority::High, &[Message::from(Command::JOIN("#rust".to_string(), None, None))], now));
+
+        // Channel messages are refused once the queue is full
+        for _ in 0..MAX_QUEUED_BATCHES {
+            assert!(backlog.admit(Priority::Normal, &reply("#rust"), now));
+        }
+        assert!(!backlog.admit(Priority::Normal, &reply("#rust"), now));
+        backlog.taken(Priority::Normal, &reply("#rust"));
+        assert!(backlog.admit(Priority::Normal, &reply("#rust"), now));
+    }
+
+    #[test]
+    fn test_refused_batches_take_no_allowance() {
+        let now = Instant::now();
+        let mut backlog = Backlog::default();
+        let notice = |target: &str| Message::from(Command::NOTICE(target.to_string(), "pong".to_string()));
+        for _ in 0..REPLY_BURST {
+            assert!(backlog.admit(Priority::High, &[notice("spammer")], now));
+            backlog.taken(Priority::High, &[notice("spammer")]);
+        }
+
+        // A batch that also answers someone out of allowance goes nowhere, and costs the others nothing
+        let batch = [notice("alice"), notice("bob"), notice("spammer")];
+        assert!(!backlog.admit(Priority::High, &batch, now));
+        assert_eq!(backlog.replies, 0);
+        for _ in 0..REPLY_BURST {
+            assert!(backlog.admit(Priority::High, &[notice("alice"), notice("bob")], now));
+            backlog.taken(Priority::High, &[notice("alice"), notice("bob")]);
+        }
+        assert!(!backlog.admit(Priority::High, &[notice("alice")], now));
+    }
+
+    #[tokio::test]
+    async fn test_replies_jump_the_queue() {
+        let (sent_tx, mut sent_rx) = mpsc::unbounded_channel();
+        let send = move |message: Message| -> Result<()> {
+            sent_tx.send(message.command)?;
+            Ok(())
+        };
+        let (queue, task) = OutgoingQueue::start(send, TokenBucket::new(1, Duration::from_millis(20), Instant::now()));
+        for line in ["one", "two", "three"] {
+            queue.send(Priority::Normal, Command::PRIVMSG("#test".to_string(), line.to_string())).unwrap();
+        }
+        queue.send_privmsg("alice", "reply").unwrap();
+        drop(queue);
+        task.await.unwrap();
+
+        let mut order = Vec::new();
+        while let Ok(Command::PRIVMSG(_, text)) = sent_rx.try_recv() {
+            order.push(text);
+        }
+        assert_eq!(order, ["reply", "one", "two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn test_batches_go_out_together() {
+        let (sent_tx, mut sent_rx) = mpsc::unbounded_channel();
+        let send = move |message: Message| -> Result<()> {
+            sent_tx.send(message.command)?;
+            Ok(())
+        };
+        let (queue, task) = OutgoingQueue::start(send, TokenBucket::new(1, Duration::from_millis(20), Instant::now()));
+        let privmsg = |text: &str| Message::from(Command::PRIVMSG("#test".to_string(), text.to_string()));
+        queue.send_all(Priority::Normal, vec![privmsg("first 1/2"), privmsg("first 2/2")]).unwrap();
+        queue.send_all(Priority::Normal, Vec::new()).unwrap();
+        queue.send(Priority::Normal, Command::PRIVMSG("#test".to_string(), "second".to_string())).unwrap();
+        queue.send_all(Priority::Normal, vec![privmsg("third 1/2"), privmsg("third 2/2")]).unwrap();
+        // Slow down the drain task mid-batch, and queue a reply for it to fit in
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        queue.send_privmsg("alice", "reply").unwrap();
+        drop(queue);
+        task.await.unwrap();
+
+        let mut order = Vec::new();
+        while let Ok(Command::PRIVMSG(_, text)) = sent_rx.try_recv() {
+            order.push(text);
+        }
+        let channel_lines: Vec<&String> = order.iter().filter(|text| *text != "reply").collect();
+        assert_eq!(channel_lines, ["first 1/2", "first 2/2", "second", "third 1/2", "third 2/2"]);
+        assert!(order.contains(&"reply".to_string()));
+    }
+}
@@ -0,0 +1,69 @@
+//! Text extracted from recently read webpages, so a few questions about the same article
+//! don't fetch and extract it every time. A page read in the last few minutes is used as-is;
+//! after that it's revalidated with the ETag or Last-Modified date the server gave, and only
+//! fetched again if it changed.
+
+use crate::ai_handler::PageText;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Pages remembered at once.
+pub const PAGE_CACHE_SIZE: usize = 50;
+/// How long a page is used without asking the server whether it changed.
+const FRESH_FOR: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedPage {
+    pub page: PageText,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// When the page was fetched, or last confirmed unchanged.
+    fetched: Instant,
+}
+
+impl CachedPage {
+    pub fn new(page: PageText, etag: Option<String>, last_modified: Option<String>) -> Self {
+        CachedPage { page, etag, last_modified, fetched: Instant::now() }
+    }
+}
+
+#[derive(Debug)]
+pub struct PageCache {
+    pages: Mutex<LruCache<String, CachedPage>>,
+    fresh_for: Duration,
+}
+
+impl PageCache {
+    /// A cache of `capacity` pages, used without revalidation for `fresh_for`.
+    pub fn new(capacity: usize, fresh_for: Duration) -> Self {
+        PageCache { pages: Mutex::new(LruCache::new(NonZeroUsize::new(capacity.max(1)).unwrap())), fresh_for }
+    }
+
+    /// Whether a cached page can be used without revalidating it.
+    pub fn is_fresh(&self, page: &CachedPage) -> bool {
+        page.fetched.elapsed() < self.fresh_for
+    }
+
+    pub fn get(&self, url: &str) -> Option<CachedPage> {
+        self.pages.lock().unwrap().get(url).cloned()
+    }
+
+    pub fn put(&self, url: &str, page: CachedPage) {
+        self.pages.lock().unwrap().put(url.to_string(), page);
+    }
+
+    /// Marks a page as just confirmed unchanged by the server.
+    pub fn revalidated(&self, url: &str) {
+        if let Some(page) = self.pages.lock().unwrap().get_mut(url) {
+            page.fetched = Instant::now();
+        }
+    }
+}
+
+impl Default for PageCache {
+    fn default() -> Self {
+        PageCache::new(PAGE_CACHE_SIZE, FRESH_FOR)
+    }
+}
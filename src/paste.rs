@@ -0,0 +1,140 @@
+//! Uploading code blocks and overlong answers to a paste service, so the channel gets a link
+//! instead of twenty lines of code. Any service that takes a multipart `file` upload and
+//! answers with the paste's URL will do, like 0x0.st.
+
+use crate::proxy::HttpProxy;
+use anyhow::{Context, Result, bail};
+use std::ops::Range;
+use std::time::Duration;
+
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Uploads `text` and returns the URL it can be read at.
+pub async fn upload(proxy: &HttpProxy, service_url: &str, text: &str) -> Result<String> {
+    let part = reqwest::multipart::Part::text(text.to_string()).file_name("paste.txt");
+    let response = proxy
+        .client()?
+        .post(service_url)
+        .timeout(UPLOAD_TIMEOUT)
+        .multipart(reqwest::multipart::Form::new().part("file", part))
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach paste service {}", service_url))?
+        .error_for_status()
+        .context("Paste service refused the upload")?;
+    let url = response.text().await.context("Failed to read paste service response")?.trim().to_string();
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        bail!("Paste service answered with something that isn't a URL: {:?}", url);
+    }
+    Ok(url)
+}
+
+/// Replaces each fenced code block of at least `min_lines` lines with a link to a paste of
+/// it. Blocks that fail to upload are left in place.
+pub async fn paste_code_blocks(proxy: &HttpProxy, service_url: &str, text: &str, min_lines: usize) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut copied = 0;
+    for block in code_blocks(text) {
+        let code = &text[block.code.clone()];
+        if code.lines().count() < min_lines {
+            continue;
+        }
+        match upload(proxy, service_url, code).await {
+            Ok(url) => {
+                out.push_str(&text[copied..block.range.start]);
+                out.push_str(&url);
+                copied = block.range.end;
+            }
+            Err(e) => tracing::warn!("Failed to paste code block: {:?}", e),
+        }
+    }
+    out.push_str(&text[copied..]);
+    out
+}
+
+/// Where the code block left open at the end of `text` starts, if there is one. Streamed
+/// text is held back from there until the block is complete, so it can be pasted whole.
+pub fn unclosed_code_block(text: &str) -> Option<usize> {
+    code_blocks(text).pop().filter(|block| !block.closed).map(|block| block.range.start)
+}
+
+#[derive(Debug, PartialEq)]
+struct CodeBlock {
+    /// The whole block, fences included but not the closing line break.
+    range: Range<usize>,
+    /// The code between the fences.
+    code: Range<usize>,
+    /// Whether the block has its closing fence; an open block runs to the end of the text.
+    closed: bool,
+}
+
+fn code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut open: Option<(usize, usize)> = None; // Start of the fence, and of the code
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\r', '\n']);
+        if content.trim_start().starts_with("```") {
+            match open.take() {
+                None => open = Some((offset, offset + line.len())),
+                Some((start, code)) => blocks.push(CodeBlock {
+                    range: start..offset + content.len(),
+                    code: code..offset,
+                    closed: true,
+                }),
+            }
+        }
+        offset += line.len();
+    }
+    if let Some((start, code)) = open {
+        blocks.push(CodeBlock { range: start..text.len(), code: code..text.len(), closed: false });
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANSWER: &str = "Here you go:\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\nEnjoy!";
+
+    #[test]
+    fn test_code_blocks() {
+        let blocks = code_blocks(ANSWER);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(&ANSWER[blocks[0].code.clone()], "fn main() {\n    println!(\"hi\");\n}\n");
+        assert_eq!(&ANSWER[blocks[0].range.clone()], "```rust\nfn main() {\n    println!(\"hi\");\n}\n```");
+        assert_eq!(unclosed_code_block(ANSWER), None);
+
+        let open = "Look:\n```\nlet x = 1;\n";
+        assert_eq!(code_blocks(open), vec![CodeBlock { range: 6..open.len(), code: 10..open.len(), closed: false }]);
+        assert_eq!(unclosed_code_block(open), Some(6));
+        assert_eq!(unclosed_code_block("No code here."), None);
+    }
+
+    #[tokio::test]
+    async fn test_paste_code_blocks() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::Regex("println".to_string()))
+            .with_body("https://paste.example/abc.txt\n")
+            .expect(1)
+            .create_async()
+            .await;
+
+        let url = server.url();
+        assert_eq!(paste_code_blocks(&HttpProxy::default(), &url, ANSWER, 3).await, "Here you go:\nhttps://paste.example/abc.txt\nEnjoy!");
+        // Short blocks stay in the channel
+        assert_eq!(paste_code_blocks(&HttpProxy::default(), &url, ANSWER, 4).await, ANSWER);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_upload_keeps_the_code() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server.mock("POST", "/").with_status(500).create_async().await;
+        assert_eq!(paste_code_blocks(&HttpProxy::default(), &server.url(), ANSWER, 1).await, ANSWER);
+        assert!(upload(&HttpProxy::default(), &server.url(), "text").await.is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! Fetching URLs that people in chat gave the bot: linked pages, images and audio, whether the
+//! bot prefetches them, the AI asks for them with a tool, or the link's title is announced.
+//!
+//! Anyone can post a link, so it's only followed to public addresses: a link (or a redirect)
+//! to this machine or the local network could otherwise have the bot probe it and say what it
+//! found there. Redirects are followed here rather than by reqwest, so every hop gets checked,
+//! and each request connects to the addresses checked rather than looking the name up again.
+
+use crate::proxy::HttpProxy;
+use anyhow::{Context, Result, bail};
+use reqwest::header::HeaderMap;
+use reqwest::redirect::Policy;
+use reqwest::{Response, Url};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+const MAX_REDIRECTS: usize = 5;
+
+/// Sends a GET request for a link, with `headers`, following redirects as long as they stay on
+/// public addresses. The response is returned whatever its status, so callers can handle a
+/// 304 Not Modified themselves.
+pub async fn get(proxy: &HttpProxy, url: &str, timeout: Duration, headers: HeaderMap) -> Result<Response> {
+    let mut url = Url::parse(url).context("Invalid link")?;
+    let mut redirects = 0;
+    loop {
+        let addrs = public_addrs(&url, proxy.allows_loopback_links()).await?;
+        let mut builder = proxy.client_builder()?.redirect(Policy::none());
+        if let Some(domain) = url.domain() {
+            // Connect to the addresses checked, not whatever the name resolves to next time
+            builder = builder.resolve_to_addrs(domain, &addrs);
+        }
+        let response = builder
+            .build()
+            .context("Failed to build the HTTP client")?
+            .get(url.clone())
+            .headers(headers.clone())
+            .timeout(timeout)
+            .send()
+            .await
+            .with_context(|| format!("Failed to send request for {}", url))?;
+        if !response.status().is_redirection() || response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(response);
+        }
+        redirects += 1;
+        if redirects > MAX_REDIRECTS {
+            bail!("Link redirects more than {} times", MAX_REDIRECTS);
+        }
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .context("Redirect without a location")?;
+        url = url.join(location).context("Invalid redirect location")?;
+    }
+}
+
+/// The addresses `url` leads to, provided they're all public (or on this machine, with
+/// `allow_loopback`). Names are looked up here, so one resolving to a private address is caught too.
+async fn public_addrs(url: &Url, allow_loopback: bool) -> Result<Vec<SocketAddr>> {
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("Not following a {} link", url.scheme());
+    }
+    let host = url.host_str().context("Link has no host")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port)).await.with_context(|| format!("Failed to look up {}", host))?.collect(),
+    };
+    if addrs.is_empty() {
+        bail!("{} has no addresses", host);
+    }
+    let allowed = |ip: IpAddr| is_public(ip) || (allow_loopback && ip.is_loopback());
+    if let Some(addr) = addrs.iter().find(|addr| !allowed(addr.ip())) {
+        bail!("Not following a link to {} at non-public address {}", host, addr.ip());
+    }
+    Ok(addrs)
+}
+
+/// Whether an address is on the public internet, rather than this machine, a private or
+/// link-local network, or a range reserved for something else.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_public_v4(mapped);
+            }
+            let segments = ip.segments();
+            // NAT64 addresses lead to the IPv4 address in their last 32 bits
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                return is_public_v4(Ipv4Addr::from(((segments[6] as u32) << 16) | segments[7] as u32));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || (segments[0] & 0xfe00) == 0xfc00 // Unique local
+                || (segments[0] & 0xffc0) == 0xfe80 // Link-local
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8)) // Documentation
+        }
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        || (a == 100 && (b & 0xc0) == 64) // Carrier-grade NAT
+        || (a == 192 && b == 0 && c == 0) // IETF protocol assignments
+        || (a == 198 && (b & 0xfe) == 18) // Benchmarking
+        || a >= 240) // Reserved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_public() {
+        for public in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c", "::ffff:93.184.215.14", "64:ff9b::5db8:d70e"] {
+            assert!(is_public(public.parse().unwrap()), "{} should be public", public);
+        }
+        for private in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+            "255.255.255.255", "::1", "::", "fe80::1", "fd00::1", "::ffff:127.0.0.1", "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public(private.parse().unwrap()), "{} should not be public", private);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_public_addrs() {
+        let addrs = async |url: &str| public_addrs(&Url::parse(url).unwrap(), false).await;
+        assert_eq!(addrs("https://93.184.215.14/").await.unwrap(), ["93.184.215.14:443".parse().unwrap()]);
+        assert!(addrs("http://127.0.0.1:8080/admin").await.is_err());
+        assert!(addrs("http://[::1]/").await.is_err());
+        assert!(addrs("http://localhost/").await.is_err());
+        assert!(addrs("file:///etc/passwd").await.is_err());
+
+        let loopback = async |url: &str| public_addrs(&Url::parse(url).unwrap(), true).await;
+        assert!(loopback("http://127.0.0.1:8080/admin").await.is_ok());
+        assert!(loopback("http://10.1.2.3/").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_checks_every_redirect() {
+        let mut server = mockito::Server::new_async().await;
+        let _hop = server.mock("GET", "/hop").with_status(302).with_header("location", "/page").create_async().await;
+        let _page = server.mock("GET", "/page").with_body("made it").create_async().await;
+        let _out = server
+            .mock("GET", "/out")
+            .with_status(302)
+            .with_header("location", "http://169.254.169.254/latest/meta-data/")
+            .create_async()
+            .await;
+        let timeout = Duration::from_secs(5);
+
+        // The mock server is on this machine, so it's only reached when that's allowed
+        let url = format!("{}/hop", server.url());
+        let error = get(&HttpProxy::default(), &url, timeout, HeaderMap::new()).await.unwrap_err();
+        assert!(error.to_string().contains("non-public address"), "unexpected error: {:#}", error);
+        let local = HttpProxy::default().allowing_loopback_links();
+        let response = get(&local, &url, timeout, HeaderMap::new()).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "made it");
+
+        // A redirect to a private address is stopped at that hop
+        let error = get(&local, &format!("{}/out", server.url()), timeout, HeaderMap::new()).await.unwrap_err();
+        assert!(error.to_string().contains("169.254.169.254"), "unexpected error: {:#}", error);
+    }
+}
@@ -0,0 +1,42 @@
+//! Quotes: memorable lines a channel saves with `!quote add`, to be brought back with
+//! `!quote get`, `!quote search` and `!quote random`. The AI can look them up with a tool.
+
+use crate::db::Quote;
+use crate::sanitize::strip_invisible;
+
+/// Longest quote that can be saved, in characters, so a quote fits in one message.
+pub const MAX_QUOTE_LENGTH: usize = 350;
+/// How many quotes `!quote search` lists.
+pub const SEARCH_RESULT_COUNT: usize = 3;
+
+/// Renders a quote for the channel, e.g. "#12: <alice> hello".
+pub fn display(quote: &Quote) -> String {
+    format!("#{}: {}", quote.id, strip_invisible(&quote.text))
+}
+
+/// Tidies a quote for saving: surrounding whitespace is dropped, and so are the quotation marks
+/// people like to wrap a quote in. None if nothing is left or it's too long.
+pub fn normalize(text: &str) -> Option<String> {
+    let text = text.trim();
+    let text = text
+        .strip_prefix('"')
+        .and_then(|inner| inner.strip_suffix('"'))
+        .unwrap_or(text)
+        .trim();
+    (!text.is_empty() && text.chars().count() <= MAX_QUOTE_LENGTH).then(|| text.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("  <alice> hello  ").as_deref(), Some("<alice> hello"));
+        assert_eq!(normalize("\"a \"quoted\" quote\"").as_deref(), Some("a \"quoted\" quote"));
+        assert_eq!(normalize("\"").as_deref(), Some("\""));
+        assert_eq!(normalize("\"\""), None);
+        assert_eq!(normalize("   "), None);
+        assert_eq!(normalize(&"x".repeat(MAX_QUOTE_LENGTH + 1)), None);
+    }
+}
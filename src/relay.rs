@@ -0,0 +1,134 @@
+//! Bridging channels, as a relay bot does: with `--relays #emul=discord:123456789`, what's said in
+//! one channel of a pair is repeated in the other as `<nick> message`, both ways, whether the two
+//! are on the same network or not.
+//!
+//! Relayed lines are logged under the nick of whoever said them and their network, like
+//! `alice@discord`, so the AI reads them as someone else talking rather than as its own words.
+//! The AI's own answers are relayed as well.
+//!
+//! Relays can't loop through the bot itself: its own lines are never relayed, and a message only
+//! goes to the channels paired with the one it was said in, not on from there. In case another
+//! bridge links the same channels, a message containing a line the bot just relayed into that
+//! channel is taken for that line coming back, and isn't relayed again.
+
+use crate::config::RelayPair;
+use crate::ctcp;
+use crate::sanitize::strip_invisible;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a relayed line is watched for coming back.
+const ECHO_WINDOW: Duration = Duration::from_secs(60);
+/// Relayed lines watched for at once; older ones are forgotten early.
+const MAX_WATCHED_LINES: usize = 100;
+
+/// The channels bridged with `channel`.
+pub fn partners<'a>(pairs: &'a [RelayPair], channel: &str) -> Vec<&'a str> {
+    let mut partners = Vec::new();
+    for pair in pairs {
+        let partner = if pair.first.eq_ignore_ascii_case(channel) {
+            &pair.second
+        } else if pair.second.eq_ignore_ascii_case(channel) {
+            &pair.first
+        } else {
+            continue;
+        };
+        if !partners.iter().any(|known: &&str| known.eq_ignore_ascii_case(partner)) {
+            partners.push(partner.as_str());
+        }
+    }
+    partners
+}
+
+/// How a message reads in the channels it's relayed to: "<nick> message", or "* nick waves" for
+/// actions.
+pub fn format_line(nick: &str, message: &str) -> String {
+    let nick = strip_invisible(nick);
+    match ctcp::action_text(message) {
+        Some(action) => format!("* {} {}", nick, strip_invisible(action)),
+        None => format!("<{}> {}", nick, strip_invisible(message)),
+    }
+}
+
+/// The nick a relayed message is logged under: its sender's, with the network it came from.
+/// IRC nicks can't hold an `@`, so this can't be taken for anyone's real nick.
+pub fn log_nick(nick: &str, network: &str) -> String {
+    format!("{}@{}", strip_invisible(nick), network)
+}
+
+/// The lines recently relayed into each channel, to notice them coming back.
+#[derive(Default)]
+pub struct EchoGuard {
+    sent: Mutex<VecDeque<(String, String, Instant)>>,
+}
+
+impl EchoGuard {
+    /// Remembers that `line` was relayed into `channel`.
+    pub fn relayed(&self, channel: &str, line: &str, now: Instant) {
+        let mut sent = self.sent.lock().unwrap();
+        if sent.len() >= MAX_WATCHED_LINES {
+            sent.pop_front();
+        }
+        sent.push_back((channel.to_lowercase(), line.to_string(), now));
+    }
+
+    /// Whether `message`, said in `channel`, holds a line just relayed there.
+    pub fn is_echo(&self, channel: &str, message: &str, now: Instant) -> bool {
+        let mut sent = self.sent.lock().unwrap();
+        sent.retain(|(_, _, at)| now.duration_since(*at) < ECHO_WINDOW);
+        let channel = channel.to_lowercase();
+        sent.iter().any(|(relayed_to, line, _)| *relayed_to == channel && message.contains(line.as_str()))
+    }
+
+    /// Whether `line` is one the bot just relayed into `channel` itself, as the server echoes it.
+    pub fn was_relayed(&self, channel: &str, line: &str, now: Instant) -> bool {
+        let sent = self.sent.lock().unwrap();
+        let channel = channel.to_lowercase();
+        sent.iter().any(|(relayed_to, sent_line, at)| {
+            *relayed_to == channel && sent_line == line && now.duration_since(*at) < ECHO_WINDOW
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partners() {
+        let pairs: Vec<RelayPair> =
+            ["#emul=discord:1", "#Emul=#emul-es", "#other=discord:2", "discord:1=#emul"].iter().map(|pair| pair.parse().unwrap()).collect();
+        assert_eq!(partners(&pairs, "#EMUL"), ["discord:1", "#emul-es"]);
+        assert_eq!(partners(&pairs, "discord:1"), ["#emul"]);
+        assert!(partners(&pairs, "#quiet").is_empty());
+    }
+
+    #[test]
+    fn test_format_line() {
+        assert_eq!(format_line("alice", "hi all"), "<alice> hi all");
+        assert_eq!(format_line("alice", "\x01ACTION waves\x01"), "* alice waves");
+    }
+
+    #[test]
+    fn test_echo_guard() {
+        let guard = EchoGuard::default();
+        let now = Instant::now();
+        guard.relayed("#emul", "<alice> hi all", now);
+        assert!(guard.is_echo("#Emul", "[discord] <alice> hi all", now));
+        assert!(!guard.is_echo("#other", "<alice> hi all", now));
+        assert!(!guard.is_echo("#emul", "hi all", now));
+        assert!(!guard.is_echo("#emul", "<alice> hi all", now + ECHO_WINDOW));
+
+        guard.relayed("#emul", "<bob> hello", now);
+        assert!(guard.was_relayed("#EMUL", "<bob> hello", now));
+        assert!(!guard.was_relayed("#emul", "[discord] <bob> hello", now));
+        assert!(!guard.was_relayed("#other", "<bob> hello", now));
+    }
+
+    #[test]
+    fn test_log_nick() {
+        assert_eq!(log_nick("alice", "discord"), "alice@discord");
+        assert_eq!(log_nick("bo\u{200b}b", "irc.libera.chat"), "bob@irc.libera.chat");
+    }
+}
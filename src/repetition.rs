@@ -0,0 +1,119 @@
+//! Keeps the bot from saying the same thing twice: its last responses in each channel are
+//! remembered as sets of word pairs, and a new one sharing most of its pairs with one of them
+//! counts as a repeat. The bot then asks the model once more for something different, and if
+//! that repeats too, an interjection is dropped rather than sent.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// Responses remembered per channel.
+const RECENT_RESPONSES_KEPT: usize = 20;
+/// Share of word pairs two responses must have in common to count as the same thing said twice.
+const SIMILARITY_THRESHOLD: f64 = 0.7;
+/// Responses with fewer words ("Hi!", "Thanks!") are fine to repeat, and aren't compared.
+const MIN_COMPARED_WORDS: usize = 4;
+
+/// A response's adjacent word pairs, lowercased and without punctuation.
+type Shingles = HashSet<(String, String)>;
+
+/// The word pairs of `text`; none for responses too short to compare.
+fn shingles(text: &str) -> Shingles {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .map(|word| word.trim_matches('\'').to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect();
+    if words.len() < MIN_COMPARED_WORDS {
+        return HashSet::new();
+    }
+    words.windows(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect()
+}
+
+/// How alike two sets of word pairs are, from 0 (nothing shared) to 1 (the same pairs).
+fn similarity(a: &Shingles, b: &Shingles) -> f64 {
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    a.intersection(b).count() as f64 / a.union(b).count() as f64
+}
+
+/// The bot's latest responses in each channel.
+#[derive(Default)]
+pub struct RecentResponses {
+    channels: Mutex<HashMap<String, VecDeque<(String, Shingles)>>>,
+}
+
+impl RecentResponses {
+    /// Remembers a response sent to `channel`.
+    pub fn record(&self, channel: &str, response: &str) {
+        let shingles = shingles(response);
+        if shingles.is_empty() {
+            return;
+        }
+        let mut channels = self.channels.lock().unwrap();
+        let recent = channels.entry(channel.to_lowercase()).or_default();
+        if recent.len() >= RECENT_RESPONSES_KEPT {
+            recent.pop_front();
+        }
+        recent.push_back((response.to_string(), shingles));
+    }
+
+    /// The earlier response in `channel` that `response` would repeat, if any.
+    pub fn repeated(&self, channel: &str, response: &str) -> Option<String> {
+        let shingles = shingles(response);
+        let channels = self.channels.lock().unwrap();
+        channels
+            .get(&channel.to_lowercase())?
+            .iter()
+            .rev()
+            .find(|(_, earlier)| similarity(&shingles, earlier) >= SIMILARITY_THRESHOLD)
+            .map(|(earlier, _)| earlier.clone())
+    }
+
+    /// Whether `message` quotes the bot's last response in `channel` word for word, as another
+    /// bot answering it back might.
+    pub fn quotes_last(&self, channel: &str, message: &str) -> bool {
+        let channels = self.channels.lock().unwrap();
+        channels
+            .get(&channel.to_lowercase())
+            .and_then(|recent| recent.back())
+            .is_some_and(|(last, _)| message.contains(last.trim()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_similarity() {
+        let a = shingles("Did you know that otters hold hands while they sleep?");
+        assert_eq!(similarity(&a, &shingles("did you know, that OTTERS hold hands while they sleep!")), 1.0);
+        assert!(similarity(&a, &shingles("Did you know that otters hold hands while they sleep, you know?")) > SIMILARITY_THRESHOLD);
+        assert!(similarity(&a, &shingles("Otters are my favourite animal, you know.")) < SIMILARITY_THRESHOLD);
+        assert_eq!(similarity(&shingles("Hi there!"), &shingles("Hi there!")), 0.0);
+    }
+
+    #[test]
+    fn test_recent_responses() {
+        let recent = RecentResponses::default();
+        recent.record("#test", "I still think tabs are better than spaces, honestly.");
+        recent.record("#test", "Hi!");
+        assert_eq!(
+            recent.repeated("#TEST", "Honestly, I still think tabs are better than spaces.").as_deref(),
+            Some("I still think tabs are better than spaces, honestly.")
+        );
+        assert_eq!(recent.repeated("#other", "I still think tabs are better than spaces, honestly."), None);
+        assert_eq!(recent.repeated("#test", "Hi!"), None);
+        assert_eq!(recent.repeated("#test", "Spaces win, and that's the end of it."), None);
+
+        assert!(recent.quotes_last("#test", "> I still think tabs are better than spaces, honestly. Why?"));
+        assert!(!recent.quotes_last("#test", "I still think tabs are better"));
+        assert!(!recent.quotes_last("#other", "I still think tabs are better than spaces, honestly."));
+
+        for n in 0..RECENT_RESPONSES_KEPT {
+            recent.record("#test", &format!("Filler response number {} to push out the old ones", n));
+        }
+        assert_eq!(recent.repeated("#test", "I still think tabs are better than spaces, honestly."), None);
+    }
+}
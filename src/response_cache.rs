@@ -0,0 +1,102 @@
+//! AI answers to questions asked moments ago, so the same question asked again by the same
+//! person in the same channel, with the same history and memories sent along, doesn't cost
+//! another API call. Times are left out of the key, as they are different for every repeat.
+//! Answers are only kept briefly, as the conversation moves on.
+
+use lru::LruCache;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Answers remembered at once.
+pub const RESPONSE_CACHE_SIZE: usize = 100;
+
+/// A request's system prompt hash, and the hash of the question, its asker and what else shapes its answer.
+pub type PromptKey = (u64, u64);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedResponse {
+    pub text: String,
+    pub finish_reason: String,
+    cached: Instant,
+}
+
+#[derive(Debug)]
+pub struct ResponseCache {
+    responses: Mutex<LruCache<PromptKey, CachedResponse>>,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> Self {
+        ResponseCache { responses: Mutex::new(LruCache::new(NonZeroUsize::new(capacity.max(1)).unwrap())) }
+    }
+
+    /// The key for `message`, asked by `nick` in `channel` under this system prompt. `settings`
+    /// holds anything else the answer depends on, like the history and memories sent with it.
+    pub fn key(system_prompt: &str, channel: &str, nick: &str, message: &str, settings: impl Hash) -> PromptKey {
+        let mut prompt_hasher = DefaultHasher::new();
+        system_prompt.hash(&mut prompt_hasher);
+        let mut question_hasher = DefaultHasher::new();
+        channel.to_lowercase().hash(&mut question_hasher);
+        nick.to_lowercase().hash(&mut question_hasher);
+        normalize(message).hash(&mut question_hasher);
+        settings.hash(&mut question_hasher);
+        (prompt_hasher.finish(), question_hasher.finish())
+    }
+
+    /// The answer given to the same prompt less than `ttl` ago, if any.
+    pub fn get(&self, key: &PromptKey, ttl: Duration) -> Option<CachedResponse> {
+        let mut responses = self.responses.lock().unwrap();
+        match responses.get(key) {
+            Some(response) if response.cached.elapsed() < ttl => Some(response.clone()),
+            Some(_) => {
+                responses.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn put(&self, key: PromptKey, text: &str, finish_reason: &str) {
+        let response = CachedResponse { text: text.to_string(), finish_reason: finish_reason.to_string(), cached: Instant::now() };
+        self.responses.lock().unwrap().put(key, response);
+    }
+}
+
+/// A message in lowercase with its spacing evened out, so a question retyped a little differently
+/// is still the same question.
+fn normalize(message: &str) -> String {
+    message.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache::new(RESPONSE_CACHE_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_responses_expire() {
+        let cache = ResponseCache::default();
+        let key = ResponseCache::key("sys", "#test", "alice", "Emul: what's 2+2?", false);
+        assert_eq!(key, ResponseCache::key("sys", "#Test", "Alice", "  emul:  What's 2+2?", false));
+        assert_ne!(key, ResponseCache::key("other", "#test", "alice", "Emul: what's 2+2?", false));
+        assert_ne!(key, ResponseCache::key("sys", "#elsewhere", "alice", "Emul: what's 2+2?", false));
+        assert_ne!(key, ResponseCache::key("sys", "#test", "bob", "Emul: what's 2+2?", false));
+        assert_ne!(key, ResponseCache::key("sys", "#test", "alice", "Emul: what's 2+3?", false));
+        assert_ne!(key, ResponseCache::key("sys", "#test", "alice", "Emul: what's 2+2?", true));
+
+        cache.put(key, "4", "STOP");
+        let cached = cache.get(&key, Duration::from_secs(60)).unwrap();
+        assert_eq!((cached.text.as_str(), cached.finish_reason.as_str()), ("4", "STOP"));
+        assert_eq!(cache.get(&key, Duration::ZERO), None);
+        // Expired answers are dropped
+        assert_eq!(cache.get(&key, Duration::from_secs(60)), None);
+    }
+}
@@ -0,0 +1,190 @@
+//! Who's in each IRC channel right now, and with what status (owner, op, voice...). Kept from
+//! the NAMES list the server sends when we join, then the JOINs, PARTs, KICKs, QUITs, nick
+//! changes and MODEs that follow. It lives in memory and starts over on each connection; the
+//! list_channel_users tool reads it to answer "who's here?".
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Channel status prefixes, highest first: owner, admin, op, half-op, voice.
+pub const STATUS_PREFIXES: &str = "~&@%+";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub nick: String,
+    /// Status prefixes held, highest first; empty for ordinary users.
+    pub status: String,
+}
+
+impl Member {
+    /// The nick with its highest status, as IRC clients show it, e.g. "@alice".
+    pub fn display(&self) -> String {
+        format!("{}{}", self.status.chars().next().map(String::from).unwrap_or_default(), self.nick)
+    }
+
+    /// Whether the member holds the status `prefix`, or a higher one.
+    pub fn has_status(&self, prefix: char) -> bool {
+        STATUS_PREFIXES.find(prefix).is_some_and(|wanted| self.rank() <= wanted)
+    }
+
+    /// How the member ranks, for sorting: 0 for owners, up to 5 for ordinary users.
+    fn rank(&self) -> usize {
+        self.status.chars().next().and_then(|symbol| STATUS_PREFIXES.find(symbol)).unwrap_or(STATUS_PREFIXES.len())
+    }
+}
+
+/// What a status prefix is called, e.g. "op" for '@'.
+pub fn status_name(prefix: char) -> &'static str {
+    match prefix {
+        '~' => "owner",
+        '&' => "admin",
+        '@' => "op",
+        '%' => "half-op",
+        '+' => "voiced",
+        _ => "unknown",
+    }
+}
+
+/// Members by lowercase channel, then lowercase nick.
+#[derive(Debug, Default)]
+pub struct Roster {
+    channels: Mutex<HashMap<String, HashMap<String, Member>>>,
+}
+
+impl Roster {
+    /// Forgets everything, for a new connection.
+    pub fn clear(&self) {
+        self.channels.lock().unwrap().clear();
+    }
+
+    /// We joined `channel`; the server's NAMES list follows.
+    pub fn joined(&self, channel: &str) {
+        self.channels.lock().unwrap().insert(channel.to_lowercase(), HashMap::new());
+    }
+
+    /// We left `channel`, or were kicked.
+    pub fn left(&self, channel: &str) {
+        self.channels.lock().unwrap().remove(&channel.to_lowercase());
+    }
+
+    /// Adds a NAMES reply: space-separated nicks with their status prefixes, like "@alice +bob carol".
+    pub fn add_names(&self, channel: &str, names: &str) {
+        let mut channels = self.channels.lock().unwrap();
+        let members = channels.entry(channel.to_lowercase()).or_default();
+        for name in names.split_whitespace() {
+            let unprefixed = name.trim_start_matches(|c| STATUS_PREFIXES.contains(c));
+            let status = sorted_status(name[..name.len() - unprefixed.len()].chars());
+            // Servers with userhost-in-names send nick!user@host
+            let nick = unprefixed.split('!').next().unwrap_or(unprefixed);
+            if nick.is_empty() {
+                continue;
+            }
+            members.insert(nick.to_lowercase(), Member { nick: nick.to_string(), status });
+        }
+    }
+
+    pub fn join(&self, channel: &str, nick: &str) {
+        if let Some(members) = self.channels.lock().unwrap().get_mut(&channel.to_lowercase()) {
+            members.insert(nick.to_lowercase(), Member { nick: nick.to_string(), status: String::new() });
+        }
+    }
+
+    /// `nick` left `channel`, or was kicked from it.
+    pub fn part(&self, channel: &str, nick: &str) {
+        if let Some(members) = self.channels.lock().unwrap().get_mut(&channel.to_lowercase()) {
+            members.remove(&nick.to_lowercase());
+        }
+    }
+
+    /// `nick` quit IRC, leaving every channel.
+    pub fn quit(&self, nick: &str) {
+        let nick = nick.to_lowercase();
+        for members in self.channels.lock().unwrap().values_mut() {
+            members.remove(&nick);
+        }
+    }
+
+    /// Follows a nick change, returning the (lowercase) channels the nick is in.
+    pub fn rename(&self, old_nick: &str, new_nick: &str) -> Vec<String> {
+        let old_nick = old_nick.to_lowercase();
+        let mut renamed_in = Vec::new();
+        for (channel, members) in self.channels.lock().unwrap().iter_mut() {
+            if let Some(mut member) = members.remove(&old_nick) {
+                member.nick = new_nick.to_string();
+                members.insert(new_nick.to_lowercase(), member);
+                renamed_in.push(channel.clone());
+            }
+        }
+        renamed_in
+    }
+
+    /// Gives `nick` the status `prefix` in `channel` (e.g. '@' for +o), or takes it away.
+    pub fn set_status(&self, channel: &str, nick: &str, prefix: char, given: bool) {
+        let mut channels = self.channels.lock().unwrap();
+        let Some(member) = channels.get_mut(&channel.to_lowercase()).and_then(|members| members.get_mut(&nick.to_lowercase()))
+        else {
+            return;
+        };
+        let others = member.status.chars().filter(|&c| c != prefix);
+        member.status = if given { sorted_status(others.chain([prefix])) } else { others.collect() };
+    }
+
+    /// `nick` in `channel`, if both we and they are there.
+    pub fn member(&self, channel: &str, nick: &str) -> Option<Member> {
+        self.channels.lock().unwrap().get(&channel.to_lowercase())?.get(&nick.to_lowercase()).cloned()
+    }
+
+    /// Who's in `channel`, highest status first, then by nick; None if we aren't in it.
+    pub fn members(&self, channel: &str) -> Option<Vec<Member>> {
+        let channels = self.channels.lock().unwrap();
+        let mut members: Vec<Member> = channels.get(&channel.to_lowercase())?.values().cloned().collect();
+        members.sort_by_cached_key(|member| (member.rank(), member.nick.to_lowercase()));
+        Some(members)
+    }
+}
+
+/// Status prefixes in rank order, highest first.
+fn sorted_status(prefixes: impl Iterator<Item = char>) -> String {
+    let held: Vec<char> = prefixes.collect();
+    STATUS_PREFIXES.chars().filter(|prefix| held.contains(prefix)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn displayed(roster: &Roster, channel: &str) -> Vec<String> {
+        roster.members(channel).unwrap().iter().map(Member::display).collect()
+    }
+
+    #[test]
+    fn test_names_and_changes() {
+        let roster = Roster::default();
+        assert_eq!(roster.members("#emul"), None);
+        roster.joined("#emul");
+        roster.add_names("#Emul", "carol @+alice +bob Emul dave!d@example.org");
+        assert_eq!(displayed(&roster, "#emul"), ["@alice", "+bob", "carol", "dave", "Emul"]);
+        assert_eq!(roster.members("#emul").unwrap()[0].status, "@+");
+
+        roster.join("#emul", "erin");
+        roster.part("#emul", "carol");
+        assert_eq!(roster.rename("bob", "Robert"), ["#emul"]);
+        assert!(roster.rename("nobody", "somebody").is_empty());
+        roster.set_status("#emul", "alice", '@', false);
+        roster.set_status("#emul", "erin", '%', true);
+        roster.set_status("#emul", "erin", '@', true);
+        assert_eq!(displayed(&roster, "#emul"), ["@erin", "+alice", "+Robert", "dave", "Emul"]);
+        assert_eq!(roster.members("#emul").unwrap()[0].status, "@%");
+        let erin = roster.member("#EMUL", "Erin").unwrap();
+        assert!(erin.has_status('@') && erin.has_status('+') && !erin.has_status('&'));
+        assert!(!roster.member("#emul", "dave").unwrap().has_status('+'));
+        assert_eq!(roster.member("#emul", "carol"), None);
+
+        roster.quit("dave");
+        roster.left("#emul");
+        assert_eq!(roster.members("#emul"), None);
+        // Nobody is added to channels we aren't in
+        roster.join("#elsewhere", "alice");
+        assert_eq!(roster.members("#elsewhere"), None);
+    }
+}
@@ -0,0 +1,137 @@
+//! RSS and Atom feeds, announced in channels. Admins subscribe channels to feeds with `!feed`; a
+//! background task polls them and announces entries it hasn't seen before, optionally with a
+//! one-line summary from the AI.
+
+use crate::ai_handler::read_body_capped;
+use crate::proxy::HttpProxy;
+use crate::sanitize::flatten;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+/// How often subscribed feeds are checked for new entries.
+pub const FEED_POLL_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// New entries announced per feed per poll; the rest are marked seen, so a feed that suddenly
+/// publishes (or reorders) dozens of entries doesn't flood the channel.
+pub const MAX_ANNOUNCEMENTS_PER_POLL: usize = 3;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(20);
+const MAX_FEED_BYTES: usize = 5 * 1024 * 1024;
+const MAX_TITLE_CHARS: usize = 200;
+/// Summaries are made from at most this much of an entry's text.
+pub const MAX_SUMMARY_INPUT_CHARS: usize = 4000;
+
+/// One entry of a feed, reduced to what announcements need.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeedItem {
+    /// The feed's own identifier for the entry (or a hash of its link), used to spot new entries.
+    pub id: String,
+    pub title: String,
+    pub link: Option<String>,
+    /// Summary or content, as plain text.
+    pub text: Option<String>,
+    pub published: Option<DateTime<Utc>>,
+}
+
+impl FeedItem {
+    /// The announcement line, e.g. "New post: Release 1.2 – https://example.com/1.2".
+    pub fn announcement(&self, summary: Option<&str>) -> String {
+        let mut line = format!("New post: {}", self.title);
+        if let Some(link) = &self.link {
+            line.push_str(&format!(" – {}", link));
+        }
+        if let Some(summary) = summary {
+            line.push_str(&format!(" | {}", summary));
+        }
+        line
+    }
+}
+
+/// Downloads and parses a feed.
+pub async fn fetch_feed(proxy: &HttpProxy, url: &str) -> Result<Vec<FeedItem>> {
+    let client = proxy.client()?;
+    let response = client
+        .get(url)
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await
+        .context("Failed to send request for feed")?
+        .error_for_status()
+        .context("Feed URL returned error status")?;
+    let body = read_body_capped(response, MAX_FEED_BYTES, "Feed").await?;
+    parse_feed(&body)
+}
+
+/// Parses an RSS or Atom document. Entries are returned in document order, which for nearly all
+/// feeds is newest first.
+pub fn parse_feed(body: &[u8]) -> Result<Vec<FeedItem>> {
+    let feed = feed_rs::parser::parse(body).context("Not a valid RSS or Atom feed")?;
+    let items = feed
+        .entries
+        .into_iter()
+        .map(|entry| {
+            let title = entry.title.map(|t| flatten(&t.content, MAX_TITLE_CHARS)).unwrap_or_default();
+            let text = entry
+                .summary
+                .map(|s| s.content)
+                .or_else(|| entry.content.and_then(|c| c.body))
+                .map(|html| flatten(&html_to_text(&html), MAX_SUMMARY_INPUT_CHARS))
+                .filter(|text| !text.is_empty());
+            FeedItem {
+                id: entry.id,
+                title: if title.is_empty() { "(untitled)".to_string() } else { title },
+                link: entry.links.into_iter().next().map(|link| link.href),
+                text,
+                published: entry.published.or(entry.updated),
+            }
+        })
+        .collect();
+    Ok(items)
+}
+
+/// Feed summaries are usually HTML fragments; keeps only their text.
+fn html_to_text(html: &str) -> String {
+    scraper::Html::parse_fragment(html).root_element().text().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rss() {
+        let rss = br#"<?xml version="1.0"?>
+            <rss version="2.0"><channel><title>Example</title>
+            <item>
+                <title>Release
+                    1.2</title>
+                <link>https://example.com/1.2</link>
+                <guid>release-1.2</guid>
+                <description>&lt;p&gt;Now with &lt;b&gt;more&lt;/b&gt; bunnies.&lt;/p&gt;</description>
+            </item>
+            <item><guid>no-title</guid></item>
+            </channel></rss>"#;
+        let items = parse_feed(rss).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].id, "release-1.2");
+        assert_eq!(items[0].text.as_deref(), Some("Now with more bunnies."));
+        assert_eq!(items[0].announcement(None), "New post: Release 1.2 – https://example.com/1.2");
+        assert_eq!(items[1].announcement(Some("Short.")), "New post: (untitled) | Short.");
+    }
+
+    #[test]
+    fn test_parse_atom() {
+        let atom = br#"<?xml version="1.0" encoding="utf-8"?>
+            <feed xmlns="http://www.w3.org/2005/Atom">
+            <title>Example</title><id>urn:example</id><updated>2025-04-11T17:00:00Z</updated>
+            <entry>
+                <title>Anime night</title><id>urn:example:1</id>
+                <updated>2025-04-11T17:00:00Z</updated>
+                <link href="https://example.com/anime-night"/>
+            </entry>
+            </feed>"#;
+        let items = parse_feed(atom).unwrap();
+        assert_eq!(items[0].link.as_deref(), Some("https://example.com/anime-night"));
+        assert!(items[0].published.is_some());
+        assert!(parse_feed(b"<html>nope</html>").is_err());
+    }
+}
@@ -0,0 +1,90 @@
+//! Recurring channel announcements, configured by admins with `!schedule` and stored in the
+//! database. Schedules use standard five-field cron patterns ("0 20 * * FRI" is 20:00 every
+//! Friday), evaluated in the server's local time.
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local};
+use croner::Cron;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How often the runner looks for due announcements. Cron's resolution is a minute.
+pub const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(20);
+
+/// A `!schedule add` command, parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewSchedule {
+    pub cron: String,
+    pub channel: String,
+    pub message: String,
+}
+
+/// Parses a cron pattern, rejecting anything croner can't evaluate.
+pub fn parse_cron(pattern: &str) -> Result<Cron> {
+    Cron::from_str(pattern).with_context(|| format!("Invalid cron pattern \"{}\"", pattern))
+}
+
+/// Parses the arguments of `!schedule add`: a quoted cron pattern, a channel, and the message,
+/// e.g. `"0 20 * * FRI" #anime Anime night starts now!`.
+pub fn parse_add_command(args: &str) -> Result<NewSchedule> {
+    let Some(rest) = args.trim_start().strip_prefix('"') else {
+        bail!("The cron pattern must be in quotes");
+    };
+    let Some((cron, rest)) = rest.split_once('"') else {
+        bail!("Missing closing quote after the cron pattern");
+    };
+    let cron = cron.split_whitespace().collect::<Vec<_>>().join(" ");
+    parse_cron(&cron)?;
+
+    let (channel, message) = rest.trim_start().split_once(char::is_whitespace).unwrap_or((rest.trim(), ""));
+    if !channel.starts_with('#') {
+        bail!("Expected a #channel after the cron pattern");
+    }
+    let message = message.trim();
+    if message.is_empty() {
+        bail!("The announcement message is empty");
+    }
+    Ok(NewSchedule { cron, channel: channel.to_string(), message: message.to_string() })
+}
+
+/// The first time after `after` that `cron` fires, if any.
+pub fn next_run(cron: &Cron, after: &DateTime<Local>) -> Option<DateTime<Local>> {
+    cron.find_next_occurrence(after, false).ok()
+}
+
+/// Whether `cron` fires in the window (`last_check`, `now`].
+pub fn is_due(cron: &Cron, last_check: &DateTime<Local>, now: &DateTime<Local>) -> bool {
+    next_run(cron, last_check).is_some_and(|next| next <= *now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_add_command() {
+        assert_eq!(
+            parse_add_command(r#" "0 20 * * FRI" #anime Anime night in 10 minutes!"#).unwrap(),
+            NewSchedule {
+                cron: "0 20 * * FRI".to_string(),
+                channel: "#anime".to_string(),
+                message: "Anime night in 10 minutes!".to_string(),
+            }
+        );
+        assert!(parse_add_command("0 20 * * FRI #anime hi").is_err());
+        assert!(parse_add_command(r#""0 25 * * FRI" #anime hi"#).is_err());
+        assert!(parse_add_command(r#""0 20 * * FRI" anime hi"#).is_err());
+        assert!(parse_add_command(r#""0 20 * * FRI" #anime"#).is_err());
+    }
+
+    #[test]
+    fn test_is_due() {
+        let cron = parse_cron("0 20 * * FRI").unwrap();
+        // 2025-04-11 was a Friday
+        let before = Local.with_ymd_and_hms(2025, 4, 11, 19, 59, 50).unwrap();
+        let after = Local.with_ymd_and_hms(2025, 4, 11, 20, 0, 10).unwrap();
+        assert!(is_due(&cron, &before, &after));
+        assert!(!is_due(&cron, &after, &(after + chrono::Duration::hours(1))));
+    }
+}
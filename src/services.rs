@@ -0,0 +1,240 @@
+//! IRC network services: logging in to NickServ (or QuakeNet's Q, or Undernet's X), taking our
+//! nickname back when someone else holds it, and deciding when to join channels. With a
+//! password, channels are joined once services have answered, so cloaks and channel access
+//! apply from the start; without one, as soon as the server has registered us. Everything
+//! happens again on each connection, so a reconnect logs in again too.
+
+use crate::config::ServicesKind;
+use crate::sanitize::strip_invisible;
+use irc::proto::Command;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Channels are joined anyway if services haven't answered this long after registration.
+pub const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// Fallback nicknames tried while someone else holds ours: nick_, nick__, nick___.
+const ALTERNATIVE_NICKS: usize = 3;
+
+/// What a notice from services told us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServicesEvent {
+    /// We're logged in to our account.
+    Identified,
+    /// Our nickname isn't registered, so there's nothing to log in to.
+    NotRegistered,
+    /// The password was refused.
+    LoginFailed,
+    /// Services want us to identify, e.g. after they restarted.
+    IdentifyRequested,
+    /// Whoever held our nickname has been disconnected, so it's free to take.
+    NickFreed,
+}
+
+impl ServicesEvent {
+    /// Whether we now know how logging in went, so channels can be joined.
+    pub fn settles_login(self) -> bool {
+        matches!(self, ServicesEvent::Identified | ServicesEvent::NotRegistered | ServicesEvent::LoginFailed)
+    }
+}
+
+/// Services as seen from one connection.
+pub struct Services {
+    kind: ServicesKind,
+    /// The nickname we want.
+    nickname: String,
+    /// The account to log in to; NickServ accounts are usually the nickname.
+    account: String,
+    password: Option<String>,
+    /// The nickname the server knows us by, which isn't `nickname` while someone else holds it.
+    current_nick: RwLock<String>,
+    /// Set once channels have been joined on this connection.
+    joined: AtomicBool,
+}
+
+impl Services {
+    pub fn new(kind: ServicesKind, nickname: &str, account: Option<&str>, password: Option<&str>) -> Self {
+        Services {
+            kind,
+            nickname: nickname.to_string(),
+            account: account.unwrap_or(nickname).to_string(),
+            password: password.filter(|password| !password.is_empty()).map(str::to_string),
+            current_nick: RwLock::new(nickname.to_string()),
+            joined: AtomicBool::new(false),
+        }
+    }
+
+    /// The nicknames the irc crate falls back on while ours is taken.
+    pub fn alternative_nicks(&self) -> Vec<String> {
+        (1..=ALTERNATIVE_NICKS).map(|n| format!("{}{}", self.nickname, "_".repeat(n))).collect()
+    }
+
+    pub fn current_nick(&self) -> String {
+        self.current_nick.read().unwrap().clone()
+    }
+
+    /// Remembers the nickname the server knows us by, from its welcome or our nick changes.
+    pub fn set_current_nick(&self, nick: &str) {
+        *self.current_nick.write().unwrap() = nick.to_string();
+    }
+
+    /// Whether `nick` is us.
+    pub fn is_me(&self, nick: &str) -> bool {
+        self.current_nick.read().unwrap().eq_ignore_ascii_case(nick)
+    }
+
+    /// Who services' notices come from.
+    fn service_nick(&self) -> Option<&'static str> {
+        match self.kind {
+            ServicesKind::Nickserv => Some("NickServ"),
+            ServicesKind::Q => Some("Q"),
+            ServicesKind::X => Some("X"),
+            ServicesKind::None => None,
+        }
+    }
+
+    fn identify(&self) -> Option<Command> {
+        let password = self.password.as_ref()?;
+        match self.kind {
+            ServicesKind::Nickserv => Some(Command::NICKSERV(vec![
+                "IDENTIFY".to_string(),
+                self.account.clone(),
+                password.clone(),
+            ])),
+            ServicesKind::Q => {
+                Some(Command::PRIVMSG("Q@CServe.quakenet.org".to_string(), format!("AUTH {} {}", self.account, password)))
+            }
+            ServicesKind::X => {
+                Some(Command::PRIVMSG("x@channels.undernet.org".to_string(), format!("LOGIN {} {}", self.account, password)))
+            }
+            ServicesKind::None => None,
+        }
+    }
+
+    /// Asks NickServ to disconnect whoever holds our nickname. Only NickServ owns nicknames.
+    fn ghost(&self) -> Option<Command> {
+        let password = self.password.as_ref()?;
+        (self.kind == ServicesKind::Nickserv && !self.is_me(&self.nickname))
+            .then(|| Command::NICKSERV(vec!["GHOST".to_string(), self.nickname.clone(), password.clone()]))
+    }
+
+    /// Commands to send once the server has registered us: logging in, and getting our
+    /// nickname back if we had to settle for another.
+    pub fn on_registered(&self) -> Vec<Command> {
+        self.identify().into_iter().chain(self.ghost()).collect()
+    }
+
+    /// Whether channels can be joined right after registration, with no login to wait for.
+    pub fn join_on_registered(&self) -> bool {
+        self.identify().is_none()
+    }
+
+    /// What a notice means, if it's from services.
+    pub fn parse_notice(&self, source: &str, text: &str) -> Option<ServicesEvent> {
+        if !self.service_nick().is_some_and(|service| service.eq_ignore_ascii_case(source)) {
+            return None;
+        }
+        let text = strip_invisible(text).to_lowercase();
+        let says = |phrases: &[&str]| phrases.iter().any(|phrase| text.contains(phrase));
+        if says(&["invalid password", "password incorrect", "incorrect password", "authentication failed"]) {
+            Some(ServicesEvent::LoginFailed)
+        } else if says(&["is not registered", "isn't registered", "is not a registered nickname"]) {
+            Some(ServicesEvent::NotRegistered)
+        } else if says(&["nickname is registered"]) {
+            Some(ServicesEvent::IdentifyRequested)
+        } else if says(&["has been ghosted", "ghost with your nick has been killed"]) {
+            Some(ServicesEvent::NickFreed)
+        } else if says(&["you are now identified", "you are now recognized", "you are now logged in", "authentication successful"]) {
+            Some(ServicesEvent::Identified)
+        } else {
+            None
+        }
+    }
+
+    /// Commands answering `event`.
+    pub fn respond(&self, event: ServicesEvent) -> Vec<Command> {
+        match event {
+            ServicesEvent::IdentifyRequested => self.identify().into_iter().collect(),
+            ServicesEvent::NickFreed if !self.is_me(&self.nickname) => vec![Command::NICK(self.nickname.clone())],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Claims joining the channels, which happens once per connection: true the first time.
+    pub fn start_joining(&self) -> bool {
+        !self.joined.swap(true, Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nickserv_login_and_ghost() {
+        let services = Services::new(ServicesKind::Nickserv, "Emul", None, Some("hunter2"));
+        assert_eq!(services.alternative_nicks(), ["Emul_", "Emul__", "Emul___"]);
+        assert!(!services.join_on_registered());
+        let identify = Command::NICKSERV(vec!["IDENTIFY".to_string(), "Emul".to_string(), "hunter2".to_string()]);
+        assert_eq!(services.on_registered(), std::slice::from_ref(&identify));
+
+        // Someone else has our nick: log in, ghost them, then take it back
+        services.set_current_nick("Emul_");
+        assert_eq!(
+            services.on_registered(),
+            [identify, Command::NICKSERV(vec!["GHOST".to_string(), "Emul".to_string(), "hunter2".to_string()])]
+        );
+        let event = services.parse_notice("NickServ", "\x02Emul\x02 has been ghosted.");
+        assert_eq!(event, Some(ServicesEvent::NickFreed));
+        assert_eq!(services.respond(ServicesEvent::NickFreed), [Command::NICK("Emul".to_string())]);
+        services.set_current_nick("Emul");
+        assert!(services.is_me("emul"));
+        assert!(services.respond(ServicesEvent::NickFreed).is_empty());
+    }
+
+    #[test]
+    fn test_parse_notice() {
+        let services = Services::new(ServicesKind::Nickserv, "Emul", None, Some("hunter2"));
+        let parse = |source, text| services.parse_notice(source, text);
+        assert_eq!(parse("NickServ", "You are now identified for \x02Emul\x02."), Some(ServicesEvent::Identified));
+        assert_eq!(parse("NickServ", "Password accepted - you are now recognized."), Some(ServicesEvent::Identified));
+        assert_eq!(parse("NickServ", "Invalid password for \x02Emul\x02."), Some(ServicesEvent::LoginFailed));
+        assert_eq!(parse("NickServ", "\x02Emul\x02 is not registered."), Some(ServicesEvent::NotRegistered));
+        assert_eq!(
+            parse("NickServ", "This nickname is registered. Please choose a different nickname, or identify via /msg NickServ IDENTIFY Emul <password>"),
+            Some(ServicesEvent::IdentifyRequested)
+        );
+        assert_eq!(parse("NickServ", "Welcome to Libera.Chat!"), None);
+        // Anyone can send a notice saying this
+        assert_eq!(parse("mallory", "You are now identified for \x02Emul\x02."), None);
+        assert!(ServicesEvent::LoginFailed.settles_login() && !ServicesEvent::NickFreed.settles_login());
+    }
+
+    #[test]
+    fn test_q_and_x() {
+        let q = Services::new(ServicesKind::Q, "Emul", Some("emulbot"), Some("hunter2"));
+        assert_eq!(q.on_registered(), [Command::PRIVMSG("Q@CServe.quakenet.org".to_string(), "AUTH emulbot hunter2".to_string())]);
+        assert_eq!(q.parse_notice("Q", "You are now logged in as emulbot."), Some(ServicesEvent::Identified));
+        assert_eq!(q.parse_notice("NickServ", "You are now identified for \x02Emul\x02."), None);
+        // Q doesn't own nicknames, so there's nobody to ghost
+        q.set_current_nick("Emul_");
+        assert_eq!(q.on_registered().len(), 1);
+
+        let x = Services::new(ServicesKind::X, "Emul", None, Some("hunter2"));
+        assert_eq!(x.on_registered(), [Command::PRIVMSG("x@channels.undernet.org".to_string(), "LOGIN Emul hunter2".to_string())]);
+        assert_eq!(x.parse_notice("X", "AUTHENTICATION FAILED as Emul"), Some(ServicesEvent::LoginFailed));
+    }
+
+    #[test]
+    fn test_without_login() {
+        for services in [
+            Services::new(ServicesKind::Nickserv, "Emul", None, None),
+            Services::new(ServicesKind::None, "Emul", None, Some("hunter2")),
+        ] {
+            assert!(services.join_on_registered());
+            assert!(services.on_registered().is_empty());
+            assert!(services.start_joining());
+            assert!(!services.start_joining());
+        }
+    }
+}
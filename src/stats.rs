@@ -0,0 +1,129 @@
+//! Channel statistics: who talks the most, how busy a channel is and when, worked out from the
+//! message log. Admins can ask with `!stats #channel`, and the AI can look them up with a tool.
+
+use crate::db::{self, DbPool};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+
+/// How far back statistics look.
+pub const STATS_DAYS: i64 = 30;
+const TOP_TALKER_COUNT: usize = 5;
+const BUSIEST_HOUR_COUNT: usize = 3;
+
+/// A channel's activity over the last `STATS_DAYS` days, not counting the bot's own messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelStats {
+    pub messages: u32,
+    /// Days the statistics cover: `STATS_DAYS`, or fewer if the log doesn't go back that far.
+    pub days: u32,
+    /// Nicks with the most messages, with their counts, most talkative first.
+    pub top_talkers: Vec<(String, u32)>,
+    /// Hours of the day (UTC) with the most messages, with their counts, busiest first.
+    pub busiest_hours: Vec<(u32, u32)>,
+}
+
+impl ChannelStats {
+    pub fn messages_per_day(&self) -> f64 {
+        self.messages as f64 / self.days.max(1) as f64
+    }
+
+    /// The statistics as one line for the channel.
+    pub fn summary(&self, channel: &str) -> String {
+        if self.messages == 0 {
+            return format!("{} has been quiet for the last {} days.", channel, STATS_DAYS);
+        }
+        let talkers: Vec<String> = self.top_talkers.iter().map(|(nick, count)| format!("{} ({})", nick, count)).collect();
+        let hours: Vec<String> = self.busiest_hours.iter().map(|(hour, count)| format!("{:02}:00 ({})", hour, count)).collect();
+        format!(
+            "{}, last {} day{}: {} messages ({:.1}/day). Top talkers: {}. Busiest hours (UTC): {}.",
+            channel,
+            self.days,
+            if self.days == 1 { "" } else { "s" },
+            self.messages,
+            self.messages_per_day(),
+            talkers.join(", "),
+            hours.join(", ")
+        )
+    }
+
+    /// The statistics as a tool result.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "days": self.days,
+            "messages": self.messages,
+            "messages_per_day": (self.messages_per_day() * 10.0).round() / 10.0,
+            "top_talkers": self.top_talkers.iter().map(|(nick, count)| json!({ "nick": nick, "messages": count })).collect::<Vec<_>>(),
+            "busiest_hours_utc": self.busiest_hours.iter().map(|(hour, count)| json!({ "hour": hour, "messages": count })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Works out a channel's statistics as of `now`, leaving out the bot's own messages.
+pub async fn channel_stats(db: &DbPool, channel: &str, bot_nick: &str, now: DateTime<Utc>) -> Result<ChannelStats> {
+    let since = now.timestamp() - STATS_DAYS * 24 * 60 * 60;
+    let (channel, bot_nick) = (channel.to_string(), bot_nick.to_string());
+    let ((messages, first), top_talkers, mut hours) = db
+        .run(move |conn| {
+            Ok((
+                db::get_message_count(conn, &channel, since, &bot_nick)?,
+                db::get_top_talkers(conn, &channel, since, &bot_nick, TOP_TALKER_COUNT)?,
+                db::get_hourly_message_counts(conn, &channel, since, &bot_nick)?,
+            ))
+        })
+        .await?;
+    // A channel logged for less than the whole period is averaged over the days it was
+    let days = first.map_or(STATS_DAYS, |first| (now.timestamp() - first) / (24 * 60 * 60) + 1);
+    hours.sort_by(|(hour_a, count_a), (hour_b, count_b)| count_b.cmp(count_a).then(hour_a.cmp(hour_b)));
+    hours.truncate(BUSIEST_HOUR_COUNT);
+    Ok(ChannelStats {
+        messages,
+        days: days.clamp(1, STATS_DAYS) as u32,
+        top_talkers,
+        busiest_hours: hours,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_channel_stats() {
+        let db = db::init_memory_db().unwrap();
+        db.run(|conn| {
+            for (nick, message) in [("alice", "hi"), ("bob", "hey"), ("Alice", "how's it going?"), ("Emul", "Hi hi!")] {
+                db::log_message(conn, "#test", nick, message)?;
+            }
+            db::log_message(conn, "#other", "carol", "elsewhere")
+        })
+        .await
+        .unwrap();
+
+        let now = Utc::now();
+        let stats = channel_stats(&db, "#test", "emul", now).await.unwrap();
+        assert_eq!(stats.messages, 3);
+        assert_eq!(stats.days, 1);
+        assert_eq!(stats.top_talkers, [("Alice".to_string(), 2), ("bob".to_string(), 1)]);
+        assert_eq!(stats.busiest_hours.len(), 1);
+        assert_eq!(stats.busiest_hours[0].1, 3);
+
+        let quiet = channel_stats(&db, "#quiet", "emul", now).await.unwrap();
+        assert_eq!(quiet.summary("#quiet"), "#quiet has been quiet for the last 30 days.");
+    }
+
+    #[test]
+    fn test_summary() {
+        let stats = ChannelStats {
+            messages: 45,
+            days: 2,
+            top_talkers: vec![("alice".to_string(), 30), ("bob".to_string(), 15)],
+            busiest_hours: vec![(21, 20), (9, 10)],
+        };
+        assert_eq!(
+            stats.summary("#test"),
+            "#test, last 2 days: 45 messages (22.5/day). Top talkers: alice (30), bob (15). Busiest hours (UTC): 21:00 (20), 09:00 (10)."
+        );
+        assert_eq!(stats.to_json()["messages_per_day"], 22.5);
+    }
+}
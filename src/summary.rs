@@ -0,0 +1,53 @@
+//! Rolling channel summaries: every so often, the messages a channel has seen since its last
+//! summary are folded into it by the fast model. The summary is prepended to AI prompts, giving
+//! the bot a sense of the channel's longer-running topics without a huge history.
+
+use crate::ai_handler;
+use crate::ctcp;
+use crate::db::{self, DbPool, LogEntry};
+use crate::llm::LlmBackend;
+use anyhow::Result;
+use std::time::Duration;
+
+/// How often channels are checked for enough new messages to update their summary.
+pub const SUMMARY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// A summary is only updated once this many messages have accumulated since the last update.
+pub const SUMMARY_MIN_NEW_LINES: usize = 100;
+/// Most new messages folded in per update; older ones since the last update are skipped.
+const SUMMARY_MAX_NEW_LINES: usize = 400;
+const SUMMARY_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M UTC";
+
+/// Folds the channel's new messages into its summary, if enough have accumulated. Returns
+/// whether the summary was updated.
+pub async fn update_summary(llm: &dyn LlmBackend, db: &DbPool, channel: &str) -> Result<bool> {
+    let log_channel = channel.to_string();
+    let (previous, entries) = db
+        .run(move |conn| {
+            let previous = db::get_channel_summary(conn, &log_channel)?;
+            let after_id = previous.as_ref().map_or(0, |summary| summary.last_message_id);
+            let entries = db::get_latest_log_after(conn, &log_channel, after_id, SUMMARY_MAX_NEW_LINES)?;
+            Ok((previous, entries))
+        })
+        .await?;
+    let Some((last_id, _)) = entries.last() else {
+        return Ok(false);
+    };
+    if entries.len() < SUMMARY_MIN_NEW_LINES {
+        return Ok(false);
+    }
+
+    let last_id = *last_id;
+    let lines = format_lines(entries.iter().map(|(_, entry)| entry));
+    let summary = ai_handler::summarize_conversation(llm, previous.as_ref().map(|s| s.summary.as_str()), &lines).await?;
+    let summary_channel = channel.to_string();
+    db.run(move |conn| db::set_channel_summary(conn, &summary_channel, &summary, last_id)).await?;
+    tracing::debug!(%channel, last_id, "Updated channel summary");
+    Ok(true)
+}
+
+fn format_lines<'a>(entries: impl Iterator<Item = &'a LogEntry>) -> String {
+    entries
+        .map(|e| format!("[{}] {}", e.timestamp.format(SUMMARY_TIMESTAMP_FORMAT), ctcp::display_line(&e.nick, &e.message)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
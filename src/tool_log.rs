@@ -0,0 +1,95 @@
+//! The tool call log: every tool call the AI makes is saved with who it was answering, what it
+//! got back and how long it took, and `!toollog` lists the latest for debugging and for looking
+//! into abuse. Calls to tools set to `confirm` wait here too, for `!approve` or `!deny`.
+
+use crate::db::{PendingToolCall, ToolCall};
+use crate::sanitize::flatten;
+
+/// How many calls `!toollog` lists without a count.
+pub const DEFAULT_COUNT: usize = 10;
+/// The most calls `!toollog` lists at once.
+pub const MAX_COUNT: usize = 50;
+/// Arguments and results are cut to this many characters, so each call fits on one line.
+const MAX_ARGS_CHARS: usize = 120;
+const MAX_RESULT_CHARS: usize = 150;
+
+/// Renders a call on one line, e.g.
+/// "12 [2026-10-16 13:05 UTC] #emul alice: roll_dice {"dice_notation":"2d6"} -> 3ms: {"result":...}".
+pub fn display(call: &ToolCall) -> String {
+    let outcome = match (&call.error, &call.result) {
+        (Some(error), _) => format!("failed: {}", flatten(error, MAX_RESULT_CHARS)),
+        (None, Some(result)) => flatten(result, MAX_RESULT_CHARS),
+        (None, None) => "no result".to_string(),
+    };
+    format!(
+        "{} [{}] {} {}: {} {} -> {}ms: {}",
+        call.id,
+        call.timestamp.format("%Y-%m-%d %H:%M UTC"),
+        call.channel,
+        call.nick,
+        call.tool,
+        flatten(&call.args, MAX_ARGS_CHARS),
+        call.duration_ms,
+        outcome
+    )
+}
+
+/// Renders a call waiting for approval on one line, e.g.
+/// "#5 [2026-10-16 13:05 UTC] #emul alice: download_torrent {"nyaa_url":"..."}".
+pub fn display_pending(call: &PendingToolCall) -> String {
+    format!(
+        "#{} [{}] {} {}: {} {}",
+        call.id,
+        call.timestamp.format("%Y-%m-%d %H:%M UTC"),
+        call.channel,
+        call.nick,
+        call.tool,
+        flatten(&call.args, MAX_ARGS_CHARS)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_display() {
+        let mut call = ToolCall {
+            id: 12,
+            timestamp: Utc.with_ymd_and_hms(2026, 10, 16, 13, 5, 0).unwrap(),
+            channel: "#emul".to_string(),
+            nick: "alice".to_string(),
+            tool: "roll_dice".to_string(),
+            args: r#"{"dice_notation":"2d6"}"#.to_string(),
+            result: Some(r#"{"result":"Rolled 2d6: [3, 4] = 7"}"#.to_string()),
+            error: None,
+            duration_ms: 3,
+        };
+        assert_eq!(
+            display(&call),
+            r#"12 [2026-10-16 13:05 UTC] #emul alice: roll_dice {"dice_notation":"2d6"} -> 3ms: {"result":"Rolled 2d6: [3, 4] = 7"}"#
+        );
+
+        call.result = None;
+        call.error = Some(format!("Bad dice\nnotation {}", "x".repeat(200)));
+        let line = display(&call);
+        assert!(line.ends_with(&format!("-> 3ms: failed: Bad dice notation {}…", "x".repeat(MAX_RESULT_CHARS - 18))), "{}", line);
+    }
+
+    #[test]
+    fn test_display_pending() {
+        let call = PendingToolCall {
+            id: 5,
+            timestamp: Utc.with_ymd_and_hms(2026, 10, 16, 13, 5, 0).unwrap(),
+            channel: "#emul".to_string(),
+            nick: "alice".to_string(),
+            tool: "download_torrent".to_string(),
+            args: r#"{"nyaa_url":"https://nyaa.si/view/1"}"#.to_string(),
+        };
+        assert_eq!(
+            display_pending(&call),
+            r#"#5 [2026-10-16 13:05 UTC] #emul alice: download_torrent {"nyaa_url":"https://nyaa.si/view/1"}"#
+        );
+    }
+}
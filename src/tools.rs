@@ -0,0 +1,1150 @@
+//! Tools the AI can call during a conversation.
+//!
+//! Each tool implements `Tool` and is registered in a `ToolRegistry`; the function-calling loop in
+//! ai_handler only looks tools up by name. New tools can be added by implementing the trait and
+//! calling `ToolRegistry::register`, without touching the loop.
+
+use crate::ai_handler::{self, ChatbotOptions};
+use crate::anilist::{self, MediaType};
+use crate::calc;
+use crate::crypto::{self, PriceCache};
+use crate::currency::RatesCache;
+use crate::db;
+use crate::dice;
+use crate::image_cache::ImageCache;
+use crate::karma;
+use crate::llm::LlmBackend;
+use crate::nyaa_parser::{self, SearchOrder};
+use crate::quotes;
+use crate::roster::Member;
+use crate::sanitize::wrap_untrusted;
+use crate::stats;
+use crate::torrent_client::TorrentClient;
+use crate::user_facts;
+use crate::wiki::{self, Wiki};
+use crate::youtube;
+use anyhow::{Context, Result, anyhow, bail};
+use futures::future::BoxFuture;
+use num_rational::BigRational;
+use num_traits::One;
+use serde_json::{Value, json};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_TOOL_RESULT_LIMIT: usize = 4000; // Max chars of a tool result before it is summarized
+const WEBPAGE_TOOL_RESULT_LIMIT: usize = 8000; // Webpages get a bigger budget; they're the point of the tool
+const TRANSCRIPT_TOOL_RESULT_LIMIT: usize = 8000; // Same for video transcripts
+const WEBPAGE_TOOL_MIN_TIMEOUT: Duration = Duration::from_secs(70); // Fetching, then maybe rendering
+const TRANSCRIPTION_MIN_TIMEOUT: Duration = Duration::from_secs(120); // Downloading, then a model call with retries
+/// Base64 image data attached in one turn. Gemini takes 20MB of inline data per request, and the
+/// rest of the conversation has to fit too.
+pub const MAX_IMAGE_BYTES_PER_TURN: usize = 12 * 1024 * 1024;
+const DEFAULT_NYAA_RESULTS: usize = 5;
+const MAX_NYAA_RESULTS: usize = 10;
+
+/// What a tool gets to work with while executing.
+pub struct ToolContext<'a> {
+    pub llm: &'a dyn LlmBackend,
+    /// The channel the request came from.
+    pub channel: &'a str,
+    pub image_cache: &'a ImageCache,
+    pub options: &'a ChatbotOptions,
+    /// What more may be attached during this function-call turn.
+    pub image_budget: ImageBudget,
+}
+
+/// How many more images, and how many bytes of base64 image data, may be attached in a turn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageBudget {
+    pub images: usize,
+    pub bytes: usize,
+}
+
+impl ImageBudget {
+    /// What's left of a turn's budget once `attached` (mime type, base64 data) images are in it.
+    pub fn remaining(max_images: usize, attached: &[(String, String)]) -> Self {
+        let bytes: usize = attached.iter().map(|(_, data)| data.len()).sum();
+        ImageBudget {
+            images: max_images.saturating_sub(attached.len()),
+            bytes: MAX_IMAGE_BYTES_PER_TURN.saturating_sub(bytes),
+        }
+    }
+
+    /// Nothing may be attached, for calls made outside a function-call turn.
+    pub fn none() -> Self {
+        ImageBudget { images: 0, bytes: 0 }
+    }
+}
+
+/// The result of a successful tool call.
+pub struct ToolOutput {
+    /// Sent back to the model as the functionResponse's `response`.
+    pub response: Value,
+    /// Images (mime type, base64 data) to show the model alongside the response.
+    pub images: Vec<(String, String)>,
+}
+
+impl ToolOutput {
+    /// A plain `{"result": ...}` response.
+    pub fn result(result: impl Into<Value>) -> Self {
+        Self {
+            response: json!({ "result": result.into() }),
+            images: Vec::new(),
+        }
+    }
+}
+
+pub trait Tool: Send + Sync {
+    /// The function name the model calls.
+    fn name(&self) -> &str;
+
+    /// Gemini-style function declaration: name, description, and JSON schema `parameters`.
+    fn declaration(&self) -> Value;
+
+    /// Maximum size, in characters, of the result before it is summarized.
+    fn result_limit(&self) -> usize {
+        DEFAULT_TOOL_RESULT_LIMIT
+    }
+
+    /// The least time a call is given, whatever the configured tool timeout; for tools that
+    /// are slow by nature.
+    fn min_timeout(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Runs the tool. Errors are reported back to the model as the tool's result.
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>>;
+}
+
+/// The set of tools offered to the model.
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: Vec<Arc<dyn Tool>>,
+}
+
+impl fmt::Debug for ToolRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+impl ToolRegistry {
+    /// The bot's built-in tools. Downloads go to `torrent_client`, if one is configured.
+    pub fn builtin(torrent_client: Option<Arc<dyn TorrentClient>>) -> Self {
+        let mut registry = Self::default();
+        registry.register(Arc::new(RollDiceTool));
+        let rates = Arc::new(RatesCache::default());
+        registry.register(Arc::new(CalculateTool { rates: rates.clone() }));
+        registry.register(Arc::new(PriceTool { rates, prices: PriceCache::default() }));
+        registry.register(Arc::new(DownloadTorrentTool { client: torrent_client.clone() }));
+        registry.register(Arc::new(TorrentStatusTool { client: torrent_client }));
+        registry.register(Arc::new(SearchNyaaTool));
+        registry.register(Arc::new(LookupAnimeTool));
+        registry.register(Arc::new(FetchImageTool));
+        registry.register(Arc::new(ReadWebpageTool));
+        registry.register(Arc::new(LookupWikiTool));
+        registry.register(Arc::new(YoutubeTranscriptTool));
+        registry.register(Arc::new(TranscribeAudioTool));
+        registry.register(Arc::new(KarmaTool));
+        registry.register(Arc::new(QuoteTool));
+        registry.register(Arc::new(ChannelStatsTool));
+        registry.register(Arc::new(ListChannelUsersTool));
+        registry.register(Arc::new(RememberFactTool));
+        registry.register(Arc::new(RecallFactsTool));
+        registry
+    }
+
+    /// A copy of this registry with `plugins` added.
+    pub fn with_plugins(&self, plugins: Vec<Arc<dyn Tool>>) -> Self {
+        let mut registry = self.clone();
+        for plugin in plugins {
+            registry.register(plugin);
+        }
+        registry
+    }
+
+    /// Adds a tool, replacing any existing tool with the same name.
+    pub fn register(&mut self, tool: Arc<dyn Tool>) {
+        self.tools.retain(|t| t.name() != tool.name());
+        self.tools.push(tool);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Tool> {
+        self.tools.iter().find(|t| t.name() == name).map(|t| &**t)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.tools.iter().map(|t| t.name()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// The Gemini-style tool list for API requests.
+    pub fn declarations(&self) -> Value {
+        let declarations: Vec<Value> = self.tools.iter().map(|t| t.declaration()).collect();
+        json!([{ "functionDeclarations": declarations }])
+    }
+}
+
+/// Reads a required string argument.
+fn string_arg<'a>(args: &'a Value, tool: &str, name: &str) -> Result<&'a str> {
+    args[name]
+        .as_str()
+        .ok_or_else(|| anyhow!("Missing '{}' argument for {}", name, tool))
+}
+
+// --- Built-in Tools ---
+
+struct RollDiceTool;
+
+impl Tool for RollDiceTool {
+    fn name(&self) -> &str {
+        "roll_dice"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Rolls dice and reports every die and the total. E.g., 3d6 means roll 3 six-sided dice. Dropped dice are shown in parentheses, and dice that exploded are marked with !.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "dice_notation": {
+                        "type": "string",
+                        "description": "A dice expression: dice and numbers added or subtracted, like '1d20', '2d6+1d4+3' or '1d20-1'. Dice can keep the highest or lowest (4d6kh3, 2d20kl1), drop the lowest or highest (4d6dl1, 4d6dh1), explode on their highest side (3d6!), and roll with advantage or disadvantage (d20adv, d20dis). 'd%' is a d100."
+                    }
+                },
+                "required": ["dice_notation"]
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, _context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let notation = string_arg(args, self.name(), "dice_notation")?;
+            Ok(ToolOutput::result(dice::roll(notation)?))
+        })
+    }
+}
+
+/// Holds the exchange rates, so they're shared by every request and with the get_price tool.
+struct CalculateTool {
+    rates: Arc<RatesCache>,
+}
+
+impl Tool for CalculateTool {
+    fn name(&self) -> &str {
+        "calculate"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Evaluates a math expression exactly, with units and currencies. Use it for any arithmetic or unit conversion instead of working it out yourself. Numbers are exact fractions of any size; sqrt of non-squares, sin, ln, log and the like use floating point and are marked with ≈. Convert with 'to'.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "expression": {
+                        "type": "string",
+                        "description": "The expression, e.g. '2^64', '(3.5 + 1/3) * 12', '15% * 80', '5 ft 11 in to cm', '60 mph to km/h', '100 F to C', '3 kg * 9.81 m/s^2', '250 USD to EUR'. Operators: + - * / ^ mod ! %. Functions: sqrt, cbrt, abs, round(x, places), floor, ceil, min, max, exp, ln, log, log2, sin, cos, tan, asin, acos, atan. Constants: pi, e. Temperatures are C, F and K; currencies are ISO codes like USD or symbols like $."
+                    },
+                    "precision": {
+                        "type": "integer",
+                        "description": "Significant digits for results that aren't exact decimals (default 20)."
+                    }
+                },
+                "required": ["expression"]
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let expression = string_arg(args, self.name(), "expression")?;
+            let precision = args["precision"]
+                .as_u64()
+                .map_or(calc::DEFAULT_PRECISION, |n| (n as usize).clamp(1, calc::MAX_PRECISION));
+            if !calc::mentions_currency(expression) {
+                return Ok(ToolOutput::result(calc::evaluate(expression, None, precision)?));
+            }
+            let url = &context.options.currency_rates_url;
+            if url.is_empty() {
+                bail!("Currency conversion is turned off.");
+            }
+            let rates = self.rates.get(&context.options.proxy, url).await?;
+            let result = calc::evaluate(expression, Some(&rates), precision)?;
+            Ok(ToolOutput {
+                response: json!({ "result": result, "rates_date": rates.date }),
+                images: Vec::new(),
+            })
+        })
+    }
+}
+
+/// Prices of currencies and coins in other currencies. Fiat exchange rates come from the cache
+/// the calculate tool uses; coin prices have their own, rate-limited one.
+struct PriceTool {
+    rates: Arc<RatesCache>,
+    prices: PriceCache,
+}
+
+impl PriceTool {
+    async fn coin_price(&self, context: &ToolContext<'_>, id: &str, currency: &str) -> Result<crypto::Price> {
+        let url = &context.options.crypto_prices_url;
+        if url.is_empty() {
+            bail!("Cryptocurrency prices are turned off.");
+        }
+        self.prices.get(&context.options.proxy, url, id, currency).await
+    }
+}
+
+impl Tool for PriceTool {
+    fn name(&self) -> &str {
+        "get_price"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Gets the live price of a currency or cryptocurrency in another currency, e.g. what 100 EUR is in JPY or what a bitcoin costs in USD. Exchange rates are the day's reference rates; coin prices are at most a minute or two old.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "asset": {
+                        "type": "string",
+                        "description": "What to price: a currency code like 'EUR', a coin symbol like 'BTC', or a coin's CoinGecko id like 'bitcoin'."
+                    },
+                    "currency": {
+                        "type": "string",
+                        "description": "The currency to give the price in, like 'JPY' or 'BTC' (default USD)."
+                    },
+                    "amount": {
+                        "type": "number",
+                        "description": "How much of the asset to price (default 1)."
+                    }
+                },
+                "required": ["asset"]
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let asset = string_arg(args, self.name(), "asset")?.trim();
+            let currency = args["currency"].as_str().unwrap_or("USD").trim();
+            let amount = match args["amount"].as_number() {
+                Some(amount) => calc::parse_decimal(&amount.to_string()).context("That amount is too big or too small")?,
+                None => BigRational::one(),
+            };
+            // Anything that isn't a three-letter currency code is taken for a coin
+            let is_coin = |name: &str| {
+                crypto::known_coin(name).is_some() || !(name.len() == 3 && name.chars().all(|c| c.is_ascii_alphabetic()))
+            };
+            let (amount_text, _) = calc::format_number(&amount, true, calc::DEFAULT_PRECISION);
+
+            if !is_coin(asset) && !is_coin(currency) {
+                let url = &context.options.currency_rates_url;
+                if url.is_empty() {
+                    bail!("Currency conversion is turned off.");
+                }
+                let rates = self.rates.get(&context.options.proxy, url).await?;
+                let result = calc::evaluate(&format!("{} {} to {}", amount_text, asset, currency), Some(&rates), 10)?;
+                return Ok(ToolOutput { response: json!({ "result": result, "rates_date": rates.date }), images: Vec::new() });
+            }
+
+            // A currency priced in a coin is the inverse of the coin priced in the currency
+            let (coin, quote, inverse) = match is_coin(asset) {
+                true => (asset, currency, false),
+                false => (currency, asset, true),
+            };
+            let (coin_symbol, coin_id) = crypto::known_coin(coin).unwrap_or((coin, coin));
+            let quote = crypto::known_coin(quote).map_or(quote, |(symbol, _)| symbol);
+            let price = self.coin_price(context, coin_id, quote).await?;
+            let value = if inverse { &amount / &price.value } else { &amount * &price.value };
+            let (value_text, _) = calc::format_number(&value, false, 10);
+            let (mut from, mut to) = (coin_symbol.to_uppercase(), quote.to_uppercase());
+            if inverse {
+                std::mem::swap(&mut from, &mut to);
+            }
+            let result = format!("{} {} ≈ {} {}", amount_text, from, value_text, to);
+            Ok(ToolOutput {
+                response: json!({ "result": result, "price_time": price.fetched_at.format("%Y-%m-%d %H:%M UTC").to_string() }),
+                images: Vec::new(),
+            })
+        })
+    }
+}
+
+struct DownloadTorrentTool {
+    client: Option<Arc<dyn TorrentClient>>,
+}
+
+impl Tool for DownloadTorrentTool {
+    fn name(&self) -> &str {
+        "download_torrent"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Downloads a torrent file from a Nyaa.si URL. Extracts the magnet link, adds it to the download client, and reports the download's status.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "nyaa_url": {
+                        "type": "string",
+                        "description": "The full URL of the Nyaa.si torrent page (e.g., 'https://nyaa.si/view/123456')."
+                    }
+                },
+                "required": ["nyaa_url"]
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let url = string_arg(args, self.name(), "nyaa_url")?;
+            let result = ai_handler::download_torrent(&context.options.proxy, url, self.client.as_deref()).await?;
+            Ok(ToolOutput::result(result))
+        })
+    }
+}
+
+struct TorrentStatusTool {
+    client: Option<Arc<dyn TorrentClient>>,
+}
+
+impl Tool for TorrentStatusTool {
+    fn name(&self) -> &str {
+        "torrent_status"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Lists the torrents in the download client with their progress, to check on downloads started earlier.",
+            "parameters": {
+                "type": "object",
+                "properties": {}
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, _args: &'a Value, _context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move { Ok(ToolOutput::result(ai_handler::torrent_status(self.client.as_deref()).await?)) })
+    }
+}
+
+struct SearchNyaaTool;
+
+impl Tool for SearchNyaaTool {
+    fn name(&self) -> &str {
+        "search_nyaa"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Searches Nyaa.si for torrents (mostly anime) and returns the top results with their size, seeders, upload date, and page URL. Use it to find something, e.g. the latest episode of a show; pass a result's URL to download_torrent to download it.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "query": {
+                        "type": "string",
+                        "description": "Search terms, e.g. 'SubsPlease Frieren 1080p'."
+                    },
+                    "sort": {
+                        "type": "string",
+                        "enum": ["newest", "seeders"],
+                        "description": "'newest' (default) lists the latest uploads first; 'seeders' the best seeded."
+                    },
+                    "limit": {
+                        "type": "integer",
+                        "description": "How many results to return (default 5, at most 10)."
+                    }
+                },
+                "required": ["query"]
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let query = string_arg(args, self.name(), "query")?;
+            let order = match args["sort"].as_str() {
+                Some("seeders") => SearchOrder::Seeders,
+                _ => SearchOrder::Newest,
+            };
+            let limit = args["limit"]
+                .as_u64()
+                .map_or(DEFAULT_NYAA_RESULTS, |n| (n as usize).clamp(1, MAX_NYAA_RESULTS));
+            let results = nyaa_parser::search(&context.options.proxy.client()?, query, order, limit).await?;
+            tracing::info!(%query, ?order, results = results.len(), "Searched Nyaa");
+            if results.is_empty() {
+                return Ok(ToolOutput::result(format!("No torrents found for '{}'.", query)));
+            }
+            let listing = results
+                .iter()
+                .enumerate()
+                .map(|(i, r)| {
+                    format!(
+                        "{}. {} | {} | {} seeders, {} leechers | uploaded {} UTC | {}",
+                        i + 1, r.title, r.size, r.seeders, r.leechers, r.date, r.url
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            // Titles are written by the uploaders
+            Ok(ToolOutput::result(wrap_untrusted("nyaa search results", &listing)))
+        })
+    }
+}
+
+struct LookupAnimeTool;
+
+impl Tool for LookupAnimeTool {
+    fn name(&self) -> &str {
+        "lookup_anime"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Looks up an anime or manga on AniList: its titles, format, status, episode or chapter count, score, season, studios, genres and synopsis. For airing shows it also says when the next episode airs, so use it for questions like 'when does the next episode of X come out?'.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "title": {
+                        "type": "string",
+                        "description": "The title to search for, in English or romaji (e.g., 'Frieren', 'Kimetsu no Yaiba')."
+                    },
+                    "type": {
+                        "type": "string",
+                        "enum": ["anime", "manga"],
+                        "description": "Whether to look for an anime (default) or a manga."
+                    }
+                },
+                "required": ["title"]
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let title = string_arg(args, self.name(), "title")?;
+            let media_type = match args["type"].as_str() {
+                Some(name) => MediaType::parse(name).ok_or_else(|| anyhow!("Unknown type '{}'; use anime or manga", name))?,
+                None => MediaType::Anime,
+            };
+            let Some(mut media) = anilist::lookup(&context.options.proxy, title, media_type).await? else {
+                return Ok(ToolOutput::result(format!("Nothing on AniList matches '{}'.", title)));
+            };
+            tracing::info!(%title, found = %media["title"], "Looked up anime on AniList");
+            // Synopses are written by AniList's users
+            if let Some(description) = media["description"].as_str() {
+                media["description"] = json!(wrap_untrusted("synopsis", description));
+            }
+            Ok(ToolOutput::result(media))
+        })
+    }
+}
+
+struct FetchImageTool;
+
+impl Tool for FetchImageTool {
+    fn name(&self) -> &str {
+        "fetch_and_prepare_image"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Downloads an image from a URL, encodes it, and prepares it for the AI to process. Checks a cache first.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "url": {
+                        "type": "string",
+                        "description": "The full URL of the image file (e.g., ending in .jpg, .png, .webp)."
+                    }
+                },
+                "required": ["url"]
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let url = string_arg(args, self.name(), "url")?;
+            if context.image_budget.images == 0 {
+                tracing::warn!(%url, limit = context.options.max_images_per_turn, "Image limit for this turn reached, skipping fetch");
+                bail!(
+                    "Too many images requested at once; at most {} can be viewed per turn.",
+                    context.options.max_images_per_turn
+                );
+            }
+            let image = ai_handler::fetch_and_prepare_image(&context.options.proxy, url, context.image_cache).await?;
+            if image.data.len() > context.image_budget.bytes {
+                tracing::warn!(%url, size = image.data.len(), remaining = context.image_budget.bytes, "Image data budget for this turn reached");
+                bail!("No room left for this image alongside the others fetched this turn; fetch it again on its own.");
+            }
+            ai_handler::screen_image(context.llm, &image.mime_type, &image.data, context.options).await?;
+            tracing::info!("Image fetched and prepared for injection.");
+            let mut response = json!({
+                "result": "Image fetched successfully. Please refer to the provided image data."
+            });
+            if let Some(note) = image.animation_note() {
+                response["animated"] = json!(note);
+            }
+            Ok(ToolOutput { response, images: vec![(image.mime_type, image.data)] })
+        })
+    }
+}
+
+struct ReadWebpageTool;
+
+impl Tool for ReadWebpageTool {
+    fn name(&self) -> &str {
+        "read_webpage_content"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Fetches a webpage URL, extracts the main article text (like reader mode), and returns it. Also reads PDFs and plain text or markdown documents.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "url": {
+                        "type": "string",
+                        "description": "The full URL of the webpage or document to read."
+                    }
+                },
+                "required": ["url"]
+            }
+        })
+    }
+
+    fn result_limit(&self) -> usize {
+        WEBPAGE_TOOL_RESULT_LIMIT
+    }
+
+    fn min_timeout(&self) -> Duration {
+        WEBPAGE_TOOL_MIN_TIMEOUT
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let url = string_arg(args, self.name(), "url")?;
+            let page = ai_handler::read_webpage_content(url, context.options).await?;
+            // Return the extracted text, marked untrusted, and how it was found
+            Ok(ToolOutput {
+                response: json!({ "result": wrap_untrusted("webpage", &page.text), "extractor": page.extractor }),
+                images: Vec::new(),
+            })
+        })
+    }
+}
+
+struct LookupWikiTool;
+
+impl Tool for LookupWikiTool {
+    fn name(&self) -> &str {
+        "lookup_wiki"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Looks a term up on Wikipedia (the article's introduction) or Wiktionary (the word's definitions) and returns the text and a link. Use it for factual background and word meanings instead of reading a search page with read_webpage_content. If the exact title isn't found, the wiki is searched and the best match is returned.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "term": {
+                        "type": "string",
+                        "description": "The article title or word to look up, e.g. 'Alan Turing' or 'serendipity'."
+                    },
+                    "wiki": {
+                        "type": "string",
+                        "enum": ["wikipedia", "wiktionary"],
+                        "description": "Which wiki to look in (default wikipedia)."
+                    },
+                    "language": {
+                        "type": "string",
+                        "description": "Language code of the wiki edition, like 'en', 'de' or 'ja' (default en)."
+                    }
+                },
+                "required": ["term"]
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let term = string_arg(args, self.name(), "term")?;
+            let wiki = match args["wiki"].as_str() {
+                Some(name) => Wiki::parse(name).ok_or_else(|| anyhow!("Unknown wiki '{}'; use wikipedia or wiktionary", name))?,
+                None => Wiki::Wikipedia,
+            };
+            let language = args["language"].as_str().unwrap_or(wiki::DEFAULT_LANGUAGE);
+            let Some(entry) = wiki::lookup(&context.options.proxy, wiki, language, term).await? else {
+                return Ok(ToolOutput::result(format!("Nothing found for '{}'.", term)));
+            };
+            tracing::info!(%term, title = %entry.title, chars = entry.extract.len(), "Looked up wiki entry");
+            // Anyone can edit a wiki, so the text is untrusted
+            Ok(ToolOutput::result(json!({
+                "title": entry.title,
+                "url": entry.url,
+                "extract": wrap_untrusted("wiki", &entry.extract),
+                "disambiguation": entry.disambiguation,
+                "truncated": entry.truncated,
+            })))
+        })
+    }
+}
+
+struct YoutubeTranscriptTool;
+
+impl Tool for YoutubeTranscriptTool {
+    fn name(&self) -> &str {
+        "get_youtube_transcript"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Fetches the title and transcript (captions) of a YouTube video, so its content can be summarized or discussed. Long transcripts are condensed.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "url": {
+                        "type": "string",
+                        "description": "The YouTube video URL (e.g., 'https://www.youtube.com/watch?v=...' or 'https://youtu.be/...')."
+                    }
+                },
+                "required": ["url"]
+            }
+        })
+    }
+
+    fn result_limit(&self) -> usize {
+        TRANSCRIPT_TOOL_RESULT_LIMIT
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let url = string_arg(args, self.name(), "url")?;
+            let transcript = youtube::fetch_transcript(&context.options.proxy, url).await?;
+            tracing::info!(%url, chars = transcript.text.len(), truncated = transcript.truncated, "Fetched YouTube transcript");
+            // Transcripts are spoken by whoever made the video, so they are untrusted too
+            Ok(ToolOutput::result(wrap_untrusted("youtube transcript", &transcript.describe())))
+        })
+    }
+}
+
+struct TranscribeAudioTool;
+
+impl Tool for TranscribeAudioTool {
+    fn name(&self) -> &str {
+        "transcribe_audio"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Downloads an audio file, such as a linked voice message (ogg, mp3, wav, flac), and returns a transcript of what is said. Use it when someone asks what was said in a clip or wants it summarized.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "url": {
+                        "type": "string",
+                        "description": "The direct URL of the audio file."
+                    }
+                },
+                "required": ["url"]
+            }
+        })
+    }
+
+    fn result_limit(&self) -> usize {
+        TRANSCRIPT_TOOL_RESULT_LIMIT
+    }
+
+    fn min_timeout(&self) -> Duration {
+        TRANSCRIPTION_MIN_TIMEOUT
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let url = string_arg(args, self.name(), "url")?;
+            let transcript = ai_handler::transcribe_audio(context.llm, &context.options.proxy, url).await?;
+            // Whoever recorded the clip wrote this, not the user
+            Ok(ToolOutput::result(wrap_untrusted("audio transcript", &transcript)))
+        })
+    }
+}
+
+struct KarmaTool;
+
+impl Tool for KarmaTool {
+    fn name(&self) -> &str {
+        "get_karma"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Looks up karma in the current channel, which people give with 'nick++' and take away with 'nick--'. Shows who the channel appreciates. Without a nick, lists the highest and lowest scores.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "nick": {
+                        "type": "string",
+                        "description": "The nick to look up. Omit to get the channel's rankings."
+                    }
+                }
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let db = context.options.db.as_ref().context("Karma is not available right now")?;
+            let channel = context.channel.to_string();
+            let response = match args["nick"].as_str().filter(|nick| !nick.is_empty()) {
+                Some(nick) => {
+                    let nick = nick.to_string();
+                    let karma = db.run(move |conn| db::get_karma(conn, &channel, &nick)).await?;
+                    json!({ "nick": args["nick"], "karma": karma })
+                }
+                None => {
+                    let (highest, lowest) = db
+                        .run(move |conn| {
+                            Ok((
+                                db::get_karma_ranking(conn, &channel, karma::TOP_KARMA_COUNT, true)?,
+                                db::get_karma_ranking(conn, &channel, karma::TOP_KARMA_COUNT, false)?,
+                            ))
+                        })
+                        .await?;
+                    let ranking = |scores: Vec<(String, i64)>| -> Vec<Value> {
+                        scores.into_iter().map(|(nick, karma)| json!({ "nick": nick, "karma": karma })).collect()
+                    };
+                    json!({ "highest": ranking(highest), "lowest": ranking(lowest) })
+                }
+            };
+            Ok(ToolOutput::result(response))
+        })
+    }
+}
+
+struct QuoteTool;
+
+impl Tool for QuoteTool {
+    fn name(&self) -> &str {
+        "lookup_quote"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Looks up the current channel's quote database, where people save memorable lines with '!quote add'. Good for bringing up a channel classic at the right moment. Without a search text or id, returns a random quote.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "search": {
+                        "type": "string",
+                        "description": "Text the quotes must contain, such as a nick or a word."
+                    },
+                    "id": {
+                        "type": "integer",
+                        "description": "The number of a specific quote."
+                    }
+                }
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let db = context.options.db.as_ref().context("Quotes are not available right now")?;
+            let channel = context.channel.to_string();
+            let search = args["search"].as_str().filter(|search| !search.is_empty()).map(str::to_string);
+            let id = args["id"].as_i64();
+            let found = db
+                .run(move |conn| match (id, search) {
+                    (Some(id), _) => Ok(db::get_quote(conn, &channel, id)?.into_iter().collect()),
+                    (None, Some(search)) => db::search_quotes(conn, &channel, &search, quotes::SEARCH_RESULT_COUNT),
+                    (None, None) => Ok(db::get_random_quote(conn, &channel)?.into_iter().collect()),
+                })
+                .await?;
+            if found.is_empty() {
+                return Ok(ToolOutput::result("No matching quotes."));
+            }
+            let found: Vec<Value> = found
+                .iter()
+                .map(|quote| json!({ "id": quote.id, "quote": quote.text, "added_by": quote.added_by }))
+                .collect();
+            Ok(ToolOutput::result(found))
+        })
+    }
+}
+
+struct ChannelStatsTool;
+
+impl Tool for ChannelStatsTool {
+    fn name(&self) -> &str {
+        "get_channel_stats"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Gets the current channel's activity over the last 30 days: message count, messages per day, who talks the most and the busiest hours (UTC). Use it for questions like 'who talks the most here?' or 'when is this channel busiest?'.",
+            "parameters": {
+                "type": "object",
+                "properties": {}
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, _args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let db = context.options.db.as_ref().context("Channel statistics are not available right now")?;
+            let stats = stats::channel_stats(db, context.channel, &context.options.nickname, chrono::Utc::now()).await?;
+            Ok(ToolOutput::result(stats.to_json()))
+        })
+    }
+}
+
+struct ListChannelUsersTool;
+
+impl Tool for ListChannelUsersTool {
+    fn name(&self) -> &str {
+        "list_channel_users"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Lists who is in the current channel right now, as the server reports it. Use it for questions like 'who's here?', 'is alice around?' or 'who are the ops?' instead of guessing from the conversation; many people idle without talking. Nicks are prefixed with their highest status: ~ owner, & admin, @ operator, % half-operator, + voice.",
+            "parameters": {
+                "type": "object",
+                "properties": {}
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, _args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let members = context
+                .options
+                .roster
+                .as_ref()
+                .and_then(|roster| roster.members(context.channel))
+                .context("The user list is not available in this channel")?;
+            let users: Vec<String> = members.iter().map(Member::display).collect();
+            Ok(ToolOutput::result(json!({ "channel": context.channel, "count": users.len(), "users": users })))
+        })
+    }
+}
+
+/// The database and network facts about users are kept in, if there are both.
+fn user_facts_store<'a>(context: &'a ToolContext<'a>) -> Result<(&'a db::DbPool, String)> {
+    let db = context.options.db.as_ref().filter(|_| !context.options.network.is_empty());
+    let db = db.context("Facts about users are not available right now")?;
+    Ok((db, context.options.network.clone()))
+}
+
+struct RememberFactTool;
+
+impl Tool for RememberFactTool {
+    fn name(&self) -> &str {
+        "remember_fact"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Remembers a lasting fact about the user you're answering for later conversations, like their interests, projects, preferences or time zone ('likes Rust', 'lives in UTC+9'). Only note what they said about themselves or clearly want remembered; never secrets, passwords or sensitive personal details. Facts about anyone else can't be noted. One short fact per call.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "fact": {
+                        "type": "string",
+                        "description": "The fact, briefly, without the nick, e.g. 'likes Rust'."
+                    }
+                },
+                "required": ["fact"]
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let (db, network) = user_facts_store(context)?;
+            // Only the requester's own word goes on record, so nobody can have the AI note things
+            // about someone else
+            let nick = context.options.requester.clone();
+            if nick.is_empty() {
+                bail!("Facts can only be noted about the user being answered");
+            }
+            let fact = user_facts::normalize(string_arg(args, self.name(), "fact")?)
+                .with_context(|| format!("Facts must be 1 to {} characters long", user_facts::MAX_FACT_LENGTH))?;
+            let (fact_nick, fact_text) = (nick.clone(), fact.clone());
+            let added = db
+                .run(move |conn| {
+                    db::add_user_fact(conn, &network, &fact_nick, &fact_text, user_facts::MAX_FACTS_PER_USER)
+                })
+                .await?;
+            tracing::info!(%nick, %fact, added, "Remembered a fact about a user");
+            Ok(ToolOutput::result(match added {
+                true => format!("Remembered that {} {}", nick, fact),
+                false => format!("Already knew that {} {}", nick, fact),
+            }))
+        })
+    }
+}
+
+struct RecallFactsTool;
+
+impl Tool for RecallFactsTool {
+    fn name(&self) -> &str {
+        "recall_facts"
+    }
+
+    fn declaration(&self) -> Value {
+        json!({
+            "name": self.name(),
+            "description": "Recalls the facts remembered about a user with remember_fact, like their interests or time zone. Use it to personalize an answer, or when asked what you know about someone.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "nick": {
+                        "type": "string",
+                        "description": "The nick of the user to recall facts about."
+                    }
+                },
+                "required": ["nick"]
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let (db, network) = user_facts_store(context)?;
+            let nick = string_arg(args, self.name(), "nick")?.trim_start_matches('@').to_string();
+            let query_nick = nick.clone();
+            let facts = db.run(move |conn| db::get_user_facts(conn, &network, &query_nick)).await?;
+            if facts.is_empty() {
+                return Ok(ToolOutput::result(format!("Nothing is remembered about {}.", nick)));
+            }
+            let facts: Vec<Value> = facts
+                .iter()
+                .map(|fact| json!({ "fact": fact.fact, "noted": fact.timestamp.format("%Y-%m-%d").to_string() }))
+                .collect();
+            // Users' own words, as the AI noted them
+            Ok(ToolOutput::result(json!({ "nick": nick, "facts": wrap_untrusted("user facts", &Value::from(facts).to_string()) })))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::EchoBackend;
+
+    #[test]
+    fn test_image_budget_remaining() {
+        assert_eq!(ImageBudget::remaining(4, &[]), ImageBudget { images: 4, bytes: MAX_IMAGE_BYTES_PER_TURN });
+        let attached = vec![("image/png".to_string(), "a".repeat(1000)), ("image/jpeg".to_string(), "b".repeat(500))];
+        assert_eq!(ImageBudget::remaining(4, &attached), ImageBudget { images: 2, bytes: MAX_IMAGE_BYTES_PER_TURN - 1500 });
+        assert_eq!(ImageBudget::remaining(1, &attached).images, 0);
+    }
+
+    #[test]
+    fn test_registry_declarations_and_replacement() {
+        let mut registry = ToolRegistry::builtin(None);
+        let declarations = registry.declarations();
+        let names: Vec<&str> = declarations[0]["functionDeclarations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| d["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "roll_dice",
+                "calculate",
+                "get_price",
+                "download_torrent",
+                "torrent_status",
+                "search_nyaa",
+                "lookup_anime",
+                "fetch_and_prepare_image",
+                "read_webpage_content",
+                "lookup_wiki",
+                "get_youtube_transcript",
+                "transcribe_audio",
+                "get_karma",
+                "lookup_quote",
+                "get_channel_stats",
+                "list_channel_users",
+                "remember_fact",
+                "recall_facts"
+            ]
+        );
+        assert_eq!(registry.get("read_webpage_content").unwrap().result_limit(), WEBPAGE_TOOL_RESULT_LIMIT);
+        assert!(registry.get("no_such_tool").is_none());
+
+        // Registering a tool with an existing name replaces it
+        registry.register(Arc::new(RollDiceTool));
+        assert_eq!(registry.declarations()[0]["functionDeclarations"].as_array().unwrap().len(), 18);
+    }
+
+    fn tool_context<'a>(image_cache: &'a ImageCache, options: &'a ChatbotOptions) -> ToolContext<'a> {
+        ToolContext { llm: &EchoBackend, channel: "#test", image_cache, options, image_budget: ImageBudget::none() }
+    }
+
+    #[tokio::test]
+    async fn test_user_facts() {
+        let image_cache = ImageCache::in_memory(1);
+        let mut options = ChatbotOptions { db: Some(db::init_memory_db().unwrap()), ..ChatbotOptions::default() };
+        let remember = |fact: &str| json!({ "fact": fact });
+        // Without a network there's nowhere to keep them
+        options.requester = "tester".to_string();
+        let context = tool_context(&image_cache, &options);
+        assert!(RememberFactTool.execute(&remember("likes Rust"), &context).await.is_err());
+
+        // Nor without someone to keep them about
+        options.network = "irc.example.org".to_string();
+        options.requester = String::new();
+        let context = tool_context(&image_cache, &options);
+        assert!(RememberFactTool.execute(&remember("likes Rust"), &context).await.is_err());
+
+        options.requester = "tester".to_string();
+        let context = tool_context(&image_cache, &options);
+        // Facts are always about the requester, whoever the model says they're about
+        let about_alice = json!({ "nick": "alice", "fact": "likes  Rust" });
+        let output = RememberFactTool.execute(&about_alice, &context).await.unwrap();
+        assert_eq!(output.response["result"], "Remembered that tester likes Rust");
+        let output = RememberFactTool.execute(&remember("likes rust"), &context).await.unwrap();
+        assert_eq!(output.response["result"], "Already knew that tester likes rust");
+        RememberFactTool.execute(&remember("lives in UTC+9"), &context).await.unwrap();
+        assert!(RememberFactTool.execute(&remember(" "), &context).await.is_err());
+
+        let output = RecallFactsTool.execute(&json!({ "nick": "TESTER" }), &context).await.unwrap();
+        let facts = output.response["result"]["facts"].as_str().unwrap();
+        assert!(facts.contains("likes Rust") && facts.contains("lives in UTC+9"));
+        let output = RecallFactsTool.execute(&json!({ "nick": "alice" }), &context).await.unwrap();
+        assert_eq!(output.response["result"], "Nothing is remembered about alice.");
+
+        // Facts are per network
+        let pool = options.db.clone().unwrap();
+        assert!(pool.run(|conn| db::get_user_facts(conn, "discord", "tester")).await.unwrap().is_empty());
+        assert_eq!(pool.run(|conn| db::delete_user_facts(conn, "IRC.example.org", "tester")).await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,390 @@
+//! Torrent client integration for the download_torrent tool.
+//!
+//! Magnet links are pushed to a Transmission or qBittorrent instance over its RPC/Web API,
+//! and the client's view of the download is reported back to the AI.
+
+use crate::config::{Config, TorrentClientKind};
+use crate::proxy::HttpProxy;
+use anyhow::{Context, Result, anyhow, bail};
+use futures::future::BoxFuture;
+use serde_json::{Value, json};
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const TRANSMISSION_SESSION_HEADER: &str = "X-Transmission-Session-Id";
+const TRANSMISSION_FIELDS: [&str; 6] = ["name", "hashString", "percentDone", "status", "error", "errorString"];
+const MAX_LISTED_TORRENTS: usize = 10; // Keeps status tool results short
+
+/// A torrent as reported by the client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentStatus {
+    pub name: String,
+    pub hash: String,
+    /// Download progress from 0.0 to 1.0.
+    pub progress: f64,
+    pub state: String,
+    pub error: Option<String>,
+}
+
+impl fmt::Display for TorrentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} ({:.1}% done)", self.name, self.state, self.progress * 100.0)?;
+        if let Some(error) = &self.error {
+            write!(f, ", error: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+pub trait TorrentClient: Send + Sync + fmt::Debug {
+    /// Short name for logs and tool results.
+    fn name(&self) -> &'static str;
+
+    /// Adds a magnet link and returns the torrent's initial status.
+    fn add_magnet<'a>(&'a self, magnet: &'a str) -> BoxFuture<'a, Result<TorrentStatus>>;
+
+    /// Lists the torrents known to the client.
+    fn list<'a>(&'a self) -> BoxFuture<'a, Result<Vec<TorrentStatus>>>;
+}
+
+/// Builds the torrent client selected in the configuration, if any, reaching it through `proxy`.
+pub fn client_from_config(config: &Config, proxy: &HttpProxy) -> Result<Option<Arc<dyn TorrentClient>>> {
+    let Some(kind) = config.torrent_client else {
+        return Ok(None);
+    };
+    let url = config
+        .torrent_rpc_url
+        .clone()
+        .ok_or_else(|| anyhow!("--torrent-rpc-url is required when --torrent-client is set"))?
+        .trim_end_matches('/')
+        .to_string();
+    let credentials = config
+        .torrent_rpc_username
+        .clone()
+        .map(|user| (user, config.torrent_rpc_password.clone().unwrap_or_default()));
+    let client: Arc<dyn TorrentClient> = match kind {
+        TorrentClientKind::Transmission => Arc::new(TransmissionClient {
+            client: proxy.client()?,
+            url,
+            credentials,
+            session_id: Mutex::new(None),
+        }),
+        TorrentClientKind::Qbittorrent => Arc::new(QbittorrentClient {
+            client: proxy.client()?,
+            url,
+            credentials,
+            cookie: Mutex::new(None),
+        }),
+    };
+    Ok(Some(client))
+}
+
+/// The info hash from a magnet link's `xt=urn:btih:` parameter, lowercased.
+fn magnet_info_hash(magnet: &str) -> Option<String> {
+    let query = magnet.strip_prefix("magnet:?")?;
+    query
+        .split('&')
+        .filter_map(|param| param.strip_prefix("xt=urn:btih:"))
+        .map(|hash| hash.to_ascii_lowercase())
+        .next()
+}
+
+/// Summarizes a client's torrent list for the AI.
+pub fn describe_torrents(torrents: &[TorrentStatus]) -> String {
+    if torrents.is_empty() {
+        return "No torrents found.".to_string();
+    }
+    let mut lines: Vec<String> = torrents
+        .iter()
+        .take(MAX_LISTED_TORRENTS)
+        .map(|t| t.to_string())
+        .collect();
+    if torrents.len() > MAX_LISTED_TORRENTS {
+        lines.push(format!("...and {} more.", torrents.len() - MAX_LISTED_TORRENTS));
+    }
+    lines.join("\n")
+}
+
+// --- Transmission ---
+
+#[derive(Debug)]
+struct TransmissionClient {
+    client: reqwest::Client,
+    /// Full RPC endpoint, e.g. http://localhost:9091/transmission/rpc
+    url: String,
+    credentials: Option<(String, String)>,
+    /// CSRF token; Transmission hands out a new one with a 409 response.
+    session_id: Mutex<Option<String>>,
+}
+
+impl TransmissionClient {
+    async fn rpc(&self, method: &str, arguments: Value) -> Result<Value> {
+        let body = json!({"method": method, "arguments": arguments});
+        // One retry, in case the first attempt only fetches a fresh session id
+        for _ in 0..2 {
+            let mut request = self.client.post(&self.url).json(&body);
+            if let Some((user, password)) = &self.credentials {
+                request = request.basic_auth(user, Some(password));
+            }
+            if let Some(session_id) = self.session_id.lock().await.as_ref() {
+                request = request.header(TRANSMISSION_SESSION_HEADER, session_id);
+            }
+            let response = request.send().await.context("Failed to reach Transmission")?;
+            if response.status() == reqwest::StatusCode::CONFLICT {
+                let session_id = response
+                    .headers()
+                    .get(TRANSMISSION_SESSION_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .ok_or_else(|| anyhow!("Transmission returned 409 without a session id"))?;
+                *self.session_id.lock().await = Some(session_id.to_string());
+                continue;
+            }
+            let response: Value = response
+                .error_for_status()
+                .context("Transmission RPC request failed")?
+                .json()
+                .await
+                .context("Failed to parse Transmission response")?;
+            if response["result"] != "success" {
+                bail!("Transmission error: {}", response["result"]);
+            }
+            return Ok(response["arguments"].clone());
+        }
+        bail!("Transmission kept rejecting the session id")
+    }
+}
+
+impl TorrentClient for TransmissionClient {
+    fn name(&self) -> &'static str {
+        "Transmission"
+    }
+
+    fn add_magnet<'a>(&'a self, magnet: &'a str) -> BoxFuture<'a, Result<TorrentStatus>> {
+        Box::pin(async move {
+            let added = self.rpc("torrent-add", json!({"filename": magnet})).await?;
+            let torrent = added
+                .get("torrent-added")
+                .or_else(|| added.get("torrent-duplicate"))
+                .ok_or_else(|| anyhow!("Transmission did not report the added torrent"))?;
+            let hash = torrent["hashString"].as_str().unwrap_or_default().to_string();
+
+            let found = self
+                .rpc("torrent-get", json!({"fields": TRANSMISSION_FIELDS, "ids": [hash]}))
+                .await?;
+            match found["torrents"].get(0) {
+                Some(status) => Ok(transmission_status(status)),
+                None => Ok(TorrentStatus {
+                    name: torrent["name"].as_str().unwrap_or("unknown").to_string(),
+                    hash,
+                    progress: 0.0,
+                    state: "added".to_string(),
+                    error: None,
+                }),
+            }
+        })
+    }
+
+    fn list<'a>(&'a self) -> BoxFuture<'a, Result<Vec<TorrentStatus>>> {
+        Box::pin(async move {
+            let found = self.rpc("torrent-get", json!({"fields": TRANSMISSION_FIELDS})).await?;
+            Ok(found["torrents"]
+                .as_array()
+                .map(|torrents| torrents.iter().map(transmission_status).collect())
+                .unwrap_or_default())
+        })
+    }
+}
+
+fn transmission_status(torrent: &Value) -> TorrentStatus {
+    let state = match torrent["status"].as_i64() {
+        Some(0) => "stopped",
+        Some(1) | Some(2) => "checking",
+        Some(3) => "queued",
+        Some(4) => "downloading",
+        Some(5) | Some(6) => "seeding",
+        _ => "unknown",
+    };
+    let error = torrent["errorString"]
+        .as_str()
+        .filter(|e| !e.is_empty() && torrent["error"].as_i64().unwrap_or(0) != 0)
+        .map(str::to_string);
+    TorrentStatus {
+        name: torrent["name"].as_str().unwrap_or("unknown").to_string(),
+        hash: torrent["hashString"].as_str().unwrap_or_default().to_string(),
+        progress: torrent["percentDone"].as_f64().unwrap_or(0.0),
+        state: state.to_string(),
+        error,
+    }
+}
+
+// --- qBittorrent ---
+
+#[derive(Debug)]
+struct QbittorrentClient {
+    client: reqwest::Client,
+    /// Web UI base URL, e.g. http://localhost:8080
+    url: String,
+    credentials: Option<(String, String)>,
+    /// Session cookie from /api/v2/auth/login.
+    cookie: Mutex<Option<String>>,
+}
+
+impl QbittorrentClient {
+    /// Returns the session cookie, logging in first if needed. Without credentials,
+    /// the Web UI must be configured to skip authentication (e.g. for localhost).
+    async fn session_cookie(&self) -> Result<Option<String>> {
+        let mut cookie = self.cookie.lock().await;
+        if cookie.is_none()
+            && let Some((user, password)) = &self.credentials
+        {
+            let response = self
+                .client
+                .post(format!("{}/api/v2/auth/login", self.url))
+                .header(reqwest::header::REFERER, &self.url)
+                .form(&[("username", user.as_str()), ("password", password.as_str())])
+                .send()
+                .await
+                .context("Failed to reach qBittorrent")?
+                .error_for_status()
+                .context("qBittorrent login failed")?;
+            let sid = response
+                .headers()
+                .get_all(reqwest::header::SET_COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .find_map(|v| v.split(';').next().filter(|c| c.starts_with("SID=")))
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("qBittorrent login was rejected"))?;
+            *cookie = Some(sid);
+        }
+        Ok(cookie.clone())
+    }
+
+    async fn get_torrents(&self, hashes: Option<&str>) -> Result<Vec<TorrentStatus>> {
+        let mut request = self.client.get(format!("{}/api/v2/torrents/info", self.url));
+        if let Some(hashes) = hashes {
+            request = request.query(&[("hashes", hashes)]);
+        }
+        if let Some(cookie) = self.session_cookie().await? {
+            request = request.header(reqwest::header::COOKIE, cookie);
+        }
+        let response = request.send().await.context("Failed to reach qBittorrent")?;
+        if response.status() == reqwest::StatusCode::FORBIDDEN {
+            *self.cookie.lock().await = None; // Session expired; log in again next time
+        }
+        let torrents: Value = response
+            .error_for_status()
+            .context("qBittorrent request failed")?
+            .json()
+            .await
+            .context("Failed to parse qBittorrent response")?;
+        Ok(torrents
+            .as_array()
+            .map(|torrents| torrents.iter().map(qbittorrent_status).collect())
+            .unwrap_or_default())
+    }
+}
+
+impl TorrentClient for QbittorrentClient {
+    fn name(&self) -> &'static str {
+        "qBittorrent"
+    }
+
+    fn add_magnet<'a>(&'a self, magnet: &'a str) -> BoxFuture<'a, Result<TorrentStatus>> {
+        Box::pin(async move {
+            let mut request = self
+                .client
+                .post(format!("{}/api/v2/torrents/add", self.url))
+                .form(&[("urls", magnet)]);
+            if let Some(cookie) = self.session_cookie().await? {
+                request = request.header(reqwest::header::COOKIE, cookie);
+            }
+            let response = request.send().await.context("Failed to reach qBittorrent")?;
+            if response.status() == reqwest::StatusCode::FORBIDDEN {
+                *self.cookie.lock().await = None;
+            }
+            let body = response
+                .error_for_status()
+                .context("qBittorrent request failed")?
+                .text()
+                .await?;
+            if body.trim() != "Ok." {
+                bail!("qBittorrent refused the torrent: {}", body.trim());
+            }
+
+            // qBittorrent doesn't return the new torrent, so look it up by info hash
+            let hash = magnet_info_hash(magnet);
+            let found = match &hash {
+                Some(hash) => self.get_torrents(Some(hash)).await?.into_iter().next(),
+                None => None,
+            };
+            Ok(found.unwrap_or_else(|| TorrentStatus {
+                name: "unknown (fetching metadata)".to_string(),
+                hash: hash.unwrap_or_default(),
+                progress: 0.0,
+                state: "added".to_string(),
+                error: None,
+            }))
+        })
+    }
+
+    fn list<'a>(&'a self) -> BoxFuture<'a, Result<Vec<TorrentStatus>>> {
+        Box::pin(self.get_torrents(None))
+    }
+}
+
+fn qbittorrent_status(torrent: &Value) -> TorrentStatus {
+    let raw_state = torrent["state"].as_str().unwrap_or("unknown");
+    let state = match raw_state {
+        "downloading" | "forcedDL" | "metaDL" | "forcedMetaDL" => "downloading",
+        "stalledDL" => "stalled",
+        "uploading" | "forcedUP" | "stalledUP" => "seeding",
+        "pausedDL" | "stoppedDL" => "stopped",
+        "pausedUP" | "stoppedUP" => "completed",
+        "queuedDL" | "queuedUP" => "queued",
+        "checkingDL" | "checkingUP" | "checkingResumeData" | "moving" | "allocating" => "checking",
+        "error" | "missingFiles" => "error",
+        other => other,
+    };
+    TorrentStatus {
+        name: torrent["name"].as_str().unwrap_or("unknown").to_string(),
+        hash: torrent["hash"].as_str().unwrap_or_default().to_string(),
+        progress: torrent["progress"].as_f64().unwrap_or(0.0),
+        state: state.to_string(),
+        error: (state == "error").then(|| raw_state.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_magnet_info_hash() {
+        let magnet = "magnet:?xt=urn:btih:ABCDEF0123456789ABCDEF0123456789ABCDEF01&dn=Some+Show&tr=udp%3A%2F%2Ftracker";
+        assert_eq!(
+            magnet_info_hash(magnet).as_deref(),
+            Some("abcdef0123456789abcdef0123456789abcdef01")
+        );
+        assert_eq!(magnet_info_hash("https://nyaa.si/view/1"), None);
+    }
+
+    #[test]
+    fn test_status_parsing() {
+        let transmission = json!({
+            "name": "Show - 01", "hashString": "abc", "percentDone": 0.5,
+            "status": 4, "error": 0, "errorString": ""
+        });
+        assert_eq!(
+            transmission_status(&transmission).to_string(),
+            "Show - 01: downloading (50.0% done)"
+        );
+
+        let qbittorrent = json!({"name": "Show - 02", "hash": "def", "progress": 1.0, "state": "missingFiles"});
+        assert_eq!(
+            qbittorrent_status(&qbittorrent).to_string(),
+            "Show - 02: error (100.0% done), error: missingFiles"
+        );
+    }
+}
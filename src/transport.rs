@@ -0,0 +1,650 @@
+//! Chat networks the bot can talk on.
+//!
+//! The message pipeline in bot.rs (logging, AI triggering, response filtering) only needs to send
+//! lines to a channel, so each network implements `ChatTransport`. Channels are identified by
+//! strings: IRC channel names as-is, Discord channels as `discord:<channel id>`, which keeps the
+//! database and per-channel settings network-agnostic.
+
+use crate::formatting::Formatting;
+use crate::outgoing::{OutgoingQueue, Priority};
+use irc::proto::message::Tag;
+use anyhow::{Context as _, Result, anyhow, bail};
+use futures::future::BoxFuture;
+use serenity::all::{ChannelId, Context, EventHandler, GatewayIntents, Message, Ready, UserId};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::sync::mpsc::UnboundedSender;
+
+const IRC_MAX_LINE_BYTES: usize = 512; // Including the sender's prefix and the closing CRLF
+// Stand-ins for the parts of our hostmask the server hasn't told us yet: the longest username
+// common servers allow (with ident's '~') and the longest hostname
+const IRC_MAX_USER_LENGTH: usize = 10;
+const IRC_MAX_HOST_LENGTH: usize = 63;
+/// How long to wait for the server to echo our lines back before going on without them. Lines can
+/// sit in the outgoing queue for a while under the flood limit.
+const IRC_ECHO_WAIT: Duration = Duration::from_secs(15);
+const DISCORD_MAX_MESSAGE_LENGTH: usize = 1900; // Discord's limit is 2000 characters
+const DISCORD_CHANNEL_PREFIX: &str = "discord:";
+
+pub trait ChatTransport: Send + Sync {
+    /// Short name for logs.
+    fn name(&self) -> &'static str;
+
+    /// Longest message (in bytes) to send to `channel` in one go; longer responses are split.
+    fn max_message_length(&self, channel: &str) -> usize;
+
+    /// How to render the AI's markdown in channels that haven't chosen with `!set`.
+    fn default_formatting(&self) -> Formatting {
+        Formatting::Plain
+    }
+
+    /// Sends a single message to a channel.
+    fn send_message<'a>(&'a self, channel: &'a str, text: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// Whether the network echoes our channel messages back, to be logged as it saw them. If so,
+    /// the bot doesn't log them itself when sending.
+    fn echoes_sent_messages(&self) -> bool {
+        false
+    }
+
+    /// Waits until the messages sent to `channel` have been echoed back and logged, so the next
+    /// AI request's history has them.
+    fn sent_messages_logged<'a>(&'a self, _channel: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    /// Sends a message to a channel as a reply to the message with the id `reply_to`, on networks
+    /// that show replies; elsewhere, just sends it.
+    fn send_reply<'a>(&'a self, channel: &'a str, text: &'a str, _reply_to: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.send_message(channel, text)
+    }
+
+    /// Sends the lines of one message in order: nothing else the bot sends to `channel` comes
+    /// between them. With `reply_to`, they're threaded under that message, as with `send_reply`.
+    fn send_lines<'a>(&'a self, channel: &'a str, lines: &'a [String], reply_to: Option<&'a str>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            for line in lines {
+                match reply_to {
+                    Some(reply_to) => self.send_reply(channel, line, reply_to).await?,
+                    None => self.send_message(channel, line).await?,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Shows us typing in `channel` for the next few seconds, on networks that show that.
+    fn send_typing<'a>(&'a self, _channel: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Whether `channel` is on this network, so messages for it go out through here.
+    fn serves(&self, _channel: &str) -> bool {
+        true
+    }
+
+    /// Removes `nick` from `channel`, on networks where the bot can. Whether it's allowed to is
+    /// up to the caller to check.
+    fn kick<'a>(&'a self, _channel: &'a str, _nick: &'a str, _reason: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { bail!("Can't kick users on {}", self.name()) })
+    }
+}
+
+/// Sends everything for one channel as replies to one message there, so an answer is threaded
+/// under the question. Messages to anywhere else go out as usual.
+pub struct ReplyTransport {
+    inner: Arc<dyn ChatTransport>,
+    channel: String,
+    reply_to: String,
+}
+
+impl ReplyTransport {
+    pub fn new(inner: Arc<dyn ChatTransport>, channel: &str, reply_to: &str) -> Self {
+        ReplyTransport { inner, channel: channel.to_string(), reply_to: reply_to.to_string() }
+    }
+}
+
+impl ChatTransport for ReplyTransport {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn max_message_length(&self, channel: &str) -> usize {
+        self.inner.max_message_length(channel)
+    }
+
+    fn default_formatting(&self) -> Formatting {
+        self.inner.default_formatting()
+    }
+
+    fn send_message<'a>(&'a self, channel: &'a str, text: &'a str) -> BoxFuture<'a, Result<()>> {
+        if channel == self.channel {
+            self.inner.send_reply(channel, text, &self.reply_to)
+        } else {
+            self.inner.send_message(channel, text)
+        }
+    }
+
+    fn echoes_sent_messages(&self) -> bool {
+        self.inner.echoes_sent_messages()
+    }
+
+    fn sent_messages_logged<'a>(&'a self, channel: &'a str) -> BoxFuture<'a, ()> {
+        self.inner.sent_messages_logged(channel)
+    }
+
+    fn send_reply<'a>(&'a self, channel: &'a str, text: &'a str, reply_to: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.inner.send_reply(channel, text, reply_to)
+    }
+
+    fn send_lines<'a>(&'a self, channel: &'a str, lines: &'a [String], reply_to: Option<&'a str>) -> BoxFuture<'a, Result<()>> {
+        let reply_to = reply_to.or_else(|| (channel == self.channel).then_some(self.reply_to.as_str()));
+        self.inner.send_lines(channel, lines, reply_to)
+    }
+
+    fn send_typing<'a>(&'a self, channel: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.inner.send_typing(channel)
+    }
+
+    fn serves(&self, channel: &str) -> bool {
+        self.inner.serves(channel)
+    }
+
+    fn kick<'a>(&'a self, channel: &'a str, nick: &'a str, reason: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.inner.kick(channel, nick, reason)
+    }
+}
+
+/// A complete message received from a network, ready for the shared pipeline.
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+    pub channel: String,
+    pub nick: String,
+    pub text: String,
+}
+
+// --- IRC ---
+
+/// Sends channel messages through the connection's outgoing queue, behind any replies to people.
+pub struct IrcTransport {
+    queue: OutgoingQueue,
+    /// Our `nick!user@host` as the server relays it to others, once we've seen it.
+    hostmask: RwLock<Option<String>>,
+    nickname: String,
+    /// Whether the server agreed to IRCv3 echo-message.
+    echo: AtomicBool,
+    /// Whether the server agreed to IRCv3 message-tags, which client tags like typing need.
+    message_tags: AtomicBool,
+    /// Whether the server keeps channel history we can ask for: IRCv3 CHATHISTORY, or a ZNC's
+    /// playback module.
+    chathistory: AtomicBool,
+    playback: AtomicBool,
+    /// Lines sent to each (lowercase) channel that haven't been echoed back yet.
+    unechoed: Mutex<HashMap<String, usize>>,
+    echoed: Notify,
+}
+
+impl IrcTransport {
+    pub fn new(queue: OutgoingQueue, nickname: &str) -> Self {
+        Self {
+            queue,
+            hostmask: RwLock::new(None),
+            nickname: nickname.to_string(),
+            echo: AtomicBool::new(false),
+            message_tags: AtomicBool::new(false),
+            chathistory: AtomicBool::new(false),
+            playback: AtomicBool::new(false),
+            unechoed: Mutex::new(HashMap::new()),
+            echoed: Notify::new(),
+        }
+    }
+
+    pub fn queue(&self) -> &OutgoingQueue {
+        &self.queue
+    }
+
+    /// Remembers our hostmask, as seen on our own JOINs and nick changes, so lines can be as
+    /// long as the server allows.
+    pub fn set_hostmask(&self, nick: &str, user: &str, host: &str) {
+        *self.hostmask.write().unwrap() = Some(format!("{}!{}@{}", nick, user, host));
+    }
+
+    /// Makes use of an IRCv3 capability the server has acknowledged.
+    pub fn enable_capability(&self, capability: &str) {
+        match capability {
+            "echo-message" => self.echo.store(true, Ordering::SeqCst),
+            "message-tags" => self.message_tags.store(true, Ordering::SeqCst),
+            "draft/chathistory" | "chathistory" => self.chathistory.store(true, Ordering::SeqCst),
+            "znc.in/playback" => self.playback.store(true, Ordering::SeqCst),
+            _ => {}
+        }
+    }
+
+    /// Sends `command` with client-only tags, like "+typing", if the server passes them on.
+    fn send_tagged(&self, command: irc::proto::Command, tags: Vec<Tag>) -> Result<()> {
+        let tags = Some(tags).filter(|tags| !tags.is_empty() && self.message_tags.load(Ordering::SeqCst));
+        self.queue.send(Priority::Normal, irc::proto::Message { tags, prefix: None, command })
+    }
+
+    /// Asks the server for what was said in `channel` after `since`, or lately if that's unknown,
+    /// up to `limit` lines, if it keeps history. The lines come back tagged with when they were
+    /// said. Returns whether there was anyone to ask.
+    pub fn request_history(&self, channel: &str, since: Option<chrono::DateTime<chrono::Utc>>, limit: usize) -> Result<bool> {
+        if self.chathistory.load(Ordering::SeqCst) {
+            let after = since.map_or("*".to_string(), |since| {
+                format!("timestamp={}", since.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+            });
+            let request = irc::proto::Command::Raw(
+                "CHATHISTORY".to_string(),
+                vec!["LATEST".to_string(), channel.to_string(), after, limit.to_string()],
+            );
+            self.queue.send(Priority::High, request).context("Failed to ask for IRC history")?;
+            Ok(true)
+        } else if self.playback.load(Ordering::SeqCst) {
+            // ZNC replays its whole buffer after the given time; it's only as long as ZNC keeps
+            let after = since.map_or(0, |since| since.timestamp());
+            self.queue.send_privmsg("*playback", format!("PLAY {} {}", channel, after)).context("Failed to ask ZNC for history")?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Counts off a line the server echoed back to `channel`.
+    pub fn echo_received(&self, channel: &str) {
+        if let Some(count) = self.unechoed.lock().unwrap().get_mut(&channel.to_lowercase()) {
+            *count = count.saturating_sub(1);
+        }
+        self.echoed.notify_waiters();
+    }
+
+    fn unechoed(&self, channel: &str) -> usize {
+        self.unechoed.lock().unwrap().get(&channel.to_lowercase()).copied().unwrap_or(0)
+    }
+
+    fn send_privmsg(&self, channel: &str, text: &str, tags: Vec<Tag>) -> Result<()> {
+        self.send_privmsgs(channel, &[text.to_string()], tags)
+    }
+
+    /// Queues lines for `channel` to go out back to back, each with `tags`.
+    fn send_privmsgs(&self, channel: &str, lines: &[String], tags: Vec<Tag>) -> Result<()> {
+        let tags = Some(tags).filter(|tags| !tags.is_empty() && self.message_tags.load(Ordering::SeqCst));
+        let messages = lines
+            .iter()
+            .map(|line| irc::proto::Message {
+                tags: tags.clone(),
+                prefix: None,
+                command: irc::proto::Command::PRIVMSG(channel.to_string(), line.clone()),
+            })
+            .collect();
+        self.queue.send_all(Priority::Normal, messages).context("Failed to send IRC message")?;
+        if self.echoes_sent_messages() && channel.starts_with('#') {
+            *self.unechoed.lock().unwrap().entry(channel.to_lowercase()).or_default() += lines.len();
+        }
+        Ok(())
+    }
+}
+
+/// How many bytes of text fit in a PRIVMSG to `channel` once the server has added our
+/// hostmask: the line others receive is ":<hostmask> PRIVMSG <channel> :<text>\r\n".
+fn irc_text_budget(hostmask_length: usize, channel: &str) -> usize {
+    let overhead = ":".len() + hostmask_length + " PRIVMSG ".len() + channel.len() + " :".len() + "\r\n".len();
+    IRC_MAX_LINE_BYTES.saturating_sub(overhead)
+}
+
+impl ChatTransport for IrcTransport {
+    fn name(&self) -> &'static str {
+        "irc"
+    }
+
+    fn max_message_length(&self, channel: &str) -> usize {
+        let hostmask_length = match &*self.hostmask.read().unwrap() {
+            Some(hostmask) => hostmask.len(),
+            None => self.nickname.len() + "!".len() + IRC_MAX_USER_LENGTH + "@".len() + IRC_MAX_HOST_LENGTH,
+        };
+        irc_text_budget(hostmask_length, channel)
+    }
+
+    fn send_message<'a>(&'a self, channel: &'a str, text: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { self.send_privmsg(channel, text, Vec::new()) })
+    }
+
+    fn send_reply<'a>(&'a self, channel: &'a str, text: &'a str, reply_to: &'a str) -> BoxFuture<'a, Result<()>> {
+        let reply = Tag("+draft/reply".to_string(), Some(reply_to.to_string()));
+        Box::pin(async move { self.send_privmsg(channel, text, vec![reply]) })
+    }
+
+    fn send_lines<'a>(&'a self, channel: &'a str, lines: &'a [String], reply_to: Option<&'a str>) -> BoxFuture<'a, Result<()>> {
+        let tags = reply_to.map(|reply_to| Tag("+draft/reply".to_string(), Some(reply_to.to_string()))).into_iter().collect();
+        Box::pin(async move { self.send_privmsgs(channel, lines, tags) })
+    }
+
+    fn send_typing<'a>(&'a self, channel: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            // Without message-tags, the server wouldn't pass the tag on
+            if !self.message_tags.load(Ordering::SeqCst) || !channel.starts_with('#') {
+                return Ok(());
+            }
+            let tagmsg = irc::proto::Command::Raw("TAGMSG".to_string(), vec![channel.to_string()]);
+            self.send_tagged(tagmsg, vec![Tag("+typing".to_string(), Some("active".to_string()))])
+                .context("Failed to send IRC typing notice")
+        })
+    }
+
+    fn serves(&self, channel: &str) -> bool {
+        !is_discord_channel(channel)
+    }
+
+    fn kick<'a>(&'a self, channel: &'a str, nick: &'a str, reason: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let kick = irc::proto::Command::KICK(channel.to_string(), nick.to_string(), Some(reason.to_string()));
+            self.queue.send(Priority::High, kick).context("Failed to send IRC kick")
+        })
+    }
+
+    fn echoes_sent_messages(&self) -> bool {
+        self.echo.load(Ordering::SeqCst)
+    }
+
+    fn sent_messages_logged<'a>(&'a self, channel: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let deadline = tokio::time::Instant::now() + IRC_ECHO_WAIT;
+            loop {
+                // Registered before checking, so an echo arriving in between isn't missed
+                let echoed = self.echoed.notified();
+                if self.unechoed(channel) == 0 {
+                    return;
+                }
+                if tokio::time::timeout_at(deadline, echoed).await.is_err() {
+                    // Lines the server dropped, e.g. in a moderated channel, never come back
+                    tracing::warn!(%channel, "The server didn't echo all our lines back, going on without them");
+                    self.unechoed.lock().unwrap().remove(&channel.to_lowercase());
+                    return;
+                }
+            }
+        })
+    }
+}
+
+// --- Discord ---
+
+pub struct DiscordTransport {
+    http: Arc<serenity::http::Http>,
+    /// Held while the lines of one message go out, so those of another can't come between them.
+    sending: tokio::sync::Mutex<()>,
+}
+
+impl ChatTransport for DiscordTransport {
+    fn name(&self) -> &'static str {
+        "discord"
+    }
+
+    fn max_message_length(&self, _channel: &str) -> usize {
+        DISCORD_MAX_MESSAGE_LENGTH
+    }
+
+    fn default_formatting(&self) -> Formatting {
+        Formatting::Markdown
+    }
+
+    fn serves(&self, channel: &str) -> bool {
+        is_discord_channel(channel)
+    }
+
+    fn send_message<'a>(&'a self, channel: &'a str, text: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let _sending = self.sending.lock().await;
+            self.say(channel, text).await
+        })
+    }
+
+    fn send_lines<'a>(&'a self, channel: &'a str, lines: &'a [String], _reply_to: Option<&'a str>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let _sending = self.sending.lock().await;
+            for line in lines {
+                self.say(channel, line).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl DiscordTransport {
+    async fn say(&self, channel: &str, text: &str) -> Result<()> {
+        let channel_id = parse_discord_channel(channel)
+            .ok_or_else(|| anyhow!("Not a Discord channel: {}", channel))?;
+        channel_id
+            .say(&*self.http, text)
+            .await
+            .context("Failed to send Discord message")?;
+        Ok(())
+    }
+}
+
+/// The channel name used for a Discord channel in logs and the database.
+pub fn discord_channel_name(channel_id: ChannelId) -> String {
+    format!("{}{}", DISCORD_CHANNEL_PREFIX, channel_id)
+}
+
+/// Whether `channel` is a Discord channel's name rather than an IRC channel or nick.
+pub fn is_discord_channel(channel: &str) -> bool {
+    channel.starts_with(DISCORD_CHANNEL_PREFIX)
+}
+
+fn parse_discord_channel(channel: &str) -> Option<ChannelId> {
+    channel
+        .strip_prefix(DISCORD_CHANNEL_PREFIX)?
+        .parse::<u64>()
+        .ok()
+        .filter(|&id| id != 0)
+        .map(ChannelId::new)
+}
+
+/// Replaces `<@id>` mentions of the bot with its nickname, so the usual
+/// "is the bot being addressed" checks work unchanged.
+fn replace_bot_mentions(content: &str, bot_id: UserId, nickname: &str) -> String {
+    content
+        .replace(&format!("<@{}>", bot_id), nickname)
+        .replace(&format!("<@!{}>", bot_id), nickname)
+}
+
+struct DiscordHandler {
+    nickname: String,
+    bot_id: OnceLock<UserId>,
+    incoming: UnboundedSender<IncomingMessage>,
+}
+
+#[serenity::async_trait]
+impl EventHandler for DiscordHandler {
+    async fn ready(&self, _ctx: Context, ready: Ready) {
+        tracing::info!(user = %ready.user.name, guilds = ready.guilds.len(), "Connected to Discord");
+        let _ = self.bot_id.set(ready.user.id);
+    }
+
+    async fn message(&self, _ctx: Context, msg: Message) {
+        // Skips our own messages as well as other bots
+        if msg.author.bot || msg.content.trim().is_empty() {
+            return;
+        }
+        if msg.guild_id.is_none() {
+            tracing::debug!(from = %msg.author.name, "Ignoring Discord direct message");
+            return;
+        }
+
+        let text = match self.bot_id.get() {
+            Some(&bot_id) => replace_bot_mentions(&msg.content, bot_id, &self.nickname),
+            None => msg.content.clone(),
+        };
+        let incoming = IncomingMessage {
+            channel: discord_channel_name(msg.channel_id),
+            nick: msg.author.name.clone(),
+            text,
+        };
+        if self.incoming.send(incoming).is_err() {
+            tracing::error!("Discord message receiver is gone, dropping message");
+        }
+    }
+}
+
+/// Logs in to Discord. Incoming guild messages are forwarded to `incoming`; the returned
+/// client must be started (`client.start()`) to begin receiving events.
+pub async fn connect_discord(
+    token: &str,
+    nickname: &str,
+    incoming: UnboundedSender<IncomingMessage>,
+) -> Result<(Arc<DiscordTransport>, serenity::Client)> {
+    let intents = GatewayIntents::GUILDS
+        | GatewayIntents::GUILD_MESSAGES
+        | GatewayIntents::MESSAGE_CONTENT;
+    let handler = DiscordHandler {
+        nickname: nickname.to_string(),
+        bot_id: OnceLock::new(),
+        incoming,
+    };
+    let client = serenity::Client::builder(token, intents)
+        .event_handler(handler)
+        .await
+        .context("Failed to create Discord client")?;
+    let transport = Arc::new(DiscordTransport {
+        http: client.http.clone(),
+        sending: tokio::sync::Mutex::new(()),
+    });
+    Ok((transport, client))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outgoing::TokenBucket;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_discord_channel_name_round_trip() {
+        let id = ChannelId::new(123456789012345678);
+        let name = discord_channel_name(id);
+        assert_eq!(name, "discord:123456789012345678");
+        assert_eq!(parse_discord_channel(&name), Some(id));
+        assert_eq!(parse_discord_channel("#rust"), None);
+        assert_eq!(parse_discord_channel("discord:0"), None);
+    }
+
+    #[tokio::test]
+    async fn test_irc_text_budget() {
+        let (queue, _) = OutgoingQueue::start(|_| Ok(()), TokenBucket::new(1, Duration::ZERO, Instant::now()));
+        let irc = IrcTransport::new(queue, "Emul");
+        // Until we know our hostmask, assume the longest one
+        let longest_hostmask = ":Emul!".len() + IRC_MAX_USER_LENGTH + "@".len() + IRC_MAX_HOST_LENGTH;
+        assert_eq!(irc.max_message_length("#rust"), 512 - longest_hostmask - " PRIVMSG #rust :\r\n".len());
+        irc.set_hostmask("Emul", "~emul", "example.org");
+        let line = format!(":Emul!~emul@example.org PRIVMSG #rust :{}\r\n", "x".repeat(irc.max_message_length("#rust")));
+        assert_eq!(line.len(), 512);
+        assert!(irc.max_message_length("#a-much-longer-channel-name") < irc.max_message_length("#rust"));
+    }
+
+    #[tokio::test]
+    async fn test_waiting_for_echoes() {
+        let (queue, _) = OutgoingQueue::start(|_| Ok(()), TokenBucket::new(10, Duration::ZERO, Instant::now()));
+        let irc = Arc::new(IrcTransport::new(queue, "Emul"));
+        irc.send_message("#rust", "not counted").await.unwrap();
+        irc.sent_messages_logged("#rust").await;
+
+        irc.enable_capability("echo-message");
+        irc.send_message("#Rust", "one").await.unwrap();
+        irc.send_message("#rust", "two").await.unwrap();
+        irc.send_message("alice", "private messages aren't logged").await.unwrap();
+        let waiting = tokio::spawn({
+            let irc = irc.clone();
+            async move { irc.sent_messages_logged("#RUST").await }
+        });
+        irc.echo_received("#rust");
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        irc.echo_received("#rust");
+        tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reply_and_typing_tags() {
+        use irc::proto::{Command, Message};
+        let (sent_tx, mut sent_rx) = tokio::sync::mpsc::unbounded_channel();
+        let send = move |message: Message| Ok(sent_tx.send(message)?);
+        let (queue, task) = OutgoingQueue::start(send, TokenBucket::new(10, Duration::ZERO, Instant::now()));
+        let irc = Arc::new(IrcTransport::new(queue, "Emul"));
+        let replying = ReplyTransport::new(irc.clone(), "#rust", "abc123");
+
+        // Without message-tags, there's nothing to tag with
+        replying.send_message("#rust", "plain").await.unwrap();
+        replying.send_typing("#rust").await.unwrap();
+        irc.enable_capability("message-tags");
+        replying.send_typing("#rust").await.unwrap();
+        replying.send_message("#rust", "threaded").await.unwrap();
+        replying.send_message("alice", "elsewhere").await.unwrap();
+        replying.send_lines("#rust", &["split 1…".to_string(), "split 2".to_string()], None).await.unwrap();
+        drop((replying, irc));
+        task.await.unwrap();
+
+        let tag = |name: &str, value: &str| Some(vec![Tag(name.to_string(), Some(value.to_string()))]);
+        let privmsg = |target: &str, text: &str| Command::PRIVMSG(target.to_string(), text.to_string());
+        let mut sent = Vec::new();
+        while let Ok(message) = sent_rx.try_recv() {
+            sent.push((message.tags, message.command));
+        }
+        assert_eq!(
+            sent,
+            [
+                (None, privmsg("#rust", "plain")),
+                (tag("+typing", "active"), Command::Raw("TAGMSG".to_string(), vec!["#rust".to_string()])),
+                (tag("+draft/reply", "abc123"), privmsg("#rust", "threaded")),
+                (None, privmsg("alice", "elsewhere")),
+                (tag("+draft/reply", "abc123"), privmsg("#rust", "split 1…")),
+                (tag("+draft/reply", "abc123"), privmsg("#rust", "split 2")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_history_requests() {
+        use chrono::TimeZone;
+        use irc::proto::Command;
+        let (sent_tx, mut sent_rx) = tokio::sync::mpsc::unbounded_channel();
+        let send = move |message: irc::proto::Message| Ok(sent_tx.send(message.command)?);
+        let (queue, task) = OutgoingQueue::start(send, TokenBucket::new(10, Duration::ZERO, Instant::now()));
+        let since = chrono::Utc.with_ymd_and_hms(2025, 1, 31, 12, 30, 0).unwrap();
+
+        let irc = IrcTransport::new(queue.clone(), "Emul");
+        assert!(!irc.request_history("#rust", Some(since), 100).unwrap());
+        irc.enable_capability("znc.in/playback");
+        assert!(irc.request_history("#rust", Some(since), 100).unwrap());
+        irc.enable_capability("draft/chathistory");
+        assert!(irc.request_history("#rust", Some(since), 100).unwrap());
+        assert!(irc.request_history("#new", None, 50).unwrap());
+        drop((irc, queue));
+        task.await.unwrap();
+
+        let mut sent = Vec::new();
+        while let Ok(command) = sent_rx.try_recv() {
+            sent.push(command);
+        }
+        let chathistory = |args: &[&str]| Command::Raw("CHATHISTORY".to_string(), args.iter().map(|arg| arg.to_string()).collect());
+        assert_eq!(
+            sent,
+            [
+                Command::PRIVMSG("*playback".to_string(), "PLAY #rust 1738326600".to_string()),
+                chathistory(&["LATEST", "#rust", "timestamp=2025-01-31T12:30:00.000Z", "100"]),
+                chathistory(&["LATEST", "#new", "*", "50"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replace_bot_mentions() {
+        let bot_id = UserId::new(42);
+        assert_eq!(
+            replace_bot_mentions("<@42> hi, and <@!42> again <@7>", bot_id, "Emul"),
+            "Emul hi, and Emul again <@7>"
+        );
+    }
+}
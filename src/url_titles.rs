@@ -0,0 +1,113 @@
+//! Link titles, announced the way classic IRC bots do it: when someone posts a URL, the page's
+//! `<title>` (and description, if it has one) is fetched and said in the channel. This doesn't
+//! involve the AI at all, and is enabled per channel with `!urltitles`.
+//!
+//! Anyone can post a link, so it's fetched with [`public_fetch`], which only follows it to public
+//! addresses.
+
+use crate::proxy::HttpProxy;
+use crate::public_fetch;
+use crate::sanitize::flatten;
+use anyhow::{Context, Result};
+use reqwest::header::HeaderMap;
+use scraper::{Html, Selector};
+use std::time::Duration;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HEAD_BYTES: usize = 256 * 1024; // Titles are in <head>; no need to download the whole page
+const MAX_TITLE_CHARS: usize = 200;
+const MAX_DESCRIPTION_CHARS: usize = 200;
+/// Links beyond this many in one message are ignored, so a pasted list doesn't flood the channel.
+pub const MAX_TITLES_PER_MESSAGE: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageTitle {
+    pub title: String,
+    /// From `og:description`, or the plain `description` meta tag.
+    pub description: Option<String>,
+}
+
+impl PageTitle {
+    /// The line to announce, e.g. "[ Example Domain ] This domain is for use in examples."
+    pub fn announcement(&self) -> String {
+        match &self.description {
+            Some(description) => format!("[ {} ] {}", self.title, description),
+            None => format!("[ {} ]", self.title),
+        }
+    }
+}
+
+/// Fetches the title of an HTML page. Other content types, and pages without a title, give `None`.
+pub async fn fetch_title(proxy: &HttpProxy, url: &str) -> Result<Option<PageTitle>> {
+    let mut response = public_fetch::get(proxy, url, FETCH_TIMEOUT, HeaderMap::new())
+        .await?
+        .error_for_status()
+        .context("Link returned error status")?;
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|val| val.to_str().ok())
+        .map(|ct| ct.split(';').next().unwrap_or(ct).trim().to_lowercase())
+        .unwrap_or_default();
+    if content_type != "text/html" && content_type != "application/xhtml+xml" {
+        return Ok(None);
+    }
+
+    let mut body = Vec::new();
+    while body.len() < MAX_HEAD_BYTES
+        && let Some(chunk) = response.chunk().await.context("Failed to read page")?
+    {
+        body.extend_from_slice(&chunk);
+    }
+    Ok(parse_title(&String::from_utf8_lossy(&body)))
+}
+
+/// Extracts the title (falling back to `og:title`) and description from a page.
+fn parse_title(html: &str) -> Option<PageTitle> {
+    let document = Html::parse_document(html);
+    let meta = |name: &str| {
+        let selector = Selector::parse(&format!(r#"meta[property="{0}"], meta[name="{0}"]"#, name)).ok()?;
+        document
+            .select(&selector)
+            .filter_map(|element| element.value().attr("content"))
+            .map(|content| flatten(content, MAX_DESCRIPTION_CHARS))
+            .find(|content| !content.is_empty())
+    };
+
+    let title_selector = Selector::parse("title").ok()?;
+    let title = document
+        .select(&title_selector)
+        .map(|element| flatten(&element.text().collect::<String>(), MAX_TITLE_CHARS))
+        .find(|title| !title.is_empty())
+        .or_else(|| meta("og:title"))?;
+    // Many sites repeat the title as the description; that's just noise
+    let description = meta("og:description")
+        .or_else(|| meta("description"))
+        .filter(|description| *description != title);
+    Some(PageTitle { title, description })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_title() {
+        let html = r#"<html><head>
+            <title>
+                Emul &amp; friends
+            </title>
+            <meta property="og:description" content="A bunny who   lives on IRC.">
+            </head><body>Hi</body></html>"#;
+        let title = parse_title(html).unwrap();
+        assert_eq!(title.announcement(), "[ Emul & friends ] A bunny who lives on IRC.");
+
+        let og_only = r#"<meta property="og:title" content="Only OG"><meta name="description" content="Only OG">"#;
+        assert_eq!(
+            parse_title(og_only),
+            Some(PageTitle { title: "Only OG".to_string(), description: None })
+        );
+        assert_eq!(parse_title("<p>No title here</p>"), None);
+    }
+}
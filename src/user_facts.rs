@@ -0,0 +1,29 @@
+//! Facts about users that the AI notes with its remember_fact tool ("tester likes Rust, lives in
+//! UTC+9") and looks up with recall_facts. They're kept per network, since a nick on one IRC
+//! network needn't be the same person on another, and `!forgetme` deletes a user's facts.
+
+use crate::sanitize::strip_invisible;
+
+/// Longest fact that can be remembered, in characters.
+pub const MAX_FACT_LENGTH: usize = 200;
+/// Facts kept about one user; remembering more drops the oldest.
+pub const MAX_FACTS_PER_USER: usize = 50;
+
+/// Tidies a fact for saving: runs of whitespace become single spaces. None if nothing is left or
+/// it's too long.
+pub fn normalize(fact: &str) -> Option<String> {
+    let fact = strip_invisible(fact).split_whitespace().collect::<Vec<_>>().join(" ");
+    (!fact.is_empty() && fact.chars().count() <= MAX_FACT_LENGTH).then_some(fact)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("  likes   Rust,\nlives in UTC+9 ").as_deref(), Some("likes Rust, lives in UTC+9"));
+        assert_eq!(normalize(" \n "), None);
+        assert_eq!(normalize(&"x".repeat(MAX_FACT_LENGTH + 1)), None);
+    }
+}
@@ -0,0 +1,361 @@
+//! Tools loaded from sandboxed WebAssembly modules, so admins can add functions (a weather lookup,
+//! say) by dropping a `.wasm` file into `--wasm-tools-dir` instead of recompiling the bot.
+//!
+//! A plugin module exports:
+//! - `memory`: its linear memory.
+//! - `alloc(len: i32) -> i32`: allocates `len` bytes for the host to write into.
+//! - `declaration() -> i64`: the tool's function declaration as JSON
+//!   (`{"name": ..., "description": ..., "parameters": {...}}`).
+//! - `call(ptr: i32, len: i32) -> i64`: runs the tool on the JSON arguments at `ptr`, returning
+//!   `{"result": ...}` or `{"error": "..."}` as JSON.
+//!
+//! Strings returned to the host are packed into an i64 as `(ptr << 32) | len`. The only import
+//! offered is `emul.http_get(url_ptr: i32, url_len: i32) -> i64`, which returns the body of a GET
+//! request (packed the same way, in memory from `alloc`) or -1 on failure. Like links from chat,
+//! it only reaches public addresses, since plugins often fetch URLs built from the model's
+//! arguments. Plugins get no other access to the host, and every call runs in a fresh instance
+//! with fuel and memory limits.
+
+use crate::proxy::HttpProxy;
+use crate::public_fetch;
+use crate::tools::{Tool, ToolContext, ToolOutput};
+use anyhow::{Context as _, Result, anyhow, bail};
+use futures::future::BoxFuture;
+use reqwest::header::HeaderMap;
+use serde_json::Value;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use wasmtime::{AsContextMut, Caller, Engine, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+const WASM_FUEL_PER_CALL: u64 = 2_000_000_000; // Roughly a couple of seconds of work
+const WASM_MAX_MEMORY_BYTES: usize = 64 * 1024 * 1024; // Per plugin instance
+const WASM_MAX_IO_BYTES: usize = 1024 * 1024; // Max size of strings passed across the boundary
+const WASM_HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Per-call state of a plugin instance.
+struct PluginState {
+    limits: StoreLimits,
+    /// Runtime for http_get; unset while loading, when plugins can't make requests.
+    runtime: Option<Handle>,
+    proxy: HttpProxy,
+}
+
+/// A compiled plugin module.
+struct Plugin {
+    engine: Engine,
+    module: Module,
+    proxy: HttpProxy,
+}
+
+/// A tool implemented by a WebAssembly module.
+pub struct WasmTool {
+    name: String,
+    declaration: Value,
+    plugin: Arc<Plugin>,
+}
+
+/// Loads every `.wasm` (or `.wat`) module in `dir` as a tool. Modules that fail to load are
+/// logged and skipped, so one broken plugin doesn't take the others down. Their HTTP requests go
+/// through `proxy`.
+pub fn load_tools_dir(dir: &Path, proxy: &HttpProxy) -> Result<Vec<Arc<dyn Tool>>> {
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read WASM tools directory {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "wasm" || ext == "wat"))
+        .collect();
+    paths.sort();
+
+    let engine = new_engine()?;
+    let mut tools: Vec<Arc<dyn Tool>> = Vec::new();
+    for path in paths {
+        match Module::from_file(&engine, &path).and_then(|module| WasmTool::new(&engine, module, proxy)) {
+            Ok(tool) => {
+                tracing::info!(tool = %tool.name, path = %path.display(), "Loaded WASM tool");
+                tools.push(Arc::new(tool));
+            }
+            Err(e) => tracing::error!(path = %path.display(), error = %format!("{:#}", e), "Failed to load WASM tool"),
+        }
+    }
+    Ok(tools)
+}
+
+fn new_engine() -> Result<Engine> {
+    let mut config = wasmtime::Config::new();
+    config.consume_fuel(true);
+    Engine::new(&config)
+}
+
+impl WasmTool {
+    /// Wraps a compiled module, reading the tool's declaration from it.
+    fn new(engine: &Engine, module: Module, proxy: &HttpProxy) -> Result<Self> {
+        let plugin = Plugin {
+            engine: engine.clone(),
+            module,
+            proxy: proxy.clone(),
+        };
+        let (mut store, instance) = plugin.instantiate(None)?;
+        let declaration_fn: TypedFunc<(), i64> = instance.get_typed_func(&mut store, "declaration")?;
+        let packed = declaration_fn.call(&mut store, ())?;
+        let memory = exported_memory(&instance, &mut store)?;
+        let declaration: Value = serde_json::from_str(&read_string(&memory, &store, packed)?)
+            .context("Tool declaration is not valid JSON")?;
+        let name = declaration["name"]
+            .as_str()
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| anyhow!("Tool declaration has no name"))?
+            .to_string();
+        Ok(Self {
+            name,
+            declaration,
+            plugin: Arc::new(plugin),
+        })
+    }
+}
+
+impl Plugin {
+    /// Creates a fresh, resource-limited instance of the module.
+    fn instantiate(&self, runtime: Option<Handle>) -> Result<(Store<PluginState>, Instance)> {
+        let state = PluginState {
+            limits: StoreLimitsBuilder::new().memory_size(WASM_MAX_MEMORY_BYTES).instances(1).build(),
+            runtime,
+            proxy: self.proxy.clone(),
+        };
+        let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(WASM_FUEL_PER_CALL)?;
+
+        let mut linker = Linker::new(&self.engine);
+        linker.func_wrap("emul", "http_get", http_get)?;
+        let instance = linker.instantiate(&mut store, &self.module)?;
+        Ok((store, instance))
+    }
+
+    /// Runs the plugin's `call` export. Blocks on http_get requests, so this must not run on
+    /// an async worker thread.
+    fn call(&self, args: &str, runtime: Handle) -> Result<Value> {
+        let (mut store, instance) = self.instantiate(Some(runtime))?;
+        let memory = exported_memory(&instance, &mut store)?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let call = instance.get_typed_func::<(i32, i32), i64>(&mut store, "call")?;
+
+        let (ptr, len) = unpack(write_string(&memory, &alloc, &mut store, args)?);
+        let packed = call.call(&mut store, (ptr as i32, len as i32))?;
+        let output = read_string(&memory, &store, packed)?;
+        serde_json::from_str(&output).context("Tool output is not valid JSON")
+    }
+}
+
+impl Tool for WasmTool {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn declaration(&self) -> Value {
+        self.declaration.clone()
+    }
+
+    fn execute<'a>(&'a self, args: &'a Value, _context: &'a ToolContext<'a>) -> BoxFuture<'a, Result<ToolOutput>> {
+        Box::pin(async move {
+            let plugin = self.plugin.clone();
+            let args = args.to_string();
+            let runtime = Handle::current();
+            let response = tokio::task::spawn_blocking(move || plugin.call(&args, runtime))
+                .await
+                .context("WASM tool panicked")??;
+            if let Some(error) = response.get("error") {
+                bail!("{}", error.as_str().map(str::to_string).unwrap_or_else(|| error.to_string()));
+            }
+            if response.get("result").is_none() {
+                bail!("Tool returned neither a result nor an error");
+            }
+            Ok(ToolOutput { response, images: Vec::new() })
+        })
+    }
+}
+
+/// `emul.http_get`: fetches a URL on behalf of the plugin.
+fn http_get(mut caller: Caller<'_, PluginState>, url_ptr: i32, url_len: i32) -> Result<i64> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|e| e.into_memory())
+        .ok_or_else(|| anyhow!("Plugin has no exported memory"))?;
+    let alloc = caller
+        .get_export("alloc")
+        .and_then(|e| e.into_func())
+        .ok_or_else(|| anyhow!("Plugin has no alloc export"))?
+        .typed::<i32, i32>(&caller)?;
+    let url = read_string(&memory, &caller, pack(url_ptr as u32, url_len as u32))?;
+
+    let state = caller.data();
+    let Some(runtime) = &state.runtime else {
+        tracing::warn!(%url, "WASM tool tried an HTTP request while loading");
+        return Ok(-1);
+    };
+    match runtime.block_on(fetch_body(&state.proxy, &url)) {
+        Ok(body) => write_string(&memory, &alloc, &mut caller, &body),
+        Err(e) => {
+            tracing::warn!(%url, error = %e, "WASM tool HTTP request failed");
+            Ok(-1)
+        }
+    }
+}
+
+/// Fetches a plugin's URL from a public address, checking every redirect on the way.
+async fn fetch_body(proxy: &HttpProxy, url: &str) -> Result<String> {
+    let mut response = public_fetch::get(proxy, url, WASM_HTTP_TIMEOUT, HeaderMap::new()).await?.error_for_status()?;
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() > WASM_MAX_IO_BYTES {
+            body.truncate(WASM_MAX_IO_BYTES);
+            break;
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+fn exported_memory(instance: &Instance, store: impl AsContextMut) -> Result<Memory> {
+    instance
+        .get_memory(store, "memory")
+        .ok_or_else(|| anyhow!("Plugin has no exported memory"))
+}
+
+fn pack(ptr: u32, len: u32) -> i64 {
+    (((ptr as u64) << 32) | len as u64) as i64
+}
+
+fn unpack(packed: i64) -> (u32, u32) {
+    ((packed as u64 >> 32) as u32, packed as u32)
+}
+
+/// Reads a packed (ptr, len) UTF-8 string out of plugin memory.
+fn read_string(memory: &Memory, store: impl wasmtime::AsContext, packed: i64) -> Result<String> {
+    let (ptr, len) = unpack(packed);
+    let (ptr, len) = (ptr as usize, len as usize);
+    if len > WASM_MAX_IO_BYTES {
+        bail!("Plugin string of {} bytes exceeds the {} byte limit", len, WASM_MAX_IO_BYTES);
+    }
+    let bytes = memory
+        .data(&store)
+        .get(ptr..ptr + len)
+        .ok_or_else(|| anyhow!("Plugin string is out of bounds"))?;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Copies a string into memory allocated by the plugin, returning it packed.
+fn write_string<T>(memory: &Memory, alloc: &TypedFunc<i32, i32>, mut store: impl AsContextMut<Data = T>, text: &str) -> Result<i64> {
+    let len = u32::try_from(text.len()).context("String too large for plugin")?;
+    let ptr = alloc.call(&mut store, len as i32)? as u32;
+    memory
+        .write(&mut store, ptr as usize, text.as_bytes())
+        .context("Plugin allocation is out of bounds")?;
+    Ok(pack(ptr, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A plugin that always answers with a fixed result; `alloc` hands out memory after its data.
+    const FORECAST_PLUGIN: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (global $next (mut i32) (i32.const 1024))
+            (data (i32.const 0) "{\"name\":\"forecast\",\"description\":\"Weather forecast\",\"parameters\":{\"type\":\"object\",\"properties\":{}}}")
+            (data (i32.const 512) "{\"result\":\"Sunny\"}")
+            (func (export "alloc") (param $len i32) (result i32)
+                (local $ptr i32)
+                (local.set $ptr (global.get $next))
+                (global.set $next (i32.add (global.get $next) (local.get $len)))
+                (local.get $ptr))
+            (func (export "declaration") (result i64)
+                (i64.const 99))
+            (func (export "call") (param i32 i32) (result i64)
+                (i64.or (i64.shl (i64.const 512) (i64.const 32)) (i64.const 18))))
+    "#;
+
+    fn load(wat: &str) -> Result<WasmTool> {
+        load_with_proxy(wat, &HttpProxy::default())
+    }
+
+    fn load_with_proxy(wat: &str, proxy: &HttpProxy) -> Result<WasmTool> {
+        let engine = new_engine()?;
+        WasmTool::new(&engine, Module::new(&engine, wat)?, proxy)
+    }
+
+    /// A plugin that fetches `url` and answers with the body, or with an error if http_get failed.
+    fn fetching_plugin(url: &str) -> String {
+        let declaration = r#"{"name":"fetcher","description":"Fetches a page","parameters":{"type":"object","properties":{}}}"#;
+        let error = r#"{"error":"refused"}"#;
+        format!(
+            r#"
+            (module
+                (import "emul" "http_get" (func $http_get (param i32 i32) (result i64)))
+                (memory (export "memory") 1)
+                (global $next (mut i32) (i32.const 2048))
+                (data (i32.const 0) "{}")
+                (data (i32.const 512) "{}")
+                (data (i32.const 768) "{}")
+                (func (export "alloc") (param $len i32) (result i32)
+                    (local $ptr i32)
+                    (local.set $ptr (global.get $next))
+                    (global.set $next (i32.add (global.get $next) (local.get $len)))
+                    (local.get $ptr))
+                (func (export "declaration") (result i64)
+                    (i64.const {}))
+                (func (export "call") (param i32 i32) (result i64)
+                    (local $body i64)
+                    (local.set $body (call $http_get (i32.const 768) (i32.const {})))
+                    (if (result i64) (i64.eq (local.get $body) (i64.const -1))
+                        (then (i64.or (i64.shl (i64.const 512) (i64.const 32)) (i64.const {})))
+                        (else (local.get $body)))))
+            "#,
+            declaration.replace('"', "\\\""),
+            error.replace('"', "\\\""),
+            url,
+            declaration.len(),
+            url.len(),
+            error.len()
+        )
+    }
+
+    #[tokio::test]
+    async fn test_wasm_tool_declaration_and_call() {
+        let tool = load(FORECAST_PLUGIN).unwrap();
+        assert_eq!(tool.name(), "forecast");
+        assert_eq!(tool.declaration()["description"], "Weather forecast");
+        let output = tool.plugin.call("{\"city\":\"Oslo\"}", Handle::current()).unwrap();
+        assert_eq!(output["result"], "Sunny");
+    }
+
+    #[tokio::test]
+    async fn test_wasm_tool_runs_out_of_fuel() {
+        let looping = FORECAST_PLUGIN.replace(
+            "(func (export \"call\") (param i32 i32) (result i64)",
+            "(func (export \"call\") (param i32 i32) (result i64) (loop $forever (br $forever))",
+        );
+        let tool = load(&looping).unwrap();
+        assert!(tool.plugin.call("{}", Handle::current()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wasm_tool_http_get_refuses_local_addresses() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server.mock("GET", "/secret").with_body(r#"{"result":"fetched"}"#).expect(1).create_async().await;
+        let plugin = fetching_plugin(&format!("{}/secret", server.url()));
+        let call = async |tool: WasmTool| {
+            let runtime = Handle::current();
+            tokio::task::spawn_blocking(move || tool.plugin.call("{}", runtime)).await.unwrap().unwrap()
+        };
+
+        // The mock server is on this machine, so the plugin's request never reaches it
+        let output = call(load(&plugin).unwrap()).await;
+        assert_eq!(output["error"], "refused");
+        // It does once local addresses are allowed, so it was the address that was refused
+        let output = call(load_with_proxy(&plugin, &HttpProxy::default().allowing_loopback_links()).unwrap()).await;
+        assert_eq!(output["result"], "fetched");
+        mock.assert_async().await;
+    }
+}
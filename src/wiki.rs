@@ -0,0 +1,208 @@
+//! Looking terms up on Wikipedia and Wiktionary. The MediaWiki API hands back a plain-text
+//! extract of an article in one request, which is much cheaper than reading the page itself.
+//! The term is tried as an article title first, following redirects, and then as a search.
+
+use crate::proxy::HttpProxy;
+use anyhow::{Context, Result, bail};
+use serde_json::Value;
+use std::time::Duration;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+/// Wiktionary entries come whole rather than just their introduction, so they're cut off here.
+const MAX_EXTRACT_CHARS: usize = 3000;
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wiki {
+    Wikipedia,
+    Wiktionary,
+}
+
+impl Wiki {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "wikipedia" => Some(Wiki::Wikipedia),
+            "wiktionary" => Some(Wiki::Wiktionary),
+            _ => None,
+        }
+    }
+
+    fn domain(self) -> &'static str {
+        match self {
+            Wiki::Wikipedia => "wikipedia.org",
+            Wiki::Wiktionary => "wiktionary.org",
+        }
+    }
+
+    /// The API endpoint of this wiki's `language` edition, like en.wikipedia.org.
+    fn api_url(self, language: &str) -> Result<String> {
+        let valid = !language.is_empty()
+            && language.len() <= 12
+            && language.chars().all(|c| c.is_ascii_lowercase() || c == '-');
+        if !valid {
+            bail!("'{}' is not a language code like 'en' or 'de'", language);
+        }
+        Ok(format!("https://{}.{}/w/api.php", language, self.domain()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub title: String,
+    pub url: Option<String>,
+    pub extract: String,
+    /// Whether the article is a list of other articles the term could mean.
+    pub disambiguation: bool,
+    /// Whether `extract` was cut short at MAX_EXTRACT_CHARS.
+    pub truncated: bool,
+}
+
+/// Looks `term` up in the `language` edition of `wiki`. `None` means nothing matched.
+pub async fn lookup(proxy: &HttpProxy, wiki: Wiki, language: &str, term: &str) -> Result<Option<Entry>> {
+    lookup_at(proxy, &wiki.api_url(language)?, wiki, term).await
+}
+
+async fn lookup_at(proxy: &HttpProxy, api_url: &str, wiki: Wiki, term: &str) -> Result<Option<Entry>> {
+    let term = term.trim();
+    if term.is_empty() {
+        bail!("Nothing to look up");
+    }
+    let client = proxy.client()?;
+    if let Some(entry) = query(&client, api_url, wiki, &[("titles", term)]).await? {
+        return Ok(Some(entry));
+    }
+    // Wiktionary titles are case-sensitive and mostly lowercase, so "Apple" is found as "apple"
+    let lowercase = term.to_lowercase();
+    if wiki == Wiki::Wiktionary
+        && lowercase != term
+        && let Some(entry) = query(&client, api_url, wiki, &[("titles", &lowercase)]).await?
+    {
+        return Ok(Some(entry));
+    }
+    query(&client, api_url, wiki, &[("generator", "search"), ("gsrsearch", term), ("gsrlimit", "1")]).await
+}
+
+/// Runs one extracts query, with `pages` choosing the article, and reads the first page found.
+async fn query(client: &reqwest::Client, api_url: &str, wiki: Wiki, pages: &[(&str, &str)]) -> Result<Option<Entry>> {
+    let mut params = vec![
+        ("action", "query"),
+        ("format", "json"),
+        ("formatversion", "2"),
+        ("prop", "extracts|info|pageprops"),
+        ("explaintext", "1"),
+        ("redirects", "1"),
+        ("inprop", "url"),
+        ("ppprop", "disambiguation"),
+    ];
+    if wiki == Wiki::Wikipedia {
+        params.push(("exintro", "1"));
+    }
+    params.extend_from_slice(pages);
+
+    let json: Value = client
+        .get(api_url)
+        .query(&params)
+        .header(reqwest::header::USER_AGENT, concat!("emul/", env!("CARGO_PKG_VERSION"), " (IRC bot)"))
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach {}", api_url))?
+        .error_for_status()
+        .context("The wiki refused the request")?
+        .json()
+        .await
+        .context("The wiki sent invalid JSON")?;
+    if let Some(error) = json["error"]["info"].as_str() {
+        bail!("The wiki answered with an error: {}", error);
+    }
+    Ok(parse_entry(&json))
+}
+
+fn parse_entry(json: &Value) -> Option<Entry> {
+    let mut pages: Vec<&Value> = json["query"]["pages"].as_array()?.iter().collect();
+    // Search results come in any order, with their rank in `index`
+    pages.sort_by_key(|page| page["index"].as_u64().unwrap_or(0));
+    let page = pages.into_iter().find(|page| page["missing"].is_null() && page["invalid"].is_null())?;
+    let extract = page["extract"].as_str()?.trim();
+    if extract.is_empty() {
+        return None;
+    }
+    let truncated = extract.chars().count() > MAX_EXTRACT_CHARS;
+    Some(Entry {
+        title: page["title"].as_str()?.to_string(),
+        url: page["fullurl"].as_str().map(str::to_string),
+        extract: if truncated { extract.chars().take(MAX_EXTRACT_CHARS).collect() } else { extract.to_string() },
+        disambiguation: !page["pageprops"]["disambiguation"].is_null(),
+        truncated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+    use serde_json::json;
+
+    #[test]
+    fn test_api_url() {
+        assert_eq!(Wiki::Wikipedia.api_url("en").unwrap(), "https://en.wikipedia.org/w/api.php");
+        assert_eq!(Wiki::Wiktionary.api_url("zh-min-nan").unwrap(), "https://zh-min-nan.wiktionary.org/w/api.php");
+        assert!(Wiki::Wikipedia.api_url("evil.com/").is_err());
+        assert!(Wiki::Wikipedia.api_url("").is_err());
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        let found = json!({"query": {"pages": [{
+            "pageid": 1, "title": "Mercury", "extract": "Mercury may refer to:\n", "fullurl": "https://en.wikipedia.org/wiki/Mercury",
+            "pageprops": {"disambiguation": ""}
+        }]}});
+        let entry = parse_entry(&found).unwrap();
+        assert_eq!(entry.title, "Mercury");
+        assert_eq!(entry.extract, "Mercury may refer to:");
+        assert!(entry.disambiguation);
+        assert!(!entry.truncated);
+
+        let searched = json!({"query": {"pages": [
+            {"title": "Second", "index": 2, "extract": "Two"},
+            {"title": "First", "index": 1, "extract": "One"}
+        ]}});
+        assert_eq!(parse_entry(&searched).unwrap().title, "First");
+
+        let long = json!({"query": {"pages": [{"title": "Long", "extract": "x".repeat(MAX_EXTRACT_CHARS + 1)}]}});
+        let entry = parse_entry(&long).unwrap();
+        assert!(entry.truncated);
+        assert_eq!(entry.extract.len(), MAX_EXTRACT_CHARS);
+
+        assert_eq!(parse_entry(&json!({"query": {"pages": [{"title": "Nope", "missing": true}]}})), None);
+        assert_eq!(parse_entry(&json!({"batchcomplete": true})), None);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_falls_back_to_search() {
+        let mut server = mockito::Server::new_async().await;
+        let _missing = server
+            .mock("GET", "/w/api.php")
+            .match_query(Matcher::UrlEncoded("titles".into(), "rust langauge".into()))
+            .with_body(r#"{"query": {"pages": [{"title": "Rust langauge", "missing": true}]}}"#)
+            .create_async()
+            .await;
+        let search = server
+            .mock("GET", "/w/api.php")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("generator".into(), "search".into()),
+                Matcher::UrlEncoded("gsrsearch".into(), "rust langauge".into()),
+                Matcher::UrlEncoded("exintro".into(), "1".into()),
+            ]))
+            .with_body(r#"{"query": {"pages": [{"title": "Rust (programming language)", "index": 1, "extract": "Rust is a programming language."}]}}"#)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let api_url = format!("{}/w/api.php", server.url());
+        let entry = lookup_at(&HttpProxy::default(), &api_url, Wiki::Wikipedia, "rust langauge").await.unwrap().unwrap();
+        assert_eq!(entry.title, "Rust (programming language)");
+        assert_eq!(entry.extract, "Rust is a programming language.");
+        search.assert_async().await;
+    }
+}
@@ -0,0 +1,301 @@
+use crate::proxy::HttpProxy;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use thiserror::Error;
+use tokio::process::Command;
+use tokio::time::{Duration, timeout};
+
+const MAX_TRANSCRIPT_CHARS: usize = 30000; // Long videos get cut off here; the tool result is summarized anyway
+const YT_DLP_TIMEOUT: Duration = Duration::from_secs(60);
+const PREFERRED_LANGUAGE: &str = "en";
+
+#[derive(Error, Debug)]
+pub enum YoutubeError {
+    #[error("Not a YouTube video URL: {0}")]
+    NotAVideoUrl(String),
+    #[error("Network request failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+    #[error("This video has no captions or transcript")]
+    NoTranscript,
+    #[error("Could not parse the transcript: {0}")]
+    ParseError(String),
+    #[error("yt-dlp failed: {0}")]
+    YtDlpError(String),
+}
+
+/// A video's transcript, ready to hand to the model.
+#[derive(Debug, Clone)]
+pub struct Transcript {
+    pub title: Option<String>,
+    pub language: String,
+    pub auto_generated: bool,
+    pub text: String,
+    /// Whether `text` was cut short at MAX_TRANSCRIPT_CHARS.
+    pub truncated: bool,
+}
+
+impl Transcript {
+    /// Formats the transcript as a tool result.
+    pub fn describe(&self) -> String {
+        format!(
+            "Title: {}\nTranscript ({}{}){}:\n{}",
+            self.title.as_deref().unwrap_or("unknown"),
+            self.language,
+            if self.auto_generated { ", auto-generated" } else { "" },
+            if self.truncated { ", cut off partway through the video" } else { "" },
+            self.text
+        )
+    }
+}
+
+/// One caption track offered for a video.
+#[derive(Debug, Clone, PartialEq)]
+struct CaptionTrack {
+    url: String,
+    language: String,
+    auto_generated: bool,
+}
+
+/// Extracts the 11-character video id from the usual YouTube URL shapes
+/// (watch?v=, youtu.be/, /shorts/, /embed/, /live/).
+pub fn extract_video_id(video_url: &str) -> Option<String> {
+    let url = url::Url::parse(video_url).ok()?;
+    let host = url.host_str()?.trim_start_matches("www.").trim_start_matches("m.");
+    let id = match host {
+        "youtu.be" => url.path_segments()?.next()?.to_string(),
+        "youtube.com" | "music.youtube.com" => {
+            let mut segments = url.path_segments()?;
+            match segments.next()? {
+                "watch" => url.query_pairs().find(|(k, _)| k == "v")?.1.into_owned(),
+                "shorts" | "embed" | "live" => segments.next()?.to_string(),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let valid = id.len() == 11 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(id)
+}
+
+/// Fetches the transcript of a YouTube video. Caption tracks are read from the watch page
+/// (the timedtext endpoint); if that yields nothing, yt-dlp is asked instead, when installed.
+pub async fn fetch_transcript(proxy: &HttpProxy, video_url: &str) -> Result<Transcript, YoutubeError> {
+    let video_id = extract_video_id(video_url).ok_or_else(|| YoutubeError::NotAVideoUrl(video_url.to_string()))?;
+    let client = proxy.client()?;
+
+    match transcript_from_watch_page(&client, &video_id).await {
+        Ok(transcript) => return Ok(transcript),
+        Err(e) => tracing::info!(%video_id, error = %e, "No transcript from the watch page, trying yt-dlp"),
+    }
+    transcript_from_yt_dlp(&client, proxy, &video_id).await
+}
+
+async fn transcript_from_watch_page(client: &reqwest::Client, video_id: &str) -> Result<Transcript, YoutubeError> {
+    let html = client
+        .get(format!("https://www.youtube.com/watch?v={}", video_id))
+        .header("Accept-Language", "en-US,en;q=0.9")
+        .header("Cookie", "CONSENT=YES+1") // Skips the EU consent interstitial
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let tracks = extract_caption_tracks(&html)?;
+    let track = pick_track(&tracks).ok_or(YoutubeError::NoTranscript)?;
+    let title = extract_title(&html);
+    fetch_track(client, track, title).await
+}
+
+async fn transcript_from_yt_dlp(client: &reqwest::Client, proxy: &HttpProxy, video_id: &str) -> Result<Transcript, YoutubeError> {
+    let mut yt_dlp = Command::new("yt-dlp");
+    if let Some(proxy) = proxy.url() {
+        yt_dlp.arg("--proxy").arg(proxy);
+    }
+    let output = timeout(
+        YT_DLP_TIMEOUT,
+        yt_dlp
+            .args(["--dump-single-json", "--skip-download", "--no-warnings", "--"])
+            .arg(video_id)
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .map_err(|_| YoutubeError::YtDlpError("timed out".to_string()))?
+    .map_err(|e| YoutubeError::YtDlpError(e.to_string()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(YoutubeError::YtDlpError(stderr.trim().to_string()));
+    }
+    let info: Value =
+        serde_json::from_slice(&output.stdout).map_err(|e| YoutubeError::YtDlpError(e.to_string()))?;
+    let tracks = yt_dlp_caption_tracks(&info);
+    let track = pick_track(&tracks).ok_or(YoutubeError::NoTranscript)?;
+    let title = info["title"].as_str().map(str::to_string);
+    fetch_track(client, track, title).await
+}
+
+async fn fetch_track(
+    client: &reqwest::Client,
+    track: &CaptionTrack,
+    title: Option<String>,
+) -> Result<Transcript, YoutubeError> {
+    let mut url = url::Url::parse(&track.url).map_err(|e| YoutubeError::ParseError(e.to_string()))?;
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| k != "fmt")
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    url.query_pairs_mut().clear().extend_pairs(query).append_pair("fmt", "json3");
+
+    let body = client.get(url).send().await?.error_for_status()?.text().await?;
+    if body.trim().is_empty() {
+        return Err(YoutubeError::NoTranscript);
+    }
+    let json: Value = serde_json::from_str(&body).map_err(|e| YoutubeError::ParseError(e.to_string()))?;
+    let text = parse_json3_transcript(&json);
+    if text.is_empty() {
+        return Err(YoutubeError::NoTranscript);
+    }
+
+    let truncated = text.chars().count() > MAX_TRANSCRIPT_CHARS;
+    let text = if truncated {
+        text.chars().take(MAX_TRANSCRIPT_CHARS).collect()
+    } else {
+        text
+    };
+    Ok(Transcript {
+        title,
+        language: track.language.clone(),
+        auto_generated: track.auto_generated,
+        text,
+        truncated,
+    })
+}
+
+/// Reads the caption tracks out of the player response embedded in a watch page.
+fn extract_caption_tracks(html: &str) -> Result<Vec<CaptionTrack>, YoutubeError> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct PlayerCaptionTrack {
+        base_url: String,
+        language_code: String,
+        kind: Option<String>,
+    }
+
+    let start = html.find("\"captionTracks\":").ok_or(YoutubeError::NoTranscript)? + "\"captionTracks\":".len();
+    // The array is followed by the rest of the player response, so only read one JSON value
+    let tracks: Vec<PlayerCaptionTrack> = serde_json::Deserializer::from_str(&html[start..])
+        .into_iter()
+        .next()
+        .ok_or(YoutubeError::NoTranscript)?
+        .map_err(|e| YoutubeError::ParseError(e.to_string()))?;
+    Ok(tracks
+        .into_iter()
+        .map(|t| CaptionTrack {
+            url: t.base_url,
+            language: t.language_code,
+            auto_generated: t.kind.as_deref() == Some("asr"),
+        })
+        .collect())
+}
+
+fn extract_title(html: &str) -> Option<String> {
+    let document = scraper::Html::parse_document(html);
+    let selector = scraper::Selector::parse(r#"meta[name="title"]"#).ok()?;
+    let title = document.select(&selector).next()?.value().attr("content")?;
+    Some(title.to_string()).filter(|t| !t.is_empty())
+}
+
+/// Caption tracks from yt-dlp's JSON output, restricted to the json3 format.
+fn yt_dlp_caption_tracks(info: &Value) -> Vec<CaptionTrack> {
+    type Formats = HashMap<String, Vec<HashMap<String, Value>>>;
+    let mut tracks = Vec::new();
+    for (field, auto_generated) in [("subtitles", false), ("automatic_captions", true)] {
+        let Ok(languages) = serde_json::from_value::<Formats>(info[field].clone()) else {
+            continue;
+        };
+        for (language, formats) in languages {
+            let url = formats
+                .iter()
+                .find(|f| f.get("ext").and_then(Value::as_str) == Some("json3"))
+                .and_then(|f| f.get("url")?.as_str());
+            if let Some(url) = url {
+                tracks.push(CaptionTrack {
+                    url: url.to_string(),
+                    language,
+                    auto_generated,
+                });
+            }
+        }
+    }
+    tracks.sort_by(|a, b| a.language.cmp(&b.language));
+    tracks
+}
+
+/// Picks the best track: English before other languages, human-written before auto-generated.
+fn pick_track(tracks: &[CaptionTrack]) -> Option<&CaptionTrack> {
+    let is_preferred = |t: &CaptionTrack| t.language == PREFERRED_LANGUAGE || t.language.starts_with("en-");
+    tracks
+        .iter()
+        .find(|t| is_preferred(t) && !t.auto_generated)
+        .or_else(|| tracks.iter().find(|t| is_preferred(t)))
+        .or_else(|| tracks.iter().find(|t| !t.auto_generated))
+        .or_else(|| tracks.first())
+}
+
+/// Flattens a json3 caption document into plain text.
+fn parse_json3_transcript(json: &Value) -> String {
+    let mut text = String::new();
+    for event in json["events"].as_array().into_iter().flatten() {
+        for segment in event["segs"].as_array().into_iter().flatten() {
+            if let Some(utf8) = segment["utf8"].as_str() {
+                text.push_str(utf8);
+            }
+        }
+        text.push(' ');
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_extract_video_id() {
+        let id = Some("dQw4w9WgXcQ".to_string());
+        assert_eq!(extract_video_id("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=42"), id);
+        assert_eq!(extract_video_id("https://youtu.be/dQw4w9WgXcQ?si=abc"), id);
+        assert_eq!(extract_video_id("https://m.youtube.com/shorts/dQw4w9WgXcQ"), id);
+        assert_eq!(extract_video_id("https://www.youtube.com/embed/dQw4w9WgXcQ"), id);
+        assert_eq!(extract_video_id("https://www.youtube.com/channel/UC123"), None);
+        assert_eq!(extract_video_id("https://example.com/watch?v=dQw4w9WgXcQ"), None);
+        assert_eq!(extract_video_id("https://youtu.be/short"), None);
+    }
+
+    #[test]
+    fn test_extract_caption_tracks_and_pick() {
+        let html = r#"<script>var ytInitialPlayerResponse = {"captions":{"playerCaptionsTracklistRenderer":{"captionTracks":[{"baseUrl":"https://www.youtube.com/api/timedtext?v=x&lang=de","languageCode":"de"},{"baseUrl":"https://www.youtube.com/api/timedtext?v=x&lang=en&kind=asr","languageCode":"en","kind":"asr"}],"audioTracks":[]}}};</script>"#;
+        let tracks = extract_caption_tracks(html).unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].url, "https://www.youtube.com/api/timedtext?v=x&lang=de");
+        let picked = pick_track(&tracks).unwrap();
+        assert_eq!(picked.language, "en");
+        assert!(picked.auto_generated);
+        assert!(matches!(extract_caption_tracks("<html></html>"), Err(YoutubeError::NoTranscript)));
+    }
+
+    #[test]
+    fn test_parse_json3_transcript() {
+        let json = json!({
+            "events": [
+                { "tStartMs": 0, "segs": [{ "utf8": "Hello" }, { "utf8": " there," }] },
+                { "tStartMs": 1500 },
+                { "tStartMs": 2000, "segs": [{ "utf8": "\n" }, { "utf8": "general   Kenobi." }] }
+            ]
+        });
+        assert_eq!(parse_json3_transcript(&json), "Hello there, general Kenobi.");
+    }
+}